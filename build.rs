@@ -0,0 +1,37 @@
+use std::path::{Path, PathBuf};
+
+/// Hashes every file of the compiler and its standard library into `QEL_SOURCE_HASH`, so
+/// the compile cache keeps entries apart that different builds of the same version wrote.
+fn main() {
+    let mut files = vec![];
+    for dir in ["src", "std"] {
+        println!("cargo:rerun-if-changed={dir}");
+        collect(Path::new(dir), &mut files);
+    }
+    files.sort();
+    // 64-bit FNV-1a, as in `cache::fnv`
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for file in &files {
+        let name = file.to_string_lossy().replace('\\', "/");
+        let content = std::fs::read(file).unwrap_or_default();
+        for b in name.bytes().chain([0]).chain(content).chain([0]) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    println!("cargo:rustc-env=QEL_SOURCE_HASH={hash:016x}");
+}
+
+fn collect(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
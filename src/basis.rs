@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
+use crate::ir::{Instruction, Operand, Program};
+use crate::qudit;
+use crate::unitary::{phase_distance, unitary};
+
+/// Gates the translator knows, every basis has to be a subset of these.
+pub const GATES: [&str; 16] = [
+    "HAD", "PX", "PY", "PZ", "S", "SDG", "T", "TDG", "SX", "ID", "RX", "RY", "RZ", "CNT", "CY",
+    "TOF",
+];
+
+/// Instructions that are not gates and are passed through unchanged.
+const PASS: [&str; 8] = ["QAL", "FRE", "SET", "MES", "CPY", "BAL", "BCP", "BFR"];
+
+/// Predefined bases that can be named on the command line instead of listing the gates.
+pub const BASES: [(&str, &[&str]); 2] = [
+    ("rz-sx-cnt", &["RZ", "SX", "CNT"]),
+    ("had-t-cnt", &["HAD", "T", "CNT"]),
+];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Angle {
+    None,
+    /// the angle of the gate being replaced
+    Param,
+    Const(f64),
+}
+
+/// A gate of a rule body and the indices of the rule's qubits it acts on.
+pub type Step = (&'static str, &'static [usize], Angle);
+
+/// `gate` acting on `qubits` registers equals `body` up to global phase.
+pub struct Rule {
+    pub gate: &'static str,
+    pub qubits: usize,
+    pub body: &'static [Step],
+}
+
+const fn gate(op: &'static str, qubits: &'static [usize]) -> Step {
+    (op, qubits, Angle::None)
+}
+
+const fn rot(op: &'static str, qubits: &'static [usize], angle: Angle) -> Step {
+    (op, qubits, angle)
+}
+
+const Q: &[usize] = &[0];
+
+pub const RULES: &[Rule] = &[
+    Rule {
+        gate: "HAD",
+        qubits: 1,
+        body: &[
+            rot("RZ", Q, Angle::Const(FRAC_PI_2)),
+            gate("SX", Q),
+            rot("RZ", Q, Angle::Const(FRAC_PI_2)),
+        ],
+    },
+    Rule {
+        gate: "HAD",
+        qubits: 1,
+        body: &[
+            rot("RZ", Q, Angle::Const(PI)),
+            rot("RY", Q, Angle::Const(FRAC_PI_2)),
+        ],
+    },
+    Rule {
+        gate: "PX",
+        qubits: 1,
+        body: &[gate("SX", Q), gate("SX", Q)],
+    },
+    Rule {
+        gate: "PX",
+        qubits: 1,
+        body: &[rot("RX", Q, Angle::Const(PI))],
+    },
+    Rule {
+        gate: "PX",
+        qubits: 1,
+        body: &[gate("HAD", Q), gate("PZ", Q), gate("HAD", Q)],
+    },
+    Rule {
+        gate: "PY",
+        qubits: 1,
+        body: &[gate("PZ", Q), gate("PX", Q)],
+    },
+    Rule {
+        gate: "PZ",
+        qubits: 1,
+        body: &[rot("RZ", Q, Angle::Const(PI))],
+    },
+    Rule {
+        gate: "PZ",
+        qubits: 1,
+        body: &[gate("S", Q), gate("S", Q)],
+    },
+    Rule {
+        gate: "S",
+        qubits: 1,
+        body: &[rot("RZ", Q, Angle::Const(FRAC_PI_2))],
+    },
+    Rule {
+        gate: "S",
+        qubits: 1,
+        body: &[gate("T", Q), gate("T", Q)],
+    },
+    Rule {
+        gate: "SDG",
+        qubits: 1,
+        body: &[rot("RZ", Q, Angle::Const(-FRAC_PI_2))],
+    },
+    Rule {
+        gate: "SDG",
+        qubits: 1,
+        body: &[gate("PZ", Q), gate("S", Q)],
+    },
+    Rule {
+        gate: "T",
+        qubits: 1,
+        body: &[rot("RZ", Q, Angle::Const(FRAC_PI_4))],
+    },
+    Rule {
+        gate: "TDG",
+        qubits: 1,
+        body: &[rot("RZ", Q, Angle::Const(-FRAC_PI_4))],
+    },
+    Rule {
+        gate: "TDG",
+        qubits: 1,
+        body: &[gate("SDG", Q), gate("T", Q)],
+    },
+    Rule {
+        gate: "SX",
+        qubits: 1,
+        body: &[gate("HAD", Q), gate("S", Q), gate("HAD", Q)],
+    },
+    Rule {
+        gate: "SX",
+        qubits: 1,
+        body: &[rot("RX", Q, Angle::Const(FRAC_PI_2))],
+    },
+    Rule {
+        gate: "ID",
+        qubits: 1,
+        body: &[],
+    },
+    Rule {
+        gate: "RX",
+        qubits: 1,
+        body: &[gate("HAD", Q), rot("RZ", Q, Angle::Param), gate("HAD", Q)],
+    },
+    Rule {
+        gate: "RY",
+        qubits: 1,
+        body: &[gate("SDG", Q), rot("RX", Q, Angle::Param), gate("S", Q)],
+    },
+    Rule {
+        gate: "RZ",
+        qubits: 1,
+        body: &[gate("HAD", Q), rot("RX", Q, Angle::Param), gate("HAD", Q)],
+    },
+    Rule {
+        gate: "CY",
+        qubits: 2,
+        body: &[gate("SDG", &[1]), gate("CNT", &[0, 1]), gate("S", &[1])],
+    },
+    // the textbook Toffoli with 6 CNOTs and 7 T/TDG
+    Rule {
+        gate: "TOF",
+        qubits: 3,
+        body: &[
+            gate("HAD", &[2]),
+            gate("CNT", &[1, 2]),
+            gate("TDG", &[2]),
+            gate("CNT", &[0, 2]),
+            gate("T", &[2]),
+            gate("CNT", &[1, 2]),
+            gate("TDG", &[2]),
+            gate("CNT", &[0, 2]),
+            gate("T", &[1]),
+            gate("T", &[2]),
+            gate("HAD", &[2]),
+            gate("CNT", &[0, 1]),
+            gate("T", &[0]),
+            gate("TDG", &[1]),
+            gate("CNT", &[0, 1]),
+        ],
+    },
+];
+
+/// Reads a basis given by name (`rz-sx-cnt`) or as a comma separated gate list (`HAD,T,CNT`).
+pub fn parse_basis(s: &str) -> Result<Vec<String>, String> {
+    if let Some((_, gates)) = BASES.iter().find(|(name, _)| *name == s) {
+        return Ok(gates.iter().map(|g| g.to_string()).collect());
+    }
+    let gates: Vec<String> = s.split(',').map(|g| g.trim().to_uppercase()).collect();
+    match gates.iter().find(|g| !GATES.contains(&g.as_str())) {
+        Some(g) => Err(format!("BASIS_ERROR: unknown gate '{g}' in basis '{s}'")),
+        None => Ok(gates),
+    }
+}
+
+fn instantiate(rule: &Rule, qubits: &[Operand], angle: Option<&Operand>) -> Vec<Instruction> {
+    rule.body
+        .iter()
+        .map(|(op, on, a)| {
+            let mut args: Vec<Operand> = on.iter().map(|k| qubits[*k].clone()).collect();
+            match a {
+                Angle::None => {}
+                Angle::Param => args.extend(angle.cloned()),
+                Angle::Const(c) => args.push(Operand::Num(*c)),
+            }
+            Instruction::new(op, args)
+        })
+        .collect()
+}
+
+/// RZ by a multiple of pi/4 is a power of T up to global phase.
+fn t_power(ins: &Instruction) -> Option<Vec<Instruction>> {
+    let Some(Operand::Num(a)) = ins.args.get(1) else {
+        return None;
+    };
+    let k = a / FRAC_PI_4;
+    if (k - k.round()).abs() > 1e-9 {
+        return None;
+    }
+    let t = Instruction::new("T", vec![ins.args[0].clone()]);
+    Some(vec![t; k.round().rem_euclid(8.0) as usize])
+}
+
+/// Replaces the qubit operands by `$0`, `$1`, ... so results can be shared between
+/// gates that only differ in the registers they act on.
+fn template(ins: &Instruction) -> (Instruction, Vec<Operand>) {
+    let mut regs = vec![];
+    let mut t = ins.clone();
+    for a in t.args.iter_mut() {
+        if let Operand::Qubit(_) = a {
+            let k = regs.len();
+            regs.push(a.clone());
+            *a = Operand::Qubit(k.to_string());
+        }
+    }
+    (t, regs)
+}
+
+struct Translator<'a> {
+    basis: &'a [String],
+    memo: HashMap<String, Option<Vec<Instruction>>>,
+    visiting: Vec<String>,
+    /// how often a search gave up on a gate because it was already being expanded
+    cuts: usize,
+}
+
+impl Translator<'_> {
+    /// The shortest rewrite of `ins` into basis gates, if there is one.
+    fn expand(&mut self, ins: &Instruction) -> Option<Vec<Instruction>> {
+        if self.basis.contains(&ins.op) {
+            return Some(vec![ins.clone()]);
+        }
+        let (t, regs) = template(ins);
+        let key = t.to_string();
+        let found = match self.memo.get(&key) {
+            Some(found) => found.clone(),
+            None if self.visiting.contains(&key) => {
+                self.cuts += 1;
+                return None;
+            }
+            None => {
+                self.visiting.push(key.clone());
+                let cuts = self.cuts;
+                let found = self.search(&t);
+                self.visiting.pop();
+                // a failure caused by a cut only holds inside the expansion that cut it
+                if found.is_some() || self.cuts == cuts {
+                    self.memo.insert(key, found.clone());
+                }
+                found
+            }
+        };
+        let rename = |mut i: Instruction| {
+            for a in i.args.iter_mut() {
+                if let Operand::Qubit(k) = a {
+                    *a = regs[k.parse::<usize>().unwrap()].clone();
+                }
+            }
+            i
+        };
+        found.map(|body| body.into_iter().map(rename).collect())
+    }
+
+    fn search(&mut self, t: &Instruction) -> Option<Vec<Instruction>> {
+        let qubits: Vec<Operand> = t
+            .args
+            .iter()
+            .filter(|a| matches!(a, Operand::Qubit(_)))
+            .cloned()
+            .collect();
+        let angle = t.args.iter().find(|a| !matches!(a, Operand::Qubit(_)));
+        let mut candidates: Vec<Vec<Instruction>> = RULES
+            .iter()
+            .filter(|r| r.gate == t.op && r.qubits == qubits.len())
+            .map(|r| instantiate(r, &qubits, angle))
+            .collect();
+        if t.op == "RZ" {
+            candidates.extend(t_power(t));
+        }
+        let mut best: Option<Vec<Instruction>> = None;
+        'rules: for body in candidates {
+            let mut out = vec![];
+            for step in &body {
+                match self.expand(step) {
+                    Some(gates) => out.extend(gates),
+                    None => continue 'rules,
+                }
+            }
+            if best.as_ref().is_none_or(|b| out.len() < b.len()) {
+                best = Some(out);
+            }
+        }
+        best
+    }
+}
+
+/// Lowers every gate of the program into the given basis using the rule library.
+/// Qudit instructions are first rewritten into the qubit gates they stand for.
+pub fn translate(program: &Program, basis: &[String]) -> Result<Program, String> {
+    let program = qudit::lower(program)?;
+    let mut t = Translator {
+        basis,
+        memo: HashMap::new(),
+        visiting: vec![],
+        cuts: 0,
+    };
+    let mut instructions = vec![];
+    for ins in &program.instructions {
+        if PASS.contains(&ins.op.as_str()) {
+            instructions.push(ins.clone());
+            continue;
+        }
+        match t.expand(ins) {
+            Some(gates) => instructions.extend(gates),
+            None => {
+                return Err(format!(
+                    "BASIS_ERROR: '{ins}' can not be expressed in the basis {{{}}}",
+                    basis.join(", ")
+                ))
+            }
+        }
+    }
+    Ok(Program { instructions })
+}
+
+/// Checks every rule of the library with the simulator. Returns one line per rule
+/// together with the largest deviation from the replaced gate up to global phase.
+pub fn verify() -> Result<Vec<(String, f64)>, String> {
+    let sample = Operand::Num(0.7);
+    let mut cases: Vec<(Instruction, Vec<Instruction>)> = vec![];
+    for rule in RULES {
+        let qubits: Vec<Operand> = (0..rule.qubits)
+            .map(|k| Operand::Qubit(format!("q{k}")))
+            .collect();
+        let mut args = qubits.clone();
+        if rule.gate.starts_with('R') {
+            args.push(sample.clone());
+        }
+        let body = instantiate(rule, &qubits, Some(&sample));
+        cases.push((Instruction::new(rule.gate, args), body));
+    }
+    for k in 0..8 {
+        let q = Operand::Qubit("q0".to_string());
+        let rz = Instruction::new("RZ", vec![q, Operand::Num(k as f64 * FRAC_PI_4)]);
+        let body = t_power(&rz).unwrap();
+        cases.push((rz, body));
+    }
+
+    let mut results = vec![];
+    for (gate, body) in cases {
+        let names: Vec<String> = gate.qubits().iter().map(|q| q.to_string()).collect();
+        let lhs = unitary(
+            &Program {
+                instructions: vec![gate.clone()],
+            },
+            &names,
+            &[],
+        )?;
+        let rhs = unitary(
+            &Program {
+                instructions: body.clone(),
+            },
+            &names,
+            &[],
+        )?;
+        let body: Vec<String> = body.iter().map(|i| i.to_string()).collect();
+        let line = format!("{gate} => {}", body.join("; "));
+        results.push((line, phase_distance(&lhs, &rhs)));
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_program;
+
+    #[test]
+    fn every_rule_matches_its_gate() {
+        for (rule, error) in verify().unwrap() {
+            assert!(error < 1e-9, "{rule} is off by {error:.1e}");
+        }
+    }
+
+    #[test]
+    fn predefined_bases_are_equivalent() {
+        // angles both bases can express, {HAD, T, CNT} only has multiples of pi/4
+        let text = format!(
+            "QAL & 0 $ \"a\"\nQAL & 0 $ \"b\"\nQAL & 0 $ \"c\"\n\
+             HAD $a\nTOF $a $b $c\nCY $b $c\nRX $a {FRAC_PI_4}\nRY $b {FRAC_PI_2}\nS $c\nTDG $a\nPY $b\n"
+        );
+        let program = parse_program(&text).unwrap();
+        let qubits: Vec<String> = ["a", "b", "c"].iter().map(|q| q.to_string()).collect();
+        let expected = unitary(&program, &qubits, &[]).unwrap();
+        for (name, _) in BASES {
+            let basis = parse_basis(name).unwrap();
+            let translated = translate(&program, &basis).unwrap();
+            for ins in &translated.instructions {
+                assert!(
+                    PASS.contains(&ins.op.as_str()) || basis.contains(&ins.op),
+                    "{ins} is not in {name}"
+                );
+            }
+            let error = phase_distance(&expected, &unitary(&translated, &qubits, &[]).unwrap());
+            assert!(error < 1e-9, "{name} is off by {error:.1e}");
+        }
+    }
+}
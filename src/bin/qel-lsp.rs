@@ -0,0 +1,15 @@
+use std::io;
+
+use tokenizer::lsp::{quiet_frontend_panics, serve};
+
+fn main() {
+    // the frontend panics on a lot of malformed input, keep the default hook from writing
+    // backtraces into the editor's log for every keystroke
+    quiet_frontend_panics();
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    if let Err(e) = serve(stdin.lock(), stdout.lock()) {
+        eprintln!("qel-lsp: {e}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,632 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::code_gen::{code_gen, code_gen_into, Comptime, View};
+use crate::json::{self, Json};
+use crate::parser::{ASTNode, Modifier};
+use crate::tokenizer::{rm_comments, tokenize_code, Token};
+
+/// Part of every key, entries written by another build of the compiler are never read. The
+/// hash covers `src/` and `std/` and is computed by `build.rs`.
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "-", env!("QEL_SOURCE_HASH"));
+
+/// 64-bit FNV-1a.
+pub fn fnv(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// `src` without comments, cut after every line that closes a top-level block. A function
+/// keeps its key as long as its own text and the statements since the previous block stay
+/// the same. Tokens never span a line, except the body of an `asm` block which ends with its
+/// block, so tokenizing the chunks one by one gives the tokens of the whole source.
+fn chunks(src: &str) -> Vec<String> {
+    let text = rm_comments(src);
+    // the tokenizer mixes byte and char positions, only ASCII tokenizes the same in pieces
+    if !text.is_ascii() {
+        return vec![text];
+    }
+    let mut out = vec![];
+    let mut chunk = String::new();
+    let mut depth = 0i32;
+    for line in text.split_inclusive('\n') {
+        let before = depth;
+        depth += line.matches('{').count() as i32 - line.matches('}').count() as i32;
+        chunk.push_str(line);
+        if before > 0 && depth <= 0 {
+            out.push(std::mem::take(&mut chunk));
+        }
+    }
+    if !chunk.is_empty() {
+        out.push(chunk);
+    }
+    out
+}
+
+/// A token value on one line of an entry, `asm` bodies contain line breaks.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Moves the declarations of a cached statement into `comptime`, which keeps its code and
+/// functions.
+fn restore(comptime: &mut Comptime, declarations: Comptime) {
+    *comptime = Comptime {
+        program: std::mem::take(&mut comptime.program),
+        functions: std::mem::take(&mut comptime.functions),
+        function_info: std::mem::take(&mut comptime.function_info),
+        function_args: std::mem::take(&mut comptime.function_args),
+        ..declarations
+    };
+}
+
+/// Everything of `comptime` but its code and functions, see `declarations`.
+fn declarations_json(comptime: &Comptime) -> Json {
+    fn map<T>(map: &HashMap<String, T>, value: impl Fn(&T) -> Json) -> Json {
+        Json::Obj(map.iter().map(|(k, v)| (k.clone(), value(v))).collect())
+    }
+    let num = |n: usize| Json::Num(n as f64);
+    let nums = |ns: &Vec<usize>| Json::Arr(ns.iter().map(|n| num(*n)).collect());
+    Json::obj(vec![
+        (
+            "vars",
+            map(&comptime.vars, |(a, b)| Json::Arr(vec![num(*a), num(*b)])),
+        ),
+        (
+            "var_info",
+            map(&comptime.var_info, |(constant, size, type_)| {
+                Json::Arr(vec![Json::Bool(*constant), num(*size), ast_json(type_)])
+            }),
+        ),
+        ("iterators", map(&comptime.iterators, |n| num(*n))),
+        ("aliass", map(&comptime.aliass, |a| Json::str(a))),
+        (
+            "views",
+            map(&comptime.views, |v| {
+                let regs = v.regs.iter().map(|r| Json::str(r)).collect();
+                Json::Arr(vec![Json::Arr(regs), nums(&v.dims)])
+            }),
+        ),
+        ("arrays", map(&comptime.arrays, nums)),
+        ("i", Json::Num(comptime.i as f64)),
+        ("j", Json::Num(comptime.j as f64)),
+        ("ancillas", Json::Num(comptime.ancillas as f64)),
+        ("phase", Json::Num(comptime.phase)),
+    ])
+}
+
+/// The declarations `declarations_json` wrote, in an otherwise empty `Comptime`.
+fn declarations(json: &Json) -> Option<Comptime> {
+    fn map<T>(
+        json: Option<&Json>,
+        value: impl Fn(&Json) -> Option<T>,
+    ) -> Option<HashMap<String, T>> {
+        match json? {
+            Json::Obj(fields) => fields
+                .iter()
+                .map(|(k, v)| Some((k.clone(), value(v)?)))
+                .collect(),
+            _ => None,
+        }
+    }
+    let num = |j: &Json| j.as_f64().map(|n| n as usize);
+    let items = |j: &Json| match j {
+        Json::Arr(items) => Some(items.clone()),
+        _ => None,
+    };
+    let nums = |j: &Json| items(j)?.iter().map(num).collect::<Option<Vec<usize>>>();
+    Some(Comptime {
+        vars: map(json.get("vars"), |j| match items(j)?.as_slice() {
+            [a, b] => Some((num(a)?, num(b)?)),
+            _ => None,
+        })?,
+        var_info: map(json.get("var_info"), |j| match items(j)?.as_slice() {
+            [Json::Bool(constant), size, type_] => Some((*constant, num(size)?, json_ast(type_)?)),
+            _ => None,
+        })?,
+        iterators: map(json.get("iterators"), num)?,
+        aliass: map(json.get("aliass"), |j| j.as_str().map(str::to_string))?,
+        views: map(json.get("views"), |j| match items(j)?.as_slice() {
+            [regs, dims] => Some(View {
+                regs: items(regs)?
+                    .iter()
+                    .map(|r| r.as_str().map(str::to_string))
+                    .collect::<Option<_>>()?,
+                dims: nums(dims)?,
+            }),
+            _ => None,
+        })?,
+        arrays: map(json.get("arrays"), nums)?,
+        i: json.get("i")?.as_f64()? as i32,
+        j: json.get("j")?.as_f64()? as i32,
+        ancillas: json.get("ancillas")?.as_f64()? as i32,
+        phase: json.get("phase")?.as_f64()?,
+        ..Comptime::new()
+    })
+}
+
+/// An AST node as JSON, an array of the name of its variant and its fields.
+fn ast_json(node: &ASTNode) -> Json {
+    let s = |s: &str| Json::str(s);
+    let num = |n: f64| Json::Num(n);
+    let opt = |node: &Option<Box<ASTNode>>| node.as_deref().map_or(Json::Null, ast_json);
+    let all = |nodes: &[ASTNode]| Json::Arr(nodes.iter().map(ast_json).collect());
+    let (variant, fields) = match node {
+        ASTNode::Program(nodes) => ("Program", vec![all(nodes)]),
+        ASTNode::FunctionDef {
+            name,
+            ret_type,
+            in_type,
+            body,
+        } => (
+            "FunctionDef",
+            vec![s(name), ast_json(ret_type), all(in_type), opt(body)],
+        ),
+        ASTNode::VariableDecl {
+            name,
+            value,
+            type_,
+            token,
+        } => (
+            "VariableDecl",
+            vec![s(name), opt(value), opt(type_), num(*token as f64)],
+        ),
+        ASTNode::Block(nodes) => ("Block", vec![all(nodes)]),
+        ASTNode::For {
+            container,
+            alias,
+            body,
+        } => ("For", vec![ast_json(container), s(alias), opt(body)]),
+        ASTNode::Return(value) => ("Return", vec![ast_json(value)]),
+        ASTNode::FunctionCall { name, args } => ("FunctionCall", vec![s(name), all(args)]),
+        ASTNode::GateCall { name, args } => ("GateCall", vec![s(name), all(args)]),
+        ASTNode::Struct { name, types } => ("Struct", vec![s(name), all(types)]),
+        ASTNode::ArrayIndex(i) => ("ArrayIndex", vec![num(*i as f64)]),
+        ASTNode::ArrayAccess { name, index } => {
+            ("ArrayAccess", vec![ast_json(name), ast_json(index)])
+        }
+        ASTNode::Reference { value } => ("Reference", vec![ast_json(value)]),
+        ASTNode::Dereference { value } => ("Dereference", vec![ast_json(value)]),
+        ASTNode::Void => ("Void", vec![]),
+        ASTNode::Qbit => ("Qbit", vec![]),
+        ASTNode::Bit => ("Bit", vec![]),
+        ASTNode::Custom => ("Custom", vec![]),
+        ASTNode::Num(n) => ("Num", vec![num(*n as f64)]),
+        ASTNode::Angle(a) => ("Angle", vec![num(*a)]),
+        ASTNode::Type { name, specifier } => ("Type", vec![s(name), ast_json(specifier)]),
+        ASTNode::Range {
+            start,
+            end,
+            step,
+            inclusive,
+        } => (
+            "Range",
+            vec![
+                ast_json(start),
+                ast_json(end),
+                opt(step),
+                Json::Bool(*inclusive),
+            ],
+        ),
+        ASTNode::BinOp { op, lhs, rhs } => (
+            "BinOp",
+            vec![s(&op.to_string()), ast_json(lhs), ast_json(rhs)],
+        ),
+        ASTNode::Concat { lhs, rhs } => ("Concat", vec![ast_json(lhs), ast_json(rhs)]),
+        ASTNode::VariableCall { name } => ("VariableCall", vec![s(name)]),
+        ASTNode::Assignment { lval, value } => {
+            ("Assignment", vec![ast_json(lval), ast_json(value)])
+        }
+        ASTNode::StructAccess { structure, member } => {
+            ("StructAccess", vec![ast_json(structure), s(member)])
+        }
+        ASTNode::ArrayType { type_, size } => ("ArrayType", vec![ast_json(type_), ast_json(size)]),
+        ASTNode::ExternArg { idx } => ("ExternArg", vec![ast_json(idx)]),
+        ASTNode::IntCall { name } => ("IntCall", vec![s(name)]),
+        ASTNode::Asm(instructions) => (
+            "Asm",
+            vec![Json::Arr(
+                instructions
+                    .iter()
+                    .map(|(op, operands)| Json::Arr(vec![s(op), all(operands)]))
+                    .collect(),
+            )],
+        ),
+        ASTNode::AsmOperand(text) => ("AsmOperand", vec![s(text)]),
+        ASTNode::PointerType { type_ } => ("PointerType", vec![ast_json(type_)]),
+        ASTNode::Qdit => ("Qdit", vec![]),
+        ASTNode::Modified { modifier, call } => {
+            let modifier = match modifier {
+                Modifier::Ctrl(n) => Json::Arr(vec![s("Ctrl"), num(*n as f64)]),
+                Modifier::Inv => Json::Arr(vec![s("Inv")]),
+                Modifier::Pow(n) => Json::Arr(vec![s("Pow"), num(*n as f64)]),
+            };
+            ("Modified", vec![modifier, ast_json(call)])
+        }
+    };
+    Json::Arr(std::iter::once(s(variant)).chain(fields).collect())
+}
+
+/// The AST node `ast_json` wrote, `None` for anything else.
+fn json_ast(json: &Json) -> Option<ASTNode> {
+    let Json::Arr(items) = json else {
+        return None;
+    };
+    let (variant, fields) = items.split_first()?;
+    let field = |k: usize| fields.get(k);
+    let s = |k: usize| field(k)?.as_str().map(str::to_string);
+    let num = |k: usize| field(k)?.as_f64();
+    let one = |k: usize| json_ast(field(k)?).map(Box::new);
+    let opt = |k: usize| match field(k)? {
+        Json::Null => Some(None),
+        node => json_ast(node).map(|n| Some(Box::new(n))),
+    };
+    let all = |k: usize| json_asts(field(k)?);
+    Some(match variant.as_str()? {
+        "Program" => ASTNode::Program(all(0)?),
+        "FunctionDef" => ASTNode::FunctionDef {
+            name: s(0)?,
+            ret_type: one(1)?,
+            in_type: all(2)?,
+            body: opt(3)?,
+        },
+        "VariableDecl" => ASTNode::VariableDecl {
+            name: s(0)?,
+            value: opt(1)?,
+            type_: opt(2)?,
+            token: num(3)? as i32,
+        },
+        "Block" => ASTNode::Block(all(0)?),
+        "For" => ASTNode::For {
+            container: one(0)?,
+            alias: s(1)?,
+            body: opt(2)?,
+        },
+        "Return" => ASTNode::Return(one(0)?),
+        "FunctionCall" => ASTNode::FunctionCall {
+            name: s(0)?,
+            args: all(1)?,
+        },
+        "GateCall" => ASTNode::GateCall {
+            name: s(0)?,
+            args: all(1)?,
+        },
+        "Struct" => ASTNode::Struct {
+            name: s(0)?,
+            types: all(1)?,
+        },
+        "ArrayIndex" => ASTNode::ArrayIndex(num(0)? as u32),
+        "ArrayAccess" => ASTNode::ArrayAccess {
+            name: one(0)?,
+            index: one(1)?,
+        },
+        "Reference" => ASTNode::Reference { value: one(0)? },
+        "Dereference" => ASTNode::Dereference { value: one(0)? },
+        "Void" => ASTNode::Void,
+        "Qbit" => ASTNode::Qbit,
+        "Bit" => ASTNode::Bit,
+        "Custom" => ASTNode::Custom,
+        "Num" => ASTNode::Num(num(0)? as i32),
+        "Angle" => ASTNode::Angle(num(0)?),
+        "Type" => ASTNode::Type {
+            name: s(0)?,
+            specifier: one(1)?,
+        },
+        "Range" => ASTNode::Range {
+            start: one(0)?,
+            end: one(1)?,
+            step: opt(2)?,
+            inclusive: matches!(field(3)?, Json::Bool(true)),
+        },
+        "BinOp" => ASTNode::BinOp {
+            op: s(0)?.chars().next()?,
+            lhs: one(1)?,
+            rhs: one(2)?,
+        },
+        "Concat" => ASTNode::Concat {
+            lhs: one(0)?,
+            rhs: one(1)?,
+        },
+        "VariableCall" => ASTNode::VariableCall { name: s(0)? },
+        "Assignment" => ASTNode::Assignment {
+            lval: one(0)?,
+            value: one(1)?,
+        },
+        "StructAccess" => ASTNode::StructAccess {
+            structure: one(0)?,
+            member: s(1)?,
+        },
+        "ArrayType" => ASTNode::ArrayType {
+            type_: one(0)?,
+            size: one(1)?,
+        },
+        "ExternArg" => ASTNode::ExternArg { idx: one(0)? },
+        "IntCall" => ASTNode::IntCall { name: s(0)? },
+        "Asm" => {
+            let Json::Arr(instructions) = field(0)? else {
+                return None;
+            };
+            let instruction = |j: &Json| match j {
+                Json::Arr(parts) => match parts.as_slice() {
+                    [Json::Str(op), operands] => Some((op.clone(), json_asts(operands)?)),
+                    _ => None,
+                },
+                _ => None,
+            };
+            ASTNode::Asm(
+                instructions
+                    .iter()
+                    .map(instruction)
+                    .collect::<Option<_>>()?,
+            )
+        }
+        "AsmOperand" => ASTNode::AsmOperand(s(0)?),
+        "PointerType" => ASTNode::PointerType { type_: one(0)? },
+        "Qdit" => ASTNode::Qdit,
+        "Modified" => {
+            let Json::Arr(modifier) = field(0)? else {
+                return None;
+            };
+            let n = || modifier.get(1)?.as_f64().map(|n| n as u32);
+            let modifier = match modifier.first()?.as_str()? {
+                "Ctrl" => Modifier::Ctrl(n()?),
+                "Inv" => Modifier::Inv,
+                "Pow" => Modifier::Pow(n()?),
+                _ => return None,
+            };
+            ASTNode::Modified {
+                modifier,
+                call: one(1)?,
+            }
+        }
+        _ => return None,
+    })
+}
+
+fn json_asts(json: &Json) -> Option<Vec<ASTNode>> {
+    match json {
+        Json::Arr(nodes) => nodes.iter().map(json_ast).collect(),
+        _ => None,
+    }
+}
+
+/// Artifacts of earlier compilations in a directory, one file per entry named after the
+/// hash of everything it was computed from. Missing, unreadable or damaged entries count as
+/// misses, and an entry that can't be written is recomputed next time.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    pub dir: PathBuf,
+    /// entries reused and recomputed since the cache was opened
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Cache {
+        Cache {
+            dir: dir.into(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn path(&self, kind: &str, input: &str) -> PathBuf {
+        let key = fnv(format!("{VERSION}\0{kind}\0{input}").as_bytes());
+        self.dir.join(format!("{kind}-{key:016x}.txt"))
+    }
+
+    /// The entry for `input`, if there is one and `decode` accepts it.
+    fn read<T>(
+        &mut self,
+        kind: &str,
+        input: &str,
+        decode: impl FnOnce(String) -> Option<T>,
+    ) -> Option<T> {
+        let entry = std::fs::read_to_string(self.path(kind, input))
+            .ok()
+            .and_then(decode);
+        match entry {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        entry
+    }
+
+    fn write(&self, kind: &str, input: &str, content: &str) {
+        let path = self.path(kind, input);
+        let tmp = path.with_extension("tmp");
+        // another compiler writing the same entry writes the same content
+        let _ = std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(&tmp, content))
+            .and_then(|_| std::fs::rename(&tmp, &path));
+    }
+
+    /// The tokens of `src` before declarations are resolved, as `tokenize` returns them.
+    pub fn tokenize(&mut self, src: &str) -> Vec<Token> {
+        let mut tokens = vec![];
+        for chunk in chunks(src) {
+            let cached = self.read("tokens", &chunk, |entry| {
+                entry
+                    .lines()
+                    .map(|line| {
+                        let (token, value) = line.split_once(' ')?;
+                        Some(Token {
+                            token: token.parse().ok()?,
+                            value: unescape(value),
+                        })
+                    })
+                    .collect::<Option<Vec<Token>>>()
+            });
+            let chunk_tokens = match cached {
+                Some(t) => t,
+                None => {
+                    let t = tokenize_code(&chunk);
+                    let entry: String = t
+                        .iter()
+                        .map(|t| format!("{} {}\n", t.token, escape(&t.value)))
+                        .collect();
+                    self.write("tokens", &chunk, &entry);
+                    t
+                }
+            };
+            tokens.extend(chunk_tokens);
+        }
+        tokens
+    }
+
+    /// `code_gen::code_gen` one top-level statement at a time. The entry of a statement is
+    /// keyed on it and every statement before it, and holds the code it generated and the
+    /// declarations after it. An edit only regenerates the statements from the first changed
+    /// one on, an unchanged function keeps the code of the statements before it. Function
+    /// definitions only record the function and run every time.
+    pub fn code_gen(&mut self, ast: ASTNode) -> Result<Comptime, String> {
+        let ASTNode::Program(nodes) = ast else {
+            return code_gen(ast);
+        };
+        let mut comptime = Comptime::new();
+        let mut key = String::new();
+        // declarations after the last statement read from the cache, needed by the next
+        // statement that is generated
+        let mut cached: Option<Comptime> = None;
+        for node in nodes {
+            key = format!("{:016x} {node:?}", fnv(key.as_bytes()));
+            if !matches!(node, ASTNode::FunctionDef { .. }) {
+                let entry = self.read("statement", &key, |entry| {
+                    let entry = json::parse(&entry).ok()?;
+                    Some((
+                        entry.get("program")?.as_str()?.to_string(),
+                        declarations(entry.get("declarations")?)?,
+                    ))
+                });
+                if let Some((program, declarations)) = entry {
+                    comptime.program.push_str(&program);
+                    cached = Some(declarations);
+                    continue;
+                }
+                if let Some(declarations) = cached.take() {
+                    restore(&mut comptime, declarations);
+                }
+            }
+            let start = comptime.program.len();
+            code_gen_into(&mut std::iter::once(node.clone()).peekable(), &mut comptime)?;
+            if !matches!(node, ASTNode::FunctionDef { .. }) {
+                let entry = Json::obj(vec![
+                    ("program", Json::str(&comptime.program[start..])),
+                    ("declarations", declarations_json(&comptime)),
+                ]);
+                self.write("statement", &key, &entry.to_string());
+            }
+        }
+        if let Some(declarations) = cached {
+            restore(&mut comptime, declarations);
+        }
+        Ok(comptime)
+    }
+
+    /// One line for the compiler notes.
+    pub fn note(&self) -> String {
+        format!(
+            "cache {}: {} entries reused, {} computed",
+            self.dir.display(),
+            self.hits,
+            self.misses
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::Loader;
+
+    /// Functions, views, grids, loops, `asm`, modifiers and angles.
+    const PROGRAM: &str = "void kette(r: qbit[3]) {
+  for (i in 0..2){
+    CNT(r[i], r[i+1]);
+  }
+}
+void bell(x: qbit, y: qbit) {
+    HAD(x);
+    CNT(x, y);
+}
+let reg: qbit[4];
+let grid: qbit[2][3];
+let a: qbit;
+let b: qbit;
+let t: qbit;
+kette(reg[1..4]);
+kette(reg[0..2] ++ a);
+for (zeile in grid){
+  kette(zeile);
+}
+for (i in 0..=2 by 2){
+  asm { CNT reg[i] reg[i + 1] }
+}
+ctrl(2) RY(a, b, t, $0);
+inv bell(a, b);
+pow(2) S(t);
+RZ(t, 0.5);
+";
+
+    fn ast(src: &str) -> ASTNode {
+        Loader::default().load(src, None).unwrap()
+    }
+
+    #[test]
+    fn ast_survives_json() {
+        let demo = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/t2.qel")).unwrap();
+        for src in [PROGRAM, demo.as_str()] {
+            let ast = ast(src);
+            let json = json::parse(&ast_json(&ast).to_string()).unwrap();
+            assert_eq!(
+                format!("{:?}", json_ast(&json).unwrap()),
+                format!("{ast:?}")
+            );
+        }
+    }
+
+    #[test]
+    fn edits_regenerate_from_the_first_changed_statement() {
+        let dir = std::env::temp_dir().join(format!("qel-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let statements = 13;
+        let edited = PROGRAM.replace("pow(2) S(t);", "pow(3) S(t);");
+        // a new program, the same one again and one with its second to last statement changed
+        for (src, hits) in [
+            (PROGRAM, 0),
+            (PROGRAM, statements),
+            (&edited, statements - 2),
+        ] {
+            let mut cache = Cache::new(&dir);
+            let cached = cache.code_gen(ast(src)).unwrap();
+            let fresh = code_gen(ast(src)).unwrap();
+            assert_eq!((cache.hits, cache.misses), (hits, statements - hits));
+            assert_eq!(cached.program, fresh.program);
+            assert_eq!(cached.vars, fresh.vars);
+            assert_eq!(cached.arrays, fresh.arrays);
+            assert_eq!((cached.i, cached.ancillas), (fresh.i, fresh.ancillas));
+            assert_eq!(cached.functions.len(), 2);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
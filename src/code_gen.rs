@@ -1,4 +1,6 @@
-use crate::parser::ASTNode;
+use crate::ir;
+use crate::modifier;
+use crate::parser::{ASTNode, Modifier};
 use std::collections::HashMap;
 use std::iter::Peekable;
 use std::ops::Deref;
@@ -17,8 +19,82 @@ pub struct Comptime {
     //pub types: HashMap<String, usize>,                         // name to size of type
     //pub structs: HashMap<(String, String), (usize, String)>, // name of type + field to size of field and type
     pub aliass: HashMap<String, String>,
+    pub views: HashMap<String, View>, // array parameter or row to the registers it stands for
+    pub arrays: HashMap<String, Vec<usize>>, // qubit array to the lengths of its dimensions
     pub i: i32,
     pub j: i32,
+    pub ancillas: i32, // ancilla qubits handed out by ctrl(n)
+    pub phase: f64,    // global phase the code falls short of, see `modifier::control`
+}
+
+impl Default for Comptime {
+    fn default() -> Self {
+        Comptime::new()
+    }
+}
+
+impl Comptime {
+    pub fn new() -> Comptime {
+        Comptime {
+            program: String::new(),
+            functions: HashMap::new(),
+            function_info: HashMap::new(),
+            function_args: HashMap::new(),
+            vars: HashMap::new(),
+            var_info: HashMap::new(),
+            iterators: HashMap::new(),
+            //tmp_vars: HashMap::new(),
+            //tmp_var_info: HashMap::new(),
+            //types: HashMap::new(),
+            //structs: HashMap::new(),
+            aliass: HashMap::new(),
+            views: HashMap::new(),
+            arrays: HashMap::new(),
+            i: 0,
+            j: 0,
+            ancillas: 0,
+            phase: 0.0,
+        }
+    }
+
+    /// The element every array register stands for, `grid_1_2` is `grid[1][2]`, so diagrams
+    /// and reports can show the names of the source.
+    pub fn source_names(&self) -> HashMap<String, String> {
+        let mut names = HashMap::new();
+        for (array, dims) in &self.arrays {
+            let elements = dims.iter().fold(vec![array.clone()], |names, d| {
+                names
+                    .iter()
+                    .flat_map(|n| (0..*d).map(move |i| format!("{n}[{i}]")))
+                    .collect()
+            });
+            names.extend(flatten(array, dims).into_iter().zip(elements));
+        }
+        names
+    }
+}
+
+/// Fails if one of `regs`, the registers of variable `owner`, is also an element of another
+/// array, as `grid_1_0` of `grid_1: qbit[2]` and of `grid: qbit[2][2]` would be.
+fn check_registers(regs: &[String], owner: &str, cmptime: &Comptime) -> Result<(), String> {
+    let elements = cmptime.source_names();
+    for reg in regs {
+        let clash = match elements.get(reg) {
+            Some(element) if !element.starts_with(&format!("{owner}[")) => Some(element.clone()),
+            _ if reg != owner
+                && matches!(cmptime.var_info.get(reg), Some((_, _, ASTNode::Qbit))) =>
+            {
+                Some(reg.clone())
+            }
+            _ => None,
+        };
+        if let Some(other) = clash {
+            return Err(format!(
+                "BACKEND_ERROR: register '{reg}' of '{owner}' would also be {other}, rename one of them"
+            ));
+        }
+    }
+    Ok(())
 }
 
 pub fn code_gen(ast: ASTNode) -> Result<Comptime, String> {
@@ -38,38 +114,33 @@ pub fn code_gen_nodes<I>(iterator: &mut Peekable<I>) -> Result<Comptime, String>
 where
     I: Iterator<Item = ASTNode>,
 {
-    let mut c: Comptime = Comptime {
-        program: String::new(),
-        functions: HashMap::new(),
-        function_info: HashMap::new(),
-        function_args: HashMap::new(),
-        vars: HashMap::new(),
-        var_info: HashMap::new(),
-        iterators: HashMap::new(),
-        //tmp_vars: HashMap::new(),
-        //tmp_var_info: HashMap::new(),
-        //types: HashMap::new(),
-        //structs: HashMap::new(),
-        aliass: HashMap::new(),
-        i: 0,
-        j: 0,
-    };
-    while let Ok(_) = code_gen_node(iterator, &mut c) {
-        iterator.next();
-    }
+    let mut c: Comptime = Comptime::new();
+    code_gen_into(iterator, &mut c)?;
+    Ok(c)
+}
 
-    match code_gen_node(iterator, &mut c) {
-        Err(e) => {
-            return if e != "Expected Node, got None1" {
-                Err(e)
-            } else {
-                Ok(c)
-            }
+// generates code for further nodes on top of an existing compile-time state, e.g. one repl line
+pub fn code_gen_with(ast: ASTNode, cmptime: &mut Comptime) -> Result<(), String> {
+    match ast {
+        ASTNode::Program(nodes) | ASTNode::Block(nodes) => {
+            code_gen_into(&mut nodes.into_iter().peekable(), cmptime)
         }
-        Ok(_thing_else) => {}
+        _ => Err("AST_ERROR: Expected Program node, got something else.".to_string()),
     }
+}
 
-    Ok(c)
+pub fn code_gen_into<I>(iterator: &mut Peekable<I>, c: &mut Comptime) -> Result<(), String>
+where
+    I: Iterator<Item = ASTNode>,
+{
+    while code_gen_node(iterator, c).is_ok() {
+        iterator.next();
+    }
+
+    match code_gen_node(iterator, c) {
+        Err(e) if e != "Expected Node, got None1" => Err(e),
+        _ => Ok(()),
+    }
 }
 
 pub fn code_gen_node<I>(
@@ -86,7 +157,10 @@ where
         Some(ASTNode::For { .. }) => generate_for(iterator, cmptime),
         Some(ASTNode::Assignment { .. }) => generate_assignment(iterator, cmptime),
         Some(ASTNode::GateCall { .. }) => generate_gate_call(iterator, cmptime),
+        Some(ASTNode::FunctionCall { .. }) => generate_call(iterator, cmptime),
+        Some(ASTNode::Modified { .. }) => generate_modified(iterator, cmptime),
         Some(ASTNode::Return(..)) => generate_return(iterator, cmptime),
+        Some(ASTNode::Asm(..)) => generate_asm(iterator, cmptime),
         _ => Err("1".to_string()),
     }
 }
@@ -162,6 +236,34 @@ where
     }
 }
 
+// classical results are passed back through the register TMP_B
+fn generate_return_bit<I>(
+    iterator: &mut Peekable<I>,
+    cmptime: &mut Comptime,
+) -> Result<Comptime, String>
+where
+    I: Iterator<Item = ASTNode>,
+{
+    match iterator.peek() {
+        Some(ASTNode::Return(value)) => match *value.clone() {
+            ASTNode::VariableCall { name } => {
+                let width = cmptime.var_info.get(&name).unwrap().1;
+                cmptime.program.push_str(
+                    format!("BAL \"TMP_B\" {width}\nBCP \"TMP_B\" \"{name}\"\n").as_str(),
+                );
+                Ok(cmptime.clone())
+            }
+            _ => Err(format!(
+                "BACKEND_ERROR: Expected ASTNode::VariableCall, got {value:?}"
+            )),
+        },
+        None => Err("BACKEND_ERROR: Expected ASTNode::Return, got None".to_string()),
+        Some(other) => Err(format!(
+            "BACKEND_ERROR: Expected ASTNode::Return, got {other:?}"
+        )),
+    }
+}
+
 pub fn generate_return<I>(
     iterator: &mut Peekable<I>,
     cmptime: &mut Comptime,
@@ -174,6 +276,10 @@ where
         Some(ASTNode::Return(value)) => match *value.clone() {
             ASTNode::VariableCall { name } => {
                 match cmptime.var_info.get(&name).unwrap().2 {
+                    ASTNode::Bit => generate_return_bit(iterator, cmptime),
+                    ASTNode::ArrayType { ref type_, .. } if matches!(**type_, ASTNode::Bit) => {
+                        generate_return_bit(iterator, cmptime)
+                    }
                     ASTNode::ArrayType { .. } => generate_return_qal(iterator, cmptime),
                     ASTNode::Qbit => generate_return_qal(iterator, cmptime),
                     ASTNode::Qdit => generate_return_dal(iterator, cmptime),
@@ -232,6 +338,7 @@ where
         Some(ASTNode::VariableDecl { value, .. }) => match *value.clone().unwrap() {
             ASTNode::Num(_num) => gen_var_decl_num(iterator, cmptime),
             ASTNode::VariableCall { name: _ } => gen_var_decl_cpy(iterator, cmptime),
+            ASTNode::GateCall { name, .. } if name == "MES" => gen_var_decl_mes(iterator, cmptime),
             ASTNode::FunctionCall { name: _, args: _ } => {
                 let _ = gen_func_call(iterator, cmptime);
                 func_cpy(iterator, cmptime)
@@ -246,6 +353,48 @@ where
     Ok(Some(cmptime.clone()))
 }
 
+// let r: bit = MES(q);
+pub fn gen_var_decl_mes<I>(
+    iterator: &mut Peekable<I>,
+    cmptime: &mut Comptime,
+) -> Result<Option<Comptime>, String>
+where
+    I: Iterator<Item = ASTNode>,
+{
+    match iterator.peek() {
+        Some(ASTNode::VariableDecl {
+            name,
+            value,
+            type_,
+            token,
+        }) => {
+            if let Some(t) = type_ {
+                if !matches!(**t, ASTNode::Bit) {
+                    return Err(format!(
+                        "BACKEND_ERROR: MES returns a bit, but '{name}' is declared as {t:?}"
+                    ));
+                }
+            }
+            let args = match *value.clone().unwrap() {
+                ASTNode::GateCall { args, .. } => args,
+                other => return Err(format!("BACKEND_ERROR: Expected MES, got {other:?}")),
+            };
+            cmptime
+                .var_info
+                .insert(name.to_string(), (*token == 13, 1, ASTNode::Bit));
+            let qubit = fuck_join(args, cmptime);
+            cmptime
+                .program
+                .push_str(format!("BAL \"{name}\" 1\nMES {qubit}\"{name}\" 0\n").as_str());
+            Ok(Some(cmptime.clone()))
+        }
+        None => Err("BACKEND_ERROR: Expected ASTNode::VariableDecl, got None".to_string()),
+        Some(other) => Err(format!(
+            "BACKEND_ERROR: Expected ASTNode::VariableDecl, got {other:?}"
+        )),
+    }
+}
+
 pub fn gen_var_decl_num<I>(
     iterator: &mut Peekable<I>,
     cmptime: &mut Comptime,
@@ -577,6 +726,9 @@ where
                 }
             }
             ASTNode::Num(_) => generate_assignment_qb(iterator, cmptime),
+            ASTNode::GateCall { name, .. } if name == "MES" => {
+                generate_assignment_mes(iterator, cmptime)
+            }
             _ => Err("BACKEND_ERROR: Expected ASTNode::VariableCall".to_string()),
         },
 
@@ -586,6 +738,69 @@ where
     }
 }
 
+// r = MES(q); or m[i] = MES(q); for bit variables and arrays
+pub fn generate_assignment_mes<I>(
+    iterator: &mut Peekable<I>,
+    cmptime: &mut Comptime,
+) -> Result<Comptime, String>
+where
+    I: Iterator<Item = ASTNode>,
+{
+    let (lval, value) = match iterator.peek() {
+        Some(ASTNode::Assignment { lval, value }) => (*lval.clone(), *value.clone()),
+        None => return Err("BACKEND_ERROR: Expected ASTNode::Assignment, got None".to_string()),
+        Some(other) => {
+            return Err(format!(
+                "BACKEND_ERROR: Expected ASTNode::Assignment, got {other:?}"
+            ))
+        }
+    };
+    let (name, index) = match lval {
+        ASTNode::VariableCall { name } => (name, 0),
+        ASTNode::ArrayAccess { name, index } => match (*name, *index) {
+            (ASTNode::VariableCall { name }, ASTNode::Num(i)) => (name, i as usize),
+            (ASTNode::VariableCall { name }, ASTNode::ArrayIndex(i)) => (name, i as usize),
+            (ASTNode::VariableCall { name }, ASTNode::IntCall { name: n }) => {
+                match cmptime.iterators.get(&n) {
+                    Some(i) => (name, *i),
+                    None => return Err(format!("BACKEND_ERROR: Unknown iterator '{n}'")),
+                }
+            }
+            other => {
+                return Err(format!(
+                    "BACKEND_ERROR: Expected bit array access, got {other:?}"
+                ))
+            }
+        },
+        other => {
+            return Err(format!(
+                "BACKEND_ERROR: Expected bit variable, got {other:?}"
+            ))
+        }
+    };
+    let size = match cmptime.var_info.get(&name) {
+        Some((_, size, ASTNode::Bit)) => *size,
+        Some((_, size, ASTNode::ArrayType { type_, .. })) if matches!(**type_, ASTNode::Bit) => {
+            *size
+        }
+        _ => return Err(format!("BACKEND_ERROR: '{name}' is not a bit variable")),
+    };
+    if index >= size {
+        return Err(format!(
+            "BACKEND_ERROR: index {index} is out of range for '{name}' of {size} bits"
+        ));
+    }
+    let args = match value {
+        ASTNode::GateCall { args, .. } => args,
+        other => return Err(format!("BACKEND_ERROR: Expected MES, got {other:?}")),
+    };
+    let qubit = fuck_join(args, cmptime);
+    cmptime
+        .program
+        .push_str(format!("MES {qubit}\"{name}\" {index}\n").as_str());
+    Ok(cmptime.clone())
+}
+
 pub fn gen_var_decl_cpy_qb<I>(
     iterator: &mut Peekable<I>,
     cmptime: &mut Comptime,
@@ -734,17 +949,7 @@ where
                     args,
                 } => {
                     // allocate arguments
-                    for (i, arg) in args.iter().enumerate() {
-                        let _ = match arg {
-                            ASTNode::VariableCall { name } => {
-                                cmptime.aliass.insert(
-                                    cmptime.function_args.get(&func_name).unwrap()[i].clone(),
-                                    name.clone(),
-                                );
-                            }
-                            _ => return Err("BACKEND_ERROR: Expected VariableDecl ".to_string()),
-                        };
-                    }
+                    bind_args(&func_name, &args, cmptime)?;
 
                     match cmptime.functions.get(&func_name.clone()).unwrap().clone() {
                         ASTNode::Block(b) => {
@@ -787,30 +992,52 @@ where
                 return Err("Error: variable declarations need either a type or a value".to_string())
             }
             Some(other) => match *other.clone() {
-                ASTNode::ArrayType { size, .. } => {
+                ASTNode::Bit => {
+                    cmptime
+                        .var_info
+                        .insert(name.to_string(), (*token == 13, 1, ASTNode::Bit));
+                    cmptime
+                        .program
+                        .push_str(format!("BAL \"{name}\" 1\n").as_str());
+                    Ok(cmptime.clone())
+                }
+                ASTNode::ArrayType { type_: t, size } if matches!(*t, ASTNode::Bit) => {
+                    let s = match *size {
+                        ASTNode::Num(num) => num,
+                        _ => return Err("BACKEND_ERROR: Expected ASTNode::Num".to_string()),
+                    };
+                    cmptime.var_info.insert(
+                        name.to_string(),
+                        (*token == 13, s as usize, *type_.clone().unwrap()),
+                    );
+                    cmptime
+                        .program
+                        .push_str(format!("BAL \"{name}\" {s}\n").as_str());
+                    Ok(cmptime.clone())
+                }
+                array @ ASTNode::ArrayType { .. } => {
                     cmptime
                         .vars
                         .insert(name.to_string(), (cmptime.i as usize, 0));
-                    let s;
-                    if let ASTNode::Num(num) = *size.clone() {
-                        s = num;
-                    } else {
-                        return Err("BACKEND_ERROR: Expected ASTNode::Num".to_string());
-                    }
-                    cmptime.i += s as i32;
+                    let (dims, _) = shape(&array)?;
+                    let regs = flatten(name, &dims);
+                    check_registers(&regs, name, cmptime)?;
+                    cmptime.i += regs.len() as i32;
                     cmptime.var_info.insert(
                         name.to_string(),
-                        (*token == 13, s as usize, *type_.clone().unwrap()),
+                        (*token == 13, regs.len(), *type_.clone().unwrap()),
                     );
+                    cmptime.arrays.insert(name.to_string(), dims);
 
-                    for i in 0..s as i32 {
+                    for reg in regs {
                         cmptime
                             .program
-                            .push_str(format!("QAL & 0 $ \"{}\"\n", format!("{name}_{i}")).as_str())
+                            .push_str(format!("QAL & 0 $ \"{}\"\n", reg).as_str())
                     }
                     Ok(cmptime.clone())
                 }
                 ASTNode::Qbit => {
+                    check_registers(std::slice::from_ref(name), name, cmptime)?;
                     cmptime
                         .vars
                         .insert(name.to_string(), (cmptime.i as usize, 0));
@@ -824,6 +1051,7 @@ where
                     Ok(cmptime.clone())
                 }
                 ASTNode::Qdit => {
+                    check_registers(std::slice::from_ref(name), name, cmptime)?;
                     cmptime
                         .vars
                         .insert(name.to_string(), (cmptime.i as usize, 0));
@@ -856,19 +1084,13 @@ where
     match iterator.peek() {
         None => Err("BACKEND_ERROR: Expected ASTNode::For, got None".to_string()),
         Some(ASTNode::For { container, .. }) => match *container.clone() {
-            ASTNode::VariableCall { name } => {
-                let type_ = cmptime.var_info.get(&name.clone()).unwrap().2.clone();
-                match type_ {
-                    ASTNode::ArrayType { .. } => {
-                        return gen_for_array(iterator, cmptime);
-                    }
-                    _ => todo!(),
-                }
-            }
             ASTNode::Range { .. } => {
                 return gen_it_for(iterator, cmptime);
             }
-            _ => Err("".to_string()),
+            ASTNode::VariableCall { .. } | ASTNode::ArrayAccess { .. } | ASTNode::Concat { .. } => {
+                gen_for_array(iterator, cmptime)
+            }
+            other => Err(format!("BACKEND_ERROR: Can't iterate over {other:?}")),
         },
         Some(thing_else) => Err(format!(
             "BACKEND_ERROR: Expected ASTNode::For, got {thing_else:?}"
@@ -880,44 +1102,430 @@ pub fn gen_it_for<I>(iterator: &mut Peekable<I>, cmptime: &mut Comptime) -> Resu
 where
     I: Iterator<Item = ASTNode>,
 {
-    let _ = match iterator.peek() {
-        None => Err::<I, String>("BACKEND_ERROR: Expected ASTNode::For, got None".to_string()),
+    let (container, alias, body) = match iterator.peek() {
+        None => return Err("BACKEND_ERROR: Expected ASTNode::For, got None".to_string()),
         Some(ASTNode::For {
             container,
             alias,
             body,
-            ..
-        }) => match *container.clone() {
-            ASTNode::Range { start, end } => {
-                let _s = match *start.clone() {
-                    ASTNode::Num(num) => num,
-                    _ => return Err("BACKEND_ERROR: Expected ASTNode::Num".to_string()),
-                };
-                let _e = match *end.clone() {
-                    ASTNode::Num(num) => num,
-                    _ => return Err("BACKEND_ERROR: Expected ASTNode::Num".to_string()),
-                };
-                if body.is_some() {
-                    match *body.clone().unwrap() {
-                        ASTNode::Block(b) => {
-                            let mut s = b.into_iter().peekable();
-                            for i in _s.._e {
-                                cmptime.iterators.insert(alias.clone(), i as usize);
-                                let _ = code_gen_node(&mut s, cmptime).unwrap().program;
-                            }
-                        }
-                        _ => return Err("BACKEND_ERROR: Expected ASTNode::Block".to_string()),
+        }) => (container.clone(), alias.clone(), body.clone()),
+        Some(thing_else) => {
+            return Err(format!(
+                "BACKEND_ERROR: Expected ASTNode::For, got {thing_else:?}"
+            ))
+        }
+    };
+    let values = range_values(&container, cmptime)?;
+    let b = match body.as_deref() {
+        None => return Ok(cmptime.clone()),
+        Some(ASTNode::Block(b)) => b.clone(),
+        Some(_) => return Err("BACKEND_ERROR: Expected ASTNode::Block".to_string()),
+    };
+    scoped(&alias, cmptime, |cmptime| {
+        let mut first = 0;
+        if values.len() > 1 && !mentions(&b, &alias) {
+            match gen_rep(&b, values.len(), cmptime)? {
+                true => return Ok(()),
+                // both iterations are already generated
+                false => first = 2,
+            }
+        }
+        for i in &values[first..] {
+            cmptime.iterators.insert(alias.clone(), *i);
+            code_gen_into(&mut b.clone().into_iter().peekable(), cmptime)?;
+        }
+        Ok(())
+    })?;
+    Ok(cmptime.clone())
+}
+
+/// The value of a range bound or step. Numbers and the indices of enclosing loops are all
+/// that is known while generating code.
+fn eval_bound(node: &ASTNode, cmptime: &Comptime) -> Result<i32, String> {
+    match node {
+        ASTNode::Num(num) => Ok(*num),
+        ASTNode::VariableCall { name } | ASTNode::IntCall { name } => {
+            match cmptime.iterators.get(name) {
+                Some(i) => Ok(*i as i32),
+                None => Err(format!(
+                    "BACKEND_ERROR: '{name}' is not a loop index, indices and range bounds must be known at compile time"
+                )),
+            }
+        }
+        ASTNode::BinOp { op, lhs, rhs } => {
+            let (l, r) = (eval_bound(lhs, cmptime)?, eval_bound(rhs, cmptime)?);
+            let value = match op {
+                '+' => l.checked_add(r),
+                '-' => l.checked_sub(r),
+                _ => l.checked_mul(r),
+            };
+            value.ok_or_else(|| format!("BACKEND_ERROR: {l} {op} {r} overflows"))
+        }
+        other => Err(format!(
+            "BACKEND_ERROR: Expected number or loop index in range, got {other:?}"
+        )),
+    }
+}
+
+/// The indices a range walks through, counting down when `end` is below `start`.
+pub fn range_values(range: &ASTNode, cmptime: &Comptime) -> Result<Vec<usize>, String> {
+    let ASTNode::Range {
+        start,
+        end,
+        step,
+        inclusive,
+    } = range
+    else {
+        return Err(format!(
+            "BACKEND_ERROR: Expected ASTNode::Range, got {range:?}"
+        ));
+    };
+    let (s, e) = (
+        eval_bound(start, cmptime)? as i64,
+        eval_bound(end, cmptime)? as i64,
+    );
+    let step = match step {
+        Some(step) => eval_bound(step, cmptime)? as i64,
+        None => 1,
+    };
+    if step <= 0 {
+        return Err(format!(
+            "BACKEND_ERROR: range step must be positive, got {step}"
+        ));
+    }
+    let (dir, mut i) = (if s <= e { 1 } else { -1 }, s);
+    let mut values = vec![];
+    while (e - i) * dir > 0 || (*inclusive && i == e) {
+        values.push(
+            usize::try_from(i).map_err(|_| format!("BACKEND_ERROR: negative loop index {i}"))?,
+        );
+        i += step * dir;
+    }
+    Ok(values)
+}
+
+/// The value of an array index, `reg[3]`, `reg[i]` or `reg[i + 1]`.
+fn index_value(index: &ASTNode, cmptime: &Comptime) -> Result<usize, String> {
+    match index {
+        ASTNode::ArrayIndex(n) => Ok(*n as usize),
+        ASTNode::Num(_)
+        | ASTNode::IntCall { .. }
+        | ASTNode::VariableCall { .. }
+        | ASTNode::BinOp { .. } => {
+            let i = eval_bound(index, cmptime)?;
+            usize::try_from(i).map_err(|_| format!("BACKEND_ERROR: negative index {i}"))
+        }
+        other => Err(format!(
+            "BACKEND_ERROR: Expected array index, got {other:?}"
+        )),
+    }
+}
+
+/// Registers selected by an array expression, with sigils and in row-major order, and the
+/// length of each of its dimensions. A single register has no dimensions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct View {
+    pub regs: Vec<String>,
+    pub dims: Vec<usize>,
+}
+
+impl View {
+    fn single(reg: String) -> View {
+        View {
+            regs: vec![reg],
+            dims: vec![],
+        }
+    }
+
+    /// `qbit`, `qbit[4]` or `qbit[4][4]`, for error messages.
+    fn type_name(&self) -> String {
+        let dims: String = self.dims.iter().map(|d| format!("[{d}]")).collect();
+        format!("qbit{dims}")
+    }
+
+    /// The rows along the first dimension, single registers for a one-dimensional view.
+    fn rows(&self) -> Vec<View> {
+        match self.dims.split_first() {
+            None => vec![self.clone()],
+            Some((_, inner)) => {
+                let stride = inner.iter().product::<usize>().max(1);
+                self.regs
+                    .chunks(stride)
+                    .map(|regs| View {
+                        regs: regs.to_vec(),
+                        dims: inner.to_vec(),
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// The lengths of the dimensions of an array type, outermost first, and its element type.
+fn shape(type_: &ASTNode) -> Result<(Vec<usize>, ASTNode), String> {
+    match type_ {
+        ASTNode::ArrayType { type_, size } => {
+            let ASTNode::Num(n) = **size else {
+                return Err("BACKEND_ERROR: Expected ASTNode::Num".to_string());
+            };
+            let (mut dims, element) = shape(type_)?;
+            dims.insert(0, n as usize);
+            Ok((dims, element))
+        }
+        other => Ok((vec![], other.clone())),
+    }
+}
+
+/// The backend registers of array `name`, `name_i_j` for `name[i][j]`, in row-major order.
+fn flatten(name: &str, dims: &[usize]) -> Vec<String> {
+    dims.iter().fold(vec![name.to_string()], |regs, d| {
+        regs.iter()
+            .flat_map(|r| (0..*d).map(move |i| format!("{r}_{i}")))
+            .collect()
+    })
+}
+
+/// The registers of an array, an element `grid[i][j]`, a row `grid[i]`, a slice `reg[a..b]`
+/// or a concatenation `a ++ b`. A range keeps its dimension, so `grid[0..4][j]` is column
+/// `j`. Nothing is copied, a view passed to a function is the registers themselves. Indices
+/// are checked against the array they select from.
+pub fn view(node: &ASTNode, cmptime: &Comptime) -> Result<View, String> {
+    match node {
+        ASTNode::VariableCall { name } => {
+            if let Some(v) = cmptime.views.get(name) {
+                return Ok(v.clone());
+            }
+            let reg = cmptime.aliass.get(name).unwrap_or(name);
+            match cmptime.var_info.get(reg) {
+                Some((_, len, type_ @ ASTNode::ArrayType { .. })) => {
+                    let (mut dims, element) = shape(type_)?;
+                    let sigil = match element {
+                        ASTNode::Qbit => '$',
+                        ASTNode::Qdit => '%',
+                        _ => return Err(format!("BACKEND_ERROR: '{name}' is not a qubit array")),
+                    };
+                    // a number keeps its value as the size, `var_info` has its qubits
+                    if dims.len() == 1 {
+                        dims[0] = *len;
                     }
+                    Ok(View {
+                        regs: flatten(reg, &dims)
+                            .into_iter()
+                            .map(|r| format!("{sigil}{r}"))
+                            .collect(),
+                        dims,
+                    })
                 }
-                return Ok(cmptime.clone());
+                Some((_, _, ASTNode::Qbit)) => Ok(View::single(format!("${reg}"))),
+                Some((_, _, ASTNode::Qdit)) => Ok(View::single(format!("%{reg}"))),
+                // elements bound by loops and parameters have no var_info
+                None if cmptime.aliass.contains_key(name) => Ok(View::single(format!("${reg}"))),
+                _ => Err(format!("BACKEND_ERROR: '{name}' is not a qubit array")),
             }
-            _ => return Err("BACKEND_ERROR: Expected ASTNode::Range".to_string()),
-        },
-        Some(thing_else) => Err(format!(
-            "BACKEND_ERROR: Expected ASTNode::For, got {thing_else:?}"
-        )),
+        }
+        ASTNode::ArrayAccess { .. } => {
+            // `grid[i][j]` nests as (grid[i])[j], the indices apply to successive dimensions
+            let mut indices = vec![];
+            let mut base = node;
+            while let ASTNode::ArrayAccess { name, index } = base {
+                indices.push(&**index);
+                base = name;
+            }
+            indices.reverse();
+            let path = match base {
+                ASTNode::VariableCall { name } => name.clone(),
+                _ => "view".to_string(),
+            };
+            select(&view(base, cmptime)?, &indices, path, cmptime)
+        }
+        ASTNode::Concat { lhs, rhs } => {
+            let (lhs, rhs) = (view(lhs, cmptime)?, view(rhs, cmptime)?);
+            // single registers count as one-element arrays
+            let dims = |v: &View| match v.dims.is_empty() {
+                true => vec![1],
+                false => v.dims.clone(),
+            };
+            let (l, r) = (dims(&lhs), dims(&rhs));
+            if l[1..] != r[1..] {
+                return Err(format!(
+                    "BACKEND_ERROR: Can't concatenate {} and {}, their rows differ",
+                    lhs.type_name(),
+                    rhs.type_name()
+                ));
+            }
+            let mut dims = l.clone();
+            dims[0] += r[0];
+            Ok(View {
+                regs: [lhs.regs, rhs.regs].concat(),
+                dims,
+            })
+        }
+        other => Err(format!("BACKEND_ERROR: Expected array, got {other:?}")),
+    }
+}
+
+/// Applies `indices` to the dimensions of `v` in turn, `path` is what `v` was written as.
+fn select(
+    v: &View,
+    indices: &[&ASTNode],
+    path: String,
+    cmptime: &Comptime,
+) -> Result<View, String> {
+    let Some((index, rest)) = indices.split_first() else {
+        return Ok(v.clone());
     };
-    Ok(cmptime.clone())
+    let Some(len) = v.dims.first() else {
+        return Err(format!(
+            "BACKEND_ERROR: {path} is a single qubit, it can't be indexed"
+        ));
+    };
+    let (picked, keep) = match index {
+        ASTNode::Range { .. } => (range_values(index, cmptime)?, true),
+        _ => (vec![index_value(index, cmptime)?], false),
+    };
+    let rows = v.rows();
+    let mut regs = vec![];
+    let mut inner = v.dims[1..].to_vec();
+    for i in &picked {
+        let Some(row) = rows.get(*i) else {
+            return Err(format!(
+                "BACKEND_ERROR: {path}[{i}] is out of bounds, '{path}' has {len} {}",
+                if v.dims.len() == 1 { "qubits" } else { "rows" }
+            ));
+        };
+        let s = select(row, rest, format!("{path}[{i}]"), cmptime)?;
+        regs.extend(s.regs);
+        inner = s.dims;
+    }
+    if keep {
+        inner.insert(0, picked.len());
+    }
+    Ok(View { regs, dims: inner })
+}
+
+/// Checks that every operand of `name` selects a single register, indices within bounds.
+fn check_operands(name: &str, args: &[ASTNode], cmptime: &Comptime) -> Result<(), String> {
+    for arg in args {
+        let v = match arg {
+            ASTNode::ArrayAccess { index, .. } if matches!(**index, ASTNode::ExternArg { .. }) => {
+                continue
+            }
+            ASTNode::ArrayAccess { .. } | ASTNode::Concat { .. } => view(arg, cmptime)?,
+            ASTNode::VariableCall { name } if cmptime.views.contains_key(name) => {
+                view(arg, cmptime)?
+            }
+            _ => continue,
+        };
+        if v.regs.len() != 1 {
+            return Err(format!(
+                "BACKEND_ERROR: {name} takes single qubits, got {} in one argument",
+                v.type_name()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs `f` with `alias` unbound, a loop index, array element or row of an enclosing loop
+/// with the same name comes back afterwards.
+fn scoped<T>(
+    alias: &str,
+    cmptime: &mut Comptime,
+    f: impl FnOnce(&mut Comptime) -> Result<T, String>,
+) -> Result<T, String> {
+    let iterator = cmptime.iterators.remove(alias);
+    let element = cmptime.aliass.remove(alias);
+    let row = cmptime.views.remove(alias);
+    let ret = f(cmptime);
+    cmptime.iterators.remove(alias);
+    cmptime.aliass.remove(alias);
+    cmptime.views.remove(alias);
+    if let Some(i) = iterator {
+        cmptime.iterators.insert(alias.to_string(), i);
+    }
+    if let Some(e) = element {
+        cmptime.aliass.insert(alias.to_string(), e);
+    }
+    if let Some(r) = row {
+        cmptime.views.insert(alias.to_string(), r);
+    }
+    ret
+}
+
+/// Whether the loop index `name` is read anywhere in `nodes`, as a number, a variable or
+/// inside an index. A hit in a nested loop that shadows it only costs unrolling.
+fn mentions(nodes: &[ASTNode], name: &str) -> bool {
+    let one = |node: &ASTNode| mentions(std::slice::from_ref(node), name);
+    let some = |node: &Option<Box<ASTNode>>| node.as_deref().is_some_and(one);
+    nodes.iter().any(|node| match node {
+        ASTNode::IntCall { name: n } | ASTNode::VariableCall { name: n } => n == name,
+        ASTNode::Program(nodes) | ASTNode::Block(nodes) | ASTNode::Struct { types: nodes, .. } => {
+            mentions(nodes, name)
+        }
+        ASTNode::FunctionCall { args, .. } | ASTNode::GateCall { args, .. } => mentions(args, name),
+        ASTNode::FunctionDef { body, .. } => some(body),
+        ASTNode::VariableDecl { value, type_, .. } => some(value) || some(type_),
+        ASTNode::For {
+            container, body, ..
+        } => one(container) || some(body),
+        ASTNode::Return(value)
+        | ASTNode::Reference { value }
+        | ASTNode::Dereference { value }
+        | ASTNode::ExternArg { idx: value }
+        | ASTNode::PointerType { type_: value }
+        | ASTNode::Type {
+            specifier: value, ..
+        }
+        | ASTNode::StructAccess {
+            structure: value, ..
+        }
+        | ASTNode::Modified { call: value, .. } => one(value),
+        ASTNode::ArrayAccess {
+            name: lhs,
+            index: rhs,
+        }
+        | ASTNode::BinOp { lhs, rhs, .. }
+        | ASTNode::Concat { lhs, rhs }
+        | ASTNode::Assignment {
+            lval: lhs,
+            value: rhs,
+        }
+        | ASTNode::ArrayType {
+            type_: lhs,
+            size: rhs,
+        } => one(lhs) || one(rhs),
+        ASTNode::Range {
+            start, end, step, ..
+        } => one(start) || one(end) || some(step),
+        ASTNode::Asm(instructions) => instructions.iter().any(|(_, ops)| mentions(ops, name)),
+        ASTNode::ArrayIndex(_)
+        | ASTNode::Void
+        | ASTNode::Qbit
+        | ASTNode::Bit
+        | ASTNode::Custom
+        | ASTNode::Num(_)
+        | ASTNode::Angle(_)
+        | ASTNode::AsmOperand(_)
+        | ASTNode::Qdit => false,
+    })
+}
+
+/// Emits a loop body that doesn't depend on the index once, as `REP count` ... `END`. The
+/// body is generated for two iterations, unless both come out the same (declarations and
+/// `ctrl` ancillas get new names) the two stay unrolled and `false` is returned.
+fn gen_rep(body: &[ASTNode], count: usize, cmptime: &mut Comptime) -> Result<bool, String> {
+    let start = cmptime.program.len();
+    code_gen_into(&mut body.iter().cloned().peekable(), cmptime)?;
+    let once = cmptime.program[start..].to_string();
+    code_gen_into(&mut body.iter().cloned().peekable(), cmptime)?;
+    if cmptime.program[start + once.len()..] != once || once.is_empty() {
+        return Ok(false);
+    }
+    cmptime.program.truncate(start);
+    cmptime
+        .program
+        .push_str(format!("REP {count}\n{once}END\n").as_str());
+    Ok(true)
 }
 
 pub fn gen_for_array<I>(
@@ -927,81 +1535,55 @@ pub fn gen_for_array<I>(
 where
     I: Iterator<Item = ASTNode>,
 {
-    match iterator.peek() {
-        None => Err("BACKEND_ERROR: Expected ASTNode::For, got None".to_string()),
+    let (container, alias, body) = match iterator.peek() {
+        None => return Err("BACKEND_ERROR: Expected ASTNode::For, got None".to_string()),
         Some(ASTNode::For {
             container,
             alias,
             body,
-            ..
-        }) => match *container.clone() {
-            ASTNode::VariableCall { name } => {
-                let name = if !cmptime.aliass.contains_key(&name) {
-                    name.clone()
-                } else {
-                    cmptime.aliass.get(&name).unwrap().clone()
-                };
-                let type_ = cmptime.var_info.get(&name.clone()).unwrap().2.clone();
-                match type_ {
-                    ASTNode::ArrayType { type_, size } => {
-                        let _s;
-                        match *size.clone() {
-                            ASTNode::Num(num) => _s = num,
-                            _ => {
-                                return Err("BACKEND_ERROR: Expected ASTNode::Num".to_string());
-                            }
-                        }
-                        cmptime.vars.insert(alias.clone(), (cmptime.i as usize, 0));
-                        let incr = match *type_.clone() {
-                            ASTNode::Type { name: _, specifier } => match *specifier {
-                                ASTNode::Qbit => 1,
-                                _ => 0,
-                            },
-                            ASTNode::Qbit => 1,
-                            _ => 0,
-                        };
-
-                        cmptime.i += incr as i32;
-                        cmptime
-                            .var_info
-                            .insert(alias.clone(), (true, incr, *type_.clone()));
-
-                        if body.is_none() {
-                            return Ok(cmptime.clone());
-                        }
-
-                        let size_ = match *size.clone() {
-                            ASTNode::Num(num) => num,
-                            _ => {
-                                return Err("BACKEND_ERROR: Expected ASTNode::Num".to_string());
-                            }
-                        };
-
-                        match body.clone().unwrap().deref() {
-                            ASTNode::Block(nodes) => {
-                                for i__ in 0..size_ {
-                                    cmptime
-                                        .aliass
-                                        .insert(alias.clone(), format!("{}_{i__}", name.clone()));
-                                    let mut it = nodes.clone().into_iter().peekable();
-                                    let _ = code_gen_node(&mut it, cmptime);
-                                }
-                            }
-                            _ => return Err("BACKEND_ERROR: Expected ASTNode::Block".to_string()),
-                        };
-                        Ok(cmptime.clone())
-                    }
-                    _ => Err(format!(
-                        "BACKEND_ERROR: Expected ASTNode::ArrayType, got {type_:?}"
-                    )),
-                }
+        }) => (*container.clone(), alias.clone(), body.clone()),
+        Some(thing_else) => {
+            return Err(format!(
+                "BACKEND_ERROR: Expected ASTNode::For, got {thing_else:?}"
+            ))
+        }
+    };
+    // an array, a slice `reg[a..b]` of one or a concatenation, checked against the arrays
+    let v = view(&container, cmptime)?;
+    let nodes = match body.as_deref() {
+        None => return Ok(cmptime.clone()),
+        Some(ASTNode::Block(nodes)) => nodes.clone(),
+        Some(_) => return Err("BACKEND_ERROR: Expected ASTNode::Block".to_string()),
+    };
+    if v.dims.len() > 1 {
+        // the rows of a grid, each bound as a view
+        scoped(&alias, cmptime, |cmptime| {
+            for row in v.rows() {
+                cmptime.views.insert(alias.clone(), row);
+                code_gen_into(&mut nodes.clone().into_iter().peekable(), cmptime)?;
             }
-            _ => Err("".to_string()),
-        },
-        Some(thing_else) => Err(format!(
-            "BACKEND_ERROR: Expected ASTNode::For, got {thing_else:?}"
-        )),
+            Ok(())
+        })?;
+        return Ok(cmptime.clone());
     }
+    cmptime.vars.insert(alias.clone(), (cmptime.i as usize, 0));
+    let (incr, type_) = match v.regs.first().map(|r| r.starts_with('$')) {
+        Some(false) => (0, ASTNode::Qdit),
+        _ => (1, ASTNode::Qbit),
+    };
+
+    cmptime.i += incr as i32;
+    cmptime.var_info.insert(alias.clone(), (true, incr, type_));
+
+    scoped(&alias, cmptime, |cmptime| {
+        for reg in v.regs {
+            cmptime.aliass.insert(alias.clone(), reg[1..].to_string());
+            let mut it = nodes.clone().into_iter().peekable();
+            code_gen_into(&mut it, cmptime)?;
+        }
+        Ok(())
+    })?;
+    Ok(cmptime.clone())
 }
 
 pub fn fuck_join(s: Vec<ASTNode>, cmptime: &mut Comptime) -> String {
@@ -1009,6 +1591,7 @@ pub fn fuck_join(s: Vec<ASTNode>, cmptime: &mut Comptime) -> String {
     for s_ in s {
         let _ = match s_ {
             ASTNode::Num(num) => ret.push_str(num.to_string().as_str()),
+            ASTNode::Angle(angle) => ret.push_str(angle.to_string().as_str()),
             ASTNode::VariableCall { name } => {
                 if !cmptime.aliass.contains_key(&name) && !cmptime.iterators.contains_key(&name) {
                     match cmptime.var_info.get(&name).unwrap().2.clone() {
@@ -1018,13 +1601,12 @@ pub fn fuck_join(s: Vec<ASTNode>, cmptime: &mut Comptime) -> String {
                     }
                     ret.push_str(name.as_str())
                 } else if !cmptime.iterators.contains_key(&name) {
+                    // aliases of array elements (loops, function arguments) have no var_info
                     match HashMap::get(
                         &cmptime.var_info,
                         cmptime.aliass.get(&name).unwrap().as_str(),
                     )
-                    .unwrap()
-                    .2
-                    .clone()
+                    .map_or(ASTNode::Qbit, |v| v.2.clone())
                     {
                         ASTNode::Qbit => ret.push('$'),
                         ASTNode::Qdit => ret.push('%'),
@@ -1047,6 +1629,16 @@ pub fn fuck_join(s: Vec<ASTNode>, cmptime: &mut Comptime) -> String {
                 };
             }
             ASTNode::ArrayAccess { name, index } => {
+                let access = ASTNode::ArrayAccess {
+                    name: name.clone(),
+                    index: index.clone(),
+                };
+                // elements of views and computed indices, `$n` indices are resolved below
+                if let Ok([reg]) = view(&access, cmptime).as_ref().map(|v| v.regs.as_slice()) {
+                    ret.push_str(reg);
+                    ret.push(' ');
+                    continue;
+                }
                 if let ASTNode::VariableCall { name } = *name {
                     if !cmptime.aliass.contains_key(&name) {
                         match cmptime.var_info.get(&name).unwrap().2.clone() {
@@ -1094,6 +1686,7 @@ where
     match iterator.peek() {
         None => Err("BACKEND_ERROR: Expected Node, got None2".to_string()),
         Some(ASTNode::GateCall { name, args }) => {
+            check_operands(name, args, cmptime)?;
             let var_name = &format!("{name} {}\n", fuck_join(args.clone(), cmptime));
             cmptime.program.push_str(var_name.as_str());
             Ok(cmptime.clone())
@@ -1102,6 +1695,253 @@ where
     }
 }
 
+/// Emits the instructions of an `asm` block, QEL names in their operands are replaced by the
+/// registers and loop indices they stand for. Every instruction has to be one the backends
+/// know, with operands of a form it takes.
+pub fn generate_asm<I>(
+    iterator: &mut Peekable<I>,
+    cmptime: &mut Comptime,
+) -> Result<Comptime, String>
+where
+    I: Iterator<Item = ASTNode>,
+{
+    let instructions = match iterator.peek() {
+        Some(ASTNode::Asm(instructions)) => instructions.clone(),
+        None => return Err("BACKEND_ERROR: Expected ASTNode::Asm, got None".to_string()),
+        Some(other) => {
+            return Err(format!(
+                "BACKEND_ERROR: Expected ASTNode::Asm, got {other:?}"
+            ))
+        }
+    };
+    let mut block = String::new();
+    for (op, operands) in instructions {
+        let mut line = op.clone();
+        for operand in operands {
+            line.push(' ');
+            match operand {
+                ASTNode::AsmOperand(text) => line.push_str(&text),
+                node => line.push_str(&asm_operand(&op, node, cmptime)?),
+            }
+        }
+        if let Some(ins) = ir::parse_instruction(&line)? {
+            ir::validate(&ins)?;
+        }
+        block.push_str(&line);
+        block.push('\n');
+    }
+    // a REP of the block has to end in it, the surrounding code can't close it
+    ir::loops(&ir::parse_program(&block)?)?;
+    cmptime.program.push_str(&block);
+    Ok(cmptime.clone())
+}
+
+// a QEL name in an asm instruction as the register or number `fuck_join` makes of it
+fn asm_operand(op: &str, node: ASTNode, cmptime: &mut Comptime) -> Result<String, String> {
+    let mut root = &node;
+    while let ASTNode::ArrayAccess { name, .. } = root {
+        root = name;
+    }
+    let ASTNode::VariableCall { name } = root else {
+        return Err(format!(
+            "BACKEND_ERROR: Expected register in {op}, got {node:?}"
+        ));
+    };
+    let known = cmptime.aliass.contains_key(name)
+        || cmptime.iterators.contains_key(name)
+        || cmptime.views.contains_key(name);
+    match (&node, cmptime.var_info.get(name).map(|v| &v.2)) {
+        _ if known => {}
+        (ASTNode::VariableCall { .. }, Some(ASTNode::Qbit | ASTNode::Qdit)) => {}
+        (ASTNode::ArrayAccess { .. }, Some(ASTNode::ArrayType { .. })) => {}
+        (_, None) => return Err(format!("BACKEND_ERROR: '{name}' in {op} is not declared")),
+        (_, Some(_)) => {
+            return Err(format!(
+                "BACKEND_ERROR: '{name}' in {op} is not a single register or loop index"
+            ))
+        }
+    }
+    check_operands(op, std::slice::from_ref(&node), cmptime)?;
+    Ok(fuck_join(vec![node], cmptime).trim().to_string())
+}
+
+// the register an argument refers to, without its sigil
+fn register_name(arg: ASTNode, cmptime: &mut Comptime) -> Result<String, String> {
+    check_operands("a qubit parameter", std::slice::from_ref(&arg), cmptime)?;
+    let joined = fuck_join(vec![arg.clone()], cmptime);
+    match joined.trim().strip_prefix(['$', '%']) {
+        Some(name) => Ok(name.to_string()),
+        None => Err(format!("BACKEND_ERROR: Expected register, got {arg:?}")),
+    }
+}
+
+// `f(a, b);` as a statement, the body is inlined with the parameters aliased to the arguments
+pub fn generate_call<I>(
+    iterator: &mut Peekable<I>,
+    cmptime: &mut Comptime,
+) -> Result<Comptime, String>
+where
+    I: Iterator<Item = ASTNode>,
+{
+    let (name, args) = match iterator.peek() {
+        Some(ASTNode::FunctionCall { name, args }) => (name.clone(), args.clone()),
+        None => return Err("BACKEND_ERROR: Expected ASTNode::FunctionCall, got None".to_string()),
+        Some(other) => {
+            return Err(format!(
+                "BACKEND_ERROR: Expected ASTNode::FunctionCall, got {other:?}"
+            ))
+        }
+    };
+    let params = match cmptime.function_args.get(&name) {
+        Some(p) => p.clone(),
+        None => return Err(format!("BACKEND_ERROR: Unknown function '{name}'")),
+    };
+    if params.len() != args.len() {
+        return Err(format!(
+            "BACKEND_ERROR: '{name}' takes {} arguments, got {}",
+            params.len(),
+            args.len()
+        ));
+    }
+    let saved = (cmptime.aliass.clone(), cmptime.views.clone());
+    bind_args(&name, &args, cmptime)?;
+    match cmptime.functions.get(&name).cloned() {
+        Some(ASTNode::Block(b)) => code_gen_into(&mut b.into_iter().peekable(), cmptime)?,
+        _ => return Err(format!("BACKEND_ERROR: '{name}' has no body")),
+    }
+    (cmptime.aliass, cmptime.views) = saved;
+    Ok(cmptime.clone())
+}
+
+// aliases the parameters of `name` to the arguments of a call, `qbit[k]` parameters to views
+fn bind_args(name: &str, args: &[ASTNode], cmptime: &mut Comptime) -> Result<(), String> {
+    let params = cmptime.function_args.get(name).cloned().unwrap_or_default();
+    let types = match cmptime.function_info.get(name) {
+        Some(info) => info.0.clone(),
+        None => return Err(format!("BACKEND_ERROR: Unknown function '{name}'")),
+    };
+    for ((param, type_), arg) in params.iter().zip(types).zip(args) {
+        match type_ {
+            ASTNode::ArrayType { .. } => {
+                let v = view(arg, cmptime)?;
+                let (dims, _) = shape(&type_)?;
+                // a single register passes for a one-element array
+                if v.dims != dims && !(v.dims.is_empty() && dims == [1]) {
+                    return Err(format!(
+                        "BACKEND_ERROR: '{param}' of '{name}' takes {}, got {}",
+                        View { regs: vec![], dims }.type_name(),
+                        v.type_name()
+                    ));
+                }
+                cmptime.aliass.remove(param);
+                cmptime.views.insert(param.clone(), v);
+            }
+            _ => {
+                let reg = register_name(arg.clone(), cmptime)?;
+                cmptime.views.remove(param);
+                cmptime.aliass.insert(param.clone(), reg);
+            }
+        }
+    }
+    Ok(())
+}
+
+// removes the first n arguments of the call under the modifiers, these are the controls of ctrl(n)
+fn take_controls(node: &mut ASTNode, n: u32) -> Result<Vec<ASTNode>, String> {
+    match node {
+        ASTNode::Modified { call, .. } => take_controls(call, n),
+        ASTNode::GateCall { name, args } | ASTNode::FunctionCall { name, args } => {
+            if args.len() <= n as usize {
+                return Err(format!(
+                    "BACKEND_ERROR: ctrl({n}) {name} needs more than {n} arguments, got {}",
+                    args.len()
+                ));
+            }
+            Ok(args.drain(..n as usize).collect())
+        }
+        other => Err(format!(
+            "BACKEND_ERROR: Expected gate or function call, got {other:?}"
+        )),
+    }
+}
+
+// generates the call under the modifiers on its own and rewrites the resulting instructions
+fn gen_modified(node: ASTNode, cmptime: &mut Comptime) -> Result<ir::Program, String> {
+    match node {
+        ASTNode::Modified { modifier, call } => {
+            let mut call = *call;
+            let controls = match modifier {
+                Modifier::Ctrl(mut n) => {
+                    // `ctrl(1) ctrl(1) g(a, b, c)` is `ctrl(2) g(a, b, c)`
+                    while let ASTNode::Modified {
+                        modifier: Modifier::Ctrl(m),
+                        call: inner,
+                    } = call
+                    {
+                        n += m;
+                        call = *inner;
+                    }
+                    take_controls(&mut call, n)?
+                        .into_iter()
+                        .map(|c| register_name(c, cmptime))
+                        .collect::<Result<Vec<_>, _>>()?
+                }
+                _ => vec![],
+            };
+            // the global phase the body falls short of, see `modifier::control`
+            let outer = std::mem::take(&mut cmptime.phase);
+            let body = gen_modified(call, cmptime)?;
+            let phase = std::mem::replace(&mut cmptime.phase, outer);
+            match modifier {
+                Modifier::Inv => {
+                    cmptime.phase -= phase;
+                    modifier::invert(&body)
+                }
+                Modifier::Pow(n) => {
+                    cmptime.phase += n as f64 * phase;
+                    modifier::power(&body, n)
+                }
+                Modifier::Ctrl(_) => {
+                    let mut ancillas = modifier::Ancillas {
+                        prefix: "CTRL",
+                        next: &mut cmptime.ancillas,
+                    };
+                    let (program, short) =
+                        modifier::control(&body, &controls, phase, &mut ancillas)?;
+                    cmptime.phase += short;
+                    Ok(program)
+                }
+            }
+        }
+        call => {
+            let start = cmptime.program.len();
+            code_gen_node(&mut std::iter::once(call).peekable(), cmptime)?;
+            let text = cmptime.program.split_off(start);
+            ir::parse_program(&text)
+        }
+    }
+}
+
+pub fn generate_modified<I>(
+    iterator: &mut Peekable<I>,
+    cmptime: &mut Comptime,
+) -> Result<Comptime, String>
+where
+    I: Iterator<Item = ASTNode>,
+{
+    match iterator.peek() {
+        Some(node @ ASTNode::Modified { .. }) => {
+            let program = gen_modified(node.clone(), cmptime)?;
+            cmptime.program.push_str(&program.to_string());
+            Ok(cmptime.clone())
+        }
+        None => Err("BACKEND_ERROR: Expected ASTNode::Modified, got None".to_string()),
+        Some(other) => Err(format!(
+            "BACKEND_ERROR: Expected ASTNode::Modified, got {other:?}"
+        )),
+    }
+}
+
 pub fn func_cpy<I>(
     iterator: &mut Peekable<I>,
     cmptime: &mut Comptime,
@@ -1120,6 +1960,32 @@ where
             ASTNode::FunctionCall { name: n, args: _ } => match cmptime.function_info.get(&n) {
                 None => Err("BACKEND_ERROR: Function is not properly registered".to_string()),
                 Some(f) => match &f.1 {
+                    ASTNode::Bit => {
+                        cmptime
+                            .var_info
+                            .insert(name.to_string(), (false, 1, ASTNode::Bit));
+                        cmptime.program.push_str(
+                            format!("BAL \"{name}\" 1\nBCP \"{name}\" \"TMP_B\"\nBFR \"TMP_B\"\n")
+                                .as_str(),
+                        );
+                        Ok(Some(cmptime.clone()))
+                    }
+                    ASTNode::ArrayType { type_, size } if matches!(**type_, ASTNode::Bit) => {
+                        let s = match **size {
+                            ASTNode::Num(num) => num,
+                            _ => return Err("BACKEND_ERROR: Expected ASTNode::Num".to_string()),
+                        };
+                        cmptime
+                            .var_info
+                            .insert(name.to_string(), (false, s as usize, f.1.clone()));
+                        cmptime.program.push_str(
+                            format!(
+                                "BAL \"{name}\" {s}\nBCP \"{name}\" \"TMP_B\"\nBFR \"TMP_B\"\n"
+                            )
+                            .as_str(),
+                        );
+                        Ok(Some(cmptime.clone()))
+                    }
                     ASTNode::ArrayType { type_, size } => {
                         cmptime
                             .vars
@@ -1160,3 +2026,257 @@ where
 }
 
 */
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Compiler;
+
+    fn ir(src: &str) -> String {
+        Compiler::new().compile_str(src).unwrap().ir
+    }
+
+    /// The instructions of `src` after its allocations, without trailing spaces.
+    fn gates(src: &str) -> Vec<String> {
+        ir(src)
+            .lines()
+            .filter(|l| !l.starts_with("QAL") && !l.starts_with("BAL"))
+            .map(|l| l.trim_end().to_string())
+            .collect()
+    }
+
+    fn error(src: &str) -> String {
+        Compiler::new().compile_str(src).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn loops_that_read_their_index_are_unrolled() {
+        let repeated = ir("let a: qbit;\nfor (k in 0..4){\n  HAD(a);\n  T(a);\n}\n");
+        assert!(repeated.contains("REP 4\n"), "{repeated}");
+        assert_eq!(repeated.matches("HAD").count(), 1);
+        for body in [
+            "HAD(q[k]);",
+            "HAD(q[(k+1)*2-2]);",
+            "for (j in 0..k){\n  HAD(q[j]);\n}",
+        ] {
+            let src = format!("let q: qbit[8];\nfor (k in 0..4){{\n  {body}\n}}\n");
+            assert!(!ir(&src).contains("REP"), "{body}");
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let reg = "let reg: qbit[6];\n";
+        let run = |body: &str| gates(&format!("{reg}{body}"));
+        assert_eq!(
+            run("for (i in 0..6 by 2){\n  PX(reg[i]);\n}"),
+            ["PX $reg_0", "PX $reg_2", "PX $reg_4"]
+        );
+        assert_eq!(
+            run("for (i in 1..=3){\n  PX(reg[i]);\n}"),
+            ["PX $reg_1", "PX $reg_2", "PX $reg_3"]
+        );
+        // the end below the start counts down
+        assert_eq!(
+            run("for (i in 5..=3){\n  PX(reg[i]);\n}"),
+            ["PX $reg_5", "PX $reg_4", "PX $reg_3"]
+        );
+        assert_eq!(
+            run("for (i in 4..1 by 2){\n  PX(reg[i]);\n}"),
+            ["PX $reg_4", "PX $reg_2"]
+        );
+        assert!(run("for (i in 2..2){\n  PX(reg[i]);\n}").is_empty());
+        // bounds from an outer index, m is 1 for n = 1 and 2, 4 for n = 2
+        assert_eq!(
+            run("for (n in 1..3){\n  for (m in n..=(n*2) by 2){\n    HAD(reg[m]);\n  }\n}"),
+            ["HAD $reg_1", "HAD $reg_2", "HAD $reg_4"]
+        );
+        // an inner loop of the same name ends with the outer value back in place
+        assert_eq!(
+            run("for (i in 0..2){\n  for (i in 4..5){\n    PX(reg[i]);\n  }\n  PZ(reg[i]);\n}"),
+            ["PX $reg_4", "PZ $reg_0", "PX $reg_4", "PZ $reg_1"]
+        );
+        assert_eq!(
+            run("for (q in reg[4..=1]){\n  PZ(q);\n}"),
+            ["PZ $reg_4", "PZ $reg_3", "PZ $reg_2", "PZ $reg_1"]
+        );
+        let e = error(&format!("{reg}for (i in 0..3 by 0){{\n  HAD(reg[i]);\n}}"));
+        assert!(e.contains("range step must be positive, got 0"), "{e}");
+        let e = error(&format!("{reg}for (q in reg[2..7]){{\n  HAD(q);\n}}"));
+        assert!(
+            e.contains("reg[6] is out of bounds, 'reg' has 6 qubits"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn views_and_concatenation() {
+        let decls = "void kette(r: qbit[3]) {\n  CNT(r[0], r[1]);\n  CNT(r[1], r[2]);\n}\nlet reg: qbit[4];\nlet extra: qbit;\n";
+        let run = |body: &str| gates(&format!("{decls}{body}"));
+        assert_eq!(
+            run("kette(reg[1..4]);"),
+            ["CNT $reg_1 $reg_2", "CNT $reg_2 $reg_3"]
+        );
+        assert_eq!(
+            run("kette(reg[0..2] ++ extra);"),
+            ["CNT $reg_0 $reg_1", "CNT $reg_1 $extra"]
+        );
+        assert_eq!(
+            run("kette(reg[3..=0 by 2] ++ extra);"),
+            ["CNT $reg_3 $reg_1", "CNT $reg_1 $extra"]
+        );
+        assert_eq!(
+            run("for (q in extra ++ reg[2..4]){\n  HAD(q);\n}"),
+            ["HAD $extra", "HAD $reg_2", "HAD $reg_3"]
+        );
+        assert_eq!(run("HAD(reg[1+2]);"), ["HAD $reg_3"]);
+
+        let error = |body: &str| error(&format!("{decls}{body}"));
+        let e = error("HAD(reg[4]);");
+        assert!(
+            e.contains("reg[4] is out of bounds, 'reg' has 4 qubits"),
+            "{e}"
+        );
+        let e = error("kette(reg[0..2]);");
+        assert!(
+            e.contains("'r' of 'kette' takes qbit[3], got qbit[2]"),
+            "{e}"
+        );
+        let e = error("kette(reg ++ extra);");
+        assert!(
+            e.contains("'r' of 'kette' takes qbit[3], got qbit[5]"),
+            "{e}"
+        );
+        let e = error("HAD(reg[0..2]);");
+        assert!(
+            e.contains("HAD takes single qubits, got qbit[2] in one argument"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn grids() {
+        let decls = "void kette(r: qbit[3]) {\n  CNT(r[0], r[1]);\n  CNT(r[1], r[2]);\n}\nlet grid: qbit[2][3];\n";
+        let src = format!("{decls}HAD(grid[1][2]);");
+        let ir = ir(&src);
+        let allocated: Vec<&str> = ir
+            .lines()
+            .filter_map(|l| l.strip_prefix("QAL & 0 $ "))
+            .map(|l| l.trim().trim_matches('"'))
+            .collect();
+        assert_eq!(
+            allocated,
+            ["grid_0_0", "grid_0_1", "grid_0_2", "grid_1_0", "grid_1_1", "grid_1_2"]
+        );
+        let registers = Compiler::new().compile_str(&src).unwrap().registers;
+        assert_eq!(registers["grid_1_2"], "grid[1][2]");
+        assert_eq!(registers.len(), 6);
+
+        let run = |body: &str| gates(&format!("{decls}{body}"));
+        assert_eq!(run("HAD(grid[1][2]);"), ["HAD $grid_1_2"]);
+        assert_eq!(
+            run("for (row in grid){\n  kette(row);\n}"),
+            [
+                "CNT $grid_0_0 $grid_0_1",
+                "CNT $grid_0_1 $grid_0_2",
+                "CNT $grid_1_0 $grid_1_1",
+                "CNT $grid_1_1 $grid_1_2"
+            ]
+        );
+        assert_eq!(
+            run("for (q in grid[0..2][2]){\n  T(q);\n}"),
+            ["T $grid_0_2", "T $grid_1_2"]
+        );
+
+        let error = |body: &str| error(&format!("{decls}{body}"));
+        let e = error("HAD(grid[2][0]);");
+        assert!(
+            e.contains("grid[2] is out of bounds, 'grid' has 2 rows"),
+            "{e}"
+        );
+        let e = error("HAD(grid[0][3]);");
+        assert!(
+            e.contains("grid[0][3] is out of bounds, 'grid[0]' has 3 qubits"),
+            "{e}"
+        );
+        let e = error("let grid_1_0: qbit;");
+        assert!(e.contains("'grid_1_0' would also be grid[1][0]"), "{e}");
+    }
+
+    #[test]
+    fn asm_is_checked_against_the_instruction_set() {
+        for (op, forms) in crate::ir::INSTRUCTIONS {
+            if ["REP", "END"].contains(&op) {
+                continue;
+            }
+            for form in forms {
+                let operands: Vec<&str> = form
+                    .chars()
+                    .map(|c| match c {
+                        'q' => "$a",
+                        'd' => "%d",
+                        'n' => "1",
+                        'r' => "\"c\"",
+                        _ => "&",
+                    })
+                    .collect();
+                let line = format!("{op} {}", operands.join(" "));
+                assert!(ir(&format!("asm {{ {line} }}")).contains(&line), "{line}");
+                // one operand too many never matches a form
+                let e = error(&format!("asm {{ {line} $a }}"));
+                assert!(e.contains("doesn't match"), "{line}: {e}");
+            }
+        }
+        assert!(ir("asm { REP 2; PX $a; END }").contains("REP 2\nPX $a\nEND\n"));
+        let e = error("asm { REP 2; PX $a }");
+        assert!(e.contains("REP without END"), "{e}");
+        let e = error("asm { FOO $a }");
+        assert!(e.contains("unknown instruction 'FOO'"), "{e}");
+    }
+
+    #[test]
+    fn asm_operands_resolve_like_gate_arguments() {
+        let src = "let q: qbit;\nlet reg: qbit[3];\nfor (i in 0..2){\n  asm { CNT reg[i] reg[i + 1]; RZ q i }\n}\nasm { TOF q, reg[0], $anc }";
+        assert_eq!(
+            gates(src),
+            [
+                "CNT $reg_0 $reg_1",
+                "RZ $q 0",
+                "CNT $reg_1 $reg_2",
+                "RZ $q 1",
+                "TOF $q $reg_0 $anc"
+            ]
+        );
+        let e = error("let reg: qbit[3];\nasm { HAD reg[3] }");
+        assert!(e.contains("reg[3] is out of bounds"), "{e}");
+        let e = error("let q: qbit;\nasm { HAD p }");
+        assert!(e.contains("'p' in HAD is not declared"), "{e}");
+        let e = error("let reg: qbit[3];\nasm { HAD reg }");
+        assert!(
+            e.contains("'reg' in HAD is not a single register or loop index"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn negative_literals() {
+        assert_eq!(
+            gates("let a: qbit;\nlet b: qbit;\nRZ(b, -0.25);\nRX(b, -1);\nctrl(1) RY(a, b, -0.5);")
+                [..2],
+            ["RZ $b -0.25", "RX $b -1"]
+        );
+        assert_eq!(gates("let b: qbit;\ninv RZ(b, -0.25);"), ["RZ $b 0.25"]);
+        let e = error("let b: qbit;\nRZ(b, -b);");
+        assert!(e.contains("Expected number after '-'"), "{e}");
+    }
+
+    #[test]
+    fn bit_array_sizes() {
+        let e = error("let q: qbit;\nlet c: bit[x];");
+        assert!(e.contains("expected size of bit array, got x"), "{e}");
+        let e = error("let q: qbit;\nlet c: bit[0.5];");
+        assert!(
+            e.contains("expected whole number as size of bit array, got 0.5"),
+            "{e}"
+        );
+    }
+}
@@ -0,0 +1,416 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::panic;
+use std::path::{Path, PathBuf};
+
+use crate::basis::{parse_basis, translate};
+use crate::cache::Cache;
+use crate::code_gen::{code_gen, Comptime};
+use crate::ir::{loops, parse_program, unroll};
+use crate::module::Loader;
+use crate::opt::{self, optimize};
+use crate::regalloc::allocate;
+use crate::route::{physical, route, CouplingMap};
+use crate::zx;
+
+// instructions are what the backends read, they only change together with `out.txt`
+pub use crate::ir::{Instruction, Operand, Program};
+
+/// The device a program is compiled for. The default accepts every gate between any two
+/// qubits, which is what the simulators run.
+#[derive(Debug, Clone, Default)]
+pub struct Target {
+    pub basis: Option<Vec<String>>,
+    pub coupling: Option<CouplingMap>,
+}
+
+impl Target {
+    pub fn ideal() -> Target {
+        Target::default()
+    }
+
+    /// A predefined gate set (`rz-sx-cnt`, `had-t-cnt`) or a list like `RX,RY,RZ,CNT`.
+    pub fn basis(gates: &str) -> Result<Target, Diagnostics> {
+        let basis = parse_basis(gates).map_err(|e| Diagnostics::one(Stage::Target, e))?;
+        Ok(Target {
+            basis: Some(basis),
+            coupling: None,
+        })
+    }
+
+    /// Restricts two-qubit gates to the edges of `edges`, one `a b` pair per line.
+    pub fn coupling(self, edges: &str) -> Result<Target, Diagnostics> {
+        let map = CouplingMap::parse(edges).map_err(|e| Diagnostics::one(Stage::Target, e))?;
+        Ok(Target {
+            coupling: Some(map),
+            ..self
+        })
+    }
+}
+
+/// The step of the pipeline a diagnostic comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Target,
+    /// tokenizer, parser and code generation
+    Frontend,
+    /// reading backend instructions
+    Ir,
+    Zx,
+    Basis,
+    Route,
+}
+
+/// A word of the QEL source, lines and columns count from 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub stage: Stage,
+    /// position in the QEL source, if the error can be traced back to one
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// The prefix of messages like `BASIS_ERROR: ...`.
+    pub fn code(&self) -> Option<&str> {
+        let (code, _) = self.message.split_once(": ")?;
+        code.ends_with("_ERROR").then_some(code)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{}:{}: {}", span.line + 1, span.col + 1, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Why a compilation failed, never empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostics {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    fn one(stage: Stage, message: String) -> Diagnostics {
+        Diagnostics {
+            diagnostics: vec![Diagnostic {
+                stage,
+                span: None,
+                message,
+            }],
+        }
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines: Vec<String> = self.diagnostics.iter().map(|d| d.to_string()).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+/// One rewrite of the optimizer, e.g. `cancel` removing `HAD $a; HAD $a`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rewrite {
+    pub rule: String,
+    pub before: Vec<Instruction>,
+    /// the replacement, `None` if the instructions were removed
+    pub after: Option<Instruction>,
+}
+
+impl From<opt::Rewrite> for Rewrite {
+    fn from(r: opt::Rewrite) -> Rewrite {
+        Rewrite {
+            rule: r.rule.to_string(),
+            before: r.before,
+            after: r.after,
+        }
+    }
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let before: Vec<String> = self.before.iter().map(|i| i.to_string()).collect();
+        write!(f, "{:<14} {}", self.rule, before.join("; "))?;
+        match &self.after {
+            Some(a) => write!(f, " => {a}"),
+            None => write!(f, " => (removed)"),
+        }
+    }
+}
+
+/// A compiled program and what the passes did to it.
+#[derive(Debug, Clone)]
+pub struct Artifact {
+    pub program: Program,
+    /// `program` as backend instructions, the format of `out.txt`
+    pub ir: String,
+    pub rewrites: Vec<Rewrite>,
+    /// one line per pass that ran, e.g. the number of SWAPs routing inserted
+    pub notes: Vec<String>,
+    /// the array element each register stands for, `grid_1_2` is `grid[1][2]`; empty when
+    /// compiling backend instructions
+    pub registers: HashMap<String, String>,
+}
+
+/// Compiles QEL source or backend instructions in memory. The file system is only read by
+/// `compile_file`, for imports that are not added with `module` and by the `cache_dir`.
+///
+/// ```
+/// use tokenizer::compiler::{Compiler, Target};
+///
+/// let artifact = Compiler::new()
+///     .target(Target::basis("rz-sx-cnt")?)
+///     .opt_level(2)
+///     .compile_str("let q: qbit; HAD(q);")?;
+/// println!("{}", artifact.ir);
+/// # Ok::<(), tokenizer::compiler::Diagnostics>(())
+/// ```
+///
+/// This module is the supported way to embed the compiler, the other modules may change
+/// between versions. Everything it returns is defined or re-exported here.
+#[derive(Debug, Clone, Default)]
+pub struct Compiler {
+    target: Target,
+    opt_level: u8,
+    regalloc: bool,
+    zx: bool,
+    loader: Loader,
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        Compiler::default()
+    }
+
+    pub fn target(self, target: Target) -> Compiler {
+        Compiler { target, ..self }
+    }
+
+    /// 0 (the default) to 2, see `opt::optimize`. Higher levels behave like 2.
+    pub fn opt_level(self, opt_level: u8) -> Compiler {
+        Compiler {
+            opt_level: opt_level.min(2),
+            ..self
+        }
+    }
+
+    /// Simplifies every run of gates as a ZX diagram to lower the T-count, see `zx::reduce`.
+    pub fn zx(self, zx: bool) -> Compiler {
+        Compiler { zx, ..self }
+    }
+
+    /// Frees qubits after their last use so later allocations reuse them.
+    pub fn regalloc(self, regalloc: bool) -> Compiler {
+        Compiler { regalloc, ..self }
+    }
+
+    /// Adds a directory `import` and `use` look in.
+    pub fn search_path(mut self, dir: impl Into<PathBuf>) -> Compiler {
+        self.loader.search_path.push(dir.into());
+        self
+    }
+
+    /// Keeps tokens and generated code in `dir`, keyed by a hash of their input and the
+    /// compiler build, so unchanged functions, modules and statements are not processed again.
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Compiler {
+        self.loader.disk = Some(Cache::new(dir));
+        self
+    }
+
+    /// Makes `src` importable as `path`, e.g. `lib/oracles.qel`, without a file.
+    pub fn module(mut self, path: impl Into<PathBuf>, src: &str) -> Compiler {
+        self.loader.add_source(path, src);
+        self
+    }
+
+    /// Compiles a QEL program, its imports are found through the search path and `module`.
+    pub fn compile_str(&self, src: &str) -> Result<Artifact, Diagnostics> {
+        self.compile_at(src, None)
+    }
+
+    /// Compiles the QEL file at `path`, its imports are looked up next to it first.
+    pub fn compile_file(&self, path: impl AsRef<Path>) -> Result<Artifact, Diagnostics> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path)
+            .map_err(|e| Diagnostics::one(Stage::Frontend, format!("{}: {e}", path.display())))?;
+        self.compile_at(&src, Some(path))
+    }
+
+    fn compile_at(&self, src: &str, from: Option<&Path>) -> Result<Artifact, Diagnostics> {
+        let mut loader = self.loader.clone();
+        let comptime =
+            frontend(src, &mut loader, from).map_err(|e| Diagnostics::one(Stage::Frontend, e))?;
+        let mut artifact = self.compile_ir(&comptime.program)?;
+        artifact.registers = comptime.source_names();
+        if let Some(cache) = &loader.disk {
+            artifact.notes.insert(0, cache.note());
+        }
+        Ok(artifact)
+    }
+
+    /// Runs the passes over backend instructions, e.g. the `ir` of an earlier artifact.
+    pub fn compile_ir(&self, ir: &str) -> Result<Artifact, Diagnostics> {
+        let program = parse_program(ir).map_err(|e| Diagnostics::one(Stage::Ir, e))?;
+        let Target { basis, coupling } = &self.target;
+        let passes = self.opt_level > 0 || self.regalloc || self.zx;
+        if !passes && basis.is_none() && coupling.is_none() {
+            return Ok(Artifact {
+                program,
+                ir: ir.to_string(),
+                rewrites: vec![],
+                notes: vec![],
+                registers: HashMap::new(),
+            });
+        }
+        let mut notes = vec![];
+        // the passes work on straight-line code
+        let blocks = loops(&program)
+            .map_err(|e| Diagnostics::one(Stage::Ir, e))?
+            .len();
+        let before = program.instructions.len();
+        let program = unroll(&program).map_err(|e| Diagnostics::one(Stage::Ir, e))?;
+        if blocks > 0 {
+            notes.push(format!(
+                "loops: {blocks} REP blocks unrolled for the passes, {before} instructions -> {}",
+                program.instructions.len()
+            ));
+        }
+        let (mut program, rewrites) = optimize(&program, self.opt_level);
+        if self.zx {
+            let reduced = zx::reduce(&program).map_err(|e| Diagnostics::one(Stage::Zx, e))?;
+            notes.push(format!(
+                "zx: T-count {} -> {}, CNOTs {} -> {}",
+                reduced.t_before, reduced.t_after, reduced.cnots_before, reduced.cnots_after
+            ));
+            program = reduced.program;
+        }
+        if let Some(basis) = basis {
+            let before = program.instructions.len();
+            program = translate(&program, basis).map_err(|e| Diagnostics::one(Stage::Basis, e))?;
+            notes.push(format!(
+                "basis {{{}}}: {before} instructions -> {}",
+                basis.join(", "),
+                program.instructions.len()
+            ));
+        }
+        if self.regalloc {
+            let alloc = allocate(&program);
+            notes.push(format!(
+                "peak qubits: {} ({} without register allocation)",
+                alloc.peak, alloc.peak_before
+            ));
+            program = alloc.program;
+        }
+        if let Some(map) = coupling {
+            let routed = route(&program, map).map_err(|e| Diagnostics::one(Stage::Route, e))?;
+            notes.push(format!(
+                "routing: {} SWAPs inserted ({} CNTs), two-qubit gates {} -> {}",
+                routed.swaps,
+                3 * routed.swaps,
+                routed.two_qubit_before,
+                routed.two_qubit_after
+            ));
+            for ((name, start), (_, end)) in routed.initial.iter().zip(&routed.layout) {
+                notes.push(format!(
+                    "  ${name}: ${} -> ${}",
+                    physical(*start),
+                    physical(*end)
+                ));
+            }
+            program = routed.program;
+        }
+        Ok(Artifact {
+            ir: program.to_string(),
+            program,
+            rewrites: rewrites.into_iter().map(Rewrite::from).collect(),
+            notes,
+            registers: HashMap::new(),
+        })
+    }
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown error".to_string()
+    }
+}
+
+thread_local! {
+    // set while the thread runs the frontend, its panics are reported as diagnostics
+    static IN_FRONTEND: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f`, which calls into the frontend, and turns a panic into an error.
+pub(crate) fn catch_frontend<T>(f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    let outer = IN_FRONTEND.replace(true);
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(f));
+    IN_FRONTEND.set(outer);
+    result.unwrap_or_else(|payload| Err(panic_message(payload)))
+}
+
+/// Installs a panic hook that stays quiet for panics of the frontend, which come back as
+/// diagnostics anyway, and hands every other panic to the previous hook. The hook belongs to
+/// the whole process, so this is for binaries to call once at startup.
+pub fn quiet_frontend_panics() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if !IN_FRONTEND.get() {
+            previous(info);
+        }
+    }));
+}
+
+/// Loads `src` with its imports and generates its code, the cache of `loader` is used if
+/// it has one.
+pub(crate) fn frontend(
+    src: &str,
+    loader: &mut Loader,
+    from: Option<&Path>,
+) -> Result<Comptime, String> {
+    catch_frontend(|| {
+        let ast = loader.load(src, from)?;
+        let comptime = match &mut loader.disk {
+            Some(cache) => cache.code_gen(ast),
+            None => code_gen(ast),
+        };
+        comptime.map_err(|e| format!("code generation failed: {e}"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frontend_errors_are_the_pipeline_errors() {
+        let e = Compiler::new()
+            .compile_str("import \"missing.qel\";\nlet q: qbit;\nHAD(q);")
+            .unwrap_err();
+        let [d] = e.diagnostics.as_slice() else {
+            panic!("{e}");
+        };
+        assert_eq!((d.stage, d.code()), (Stage::Frontend, Some("MODULE_ERROR")));
+        let e = Compiler::new()
+            .compile_str("let q: qbit;\nctrl(2) HAD(q);")
+            .unwrap_err();
+        assert!(e.to_string().contains("code generation failed"), "{e}");
+    }
+}
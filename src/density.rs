@@ -0,0 +1,283 @@
+use crate::complex::{Complex, ONE, ZERO};
+use crate::sim::{Engine, Gate, Matrix2, Rng};
+
+/// Memory grows with 4^n, 12 qubits already take 256 MiB.
+const MAX_QUBITS: usize = 12;
+
+/// A mixed state `rho` of `n` qubits, stored row major. Qubit `q` is bit `q` of the row and
+/// column index, like the amplitudes of a `Statevector`.
+#[derive(Debug, Clone)]
+pub struct DensityMatrix {
+    pub n: usize,
+    pub rho: Vec<Complex>,
+}
+
+impl Default for DensityMatrix {
+    fn default() -> Self {
+        DensityMatrix::new()
+    }
+}
+
+impl DensityMatrix {
+    pub fn new() -> DensityMatrix {
+        DensityMatrix {
+            n: 0,
+            rho: vec![ONE],
+        }
+    }
+
+    fn dim(&self) -> usize {
+        1 << self.n
+    }
+
+    /// Applies the channel `rho -> sum K rho K^dagger` with single qubit Kraus operators on `q`.
+    pub fn channel(&mut self, q: usize, kraus: &[Matrix2]) {
+        // the channel as a 4x4 map on the entries (00, 01, 10, 11) of each 2x2 block
+        let mut sup = [[ZERO; 4]; 4];
+        for k in kraus {
+            for (ij, row) in sup.iter_mut().enumerate() {
+                for (ab, x) in row.iter_mut().enumerate() {
+                    *x += k[ij >> 1][ab >> 1] * k[ij & 1][ab & 1].conj();
+                }
+            }
+        }
+        let (dim, bit) = (self.dim(), 1 << q);
+        let offsets = [0, bit, bit * dim, bit * dim + bit];
+        for r in (0..dim).filter(|r| r & bit == 0) {
+            for c in (0..dim).filter(|c| c & bit == 0) {
+                let at = r * dim + c;
+                let block = offsets.map(|o| self.rho[at + o]);
+                for (row, o) in sup.iter().zip(offsets) {
+                    let mut x = ZERO;
+                    for (s, b) in row.iter().zip(block) {
+                        x += *s * b;
+                    }
+                    self.rho[at + o] = x;
+                }
+            }
+        }
+    }
+
+    /// Probability of every basis state, the diagonal of `rho`.
+    pub fn probabilities(&self) -> Vec<f64> {
+        let dim = self.dim();
+        (0..dim).map(|i| self.rho[i * dim + i].re).collect()
+    }
+
+    /// `<psi|rho|psi>`, the fidelity with a pure state over the same qubits.
+    pub fn fidelity(&self, psi: &[Complex]) -> f64 {
+        let dim = self.dim();
+        let mut f = ZERO;
+        for r in 0..dim {
+            for c in 0..dim {
+                f += psi[r].conj() * self.rho[r * dim + c] * psi[c];
+            }
+        }
+        f.re
+    }
+
+    /// The reduced 2x2 state of `q`.
+    fn reduced(&self, q: usize) -> Matrix2 {
+        let (dim, bit) = (self.dim(), 1 << q);
+        let mut out = [[ZERO; 2]; 2];
+        for r in (0..dim).filter(|r| r & bit == 0) {
+            for (i, row) in out.iter_mut().enumerate() {
+                for (j, x) in row.iter_mut().enumerate() {
+                    *x += self.rho[(r + i * bit) * dim + (r + j * bit)];
+                }
+            }
+        }
+        out
+    }
+}
+
+impl Engine for DensityMatrix {
+    fn alloc(&mut self) -> Result<usize, String> {
+        if self.n >= MAX_QUBITS {
+            return Err(format!(
+                "SIM_ERROR: the density matrix simulator is limited to {MAX_QUBITS} qubits"
+            ));
+        }
+        let dim = self.dim();
+        let mut rho = vec![ZERO; 4 * dim * dim];
+        for r in 0..dim {
+            rho[r * 2 * dim..r * 2 * dim + dim].copy_from_slice(&self.rho[r * dim..(r + 1) * dim]);
+        }
+        self.rho = rho;
+        self.n += 1;
+        Ok(self.n - 1)
+    }
+
+    fn apply(&mut self, gate: &Gate, controls: &[usize], target: usize) -> Result<(), String> {
+        let m = gate.matrix();
+        let (dim, bit) = (self.dim(), 1 << target);
+        let mask = controls.iter().fold(0, |m, c| m | (1 << c));
+        if mask & bit != 0 {
+            return Err("SIM_ERROR: control and target are the same register".to_string());
+        }
+        let active = |i: usize| i & bit == 0 && i & mask == mask;
+        // U rho: the gate on every column
+        for r in (0..dim).filter(|r| active(*r)) {
+            for c in 0..dim {
+                let (a, b) = (self.rho[r * dim + c], self.rho[(r | bit) * dim + c]);
+                self.rho[r * dim + c] = m[0][0] * a + m[0][1] * b;
+                self.rho[(r | bit) * dim + c] = m[1][0] * a + m[1][1] * b;
+            }
+        }
+        // (U rho) U^dagger: the conjugate gate on every row
+        for r in 0..dim {
+            for c in (0..dim).filter(|c| active(*c)) {
+                let (a, b) = (self.rho[r * dim + c], self.rho[r * dim + (c | bit)]);
+                self.rho[r * dim + c] = a * m[0][0].conj() + b * m[0][1].conj();
+                self.rho[r * dim + (c | bit)] = a * m[1][0].conj() + b * m[1][1].conj();
+            }
+        }
+        Ok(())
+    }
+
+    fn measure(&mut self, q: usize, rng: &mut Rng) -> Result<bool, String> {
+        let (dim, bit) = (self.dim(), 1 << q);
+        let p1: f64 = (0..dim)
+            .filter(|i| i & bit != 0)
+            .map(|i| self.rho[i * dim + i].re)
+            .sum();
+        let outcome = rng.next_f64() < p1;
+        let p = if outcome { p1 } else { 1.0 - p1 };
+        for r in 0..dim {
+            for c in 0..dim {
+                let x = &mut self.rho[r * dim + c];
+                if (r & bit != 0) == outcome && (c & bit != 0) == outcome {
+                    *x = x.scale(1.0 / p);
+                } else {
+                    *x = ZERO;
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Resetting does not need a random outcome on a mixed state, it is the channel
+    /// `|0><0| rho |0><0| + |0><1| rho |1><0|`.
+    fn reset(&mut self, q: usize, _: &mut Rng) -> Result<(), String> {
+        self.channel(
+            q,
+            &[[[ONE, ZERO], [ZERO, ZERO]], [[ZERO, ONE], [ZERO, ZERO]]],
+        );
+        Ok(())
+    }
+
+    fn set(&mut self, q: usize, a: Complex, b: Complex) -> Result<(), String> {
+        let norm = (a.norm_sqr() + b.norm_sqr()).sqrt();
+        if norm == 0.0 {
+            return Err("SIM_ERROR: SET with a zero state".to_string());
+        }
+        let psi = [a.scale(1.0 / norm), b.scale(1.0 / norm)];
+        let (dim, bit) = (self.dim(), 1 << q);
+        for r in (0..dim).filter(|r| r & bit == 0) {
+            for c in (0..dim).filter(|c| c & bit == 0) {
+                let at = |i: usize, j: usize| (r + i * bit) * dim + (c + j * bit);
+                let rest = self.rho[at(0, 0)] + self.rho[at(1, 1)];
+                for i in 0..2 {
+                    for j in 0..2 {
+                        self.rho[at(i, j)] = rest * psi[i] * psi[j].conj();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn amplitudes(&self, q: usize) -> Result<(Complex, Complex), String> {
+        let [[p0, c], [_, p1]] = self.reduced(q);
+        let purity = p0.re * p0.re + p1.re * p1.re + 2.0 * c.norm_sqr();
+        if (purity - 1.0).abs() > 1e-9 {
+            return Err(
+                "SIM_ERROR: register is mixed or entangled, its state can't be read".to_string(),
+            );
+        }
+        if p0.re > 1e-12 {
+            let x0 = p0.re.sqrt();
+            Ok((Complex::real(x0), c.conj().scale(1.0 / x0)))
+        } else {
+            Ok((ZERO, ONE))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_program;
+    use crate::sim::{Machine, Statevector};
+
+    fn trace(m: &DensityMatrix) -> f64 {
+        m.probabilities().iter().sum()
+    }
+
+    const CIRCUIT: &str = "QAL & 0 $ \"a\"
+QAL & 0 $ \"b\"
+QAL & 0 $ \"c\"
+HAD $a
+RY $b 0.4
+CNT $a $c
+T $c
+CY $c $b
+RX $a -1.3
+TOF $a $b $c
+S $b
+";
+
+    #[test]
+    fn pure_states_match_the_statevector() {
+        let program = parse_program(CIRCUIT).unwrap();
+        let mut pure = Machine::new(Statevector::new(), vec![], 0);
+        let mut mixed = Machine::new(DensityMatrix::new(), vec![], 0);
+        pure.run(&program).unwrap();
+        mixed.run(&program).unwrap();
+        let expected: Vec<f64> = pure.engine.amps.iter().map(|a| a.norm_sqr()).collect();
+        for (p, q) in mixed.engine.probabilities().iter().zip(&expected) {
+            assert!((p - q).abs() < 1e-12, "{p} != {q}");
+        }
+        assert!((mixed.engine.fidelity(&pure.engine.amps) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn channels_keep_the_trace() {
+        let program = parse_program(CIRCUIT).unwrap();
+        let mut m = Machine::new(DensityMatrix::new(), vec![], 0);
+        m.run(&program).unwrap();
+        let g = 0.3_f64;
+        let damping = [
+            [[ONE, ZERO], [ZERO, Complex::real((1.0 - g).sqrt())]],
+            [[ZERO, Complex::real(g.sqrt())], [ZERO, ZERO]],
+        ];
+        for q in 0..3 {
+            m.engine.channel(q, &damping);
+            assert!((trace(&m.engine) - 1.0).abs() < 1e-12);
+        }
+        let mut rng = Rng::new(1);
+        m.engine.reset(1, &mut rng).unwrap();
+        assert!((trace(&m.engine) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn amplitude_damping_of_one() {
+        // |1> decays to |0> with probability gamma
+        let mut m = DensityMatrix::new();
+        let q = m.alloc().unwrap();
+        m.apply(&Gate::X, &[], q).unwrap();
+        let g = 0.25_f64;
+        m.channel(
+            q,
+            &[
+                [[ONE, ZERO], [ZERO, Complex::real((1.0 - g).sqrt())]],
+                [[ZERO, Complex::real(g.sqrt())], [ZERO, ZERO]],
+            ],
+        );
+        let p = m.probabilities();
+        assert!(
+            (p[0] - g).abs() < 1e-12 && (p[1] - (1.0 - g)).abs() < 1e-12,
+            "{p:?}"
+        );
+    }
+}
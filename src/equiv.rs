@@ -0,0 +1,332 @@
+use std::f64::consts::PI;
+
+use crate::complex::{Complex, ONE, ZERO};
+use crate::ir::{unroll, Program};
+use crate::qudit;
+use crate::sim::{self, Engine, Machine, Rng};
+use crate::stabilizer::{non_clifford, Tableau};
+use crate::unitary::{self, Matrix, MAX_QUBITS};
+
+/// Random product states tried when the unitary is too large to build.
+const TRIALS: usize = 8;
+
+/// Amplitudes below this are left out when a state is printed.
+const SHOWN: f64 = 1e-6;
+
+/// How two programs were compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// full unitaries, up to `MAX_QUBITS` registers
+    Unitary,
+    /// images of every X and Z under two Clifford programs, any size
+    Tableau,
+    /// statevector runs on random product states, equivalence holds with probability one
+    /// but is not a proof
+    RandomStates,
+}
+
+/// An input on which the programs differ by more than a global phase.
+#[derive(Debug, Clone)]
+pub struct Counterexample {
+    pub input: String,
+    pub a: String,
+    pub b: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub method: Method,
+    /// the compared registers, qubit k is character k of every ket
+    pub registers: Vec<String>,
+    pub counterexample: Option<Counterexample>,
+}
+
+impl Report {
+    pub fn equivalent(&self) -> bool {
+        self.counterexample.is_none()
+    }
+
+    pub fn report(&self) -> String {
+        let method = match self.method {
+            Method::Unitary => "unitary".to_string(),
+            Method::Tableau => "stabilizer tableau".to_string(),
+            Method::RandomStates => format!("{TRIALS} random product states"),
+        };
+        let registers: Vec<String> = self.registers.iter().map(|r| format!("${r}")).collect();
+        let mut out = format!("registers  {}\nmethod     {method}\n", registers.join(" "));
+        match &self.counterexample {
+            None if self.method == Method::RandomStates => {
+                out.push_str("no difference found (not a proof)\n")
+            }
+            None => out.push_str("equivalent up to global phase\n"),
+            Some(c) => {
+                out.push_str("NOT equivalent\n");
+                out.push_str(&format!("input      {}\n", c.input));
+                out.push_str(&format!("a gives    {}\n", c.a));
+                out.push_str(&format!("b gives    {}\n", c.b));
+            }
+        }
+        out
+    }
+}
+
+/// Registers a program keeps to the end, the ones it frees are ancillas.
+fn inputs(program: &Program) -> Vec<String> {
+    let freed: Vec<&str> = program
+        .instructions
+        .iter()
+        .filter(|i| i.op == "FRE")
+        .filter_map(|i| i.alloc_name())
+        .collect();
+    unitary::qubits(program)
+        .into_iter()
+        .filter(|q| !freed.contains(&q.as_str()))
+        .collect()
+}
+
+/// Checks that `a` and `b` act the same on their registers, up to global phase. Both have to
+/// use the same register names and be free of measurements; loops are written out and qudit
+/// registers lowered to qubits first.
+pub fn check(a: &Program, b: &Program, externs: &[f64], seed: u64) -> Result<Report, String> {
+    let (a, b) = (qudit::lower(&unroll(a)?)?, qudit::lower(&unroll(b)?)?);
+    let registers = inputs(&a);
+    let other = inputs(&b);
+    let missing = |x: &[String], y: &[String]| -> Vec<String> {
+        x.iter()
+            .filter(|r| !y.contains(r))
+            .map(|r| format!("${r}"))
+            .collect()
+    };
+    let (only_a, only_b) = (missing(&registers, &other), missing(&other, &registers));
+    if !only_a.is_empty() || !only_b.is_empty() {
+        return Err(format!(
+            "EQUIV_ERROR: the programs use different registers, only in a: [{}], only in b: [{}]",
+            only_a.join(", "),
+            only_b.join(", ")
+        ));
+    }
+    let clifford = non_clifford(&a).is_empty() && non_clifford(&b).is_empty();
+    let ancillas = unitary::qubits(&a).len() + unitary::qubits(&b).len() > 2 * registers.len();
+    let (method, counterexample) = if registers.len() <= MAX_QUBITS {
+        (Method::Unitary, by_unitary(&a, &b, &registers, externs)?)
+    } else if clifford && !ancillas {
+        (Method::Tableau, by_tableau(&a, &b, &registers, externs)?)
+    } else {
+        (
+            Method::RandomStates,
+            by_random_states(&a, &b, &registers, externs, seed)?,
+        )
+    };
+    Ok(Report {
+        method,
+        registers,
+        counterexample,
+    })
+}
+
+/// `a` followed by `|bits>` for every basis state with a visible amplitude.
+fn ket(state: &[Complex], n: usize) -> String {
+    let terms: Vec<String> = state
+        .iter()
+        .enumerate()
+        .filter(|(_, a)| a.abs() > SHOWN)
+        .map(|(i, a)| {
+            let bits: String = (0..n)
+                .map(|k| if i >> k & 1 == 1 { '1' } else { '0' })
+                .collect();
+            format!("({a})|{bits}>")
+        })
+        .collect();
+    match terms.len() {
+        0..=8 => terms.join(" + "),
+        len => format!("{} + ... ({len} terms)", terms[..8].join(" + ")),
+    }
+}
+
+fn inner(a: &[Complex], b: &[Complex]) -> Complex {
+    let mut sum = ZERO;
+    for (x, y) in a.iter().zip(b) {
+        sum += x.conj() * *y;
+    }
+    sum
+}
+
+/// Two states are the same up to global phase when their overlap has length one.
+fn same_ray(a: &[Complex], b: &[Complex]) -> bool {
+    inner(a, b).abs() > 1.0 - 1e-9
+}
+
+fn differ(input: String, a: &[Complex], b: &[Complex], n: usize) -> Option<Counterexample> {
+    Some(Counterexample {
+        input,
+        a: ket(a, n),
+        b: ket(b, n),
+    })
+}
+
+fn column(m: &Matrix, col: usize) -> Vec<Complex> {
+    m.iter().map(|row| row[col]).collect()
+}
+
+/// A basis state mapped to different states, or failing that two basis states whose
+/// outputs pick up different phases, shown on their equal superposition.
+fn by_unitary(
+    a: &Program,
+    b: &Program,
+    registers: &[String],
+    externs: &[f64],
+) -> Result<Option<Counterexample>, String> {
+    let n = registers.len();
+    let ua = unitary::unitary(a, registers, externs)?;
+    let ub = unitary::unitary(b, registers, externs)?;
+    if unitary::phase_distance(&ua, &ub) < 1e-9 {
+        return Ok(None);
+    }
+    let basis = |i: usize| {
+        let mut state = vec![ZERO; 1 << n];
+        state[i] = ONE;
+        state
+    };
+    let mut phases = vec![];
+    for col in 0..1 << n {
+        let (x, y) = (column(&ua, col), column(&ub, col));
+        if !same_ray(&x, &y) {
+            return Ok(differ(ket(&basis(col), n), &x, &y, n));
+        }
+        phases.push(inner(&x, &y));
+    }
+    let col = (1..1 << n)
+        .find(|c| (phases[*c] - phases[0]).abs() > 1e-9)
+        .unwrap_or(1);
+    let h = Complex::real(0.5f64.sqrt());
+    let mut input = vec![ZERO; 1 << n];
+    input[0] = h;
+    input[col] = h;
+    let x = unitary::apply(a, registers, input.clone(), externs)?;
+    let y = unitary::apply(b, registers, input.clone(), externs)?;
+    Ok(differ(ket(&input, n), &x, &y, n))
+}
+
+fn tableau(program: &Program, registers: &[String], externs: &[f64]) -> Result<Tableau, String> {
+    let mut m = Machine::new(Tableau::new(), externs.to_vec(), 0);
+    for r in registers {
+        let q = m.engine.alloc()?;
+        m.qubits.insert(r.clone(), q);
+    }
+    for ins in &program.instructions {
+        if ["MES", "SET", "CPY", "DCP"].contains(&ins.op.as_str()) {
+            return Err(format!("UNITARY_ERROR: '{ins}' is not a unitary operation"));
+        }
+        if !["QAL", "DAL"].contains(&ins.op.as_str()) {
+            m.step(ins)?;
+        }
+    }
+    Ok(m.engine)
+}
+
+/// Clifford gates are determined up to phase by how they conjugate X and Z on every
+/// register, so comparing the two tableaus is exact. The counterexample is the first Pauli
+/// the programs map differently.
+fn by_tableau(
+    a: &Program,
+    b: &Program,
+    registers: &[String],
+    externs: &[f64],
+) -> Result<Option<Counterexample>, String> {
+    let n = registers.len();
+    let (ta, tb) = (
+        tableau(a, registers, externs)?,
+        tableau(b, registers, externs)?,
+    );
+    let pauli = |(minus, factors): (bool, Vec<(usize, char)>)| {
+        let factors: Vec<String> = factors
+            .iter()
+            .map(|(q, p)| format!("{p}${}", registers[*q]))
+            .collect();
+        let sign = if minus { '-' } else { '+' };
+        match factors.is_empty() {
+            true => format!("{sign}I"),
+            false => format!("{sign}{}", factors.join(" ")),
+        }
+    };
+    for k in 0..2 * n {
+        let (ga, gb) = (ta.generator(k), tb.generator(k));
+        if ga != gb {
+            let p = if k < n { 'X' } else { 'Z' };
+            return Ok(Some(Counterexample {
+                input: format!("the Pauli {p}${}", registers[k % n]),
+                a: pauli(ga),
+                b: pauli(gb),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// If `a` and `b` differ by more than a global phase, almost every product state tells
+/// them apart.
+fn by_random_states(
+    a: &Program,
+    b: &Program,
+    registers: &[String],
+    externs: &[f64],
+    seed: u64,
+) -> Result<Option<Counterexample>, String> {
+    let n = registers.len();
+    if n > sim::MAX_QUBITS {
+        return Err(format!(
+            "EQUIV_ERROR: {n} registers and not Clifford-only, the statevector holds at most {}",
+            sim::MAX_QUBITS
+        ));
+    }
+    let mut rng = Rng::new(seed);
+    for _ in 0..TRIALS {
+        let mut input = vec![ONE];
+        let mut factors = vec![];
+        for r in registers {
+            let theta = (1.0 - 2.0 * rng.next_f64()).acos();
+            let phi = 2.0 * PI * rng.next_f64();
+            let (zero, one) = (
+                Complex::real((theta / 2.0).cos()),
+                Complex::phase(phi).scale((theta / 2.0).sin()),
+            );
+            factors.push(format!("${r}: ({zero})|0> + ({one})|1>"));
+            input = input
+                .iter()
+                .map(|x| *x * zero)
+                .chain(input.iter().map(|x| *x * one))
+                .collect();
+        }
+        let x = unitary::apply(a, registers, input.clone(), externs)?;
+        let y = unitary::apply(b, registers, input, externs)?;
+        if !same_ray(&x, &y) {
+            return Ok(differ(factors.join(", "), &x, &y, n));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_program;
+
+    fn report(text: &str) -> String {
+        let program = parse_program(text).unwrap();
+        check(&program, &program, &[], 0).unwrap().report()
+    }
+
+    #[test]
+    fn random_states_are_not_a_proof() {
+        let mut text = String::new();
+        for k in 0..=MAX_QUBITS {
+            text.push_str(&format!("QAL & 0 $ \"q{k}\"\nT $q{k}\nCNT $q0 $q{k}\n"));
+        }
+        let out = report(&text.replacen("CNT $q0 $q0\n", "", 1));
+        assert!(out.contains("random product states"));
+        assert!(out.contains("no difference found (not a proof)"));
+        assert!(!out.contains("equivalent up to global phase"));
+        let out = report("QAL & 0 $ \"a\"\nT $a\n");
+        assert!(out.contains("equivalent up to global phase"));
+    }
+}
@@ -0,0 +1,351 @@
+use std::fmt;
+
+/// One operand of a backend instruction as it appears in `out.txt`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Qubit(String), // $name
+    Qudit(String), // %name
+    Name(String),  // "name", only used by the allocation instructions
+    Num(f64),
+    Extern(usize),      // ??n, read from args.txt at runtime
+    Scaled(usize, f64), // ??n*f, an extern angle times a constant (gate modifiers)
+    Sigil(char),        // the bare & $ % # of QAL/DAL/FRE/DFR
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub op: String,
+    pub args: Vec<Operand>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Qubit(n) => write!(f, "${n}"),
+            Operand::Qudit(n) => write!(f, "%{n}"),
+            Operand::Name(n) => write!(f, "\"{n}\""),
+            Operand::Num(n) => {
+                if n.fract() == 0.0 {
+                    write!(f, "{}", *n as i64)
+                } else {
+                    write!(f, "{n}")
+                }
+            }
+            Operand::Extern(i) => write!(f, "??{i}"),
+            Operand::Scaled(i, k) => write!(f, "??{i}*{k}"),
+            Operand::Sigil(c) => write!(f, "{c}"),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.op)?;
+        for a in &self.args {
+            write!(f, " {a}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for i in &self.instructions {
+            writeln!(f, "{i}")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_operand(s: &str) -> Result<Operand, String> {
+    if let Some(n) = s.strip_prefix("??") {
+        let invalid = |_| format!("IR_ERROR: invalid extern argument '{s}'");
+        return match n.split_once('*') {
+            Some((i, k)) => Ok(Operand::Scaled(
+                i.parse::<usize>().map_err(invalid)?,
+                k.parse::<f64>()
+                    .map_err(|_| format!("IR_ERROR: invalid extern argument '{s}'"))?,
+            )),
+            None => n.parse::<usize>().map(Operand::Extern).map_err(invalid),
+        };
+    }
+    if s.len() == 1 && "&$%#".contains(s) {
+        return Ok(Operand::Sigil(s.chars().next().unwrap()));
+    }
+    if let Some(n) = s.strip_prefix('$') {
+        return Ok(Operand::Qubit(n.to_string()));
+    }
+    if let Some(n) = s.strip_prefix('%') {
+        return Ok(Operand::Qudit(n.to_string()));
+    }
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        return Ok(Operand::Name(s[1..s.len() - 1].to_string()));
+    }
+    s.parse::<f64>()
+        .map(Operand::Num)
+        .map_err(|_| format!("IR_ERROR: unknown operand '{s}'"))
+}
+
+pub fn parse_instruction(line: &str) -> Result<Option<Instruction>, String> {
+    let mut parts = line.split_whitespace();
+    let op = match parts.next() {
+        None => return Ok(None),
+        Some(op) => op.to_string(),
+    };
+    let args = parts.map(parse_operand).collect::<Result<Vec<_>, _>>()?;
+    Ok(Some(Instruction { op, args }))
+}
+
+/// Every instruction the backends execute with the operands it takes, one letter per operand:
+/// `q` a `$` register, `d` a `%` register, `n` a number or extern argument, `r` a `"name"`
+/// and `s` a bare sigil. Some instructions have more than one form.
+pub const INSTRUCTIONS: [(&str, &[&str]); 36] = [
+    ("QAL", &["snsr"]),
+    ("FRE", &["ssr"]),
+    ("DAL", &["ssr"]),
+    ("DFR", &["ssr"]),
+    ("BAL", &["rn"]),
+    ("BFR", &["r"]),
+    ("BCP", &["rr"]),
+    ("SET", &["qnn"]),
+    ("CPY", &["qq", "dd"]),
+    ("DCP", &["dd"]),
+    ("HAD", &["q"]),
+    ("PX", &["q"]),
+    ("PY", &["q"]),
+    ("PZ", &["q"]),
+    ("S", &["q"]),
+    ("SDG", &["q"]),
+    ("T", &["q"]),
+    ("TDG", &["q"]),
+    ("SX", &["q"]),
+    ("ID", &["q"]),
+    ("RX", &["qn"]),
+    ("RY", &["qn"]),
+    ("RZ", &["qn"]),
+    ("CNT", &["qq", "qqd"]),
+    ("CY", &["qq"]),
+    ("TOF", &["qqq"]),
+    ("MES", &["q", "qrn"]),
+    ("CMB", &["qqd"]),
+    ("DCB", &["qd"]),
+    ("DHAD", &["d"]),
+    ("DPX", &["d"]),
+    ("DCX", &["qd"]),
+    ("DTR", &["dn"]),
+    ("TR", &["dqn"]),
+    ("REP", &["n"]),
+    ("END", &[""]),
+];
+
+/// Checks that `ins` is one of `INSTRUCTIONS` with operands of one of its forms.
+pub fn validate(ins: &Instruction) -> Result<(), String> {
+    let Some((_, forms)) = INSTRUCTIONS.iter().find(|(op, _)| *op == ins.op) else {
+        return Err(format!("IR_ERROR: unknown instruction '{}'", ins.op));
+    };
+    let form: String = ins
+        .args
+        .iter()
+        .map(|a| match a {
+            Operand::Qubit(_) => 'q',
+            Operand::Qudit(_) => 'd',
+            Operand::Num(_) | Operand::Extern(_) | Operand::Scaled(..) => 'n',
+            Operand::Name(_) => 'r',
+            Operand::Sigil(_) => 's',
+        })
+        .collect();
+    if forms.contains(&form.as_str()) {
+        return Ok(());
+    }
+    let expected: Vec<String> = forms
+        .iter()
+        .map(|f| format!("{} {f}", ins.op).trim().to_string())
+        .collect();
+    Err(format!(
+        "IR_ERROR: '{ins}' doesn't match {} (q = $register, d = %register, n = number, r = \"name\", s = sigil)",
+        expected.join(" or ")
+    ))
+}
+
+/// For every `REP n` the index of its `END`. `REP` repeats the instructions up to the
+/// matching `END` `n` times, blocks nest.
+pub fn loops(program: &Program) -> Result<Vec<(usize, usize)>, String> {
+    let mut open = vec![];
+    let mut blocks = vec![];
+    for (k, ins) in program.instructions.iter().enumerate() {
+        match ins.op.as_str() {
+            "REP" => {
+                repetitions(ins)?;
+                open.push(k);
+            }
+            "END" => {
+                let start = open
+                    .pop()
+                    .ok_or(format!("IR_ERROR: END without REP (instruction {})", k + 1))?;
+                blocks.push((start, k));
+            }
+            _ => {}
+        }
+    }
+    match open.pop() {
+        Some(k) => Err(format!("IR_ERROR: REP without END (instruction {})", k + 1)),
+        None => Ok(blocks),
+    }
+}
+
+/// The repeat count of a `REP` instruction.
+pub fn repetitions(ins: &Instruction) -> Result<usize, String> {
+    match ins.args.as_slice() {
+        [Operand::Num(n)] if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as usize),
+        _ => Err(format!(
+            "IR_ERROR: '{ins}' needs a non-negative whole repeat count"
+        )),
+    }
+}
+
+/// The instructions of a program in the order they run, see `trace`.
+pub struct Trace<'a> {
+    program: &'a Program,
+    blocks: Vec<(usize, usize)>,
+    // body start and iterations left of every block being executed
+    active: Vec<(usize, usize)>,
+    pc: usize,
+}
+
+/// Walks `program` with every `REP` block repeated in place instead of written out, so a
+/// long loop costs time but no memory. `REP` and `END` themselves are not yielded.
+pub fn trace(program: &Program) -> Result<Trace<'_>, String> {
+    Ok(Trace {
+        blocks: loops(program)?,
+        program,
+        active: vec![],
+        pc: 0,
+    })
+}
+
+impl<'a> Iterator for Trace<'a> {
+    type Item = &'a Instruction;
+
+    fn next(&mut self) -> Option<&'a Instruction> {
+        loop {
+            let ins = self.program.instructions.get(self.pc)?;
+            self.pc += 1;
+            match ins.op.as_str() {
+                // the counts were checked by `loops`
+                "REP" => match repetitions(ins).unwrap() {
+                    0 => {
+                        let start = self.pc - 1;
+                        self.pc = self.blocks.iter().find(|(s, _)| *s == start).unwrap().1 + 1;
+                    }
+                    n => self.active.push((self.pc, n)),
+                },
+                "END" => {
+                    let (start, left) = self.active.last_mut().unwrap();
+                    *left -= 1;
+                    if *left > 0 {
+                        self.pc = *start;
+                    } else {
+                        self.active.pop();
+                    }
+                }
+                _ => return Some(ins),
+            }
+        }
+    }
+}
+
+/// `program` with every `REP` block written out, for passes that expect straight-line code.
+pub fn unroll(program: &Program) -> Result<Program, String> {
+    if loops(program)?.is_empty() {
+        return Ok(program.clone());
+    }
+    // innermost blocks are expanded into their parents first
+    let mut stack: Vec<(usize, Vec<Instruction>)> = vec![(1, vec![])];
+    for ins in &program.instructions {
+        match ins.op.as_str() {
+            "REP" => stack.push((repetitions(ins)?, vec![])),
+            "END" => {
+                let (n, body) = stack.pop().unwrap();
+                let parent = &mut stack.last_mut().unwrap().1;
+                for _ in 0..n {
+                    parent.extend(body.iter().cloned());
+                }
+            }
+            _ => stack.last_mut().unwrap().1.push(ins.clone()),
+        }
+    }
+    Ok(Program {
+        instructions: stack.pop().unwrap().1,
+    })
+}
+
+/// Parses the text that `code_gen` writes into `Comptime::program`.
+pub fn parse_program(text: &str) -> Result<Program, String> {
+    let mut instructions = vec![];
+    for (n, line) in text.lines().enumerate() {
+        match parse_instruction(line) {
+            Ok(Some(i)) => instructions.push(i),
+            Ok(None) => {}
+            Err(e) => return Err(format!("{e} (line {})", n + 1)),
+        }
+    }
+    Ok(Program { instructions })
+}
+
+impl Instruction {
+    pub fn new(op: &str, args: Vec<Operand>) -> Instruction {
+        Instruction {
+            op: op.to_string(),
+            args,
+        }
+    }
+
+    /// Names of all `$` registers the instruction reads or writes, in operand order.
+    pub fn qubits(&self) -> Vec<&str> {
+        self.args
+            .iter()
+            .filter_map(|a| match a {
+                Operand::Qubit(n) => Some(n.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn qudits(&self) -> Vec<&str> {
+        self.args
+            .iter()
+            .filter_map(|a| match a {
+                Operand::Qudit(n) => Some(n.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Classical registers (`BAL`, `BCP`, `BFR` and `MES` into a bit) the instruction touches.
+    pub fn registers(&self) -> Vec<&str> {
+        match self.op.as_str() {
+            "BAL" | "BCP" | "BFR" | "MES" => self
+                .args
+                .iter()
+                .filter_map(|a| match a {
+                    Operand::Name(n) => Some(n.as_str()),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// The register named by an allocation or free instruction.
+    pub fn alloc_name(&self) -> Option<&str> {
+        self.args.iter().find_map(|a| match a {
+            Operand::Name(n) => Some(n.as_str()),
+            _ => None,
+        })
+    }
+}
@@ -0,0 +1,235 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Obj(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Num(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn obj(fields: Vec<(&str, Json)>) -> Json {
+        Json::Obj(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+
+    pub fn str(s: &str) -> Json {
+        Json::Str(s.to_string())
+    }
+}
+
+fn escape(s: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{b}"),
+            Json::Num(n) => {
+                if n.fract() == 0.0 && n.abs() < 1e15 {
+                    write!(f, "{}", *n as i64)
+                } else {
+                    write!(f, "{n}")
+                }
+            }
+            Json::Str(s) => escape(s, f),
+            Json::Arr(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+            Json::Obj(fields) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    escape(k, f)?;
+                    write!(f, ":{v}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Json, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut i = 0;
+    let value = parse_value(&chars, &mut i)?;
+    skip_ws(&chars, &mut i);
+    if i != chars.len() {
+        return Err(format!("JSON_ERROR: trailing characters at {i}"));
+    }
+    Ok(value)
+}
+
+fn skip_ws(chars: &[char], i: &mut usize) {
+    while *i < chars.len() && chars[*i].is_whitespace() {
+        *i += 1;
+    }
+}
+
+fn expect(chars: &[char], i: &mut usize, word: &str) -> Result<(), String> {
+    for c in word.chars() {
+        if chars.get(*i) != Some(&c) {
+            return Err(format!("JSON_ERROR: expected '{word}' at {i}"));
+        }
+        *i += 1;
+    }
+    Ok(())
+}
+
+fn parse_value(chars: &[char], i: &mut usize) -> Result<Json, String> {
+    skip_ws(chars, i);
+    match chars.get(*i) {
+        None => Err("JSON_ERROR: expected value, got end of input".to_string()),
+        Some('n') => expect(chars, i, "null").map(|_| Json::Null),
+        Some('t') => expect(chars, i, "true").map(|_| Json::Bool(true)),
+        Some('f') => expect(chars, i, "false").map(|_| Json::Bool(false)),
+        Some('"') => parse_string(chars, i).map(Json::Str),
+        Some('[') => {
+            *i += 1;
+            let mut items = vec![];
+            skip_ws(chars, i);
+            if chars.get(*i) == Some(&']') {
+                *i += 1;
+                return Ok(Json::Arr(items));
+            }
+            loop {
+                items.push(parse_value(chars, i)?);
+                skip_ws(chars, i);
+                match chars.get(*i) {
+                    Some(',') => *i += 1,
+                    Some(']') => {
+                        *i += 1;
+                        return Ok(Json::Arr(items));
+                    }
+                    other => return Err(format!("JSON_ERROR: expected ',' or ']', got {other:?}")),
+                }
+            }
+        }
+        Some('{') => {
+            *i += 1;
+            let mut fields = vec![];
+            skip_ws(chars, i);
+            if chars.get(*i) == Some(&'}') {
+                *i += 1;
+                return Ok(Json::Obj(fields));
+            }
+            loop {
+                skip_ws(chars, i);
+                let key = parse_string(chars, i)?;
+                skip_ws(chars, i);
+                expect(chars, i, ":")?;
+                fields.push((key, parse_value(chars, i)?));
+                skip_ws(chars, i);
+                match chars.get(*i) {
+                    Some(',') => *i += 1,
+                    Some('}') => {
+                        *i += 1;
+                        return Ok(Json::Obj(fields));
+                    }
+                    other => {
+                        return Err(format!("JSON_ERROR: expected ',' or '}}', got {other:?}"))
+                    }
+                }
+            }
+        }
+        Some(_) => {
+            let start = *i;
+            while *i < chars.len() && "+-0123456789.eE".contains(chars[*i]) {
+                *i += 1;
+            }
+            let s: String = chars[start..*i].iter().collect();
+            s.parse::<f64>()
+                .map(Json::Num)
+                .map_err(|_| format!("JSON_ERROR: invalid number '{s}' at {start}"))
+        }
+    }
+}
+
+fn parse_string(chars: &[char], i: &mut usize) -> Result<String, String> {
+    expect(chars, i, "\"")?;
+    let mut s = String::new();
+    loop {
+        match chars.get(*i) {
+            None => return Err("JSON_ERROR: unterminated string".to_string()),
+            Some('"') => {
+                *i += 1;
+                return Ok(s);
+            }
+            Some('\\') => {
+                *i += 1;
+                match chars.get(*i) {
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = chars.get(*i + 1..*i + 5).unwrap_or(&[]).iter().collect();
+                        let code = u32::from_str_radix(&hex, 16)
+                            .map_err(|_| format!("JSON_ERROR: invalid escape \\u{hex}"))?;
+                        s.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                        *i += 4;
+                    }
+                    Some(c) => s.push(*c),
+                    None => return Err("JSON_ERROR: unterminated escape".to_string()),
+                }
+                *i += 1;
+            }
+            Some(c) => {
+                s.push(*c);
+                *i += 1;
+            }
+        }
+    }
+}
@@ -1,3 +1,28 @@
+pub mod basis;
+pub mod cache;
 pub mod code_gen;
+pub mod compiler;
+pub mod complex;
+pub mod density;
+pub mod equiv;
+pub mod ir;
+pub mod json;
+pub mod lsp;
+pub mod modifier;
+pub mod module;
+pub mod noise;
+pub mod opt;
 pub mod parser;
+pub mod qudit;
+pub mod regalloc;
+pub mod render;
+pub mod repl;
+pub mod route;
+pub mod shots;
+pub mod sim;
+pub mod stabilizer;
+pub mod stats;
+pub mod stdlib;
 pub mod tokenizer;
+pub mod unitary;
+pub mod zx;
@@ -0,0 +1,762 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::code_gen::Comptime;
+use crate::compiler::frontend;
+pub use crate::compiler::quiet_frontend_panics;
+use crate::json::{self, Json};
+use crate::module::{self, Loader};
+use crate::parser::ASTNode;
+use crate::tokenizer::{__TOKENS, GATES};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
+
+impl Span {
+    fn contains(&self, line: usize, col: usize) -> bool {
+        self.line == line && col >= self.col && col < self.col + self.len
+    }
+
+    fn before(&self, other: &Span) -> bool {
+        (self.line, self.col) < (other.line, other.col)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Word {
+    pub text: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DefKind {
+    Variable,
+    Function,
+    Parameter,
+    Iterator,
+    Module,
+}
+
+#[derive(Debug, Clone)]
+pub struct Definition {
+    pub name: String,
+    pub kind: DefKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub words: Vec<Word>,
+    pub definitions: Vec<Definition>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub comptime: Option<Comptime>,
+}
+
+fn is_keyword(s: &str) -> bool {
+    __TOKENS
+        .iter()
+        .any(|t| *t == s && t.chars().all(|c| c.is_alphabetic()))
+}
+
+/// Splits the source into identifiers, numbers and punctuation, keeping their positions.
+/// Comments are skipped, unlike `rm_comments` this keeps line and column information intact.
+pub fn scan(src: &str) -> Vec<Word> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut words = vec![];
+    let (mut i, mut line, mut col) = (0, 0, 0);
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\n' {
+            line += 1;
+            col = 0;
+            i += 1;
+        } else if c.is_whitespace() {
+            col += 1;
+            i += 1;
+        } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            col += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                if chars[i] == '\n' {
+                    line += 1;
+                    col = 0;
+                } else {
+                    col += 1;
+                }
+                i += 1;
+            }
+            i += 2;
+            col += 2;
+        } else if c.is_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            words.push(Word {
+                text: chars[start..i].iter().collect(),
+                span: Span {
+                    line,
+                    col,
+                    len: i - start,
+                },
+            });
+            col += i - start;
+        } else {
+            let len = if c == '.' && chars.get(i + 1) == Some(&'.') {
+                2
+            } else {
+                1
+            };
+            words.push(Word {
+                text: chars[i..i + len].iter().collect(),
+                span: Span { line, col, len },
+            });
+            i += len;
+            col += len;
+        }
+    }
+    words
+}
+
+fn is_ident(s: &str) -> bool {
+    s.chars()
+        .next()
+        .map(|c| c.is_alphabetic() || c == '_')
+        .unwrap_or(false)
+}
+
+fn find_definitions(words: &[Word]) -> Vec<Definition> {
+    let mut defs = vec![];
+    let text = |i: usize| words.get(i).map(|w| w.text.as_str()).unwrap_or("");
+    let mut i = 0;
+    while i < words.len() {
+        match text(i) {
+            "let" | "const" if is_ident(text(i + 1)) => defs.push(Definition {
+                name: text(i + 1).to_string(),
+                kind: DefKind::Variable,
+                span: words[i + 1].span,
+            }),
+            "for" if text(i + 1) == "(" && text(i + 3) == "in" => defs.push(Definition {
+                name: text(i + 2).to_string(),
+                kind: DefKind::Iterator,
+                span: words[i + 2].span,
+            }),
+            "qbit" | "qudit" | "void" if i == 0 || [";", "}", "{"].contains(&text(i - 1)) => {
+                let mut j = i + 1;
+                if text(j) == "[" {
+                    while j < words.len() && text(j) != "]" {
+                        j += 1;
+                    }
+                    j += 1;
+                }
+                if is_ident(text(j)) && text(j + 1) == "(" {
+                    defs.push(Definition {
+                        name: text(j).to_string(),
+                        kind: DefKind::Function,
+                        span: words[j].span,
+                    });
+                    j += 2;
+                    while j < words.len() && text(j) != ")" {
+                        if is_ident(text(j)) && text(j + 1) == ":" {
+                            defs.push(Definition {
+                                name: text(j).to_string(),
+                                kind: DefKind::Parameter,
+                                span: words[j].span,
+                            });
+                        }
+                        j += 1;
+                    }
+                    i = j;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    defs
+}
+
+fn check_names(words: &[Word], defs: &[Definition]) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let backend = backend_words(words);
+    for (i, w) in words.iter().enumerate() {
+        if !is_ident(&w.text) || is_keyword(&w.text) || backend.contains(&i) {
+            continue;
+        }
+        // member names and the type position of declarations are not variable uses
+        if i > 0 && [":", "."].contains(&words[i - 1].text.as_str()) {
+            continue;
+        }
+        let declared = defs
+            .iter()
+            .any(|d| d.name == w.text && (d.span == w.span || d.span.before(&w.span)));
+        if !declared {
+            diagnostics.push(Diagnostic {
+                span: w.span,
+                message: format!("'{}' is used before it is declared", w.text),
+            });
+        }
+    }
+    diagnostics
+}
+
+/// Words of `asm` blocks that are not QEL names: the instructions, checked by code generation,
+/// and the registers written the backend way (`$anc`, `"anc"`).
+fn backend_words(words: &[Word]) -> Vec<usize> {
+    let mut backend = vec![];
+    let mut i = 0;
+    while i < words.len() {
+        if words[i].text != "asm" || words.get(i + 1).map(|w| w.text.as_str()) != Some("{") {
+            i += 1;
+            continue;
+        }
+        i += 2;
+        while i < words.len() && words[i].text != "}" {
+            let prev = &words[i - 1];
+            let instruction =
+                ["{", ";"].contains(&prev.text.as_str()) || prev.span.line != words[i].span.line;
+            if instruction || ["$", "%", "\""].contains(&prev.text.as_str()) {
+                backend.push(i);
+            }
+            i += 1;
+        }
+    }
+    backend
+}
+
+/// Tokenizes, parses and generates code for `src`, collecting everything the language server
+/// needs to answer requests about the document.
+pub fn analyze(src: &str) -> Analysis {
+    analyze_with(src, &mut Loader::default(), None)
+}
+
+/// Like `analyze`, with imports resolved by `loader` relative to the file `from`.
+pub fn analyze_with(src: &str, loader: &mut Loader, from: Option<&Path>) -> Analysis {
+    let words = scan(src);
+    let mut definitions = find_definitions(&words);
+    // import and use lines are checked by the module loader, their names count as declared
+    let header = module::header(src);
+    let known = header.is_ok();
+    let header = header.unwrap_or_default();
+    for (line, name) in header.names() {
+        if let Some(w) = words.iter().find(|w| w.span.line == line && w.text == name) {
+            let is_module = header.imports.iter().any(|(l, _)| *l == line)
+                || header.uses.iter().any(|(l, m, _)| *l == line && *m == name);
+            definitions.push(Definition {
+                name,
+                kind: if is_module {
+                    DefKind::Module
+                } else {
+                    DefKind::Function
+                },
+                span: w.span,
+            });
+        }
+    }
+    let header_lines: Vec<usize> = header
+        .imports
+        .iter()
+        .map(|(l, _)| *l)
+        .chain(header.uses.iter().map(|(l, _, _)| *l))
+        .collect();
+    let checked: Vec<Word> = words
+        .iter()
+        .filter(|w| !header_lines.contains(&w.span.line))
+        .cloned()
+        .collect();
+    // a malformed header is reported by the pipeline, its words aren't names
+    let mut diagnostics = if known {
+        check_names(&checked, &definitions)
+    } else {
+        vec![]
+    };
+
+    let comptime = match frontend(src, loader, from) {
+        Ok(c) => Some(c),
+        Err(e) => {
+            if diagnostics.is_empty() {
+                diagnostics.push(Diagnostic {
+                    span: Span {
+                        line: 0,
+                        col: 0,
+                        len: 0,
+                    },
+                    message: e,
+                });
+            }
+            None
+        }
+    };
+
+    Analysis {
+        words,
+        definitions,
+        diagnostics,
+        comptime,
+    }
+}
+
+pub fn type_name(node: &ASTNode) -> String {
+    match node {
+        ASTNode::Qbit => "qbit".to_string(),
+        ASTNode::Qdit => "qudit".to_string(),
+        ASTNode::Void => "void".to_string(),
+        ASTNode::ArrayType { type_, size } => match size.as_ref() {
+            ASTNode::Num(n) => format!("{}[{n}]", type_name(type_)),
+            _ => format!("{}[]", type_name(type_)),
+        },
+        ASTNode::Type { name, .. } => name.clone(),
+        ASTNode::PointerType { type_ } => format!("*{}", type_name(type_)),
+        other => format!("{other:?}"),
+    }
+}
+
+impl Analysis {
+    pub fn word_at(&self, line: usize, col: usize) -> Option<&Word> {
+        self.words.iter().find(|w| w.span.contains(line, col))
+    }
+
+    pub fn definition(&self, line: usize, col: usize) -> Option<&Definition> {
+        let word = self.word_at(line, col)?;
+        self.definitions
+            .iter()
+            .rev()
+            .find(|d| d.name == word.text && (d.span == word.span || d.span.before(&word.span)))
+            .or_else(|| self.definitions.iter().find(|d| d.name == word.text))
+    }
+
+    pub fn hover(&self, line: usize, col: usize) -> Option<String> {
+        let word = self.word_at(line, col)?;
+        let name = word.text.as_str();
+        if GATES.contains(&name) {
+            return Some(format!("built-in gate `{name}`"));
+        }
+        let comptime = self.comptime.as_ref()?;
+        if let Some((ins, ret)) = comptime.function_info.get(name) {
+            let args = comptime
+                .function_args
+                .get(name)
+                .cloned()
+                .unwrap_or_default();
+            let params: Vec<String> = args
+                .iter()
+                .zip(ins.iter())
+                .map(|(a, t)| format!("{a}: {}", type_name(t)))
+                .collect();
+            return Some(format!("{} {name}({})", type_name(ret), params.join(", ")));
+        }
+        if let Some((is_const, size, type_)) = comptime.var_info.get(name) {
+            return Some(format!(
+                "{}{name}: {} ({size} register{})",
+                if *is_const { "const " } else { "" },
+                type_name(type_),
+                if *size == 1 { "" } else { "s" }
+            ));
+        }
+        None
+    }
+
+    pub fn completions(&self) -> Vec<(String, u32)> {
+        let mut items: Vec<(String, u32)> = GATES.iter().map(|g| (g.to_string(), 3)).collect();
+        for d in &self.definitions {
+            let kind = if d.kind == DefKind::Function { 3 } else { 6 };
+            if !items.iter().any(|(n, _)| *n == d.name) {
+                items.push((d.name.clone(), kind));
+            }
+        }
+        items
+    }
+}
+
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(v) = header.strip_prefix("Content-Length:") {
+            length = v.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+    })?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    json::parse(&String::from_utf8_lossy(&body))
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    output.flush()
+}
+
+fn position(p: Option<&Json>) -> (usize, usize) {
+    let line = p.and_then(|p| p.get("line")).and_then(Json::as_f64);
+    let col = p.and_then(|p| p.get("character")).and_then(Json::as_f64);
+    (line.unwrap_or(0.0) as usize, col.unwrap_or(0.0) as usize)
+}
+
+fn range(span: &Span) -> Json {
+    let pos = |col: usize| {
+        Json::obj(vec![
+            ("line", Json::Num(span.line as f64)),
+            ("character", Json::Num(col as f64)),
+        ])
+    };
+    Json::obj(vec![
+        ("start", pos(span.col)),
+        ("end", pos(span.col + span.len)),
+    ])
+}
+
+fn publish_diagnostics(uri: &str, analysis: &Analysis) -> Json {
+    let diagnostics = analysis
+        .diagnostics
+        .iter()
+        .map(|d| {
+            Json::obj(vec![
+                ("range", range(&d.span)),
+                ("severity", Json::Num(1.0)),
+                ("source", Json::str("qel")),
+                ("message", Json::str(&d.message)),
+            ])
+        })
+        .collect();
+    Json::obj(vec![
+        ("jsonrpc", Json::str("2.0")),
+        ("method", Json::str("textDocument/publishDiagnostics")),
+        (
+            "params",
+            Json::obj(vec![
+                ("uri", Json::str(uri)),
+                ("diagnostics", Json::Arr(diagnostics)),
+            ]),
+        ),
+    ])
+}
+
+fn capabilities() -> Json {
+    Json::obj(vec![(
+        "capabilities",
+        Json::obj(vec![
+            (
+                "textDocumentSync",
+                Json::obj(vec![
+                    ("openClose", Json::Bool(true)),
+                    ("change", Json::Num(1.0)),
+                    ("save", Json::obj(vec![("includeText", Json::Bool(true))])),
+                ]),
+            ),
+            ("definitionProvider", Json::Bool(true)),
+            ("hoverProvider", Json::Bool(true)),
+            ("completionProvider", Json::obj(vec![])),
+        ]),
+    )])
+}
+
+/// Analyzes an open document, imports are resolved next to it if it is a local file.
+fn analyze_document(text: &str, uri: &str) -> Analysis {
+    let path = uri.strip_prefix("file://").map(PathBuf::from);
+    analyze_with(text, &mut Loader::default(), path.as_deref())
+}
+
+/// Runs the language server on the given streams until the client sends `exit`.
+/// The editor binary passes stdin/stdout, a scripted client can pass any reader and writer.
+pub fn serve<R: BufRead, W: Write>(mut input: R, mut output: W) -> io::Result<()> {
+    let mut documents: HashMap<String, String> = HashMap::new();
+    let mut analyses: HashMap<String, Analysis> = HashMap::new();
+
+    while let Some(message) = read_message(&mut input)? {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let id = message.get("id").cloned();
+        let params = message.get("params");
+        let doc = params.and_then(|p| p.get("textDocument"));
+        let uri = doc
+            .and_then(|d| d.get("uri"))
+            .and_then(Json::as_str)
+            .unwrap_or("")
+            .to_string();
+
+        let result = match method {
+            "initialize" => Some(capabilities()),
+            "shutdown" => Some(Json::Null),
+            "exit" => return Ok(()),
+            "textDocument/didOpen" | "textDocument/didSave" => {
+                let text = doc
+                    .and_then(|d| d.get("text"))
+                    .or_else(|| params.and_then(|p| p.get("text")))
+                    .and_then(Json::as_str);
+                if let Some(text) = text {
+                    documents.insert(uri.clone(), text.to_string());
+                }
+                let text = documents.get(&uri).map(|s| s.as_str()).unwrap_or("");
+                let analysis = analyze_document(text, &uri);
+                write_message(&mut output, &publish_diagnostics(&uri, &analysis))?;
+                analyses.insert(uri.clone(), analysis);
+                None
+            }
+            "textDocument/didChange" => {
+                let text = params
+                    .and_then(|p| p.get("contentChanges"))
+                    .and_then(|c| match c {
+                        Json::Arr(changes) => changes.last(),
+                        _ => None,
+                    })
+                    .and_then(|c| c.get("text"))
+                    .and_then(Json::as_str);
+                if let Some(text) = text {
+                    documents.insert(uri.clone(), text.to_string());
+                    analyses.remove(&uri);
+                }
+                None
+            }
+            "textDocument/didClose" => {
+                documents.remove(&uri);
+                analyses.remove(&uri);
+                None
+            }
+            "textDocument/definition" | "textDocument/hover" | "textDocument/completion" => {
+                if !analyses.contains_key(&uri) {
+                    let text = documents.get(&uri).map(|s| s.as_str()).unwrap_or("");
+                    analyses.insert(uri.clone(), analyze_document(text, &uri));
+                }
+                let analysis = &analyses[&uri];
+                let (line, col) = position(params.and_then(|p| p.get("position")));
+                Some(match method {
+                    "textDocument/definition" => match analysis.definition(line, col) {
+                        Some(d) => {
+                            Json::obj(vec![("uri", Json::str(&uri)), ("range", range(&d.span))])
+                        }
+                        None => Json::Null,
+                    },
+                    "textDocument/hover" => match analysis.hover(line, col) {
+                        Some(text) => Json::obj(vec![(
+                            "contents",
+                            Json::obj(vec![
+                                ("kind", Json::str("markdown")),
+                                ("value", Json::Str(format!("```qel\n{text}\n```"))),
+                            ]),
+                        )]),
+                        None => Json::Null,
+                    },
+                    _ => Json::Arr(
+                        analysis
+                            .completions()
+                            .into_iter()
+                            .map(|(label, kind)| {
+                                Json::obj(vec![
+                                    ("label", Json::Str(label)),
+                                    ("kind", Json::Num(kind as f64)),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                })
+            }
+            _ if id.is_some() => {
+                let error = Json::obj(vec![
+                    ("code", Json::Num(-32601.0)),
+                    ("message", Json::Str(format!("method not found: {method}"))),
+                ]);
+                write_message(
+                    &mut output,
+                    &Json::obj(vec![
+                        ("jsonrpc", Json::str("2.0")),
+                        ("id", id.clone().unwrap()),
+                        ("error", error),
+                    ]),
+                )?;
+                None
+            }
+            _ => None,
+        };
+
+        if let (Some(id), Some(result)) = (id, result) {
+            write_message(
+                &mut output,
+                &Json::obj(vec![
+                    ("jsonrpc", Json::str("2.0")),
+                    ("id", id),
+                    ("result", result),
+                ]),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(id: usize, method: &str, params: Json) -> Json {
+        Json::obj(vec![
+            ("jsonrpc", Json::str("2.0")),
+            ("id", Json::Num(id as f64)),
+            ("method", Json::str(method)),
+            ("params", params),
+        ])
+    }
+
+    fn at(line: usize, character: usize) -> Json {
+        Json::obj(vec![
+            (
+                "textDocument",
+                Json::obj(vec![("uri", Json::str("mem.qel"))]),
+            ),
+            (
+                "position",
+                Json::obj(vec![
+                    ("line", Json::Num(line as f64)),
+                    ("character", Json::Num(character as f64)),
+                ]),
+            ),
+        ])
+    }
+
+    /// Runs `serve` on the messages and returns the results by request id.
+    fn session(text: &str, requests: Vec<Json>) -> Vec<(usize, Json)> {
+        let open = Json::obj(vec![
+            ("jsonrpc", Json::str("2.0")),
+            ("method", Json::str("textDocument/didOpen")),
+            (
+                "params",
+                Json::obj(vec![(
+                    "textDocument",
+                    Json::obj(vec![
+                        ("uri", Json::str("mem.qel")),
+                        ("text", Json::str(text)),
+                    ]),
+                )]),
+            ),
+        ]);
+        let mut input = vec![];
+        let messages = [request(0, "initialize", Json::obj(vec![])), open]
+            .into_iter()
+            .chain(requests);
+        for message in messages {
+            write_message(&mut input, &message).unwrap();
+        }
+        let mut output = vec![];
+        serve(input.as_slice(), &mut output).unwrap();
+        let mut reader = output.as_slice();
+        let mut results = vec![];
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            if let (Some(id), Some(result)) = (message.get("id"), message.get("result")) {
+                results.push((id.as_f64().unwrap() as usize, result.clone()));
+            }
+        }
+        results
+    }
+
+    fn result(results: &[(usize, Json)], id: usize) -> &Json {
+        &results.iter().find(|(i, _)| *i == id).unwrap().1
+    }
+
+    fn hover_text(result: &Json) -> Option<&str> {
+        result.get("contents")?.get("value")?.as_str()
+    }
+
+    #[test]
+    fn spans_end_before_the_next_word() {
+        let span = Span {
+            line: 0,
+            col: 3,
+            len: 1,
+        };
+        assert!(span.contains(0, 3));
+        assert!(!span.contains(0, 4));
+    }
+
+    #[test]
+    fn scripted_client() {
+        let text = "let a: qbit;\nlet bb: qbit;\nCNT(a, bb);\n";
+        let results = session(
+            text,
+            vec![
+                request(1, "textDocument/hover", at(2, 4)),
+                request(2, "textDocument/hover", at(2, 5)),
+                request(3, "textDocument/definition", at(2, 7)),
+                request(4, "textDocument/hover", at(2, 0)),
+                request(5, "textDocument/completion", at(2, 0)),
+                request(6, "shutdown", Json::obj(vec![])),
+            ],
+        );
+        let a = hover_text(result(&results, 1)).unwrap();
+        assert!(a.contains("a: qbit"), "{a}");
+        assert_eq!(*result(&results, 2), Json::Null);
+        let range = result(&results, 3).get("range").unwrap();
+        let start = range.get("start").unwrap();
+        assert_eq!(start.get("line").and_then(Json::as_f64), Some(1.0));
+        assert_eq!(start.get("character").and_then(Json::as_f64), Some(4.0));
+        let cnt = hover_text(result(&results, 4)).unwrap();
+        assert!(cnt.contains("built-in gate `CNT`"), "{cnt}");
+        let Json::Arr(items) = result(&results, 5) else {
+            panic!("completion did not return a list");
+        };
+        let labels: Vec<&str> = items
+            .iter()
+            .filter_map(|i| i.get("label").and_then(Json::as_str))
+            .collect();
+        assert!(
+            labels.contains(&"HAD") && labels.contains(&"bb"),
+            "{labels:?}"
+        );
+    }
+
+    #[test]
+    fn diagnostics_on_open() {
+        let mut input = vec![];
+        let open = Json::obj(vec![
+            ("jsonrpc", Json::str("2.0")),
+            ("method", Json::str("textDocument/didOpen")),
+            (
+                "params",
+                Json::obj(vec![(
+                    "textDocument",
+                    Json::obj(vec![
+                        ("uri", Json::str("mem.qel")),
+                        ("text", Json::str("let a: qbit;\nHAD(b);\n")),
+                    ]),
+                )]),
+            ),
+        ]);
+        write_message(&mut input, &open).unwrap();
+        let mut output = vec![];
+        serve(input.as_slice(), &mut output).unwrap();
+        let message = read_message(&mut output.as_slice()).unwrap().unwrap();
+        let Some(Json::Arr(diagnostics)) = message.get("params").and_then(|p| p.get("diagnostics"))
+        else {
+            panic!("no diagnostics published");
+        };
+        let messages: Vec<&str> = diagnostics
+            .iter()
+            .filter_map(|d| d.get("message").and_then(Json::as_str))
+            .collect();
+        assert_eq!(messages, ["'b' is used before it is declared"]);
+    }
+}
@@ -1,24 +1,452 @@
-pub mod code_gen;
-pub mod parser;
-pub mod tokenizer;
-
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
-use code_gen::code_gen;
-use parser::from_tokens;
-use tokenizer::filter_all;
+use tokenizer::basis::{parse_basis, verify, BASES};
+use tokenizer::cache::Cache;
+use tokenizer::code_gen::code_gen;
+use tokenizer::compiler::{quiet_frontend_panics, Artifact, Compiler, Diagnostics, Target};
+use tokenizer::equiv;
+use tokenizer::ir::{parse_program, unroll, Program};
+use tokenizer::module::{self, Loader};
+use tokenizer::noise::{self, NoiseModel};
+use tokenizer::parser::{from_tokens, parse_};
+use tokenizer::render::{render_ascii, render_svg, Circuit};
+use tokenizer::repl;
+use tokenizer::shots;
+use tokenizer::sim::{Engine, Machine, Simulator, Statevector};
+use tokenizer::stabilizer::Tableau;
+use tokenizer::stats;
+use tokenizer::tokenizer::{filter50s, filter_all, tokenize};
+
+fn read_externs() -> Vec<f64> {
+    std::fs::read_to_string("args.txt")
+        .map(|s| s.lines().filter_map(|l| l.trim().parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Flags understood by the single file mode and the subcommands, everything else is a file.
+struct Options {
+    files: Vec<String>,
+    opt_level: u8,
+    print_rewrites: bool,
+    regalloc: bool,
+    zx: bool,
+    /// keep `REP` blocks in `out.txt` instead of writing them out
+    rep: bool,
+    basis: Option<Vec<String>>,
+    coupling: Option<String>,
+    svg: Option<String>,
+    shots: usize,
+    seed: u64,
+    format: String,
+    noise: Option<String>,
+    /// `None` picks the stabilizer simulator for Clifford-only programs
+    sim: Option<Simulator>,
+    /// directories searched by `import` after the importing file's own
+    search_path: Vec<String>,
+    cache_dir: Option<String>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options, String> {
+        let mut opts = Options {
+            files: vec![],
+            opt_level: 0,
+            print_rewrites: false,
+            regalloc: false,
+            zx: false,
+            rep: false,
+            basis: None,
+            coupling: None,
+            svg: None,
+            shots: 1024,
+            seed: 0,
+            format: "text".to_string(),
+            noise: None,
+            sim: None,
+            search_path: vec![],
+            cache_dir: None,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-O0" => opts.opt_level = 0,
+                "-O1" => opts.opt_level = 1,
+                "-O2" => opts.opt_level = 2,
+                "--print-rewrites" => opts.print_rewrites = true,
+                "--alloc" => opts.regalloc = true,
+                "--zx" => opts.zx = true,
+                "--rep" => opts.rep = true,
+                "--basis" => {
+                    let basis = args.next().ok_or("--basis needs a gate set")?;
+                    opts.basis = Some(parse_basis(basis)?);
+                }
+                "--coupling" => {
+                    let file = args.next().ok_or("--coupling needs a coupling map file")?;
+                    opts.coupling = Some(file.clone());
+                }
+                "--svg" => {
+                    opts.svg = Some(args.next().ok_or("--svg needs an output file")?.clone())
+                }
+                "--shots" => {
+                    let n = args.next().ok_or("--shots needs a number")?;
+                    opts.shots = n.parse().map_err(|_| format!("invalid shot count {n}"))?;
+                }
+                "--seed" => {
+                    let s = args.next().ok_or("--seed needs a number")?;
+                    opts.seed = s.parse().map_err(|_| format!("invalid seed {s}"))?;
+                }
+                "--format" => {
+                    let f = args.next().ok_or("--format needs text, json or csv")?;
+                    if !["text", "json", "csv"].contains(&f.as_str()) {
+                        return Err(format!("unknown format {f}, expected text, json or csv"));
+                    }
+                    opts.format = f.clone();
+                }
+                "--sim" => {
+                    let sim = args
+                        .next()
+                        .ok_or("--sim needs auto, statevector or stabilizer")?;
+                    opts.sim = Simulator::parse(sim)?;
+                }
+                "--noise" => {
+                    let file = args.next().ok_or("--noise needs a noise model file")?;
+                    opts.noise = Some(file.clone());
+                }
+                "--cache-dir" => {
+                    let dir = args.next().ok_or("--cache-dir needs a directory")?;
+                    opts.cache_dir = Some(dir.clone());
+                }
+                "-I" => {
+                    let dir = args.next().ok_or("-I needs a directory")?;
+                    opts.search_path.push(dir.clone());
+                }
+                flag if flag.starts_with('-') => return Err(format!("unknown flag {flag}")),
+                file => opts.files.push(file.to_string()),
+            }
+        }
+        Ok(opts)
+    }
+
+    /// The compiler configured by the pass flags.
+    fn compiler(&self) -> Result<Compiler, String> {
+        let mut target = Target {
+            basis: self.basis.clone(),
+            ..Target::ideal()
+        };
+        if let Some(file) = &self.coupling {
+            let text = std::fs::read_to_string(file).map_err(|e| format!("{file}: {e}"))?;
+            target = target.coupling(&text).map_err(|e| e.to_string())?;
+        }
+        let mut compiler = self
+            .search_path
+            .iter()
+            .fold(Compiler::new(), |c, dir| c.search_path(dir));
+        if let Some(dir) = &self.cache_dir {
+            compiler = compiler.cache_dir(dir);
+        }
+        Ok(compiler
+            .target(target)
+            .opt_level(self.opt_level)
+            .regalloc(self.regalloc)
+            .zx(self.zx))
+    }
+
+    /// Runs the passes selected on the command line over the generated program.
+    fn transform(&self, program: String) -> Result<String, String> {
+        self.emit(self.compiler()?.compile_ir(&program))
+    }
+
+    /// Reports what the passes did and returns the backend instructions.
+    fn emit(&self, artifact: Result<Artifact, Diagnostics>) -> Result<String, String> {
+        self.emit_mapped(artifact).map(|(ir, _)| ir)
+    }
+
+    /// Like `emit`, also returning the source names of the array registers.
+    fn emit_mapped(
+        &self,
+        artifact: Result<Artifact, Diagnostics>,
+    ) -> Result<(String, HashMap<String, String>), String> {
+        let artifact = artifact.map_err(|e| e.to_string())?;
+        if self.print_rewrites {
+            for r in &artifact.rewrites {
+                self.note(r.to_string());
+            }
+            self.note(format!("{} rewrites applied", artifact.rewrites.len()));
+        }
+        for line in artifact.notes {
+            self.note(line);
+        }
+        Ok((artifact.ir, artifact.registers))
+    }
+
+    /// Reports of the passes, kept off stdout when that carries JSON or CSV.
+    fn note(&self, line: String) {
+        if self.format == "text" {
+            println!("{line}");
+        } else {
+            eprintln!("{line}");
+        }
+    }
+}
+
+fn compile(path: &str, opts: &Options) -> Result<String, String> {
+    opts.emit(opts.compiler()?.compile_file(path))
+}
+
+fn compile_mapped(path: &str, opts: &Options) -> Result<(String, HashMap<String, String>), String> {
+    opts.emit_mapped(opts.compiler()?.compile_file(path))
+}
+
+fn draw(args: &[String]) -> Result<(), String> {
+    let opts = Options::parse(args)?;
+    let path = opts
+        .files
+        .first()
+        .ok_or("usage: draw <file.qel> [-O0|-O1|-O2] [--basis <gates>] [--alloc] [--zx] [--coupling <map>] [--svg <out.svg>]")?;
+    let (ir, registers) = compile_mapped(path, &opts)?;
+    let mut circuit = Circuit::from_program(&unroll(&parse_program(&ir)?)?);
+    circuit.rename(&registers);
+    match &opts.svg {
+        Some(out) => std::fs::write(out, render_svg(&circuit)).map_err(|e| format!("{out}: {e}")),
+        None => {
+            print!("{}", render_ascii(&circuit));
+            Ok(())
+        }
+    }
+}
+
+/// Runs a file for `--shots` shots and prints the counts per classical register.
+fn run_shots(args: &[String]) -> Result<(), String> {
+    let opts = Options::parse(args)?;
+    let path = opts
+        .files
+        .first()
+        .ok_or("usage: run <file.qel> [--shots <n>] [--seed <s>] [--format text|json|csv] [--noise <model.toml>] [--sim auto|statevector|stabilizer] [-O0|-O1|-O2] [--basis <gates>] [--alloc] [--zx] [--coupling <map>]")?;
+    let program = parse_program(&compile(path, &opts)?)?;
+    if let Some(file) = &opts.noise {
+        let text = std::fs::read_to_string(file).map_err(|e| format!("{file}: {e}"))?;
+        let model = NoiseModel::parse(&text)?;
+        let run = noise::run(&program, &read_externs(), opts.shots, opts.seed, &model)?;
+        match opts.format.as_str() {
+            "json" => println!("{}", run.to_json()),
+            "csv" => print!("{}", run.to_csv()),
+            _ => print!("{}", run.report()),
+        }
+        return Ok(());
+    }
+    let sim = Simulator::select(&program, opts.sim)?;
+    let counts = shots::run(&program, &read_externs(), opts.shots, opts.seed, sim)?;
+    match opts.format.as_str() {
+        "json" => println!("{}", counts.to_json()),
+        "csv" => print!("{}", counts.to_csv()),
+        _ => print!("{}", counts.histogram()),
+    }
+    Ok(())
+}
+
+/// Checks the decomposition rules of the basis translation with the simulator.
+fn check_basis() -> Result<(), String> {
+    let mut failed = 0;
+    for (rule, error) in verify()? {
+        let ok = error < 1e-9;
+        failed += !ok as usize;
+        println!("{} {rule}  ({error:.1e})", if ok { "ok  " } else { "FAIL" });
+    }
+    let names: Vec<&str> = BASES.iter().map(|(name, _)| *name).collect();
+    println!("predefined bases: {}", names.join(", "));
+    match failed {
+        0 => Ok(()),
+        n => Err(format!(
+            "{n} rules are not equivalent to the gate they replace"
+        )),
+    }
+}
+
+/// Prints the resource estimate of a `.qel` file, or of backend instructions such as
+/// `out.txt` from any other file, after the selected passes.
+fn print_stats(args: &[String]) -> Result<(), String> {
+    let opts = Options::parse(args)?;
+    let path = opts
+        .files
+        .first()
+        .ok_or("usage: stats <file.qel|out.txt> [--format text|json] [-O0|-O1|-O2] [--basis <gates>] [--alloc] [--zx] [--coupling <map>]")?;
+    let (program, registers) = if path.ends_with(".qel") {
+        compile_mapped(path, &opts)?
+    } else {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        (opts.transform(text)?, HashMap::new())
+    };
+    let mut stats = stats::analyze(&parse_program(&program)?)?;
+    stats.rename(&registers);
+    match opts.format.as_str() {
+        "json" => println!("{}", stats.to_json()),
+        "csv" => return Err("stats supports --format text or json".to_string()),
+        _ => print!("{}", stats.report()),
+    }
+    Ok(())
+}
+
+/// Compares two programs, `.qel` files or backend instructions, after the selected passes.
+/// Fails if they are not equivalent up to global phase.
+fn check_equiv(args: &[String]) -> Result<(), String> {
+    let opts = Options::parse(args)?;
+    let [a, b] = opts.files.as_slice() else {
+        return Err("usage: equiv <a.qel> <b.qel> [--seed <n>] [-O0|-O1|-O2] [--basis <gates>] [--alloc] [--zx] [--coupling <map>]".to_string());
+    };
+    let load = |path: &String| -> Result<Program, String> {
+        let program = if path.ends_with(".qel") {
+            compile(path, &opts)?
+        } else {
+            let text = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+            opts.transform(text)?
+        };
+        parse_program(&program)
+    };
+    let report = equiv::check(&load(a)?, &load(b)?, &read_externs(), opts.seed)?;
+    println!("a          {a}\nb          {b}");
+    print!("{}", report.report());
+    match report.equivalent() {
+        true => Ok(()),
+        false => Err(format!("{a} and {b} are not equivalent")),
+    }
+}
+
+/// The text of `out.txt`: the program with its `REP` blocks written out, which the
+/// external `qbackend` doesn't know, unless `rep` keeps them.
+fn out_txt(program: &str, rep: bool) -> Result<String, String> {
+    match rep {
+        true => Ok(program.to_string()),
+        false => Ok(unroll(&parse_program(program)?)?.to_string()),
+    }
+}
+
+/// Runs `out.txt` on the external `qbackend`, `None` if it isn't installed.
+fn qbackend() -> Option<String> {
+    let executer = std::process::Command::new("qbackend")
+        .arg("out.txt")
+        .arg("| cat args.txt")
+        .output()
+        .ok()?;
+    Some(String::from_utf8_lossy(&executer.stdout).into_owned())
+}
+
+/// Runs a compiled program on the selected simulator and returns its classical registers.
+/// Measurements that were not stored are listed by qubit, as `$name`.
+fn execute(program: &str, sim: Option<Simulator>) -> Result<Vec<(String, Vec<bool>)>, String> {
+    let program = parse_program(program)?;
+    match Simulator::select(&program, sim)? {
+        Simulator::Statevector => registers(
+            Machine::new(Statevector::new(), read_externs(), 0),
+            &program,
+        ),
+        Simulator::Stabilizer => {
+            registers(Machine::new(Tableau::new(), read_externs(), 0), &program)
+        }
+    }
+}
+
+fn registers<E: Engine>(
+    mut machine: Machine<E>,
+    program: &Program,
+) -> Result<Vec<(String, Vec<bool>)>, String> {
+    machine.run(program)?;
+    if machine.registers.is_empty() {
+        let measured = machine.measurements.into_iter();
+        return Ok(measured
+            .map(|(q, bit)| (format!("${q}"), vec![bit]))
+            .collect());
+    }
+    Ok(machine.registers)
+}
 
-use crate::parser::parse_;
-use crate::tokenizer::{filter50s, tokenize};
+/// One `name = value` line per register.
+fn lines(registers: &[(String, Vec<bool>)]) -> Vec<String> {
+    registers
+        .iter()
+        .map(|(name, bits)| match bits.as_slice() {
+            [bit] => format!("{name} = {}", *bit as u8),
+            _ => {
+                let bits: Vec<String> = bits.iter().map(|b| (*b as u8).to_string()).collect();
+                format!("{name} = [{}]", bits.join(", "))
+            }
+        })
+        .collect()
+}
 
 fn main() {
-    if std::env::args().count() == 2 {
-        let path = std::env::args().nth(1).unwrap();
-        let code = std::fs::read_to_string(path.clone()).unwrap();
+    quiet_frontend_panics();
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|s| s.as_str()) {
+        Some("repl") => {
+            let stdin = std::io::stdin();
+            if let Err(e) = repl::run(stdin.lock(), std::io::stdout(), read_externs()) {
+                eprintln!("{e}");
+                exit(1);
+            }
+            return;
+        }
+        Some("basis") => {
+            if let Err(e) = check_basis() {
+                eprintln!("{e}");
+                exit(1);
+            }
+            return;
+        }
+        Some("equiv") => {
+            if let Err(e) = check_equiv(&args[2..]) {
+                eprintln!("{e}");
+                exit(1);
+            }
+            return;
+        }
+        Some("draw") => {
+            if let Err(e) = draw(&args[2..]) {
+                eprintln!("{e}");
+                exit(1);
+            }
+            return;
+        }
+        Some("stats") => {
+            if let Err(e) = print_stats(&args[2..]) {
+                eprintln!("{e}");
+                exit(1);
+            }
+            return;
+        }
+        Some("run") => {
+            if let Err(e) = run_shots(&args[2..]) {
+                eprintln!("{e}");
+                exit(1);
+            }
+            return;
+        }
+        _ => {}
+    }
+    let opts = Options::parse(&args[1..]).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        exit(1);
+    });
+    if opts.noise.is_some() {
+        eprintln!("--noise is only supported by run, e.g. run <file.qel> --noise <model.toml>");
+        exit(1);
+    }
+    if opts.files.len() == 1 {
+        let path = opts.files[0].clone();
+        let code = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+            eprintln!("{path}: {e}");
+            exit(1);
+        });
         //println!("{code}\n\n");
-        let mut tokens = tokenize(&code);
-        tokens = filter50s(tokens.clone());
-        tokens = filter_all(tokens.clone());
+        let header = module::header(&code).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            exit(1);
+        });
+        let search_path = opts.search_path.iter().map(PathBuf::from).collect();
+        let mut loader = Loader::new(search_path);
+        loader.disk = opts.cache_dir.as_ref().map(Cache::new);
+        let tokens = loader.tokens(&header);
         //println!("{:#?}\n\n", tokens);
 
         let tokens1 = from_tokens(tokens.clone());
@@ -39,6 +467,12 @@ fn main() {
                 exit(1);
             }
         };
+        let ast_ = loader
+            .link(&header, ast_, Some(Path::new(&path)))
+            .unwrap_or_else(|e| {
+                eprintln!("{e}");
+                exit(1);
+            });
 
         std::fs::write(format!("ast_{}.txt", path), format!("{:#?}", ast_)).unwrap();
 
@@ -54,36 +488,30 @@ fn main() {
             }
         }
 
-        /*
-            let mut buffer = String::new();
-            while let Ok(_) = io::stdin().read_line(&mut buffer) {
-                if buffer.contains("q\n") {
-                    buffer = buffer.trim_end_matches("q\n").to_string();
-                    buffer.pop();
-                    break;
-                }
-            }
-
-            buffer = buffer.replace("qb", "%cmb");
-            buffer = buffer.replace("CX", "CNT $bit $controll %cmb");
-            o.program = o.program.replace(
-                "CNT $bit $controll %cmb \nDPX %cmb \nTR %cmb $bit 0 \n",
-                buffer.as_str(),
-            );
-
-        */
-        std::fs::write("out.txt", o.program.clone()).unwrap();
+        let program = opts.transform(o.program.clone()).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            exit(1);
+        });
+        let out = out_txt(&program, opts.rep).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            exit(1);
+        });
+        std::fs::write("out.txt", out).unwrap();
         std::fs::write(format!("comptime_{}.txt", path), format!("{:#?}", o)).unwrap();
 
-        let executer = std::process::Command::new("qbackend")
-            .arg("out.txt")
-            .arg("| cat args.txt")
-            .output()
-            .expect("Failed to execute qbackend");
-
-        let a = String::from_utf8_lossy(&executer.stdout);
-
-        println!("QBACKEND output:\n\t{}", a);
+        // the simulator runs REP blocks in place, qbackend only gets straight-line code
+        match qbackend().filter(|_| opts.sim.is_none() && !opts.rep) {
+            Some(a) => println!("QBACKEND output:\n\t{}", a),
+            None => {
+                let registers = execute(&program, opts.sim).unwrap_or_else(|e| {
+                    eprintln!("{e}");
+                    exit(1);
+                });
+                for line in lines(&registers) {
+                    println!("\t{line}");
+                }
+            }
+        }
 
         /*
             unsafe {
@@ -107,7 +535,10 @@ fn main() {
     ];
     for (path, message) in paths.iter().zip(messages.iter()) {
         println!("{}", message);
-        let code = std::fs::read_to_string(path).unwrap();
+        let code = std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("{path}: {e}");
+            exit(1);
+        });
         //println!("{code}\n\n");
         let mut tokens = tokenize(&code);
         tokens = filter50s(tokens.clone());
@@ -147,36 +578,29 @@ fn main() {
             }
         }
 
-        /*
-            let mut buffer = String::new();
-            while let Ok(_) = io::stdin().read_line(&mut buffer) {
-                if buffer.contains("q\n") {
-                    buffer = buffer.trim_end_matches("q\n").to_string();
-                    buffer.pop();
-                    break;
-                }
-            }
-
-            buffer = buffer.replace("qb", "%cmb");
-            buffer = buffer.replace("CX", "CNT $bit $controll %cmb");
-            o.program = o.program.replace(
-                "CNT $bit $controll %cmb \nDPX %cmb \nTR %cmb $bit 0 \n",
-                buffer.as_str(),
-            );
-
-        */
-        std::fs::write("out.txt", o.program.clone()).unwrap();
+        let out = out_txt(&o.program, false).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            exit(1);
+        });
+        std::fs::write("out.txt", out).unwrap();
         std::fs::write(format!("comptime_{}.txt", path), format!("{:#?}", o)).unwrap();
 
-        let executer = std::process::Command::new("qbackend")
-            .arg("out.txt")
-            .arg("| cat args.txt")
-            .output()
-            .expect("Failed to execute qbackend");
-
-        let a = String::from_utf8_lossy(&executer.stdout);
-
-        println!("QBACKEND output:\n\t{}", a);
+        if let Some(a) = qbackend() {
+            println!("QBACKEND output:\n\t{}", a);
+            continue;
+        }
+        let registers = execute(&o.program, None).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            exit(1);
+        });
+        for line in lines(&registers) {
+            println!("\t{line}");
+        }
+        // the demos only measure their input qubits, a constant oracle leaves all of them in |0>
+        match registers.iter().flat_map(|(_, bits)| bits).any(|bit| *bit) {
+            true => println!("\t=> balanciert"),
+            false => println!("\t=> konstant"),
+        }
 
         /*
             unsafe {
@@ -0,0 +1,501 @@
+use crate::complex::Complex;
+use crate::ir::{Instruction, Operand, Program};
+use crate::sim::Gate;
+
+/// Angles below this are treated as zero and their rotation is not emitted.
+const EPS: f64 = 1e-12;
+
+/// Instructions that only manage registers, modifiers pass them through unchanged.
+const ALLOCATION: [&str; 4] = ["QAL", "FRE", "DAL", "DFR"];
+
+fn qubit(name: &str) -> Operand {
+    Operand::Qubit(name.to_string())
+}
+
+fn gate(op: &str, target: &str, angle: Option<Operand>) -> Instruction {
+    Instruction::new(op, std::iter::once(qubit(target)).chain(angle).collect())
+}
+
+fn qubits(ins: &Instruction) -> Vec<String> {
+    ins.qubits().into_iter().map(str::to_string).collect()
+}
+
+/// `op` multiplied by `k`, extern angles become `??n*k`.
+fn scale(op: Option<&Operand>, k: f64) -> Result<Operand, String> {
+    match op {
+        Some(Operand::Num(x)) => Ok(Operand::Num(x * k)),
+        Some(Operand::Extern(i)) => Ok(Operand::Scaled(*i, k)),
+        Some(Operand::Scaled(i, f)) => Ok(Operand::Scaled(*i, f * k)),
+        other => Err(format!("MODIFIER_ERROR: expected angle, got {other:?}")),
+    }
+}
+
+fn rejected(ins: &Instruction, modifier: &str) -> Option<String> {
+    if ["MES", "SET", "CPY", "DCP"].contains(&ins.op.as_str()) {
+        return Some(format!(
+            "MODIFIER_ERROR: '{ins}' is not unitary and can not be used under '{modifier}'"
+        ));
+    }
+    if !ALLOCATION.contains(&ins.op.as_str()) && !ins.qudits().is_empty() {
+        return Some(format!(
+            "MODIFIER_ERROR: qudit instruction '{ins}' can not be used under '{modifier}'"
+        ));
+    }
+    None
+}
+
+/// `inv`: the gates in reverse order, each replaced by its inverse. Allocations stay in
+/// front and frees at the end, so ancillas of a user gate still wrap the whole body.
+pub fn invert(program: &Program) -> Result<Program, String> {
+    let mut allocs = vec![];
+    let mut frees = vec![];
+    let mut body = vec![];
+    for ins in program.instructions.iter().rev() {
+        if let Some(e) = rejected(ins, "inv") {
+            return Err(e);
+        }
+        match ins.op.as_str() {
+            "QAL" | "DAL" => allocs.insert(0, ins.clone()),
+            "FRE" | "DFR" => frees.insert(0, ins.clone()),
+            "S" | "T" | "SDG" | "TDG" => {
+                let op = match ins.op.as_str() {
+                    "S" => "SDG",
+                    "SDG" => "S",
+                    "T" => "TDG",
+                    _ => "T",
+                };
+                body.push(Instruction::new(op, ins.args.clone()));
+            }
+            // SX^4 = I
+            "SX" => body.extend(std::iter::repeat_n(ins.clone(), 3)),
+            "RX" | "RY" | "RZ" => {
+                let angle = scale(ins.args.get(1), -1.0)?;
+                body.push(Instruction::new(&ins.op, vec![ins.args[0].clone(), angle]));
+            }
+            "HAD" | "PX" | "PY" | "PZ" | "ID" | "CNT" | "CY" | "TOF" => body.push(ins.clone()),
+            _ => {
+                return Err(format!(
+                    "MODIFIER_ERROR: no inverse is known for '{}'",
+                    ins.op
+                ))
+            }
+        }
+    }
+    Ok(Program {
+        instructions: allocs.into_iter().chain(body).chain(frees).collect(),
+    })
+}
+
+/// `pow(n)`: a single rotation gets its angle multiplied, anything else is repeated.
+pub fn power(program: &Program, n: u32) -> Result<Program, String> {
+    if let Some(e) = program.instructions.iter().find_map(|i| rejected(i, "pow")) {
+        return Err(e);
+    }
+    if let [ins] = program.instructions.as_slice() {
+        if ["RX", "RY", "RZ"].contains(&ins.op.as_str()) {
+            let angle = scale(ins.args.get(1), n as f64)?;
+            return Ok(Program {
+                instructions: vec![Instruction::new(&ins.op, vec![ins.args[0].clone(), angle])],
+            });
+        }
+    }
+    Ok(Program {
+        instructions: (0..n)
+            .flat_map(|_| program.instructions.iter().cloned())
+            .collect(),
+    })
+}
+
+/// Hands out the ancilla registers `ctrl` needs for more than two controls.
+pub struct Ancillas<'a> {
+    pub prefix: &'a str,
+    pub next: &'a mut i32,
+}
+
+impl Ancillas<'_> {
+    fn take(&mut self) -> String {
+        *self.next += 1;
+        format!("{}_{}", self.prefix, self.next)
+    }
+}
+
+/// Computes the AND of `controls` into a fresh ancilla with a ladder of Toffolis.
+/// Returns the ancilla, the instructions that compute it and the ones that undo them.
+fn and_ladder(
+    controls: &[String],
+    anc: &mut Ancillas,
+) -> (String, Vec<Instruction>, Vec<Instruction>) {
+    let mut compute = vec![];
+    let mut uncompute = vec![];
+    let mut acc = controls[0].clone();
+    for c in &controls[1..] {
+        let a = anc.take();
+        let name = Operand::Name(a.clone());
+        let tof = Instruction::new("TOF", vec![qubit(&acc), qubit(c), qubit(&a)]);
+        compute.push(Instruction::new(
+            "QAL",
+            vec![
+                Operand::Sigil('&'),
+                Operand::Num(0.0),
+                Operand::Sigil('$'),
+                name.clone(),
+            ],
+        ));
+        compute.push(tof.clone());
+        let fre = Instruction::new("FRE", vec![Operand::Sigil('&'), Operand::Sigil('$'), name]);
+        uncompute.splice(0..0, [tof, fre]);
+        acc = a;
+    }
+    (acc, compute, uncompute)
+}
+
+/// X on `target` controlled by all of `controls`. Up to two controls map onto
+/// `PX`/`CNT`/`TOF` directly, more go through an ancilla ladder.
+fn mcx(controls: &[String], target: &str, anc: &mut Ancillas) -> Vec<Instruction> {
+    match controls {
+        [] => vec![gate("PX", target, None)],
+        [c] => vec![Instruction::new("CNT", vec![qubit(c), qubit(target)])],
+        [c0, c1] => vec![Instruction::new(
+            "TOF",
+            vec![qubit(c0), qubit(c1), qubit(target)],
+        )],
+        _ => {
+            let (last, rest) = controls.split_last().unwrap();
+            let (acc, mut out, uncompute) = and_ladder(rest, anc);
+            out.push(Instruction::new(
+                "TOF",
+                vec![qubit(&acc), qubit(last), qubit(target)],
+            ));
+            out.extend(uncompute);
+            out
+        }
+    }
+}
+
+/// `e^(i a) RZ(b) RY(c) RZ(d)`, the ZYZ Euler angles `(a, b, c, d)` of a single qubit gate.
+fn zyz(u: &[[Complex; 2]; 2]) -> (f64, f64, f64, f64) {
+    let det = u[0][0] * u[1][1] - u[0][1] * u[1][0];
+    let alpha = det.arg() / 2.0;
+    let p = Complex::phase(-alpha);
+    let (v00, v10, v11) = (u[0][0] * p, u[1][0] * p, u[1][1] * p);
+    let gamma = 2.0 * v10.abs().atan2(v00.abs());
+    let (beta, delta) = if v10.abs() < EPS {
+        (2.0 * v11.arg(), 0.0)
+    } else if v00.abs() < EPS {
+        (2.0 * v10.arg(), 0.0)
+    } else {
+        (v11.arg() + v10.arg(), v11.arg() - v10.arg())
+    };
+    (alpha, beta, gamma, delta)
+}
+
+fn rotation(op: &str, target: &str, angle: f64, out: &mut Vec<Instruction>) {
+    if angle.abs() > EPS {
+        out.push(gate(op, target, Some(Operand::Num(angle))));
+    }
+}
+
+/// A single qubit gate with one control and no ancilla. Rotations keep their (possibly
+/// extern) angle, fixed gates are split into `A X B X C` from their Euler angles. The
+/// phase these need on the control is emitted as an `RZ`, which falls short by a global
+/// phase that is added to `phase`.
+fn controlled_single(
+    c: &str,
+    ins: &Instruction,
+    phase: &mut f64,
+) -> Result<Vec<Instruction>, String> {
+    let t = match ins.args.first() {
+        Some(Operand::Qubit(t)) => t.as_str(),
+        other => {
+            return Err(format!(
+                "MODIFIER_ERROR: expected qubit register, got {other:?}"
+            ))
+        }
+    };
+    let cnt = Instruction::new("CNT", vec![qubit(c), qubit(t)]);
+    let rz_like = |op: &str| -> Result<Vec<Instruction>, String> {
+        Ok(vec![
+            gate(op, t, Some(scale(ins.args.get(1), 0.5)?)),
+            cnt.clone(),
+            gate(op, t, Some(scale(ins.args.get(1), -0.5)?)),
+            cnt.clone(),
+        ])
+    };
+    match ins.op.as_str() {
+        "RZ" | "RY" => rz_like(&ins.op),
+        "RX" => {
+            let mut out = vec![gate("HAD", t, None)];
+            out.extend(rz_like("RZ")?);
+            out.push(gate("HAD", t, None));
+            Ok(out)
+        }
+        op => {
+            let g = Gate::from_name(op, None).ok_or_else(|| {
+                format!("MODIFIER_ERROR: no controlled version is known for '{op}'")
+            })?;
+            let (alpha, beta, gamma, delta) = zyz(&g.matrix());
+            let mut out = vec![];
+            rotation("RZ", t, (delta - beta) / 2.0, &mut out);
+            out.push(cnt.clone());
+            rotation("RZ", t, -(delta + beta) / 2.0, &mut out);
+            rotation("RY", t, -gamma / 2.0, &mut out);
+            out.push(cnt);
+            rotation("RY", t, gamma / 2.0, &mut out);
+            rotation("RZ", t, beta, &mut out);
+            phase_gate(c, alpha, phase, &mut out);
+            Ok(out)
+        }
+    }
+}
+
+/// diag(1, e^(i angle)) on `q` as `RZ(angle)`, which is e^(-i angle/2) times that.
+fn phase_gate(q: &str, angle: f64, phase: &mut f64, out: &mut Vec<Instruction>) {
+    rotation("RZ", q, angle, out);
+    *phase += angle / 2.0;
+}
+
+fn controlled(
+    ins: &Instruction,
+    controls: &[String],
+    anc: &mut Ancillas,
+    phase: &mut f64,
+) -> Result<Vec<Instruction>, String> {
+    let qs = qubits(ins);
+    if let Some(q) = qs.iter().find(|q| controls.contains(q)) {
+        return Err(format!(
+            "MODIFIER_ERROR: ${q} is used as control and as operand of '{ins}'"
+        ));
+    }
+    let with = |extra: &[String]| [controls, extra].concat();
+    Ok(match ins.op.as_str() {
+        op if ALLOCATION.contains(&op) => vec![ins.clone()],
+        "ID" => vec![],
+        "PX" => mcx(controls, &qs[0], anc),
+        "CNT" => mcx(&with(&qs[..1]), &qs[1], anc),
+        "TOF" => mcx(&with(&qs[..2]), &qs[2], anc),
+        // Y = S X SDG, Z = H X H
+        "PY" | "CY" => {
+            let (t, cs) = qs.split_last().unwrap();
+            let mut out = vec![gate("SDG", t, None)];
+            out.extend(mcx(&with(cs), t, anc));
+            out.push(gate("S", t, None));
+            out
+        }
+        "PZ" => {
+            let mut out = vec![gate("HAD", &qs[0], None)];
+            out.extend(mcx(controls, &qs[0], anc));
+            out.push(gate("HAD", &qs[0], None));
+            out
+        }
+        _ if controls.len() == 1 => controlled_single(&controls[0], ins, phase)?,
+        _ => {
+            let (acc, mut out, uncompute) = and_ladder(controls, anc);
+            out.extend(controlled_single(&acc, ins, phase)?);
+            out.extend(uncompute);
+            out
+        }
+    })
+}
+
+/// `ctrl(n)`: every gate of the program conditioned on all of `controls`.
+///
+/// One control and Toffoli style gates with up to two controls in total are lowered
+/// without ancillas. Beyond that the controls are first ANDed into an ancilla qubit,
+/// which is uncomputed and freed again right after the gate.
+///
+/// A global phase is invisible until the program is controlled, so the lowering tracks it:
+/// `phase` is the global phase `program` falls short of the code it was generated from,
+/// under the controls it becomes a phase gate on them. Returns the controlled program and
+/// the global phase it falls short of in turn, for a `ctrl` around this one.
+pub fn control(
+    program: &Program,
+    controls: &[String],
+    phase: f64,
+    anc: &mut Ancillas,
+) -> Result<(Program, f64), String> {
+    if controls.is_empty() {
+        return Ok((program.clone(), phase));
+    }
+    let mut instructions = vec![];
+    let mut short = 0.0;
+    for ins in &program.instructions {
+        if let Some(e) = rejected(ins, "ctrl") {
+            return Err(e);
+        }
+        instructions.extend(controlled(ins, controls, anc, &mut short)?);
+    }
+    if phase.abs() > EPS {
+        // e^(i phase) on the part of the state where all controls are 1
+        match controls {
+            [c] => phase_gate(c, phase, &mut short, &mut instructions),
+            _ => {
+                let (acc, compute, uncompute) = and_ladder(controls, anc);
+                instructions.extend(compute);
+                phase_gate(&acc, phase, &mut short, &mut instructions);
+                instructions.extend(uncompute);
+            }
+        }
+    }
+    Ok((Program { instructions }, short))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::{FRAC_1_SQRT_2, FRAC_PI_2, FRAC_PI_4, PI};
+
+    use crate::compiler::Compiler;
+    use crate::complex::{Complex, ONE, ZERO};
+    use crate::unitary::{phase_distance, unitary, Matrix};
+
+    fn diag(phase: f64) -> Matrix {
+        vec![vec![ONE, ZERO], vec![ZERO, Complex::phase(phase)]]
+    }
+
+    fn had() -> Matrix {
+        let h = Complex::real(FRAC_1_SQRT_2);
+        vec![vec![h, h], vec![h, h.scale(-1.0)]]
+    }
+
+    fn ry(t: f64) -> Matrix {
+        let (c, s) = (
+            Complex::real((t / 2.0).cos()),
+            Complex::real((t / 2.0).sin()),
+        );
+        vec![vec![c, s.scale(-1.0)], vec![s, c]]
+    }
+
+    /// The QFT on two qubits, the first one the least significant bit.
+    fn fourier2(sign: f64) -> Matrix {
+        (0..4)
+            .map(|k| {
+                (0..4)
+                    .map(|j| Complex::phase(sign * FRAC_PI_2 * (j * k) as f64).scale(0.5))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// `u` on the registers after the first `k`, if those are all 1.
+    fn controlled(k: usize, u: &Matrix) -> Matrix {
+        let dim = u.len() << k;
+        let mask = (1 << k) - 1;
+        (0..dim)
+            .map(|row| {
+                (0..dim)
+                    .map(|col| match (row & mask == col & mask, row & mask == mask) {
+                        (false, _) => ZERO,
+                        (true, true) => u[row >> k][col >> k],
+                        (true, false) if row == col => ONE,
+                        _ => ZERO,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Compiles `body` after declaring `qubits` and compares its unitary over them with
+    /// `expected`, ancillas have to be returned to |0>.
+    fn check(body: &str, qubits: &[&str], expected: Matrix) {
+        let mut src = "import \"std/qft.qel\";\n".to_string();
+        for q in qubits {
+            src.push_str(&format!("let {q}: qbit;\n"));
+        }
+        src.push_str(body);
+        let program = Compiler::new().compile_str(&src).unwrap().program;
+        let names: Vec<String> = qubits.iter().map(|q| q.to_string()).collect();
+        let actual = unitary(&program, &names, &[]).unwrap();
+        let error = phase_distance(&expected, &actual);
+        assert!(error < 1e-9, "'{body}' is off by {error:.1e}");
+    }
+
+    #[test]
+    fn ctrl() {
+        check("ctrl(1) HAD(a, b);", &["a", "b"], controlled(1, &had()));
+        check(
+            "ctrl(1) RY(a, b, 0.7);",
+            &["a", "b"],
+            controlled(1, &ry(0.7)),
+        );
+        check(
+            "ctrl(2) T(a, b, c);",
+            &["a", "b", "c"],
+            controlled(2, &diag(FRAC_PI_4)),
+        );
+        check(
+            "ctrl(3) HAD(a, b, c, d);",
+            &["a", "b", "c", "d"],
+            controlled(3, &had()),
+        );
+        check(
+            "ctrl(1) qft::qft2(c, a, b);",
+            &["c", "a", "b"],
+            controlled(1, &fourier2(1.0)),
+        );
+    }
+
+    #[test]
+    fn nested_ctrl() {
+        for (gate, u) in [
+            ("HAD", had()),
+            ("S", diag(FRAC_PI_2)),
+            ("T", diag(FRAC_PI_4)),
+        ] {
+            let expected = controlled(2, &u);
+            check(
+                &format!("ctrl(1) ctrl(1) {gate}(a, b, c);"),
+                &["a", "b", "c"],
+                expected,
+            );
+        }
+        check(
+            "ctrl(1) ctrl(2) T(a, b, c, d);",
+            &["a", "b", "c", "d"],
+            controlled(3, &diag(FRAC_PI_4)),
+        );
+        // the controlled S and T inside the QFT are relative phases under the outer control
+        check(
+            "ctrl(2) qft::qft2(c, d, a, b);",
+            &["c", "d", "a", "b"],
+            controlled(2, &fourier2(1.0)),
+        );
+        check(
+            "ctrl(1) inv ctrl(1) T(a, b, c);",
+            &["a", "b", "c"],
+            controlled(2, &diag(-FRAC_PI_4)),
+        );
+        check(
+            "ctrl(1) pow(2) ctrl(1) T(a, b, c);",
+            &["a", "b", "c"],
+            controlled(2, &diag(FRAC_PI_2)),
+        );
+    }
+
+    #[test]
+    fn inv() {
+        check("inv T(a);", &["a"], diag(-FRAC_PI_4));
+        check("inv RY(a, 0.7);", &["a"], ry(-0.7));
+        check("inv qft::qft2(a, b);", &["a", "b"], fourier2(-1.0));
+        check(
+            "inv ctrl(2) S(a, b, c);",
+            &["a", "b", "c"],
+            controlled(2, &diag(-FRAC_PI_2)),
+        );
+    }
+
+    #[test]
+    fn pow() {
+        check("pow(3) T(a);", &["a"], diag(3.0 * FRAC_PI_4));
+        check("pow(2) RY(a, 0.7);", &["a"], ry(1.4));
+        // the QFT has order 4
+        check(
+            "pow(4) qft::qft2(a, b);",
+            &["a", "b"],
+            controlled(1, &diag(0.0)),
+        );
+        check(
+            "ctrl(1) pow(2) S(a, b);",
+            &["a", "b"],
+            controlled(1, &diag(PI)),
+        );
+    }
+}
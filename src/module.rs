@@ -0,0 +1,459 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::cache::Cache;
+use crate::parser::{from_tokens, parse_, ASTNode};
+use crate::stdlib;
+use crate::tokenizer::{filter50s_with, filter_all, tokenize, Token};
+
+/// The `import "lib/oracles.qel";` and `use oracles::balanced;` lines of a file. Both have to
+/// stand on a line of their own, `body` is the source with these lines left empty so line
+/// numbers still match.
+#[derive(Debug, Clone, Default)]
+pub struct Header {
+    /// line, path as written
+    pub imports: Vec<(usize, String)>,
+    /// line, module, function
+    pub uses: Vec<(usize, String, String)>,
+    pub body: String,
+}
+
+fn is_name(s: &str) -> bool {
+    s.chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// The module name of an imported file, `lib/oracles.qel` is `oracles`.
+pub fn module_name(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+pub fn header(src: &str) -> Result<Header, String> {
+    let mut header = Header::default();
+    let mut body = vec![];
+    for (n, line) in src.lines().enumerate() {
+        let code = line.split("//").next().unwrap().trim();
+        // `important(q);` is a call, not an import
+        let import = code.strip_prefix("import").filter(|rest| {
+            rest.is_empty() || rest.starts_with(|c: char| c.is_whitespace() || c == '"')
+        });
+        if let Some(rest) = import {
+            let path = rest
+                .trim()
+                .strip_suffix(';')
+                .and_then(|p| p.trim().strip_prefix('"'))
+                .and_then(|p| p.strip_suffix('"'))
+                .filter(|p| !p.is_empty() && !p.contains('"'))
+                .ok_or(format!(
+                    "MODULE_ERROR: line {}: expected import \"file.qel\";",
+                    n + 1
+                ))?;
+            if !is_name(&module_name(path)) {
+                return Err(format!(
+                    "MODULE_ERROR: line {}: '{path}' is not a valid module name",
+                    n + 1
+                ));
+            }
+            header.imports.push((n, path.to_string()));
+            body.push("");
+        } else if let Some(rest) = code.strip_prefix("use ") {
+            let parts: Option<Vec<&str>> = rest
+                .trim()
+                .strip_suffix(';')
+                .map(|p| p.split("::").map(str::trim).collect());
+            match parts.as_deref() {
+                Some([module, function]) if is_name(module) && is_name(function) => {
+                    header
+                        .uses
+                        .push((n, module.to_string(), function.to_string()));
+                }
+                _ => {
+                    return Err(format!(
+                        "MODULE_ERROR: line {}: expected use module::function;",
+                        n + 1
+                    ))
+                }
+            }
+            body.push("");
+        } else {
+            body.push(line);
+        }
+    }
+    // the tokenizer looks one character past every keyword, keep the final newline
+    header.body = body.iter().map(|l| format!("{l}\n")).collect();
+    Ok(header)
+}
+
+impl Header {
+    /// Names the header brings into scope: imported modules and used functions.
+    pub fn names(&self) -> Vec<(usize, String)> {
+        let imports = self.imports.iter().map(|(n, p)| (*n, module_name(p)));
+        let uses = self
+            .uses
+            .iter()
+            .flat_map(|(n, m, f)| [(*n, m.clone()), (*n, f.clone())]);
+        imports.chain(uses).collect()
+    }
+}
+
+/// Tokens of `body`, with the names from the header already declared so that calls to
+/// imported functions are references like calls to functions defined in the file.
+pub fn tokens(header: &Header) -> Vec<Token> {
+    declare(header, tokenize(&header.body))
+}
+
+fn declare(header: &Header, tokens: Vec<Token>) -> Vec<Token> {
+    let mut declared: HashSet<(u32, String)> =
+        header.names().into_iter().map(|(_, s)| (0, s)).collect();
+    filter_all(filter50s_with(tokens, &mut declared))
+}
+
+/// Renames the calls in `node` according to `names`.
+fn qualify(node: &mut ASTNode, names: &HashMap<String, String>) {
+    match node {
+        ASTNode::FunctionCall { name, .. } => {
+            if let Some(full) = names.get(name) {
+                *name = full.clone();
+            }
+        }
+        ASTNode::Program(nodes) | ASTNode::Block(nodes) => {
+            nodes.iter_mut().for_each(|n| qualify(n, names))
+        }
+        ASTNode::FunctionDef { body: Some(b), .. } | ASTNode::For { body: Some(b), .. } => {
+            qualify(b, names)
+        }
+        ASTNode::VariableDecl { value: Some(v), .. }
+        | ASTNode::Assignment { value: v, .. }
+        | ASTNode::Return(v)
+        | ASTNode::Modified { call: v, .. } => qualify(v, names),
+        _ => {}
+    }
+}
+
+/// Calls of the form `module::function` in `node`.
+fn qualified_calls(node: &ASTNode, out: &mut Vec<String>) {
+    match node {
+        ASTNode::FunctionCall { name, .. } if name.contains("::") => out.push(name.clone()),
+        ASTNode::Program(nodes) | ASTNode::Block(nodes) => {
+            nodes.iter().for_each(|n| qualified_calls(n, out))
+        }
+        ASTNode::FunctionDef { body: Some(b), .. } | ASTNode::For { body: Some(b), .. } => {
+            qualified_calls(b, out)
+        }
+        ASTNode::VariableDecl { value: Some(v), .. }
+        | ASTNode::Assignment { value: v, .. }
+        | ASTNode::Return(v)
+        | ASTNode::Modified { call: v, .. } => qualified_calls(v, out),
+        _ => {}
+    }
+}
+
+fn on_line(n: usize, e: String) -> String {
+    let e = e.strip_prefix("MODULE_ERROR: ").unwrap_or(&e);
+    format!("MODULE_ERROR: line {}: {e}", n + 1)
+}
+
+/// A parsed module, its functions are named `module::function`.
+#[derive(Debug, Clone)]
+struct Module {
+    name: String,
+    /// what the importing file wrote, for error messages
+    shown: String,
+    functions: Vec<String>,
+    definitions: Vec<ASTNode>,
+    imports: Vec<PathBuf>,
+}
+
+/// Resolves imports and keeps every module it parsed, so a module imported from several
+/// files is only parsed once.
+///
+/// An import is looked up next to the importing file first, then in every directory of the
+/// search path. `use m::f;` without an import of `m` looks for `m.qel` the same way. Sources
+/// added with `add_source` take precedence over the file system.
+#[derive(Debug, Clone, Default)]
+pub struct Loader {
+    pub search_path: Vec<PathBuf>,
+    /// tokens and generated code of earlier runs, see `cache::Cache`
+    pub disk: Option<Cache>,
+    sources: HashMap<PathBuf, String>,
+    cache: HashMap<PathBuf, Module>,
+    /// modules being loaded, to detect import cycles
+    loading: Vec<PathBuf>,
+}
+
+impl Loader {
+    pub fn new(search_path: Vec<PathBuf>) -> Loader {
+        Loader {
+            search_path,
+            ..Loader::default()
+        }
+    }
+
+    /// Makes `src` importable as `path` without a file.
+    pub fn add_source(&mut self, path: impl Into<PathBuf>, src: &str) {
+        self.sources.insert(path.into(), src.to_string());
+    }
+
+    /// Like the free function `tokens`, reusing the tokens of unchanged functions if there
+    /// is a disk cache.
+    pub fn tokens(&mut self, header: &Header) -> Vec<Token> {
+        match &mut self.disk {
+            Some(cache) => declare(header, cache.tokenize(&header.body)),
+            None => tokens(header),
+        }
+    }
+
+    /// Parses `src`, located at `from` if it is a file, and links in its imports.
+    pub fn load(&mut self, src: &str, from: Option<&Path>) -> Result<ASTNode, String> {
+        let header = header(src)?;
+        let tokens = self.tokens(&header);
+        let ast = parse_(from_tokens(tokens.clone()), tokens)?;
+        self.link(&header, ast, from)
+    }
+
+    /// Puts the definitions of everything `header` imports, directly or not, in front of
+    /// `ast` and renames the calls of used functions to their full name.
+    pub fn link(
+        &mut self,
+        header: &Header,
+        mut ast: ASTNode,
+        from: Option<&Path>,
+    ) -> Result<ASTNode, String> {
+        let (names, imports) = self.scope(header, from)?;
+        self.check_calls(&ast, &imports)?;
+        qualify(&mut ast, &names);
+
+        let mut order = vec![];
+        let mut seen = HashSet::new();
+        for key in &imports {
+            self.dependencies(key, &mut seen, &mut order);
+        }
+        let mut owners: HashMap<&str, &str> = HashMap::new();
+        for key in &order {
+            let module = &self.cache[key];
+            if let Some(other) = owners.insert(&module.name, &module.shown) {
+                return Err(format!(
+                    "MODULE_ERROR: '{other}' and '{}' are both module '{}'",
+                    module.shown, module.name
+                ));
+            }
+        }
+        let mut nodes: Vec<ASTNode> = order
+            .iter()
+            .flat_map(|key| self.cache[key].definitions.clone())
+            .collect();
+        match ast {
+            ASTNode::Program(main) => nodes.extend(main),
+            other => nodes.push(other),
+        }
+        Ok(ASTNode::Program(nodes))
+    }
+
+    /// Loads the imports of `header` and returns how calls in the file are renamed, along with
+    /// the modules it can call into.
+    fn scope(
+        &mut self,
+        header: &Header,
+        from: Option<&Path>,
+    ) -> Result<(HashMap<String, String>, Vec<PathBuf>), String> {
+        let mut modules: Vec<PathBuf> = vec![];
+        for (n, path) in &header.imports {
+            let key = self.module(path, from).map_err(|e| on_line(*n, e))?;
+            modules.push(key);
+        }
+        let mut names = HashMap::new();
+        for (n, module, function) in &header.uses {
+            let key = match modules.iter().find(|k| self.cache[*k].name == *module) {
+                Some(key) => key.clone(),
+                None => {
+                    let key = self
+                        .module(&format!("{module}.qel"), from)
+                        .map_err(|e| on_line(*n, e))?;
+                    modules.push(key.clone());
+                    key
+                }
+            };
+            if !self.cache[&key].functions.contains(function) {
+                return Err(format!(
+                    "MODULE_ERROR: line {}: module '{module}' has no function '{function}'",
+                    n + 1
+                ));
+            }
+            names.insert(function.clone(), format!("{module}::{function}"));
+        }
+        Ok((names, modules))
+    }
+
+    /// Qualified calls have to name a function of a module the file imports.
+    fn check_calls(&self, ast: &ASTNode, imports: &[PathBuf]) -> Result<(), String> {
+        let mut calls = vec![];
+        qualified_calls(ast, &mut calls);
+        for call in calls {
+            let (module, function) = call.split_once("::").unwrap();
+            match imports
+                .iter()
+                .map(|k| &self.cache[k])
+                .find(|m| m.name == module)
+            {
+                None => {
+                    return Err(format!(
+                        "MODULE_ERROR: '{call}': '{module}' is not imported"
+                    ))
+                }
+                Some(m) if !m.functions.iter().any(|f| f == function) => {
+                    return Err(format!(
+                        "MODULE_ERROR: '{call}': module '{module}' has no function '{function}'"
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn dependencies(&self, key: &PathBuf, seen: &mut HashSet<PathBuf>, order: &mut Vec<PathBuf>) {
+        if seen.insert(key.clone()) {
+            for import in &self.cache[key].imports {
+                self.dependencies(import, seen, order);
+            }
+            order.push(key.clone());
+        }
+    }
+
+    /// Finds `path` in the standard library, relative to the importing file or on the search
+    /// path.
+    fn resolve(&self, path: &str, from: Option<&Path>) -> Result<(PathBuf, String), String> {
+        if let Some(src) = stdlib::source(path) {
+            return Ok((PathBuf::from(path), src.to_string()));
+        }
+        let base = from.and_then(Path::parent).unwrap_or(Path::new(""));
+        let candidates: Vec<PathBuf> = std::iter::once(base.join(path))
+            .chain(self.search_path.iter().map(|dir| dir.join(path)))
+            .collect();
+        for candidate in &candidates {
+            if let Some(src) = self.sources.get(candidate) {
+                return Ok((candidate.clone(), src.clone()));
+            }
+            // imports between library modules, relative to `std/`
+            if let Some(src) = candidate.to_str().and_then(stdlib::source) {
+                return Ok((candidate.clone(), src.to_string()));
+            }
+            if candidate.is_file() {
+                let key = candidate.canonicalize().unwrap_or(candidate.clone());
+                let src = std::fs::read_to_string(candidate)
+                    .map_err(|e| format!("MODULE_ERROR: {}: {e}", candidate.display()))?;
+                return Ok((key, src));
+            }
+        }
+        // `use qft::qft3;` without a `qft.qel` of the program's own
+        let library = format!("std/{path}");
+        if let Some(src) = stdlib::source(&library) {
+            return Ok((PathBuf::from(library), src.to_string()));
+        }
+        let searched: Vec<String> = candidates.iter().map(|c| c.display().to_string()).collect();
+        Err(format!(
+            "MODULE_ERROR: can't find '{path}', searched {}",
+            searched.join(", ")
+        ))
+    }
+
+    /// Parses the module at `path` unless it is cached, returns its cache key.
+    fn module(&mut self, path: &str, from: Option<&Path>) -> Result<PathBuf, String> {
+        let (key, src) = self.resolve(path, from)?;
+        if let Some(at) = self.loading.iter().position(|k| *k == key) {
+            let mut cycle: Vec<String> = self.loading[at..]
+                .iter()
+                .map(|k| k.display().to_string())
+                .collect();
+            cycle.push(key.display().to_string());
+            return Err(format!("MODULE_ERROR: import cycle {}", cycle.join(" -> ")));
+        }
+        if self.cache.contains_key(&key) {
+            return Ok(key);
+        }
+        self.loading.push(key.clone());
+        let module = self.parse_module(path, &key, &src);
+        self.loading.pop();
+        self.cache.insert(key.clone(), module?);
+        Ok(key)
+    }
+
+    fn parse_module(&mut self, path: &str, key: &Path, src: &str) -> Result<Module, String> {
+        let in_module = |e: String| {
+            let e = e.strip_prefix("MODULE_ERROR: ").unwrap_or(&e);
+            format!("MODULE_ERROR: {path}: {e}")
+        };
+        let name = module_name(path);
+        let header = header(src).map_err(in_module)?;
+        let tokens = self.tokens(&header);
+        let mut ast = parse_(from_tokens(tokens.clone()), tokens).map_err(in_module)?;
+
+        let (mut names, imports) = self.scope(&header, Some(key)).map_err(in_module)?;
+        let ASTNode::Program(nodes) = &ast else {
+            return Err(in_module("expected a program".to_string()));
+        };
+        let mut functions = vec![];
+        for node in nodes {
+            match node {
+                ASTNode::FunctionDef { name: f, .. } => functions.push(f.clone()),
+                other => {
+                    return Err(in_module(format!(
+                        "only functions can be defined in a module, found {other:?}"
+                    )))
+                }
+            }
+        }
+        self.check_calls(&ast, &imports).map_err(in_module)?;
+        for f in &functions {
+            names.insert(f.clone(), format!("{name}::{f}"));
+        }
+        qualify(&mut ast, &names);
+        let ASTNode::Program(mut definitions) = ast else {
+            unreachable!()
+        };
+        for def in &mut definitions {
+            if let ASTNode::FunctionDef { name: f, .. } = def {
+                *f = format!("{name}::{f}");
+            }
+        }
+        Ok(Module {
+            name,
+            shown: path.to_string(),
+            functions,
+            definitions,
+            imports,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+
+    #[test]
+    fn import_needs_a_separator() {
+        let src = "void important(x: qbit) {\n    HAD(x);\n}\nlet q: qbit;\nimportant(q);\n";
+        assert!(header(src).unwrap().imports.is_empty());
+        assert!(Compiler::new().compile_str(src).is_ok());
+        let imports = header("import\"lib/a.qel\";\n").unwrap().imports;
+        assert_eq!(imports, [(0, "lib/a.qel".to_string())]);
+    }
+
+    #[test]
+    fn parameters_are_local_to_their_function() {
+        let lib = "void balanciert(x: qbit, y: qbit) {\n    CNT(x, y);\n}\n\nvoid konstant(x: qbit, y: qbit) {\n    PX(y);\n}\n";
+        let src = "import \"lib/orakel.qel\";\nuse orakel::balanciert;\n\nlet x: qbit;\nlet y: qbit;\nbalanciert(x, y);\norakel::konstant(x, y);\n";
+        let artifact = Compiler::new()
+            .module("lib/orakel.qel", lib)
+            .compile_str(src)
+            .unwrap();
+        assert!(artifact.ir.contains("CNT $x $y"));
+        assert!(artifact.ir.contains("PX $y"));
+    }
+}
@@ -0,0 +1,457 @@
+use crate::complex::{Complex, I, ONE, ZERO};
+use crate::density::DensityMatrix;
+use crate::ir::{trace, Instruction, Operand, Program};
+use crate::json::Json;
+use crate::shots::{self, replay, tally, terminal, Counts, Sampler};
+use crate::sim::{Engine, Gate, Machine, Matrix2, Rng, Simulator, Statevector};
+
+/// Instructions that do not act on the quantum state and therefore add no noise.
+const PASSIVE: [&str; 10] = [
+    "QAL", "FRE", "DAL", "DFR", "BAL", "BCP", "BFR", "MES", "CMB", "DCB",
+];
+
+/// Multi qubit and qudit instructions a `[gate.NAME]` section may refer to, the single qubit
+/// gates are whatever `Gate::from_name` knows.
+const GATES: [&str; 10] = [
+    "CNT", "CY", "TOF", "SET", "CPY", "DHAD", "DPX", "DCX", "TR", "MES",
+];
+
+/// Error probabilities of one section of a noise model.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Channels {
+    /// X, Y and Z each with probability p/3
+    pub depolarizing: f64,
+    /// decay |1> -> |0> with probability gamma
+    pub amplitude_damping: f64,
+    /// loss of phase coherence with probability lambda
+    pub phase_damping: f64,
+    /// a measured bit is flipped with probability p
+    pub readout: f64,
+}
+
+impl Channels {
+    /// Kraus operators of the quantum channels, in the order they are applied.
+    fn kraus(&self) -> Vec<Vec<Matrix2>> {
+        let r = |x: f64| Complex::real(x.sqrt());
+        let mut out = vec![];
+        let p = self.depolarizing;
+        if p > 0.0 {
+            let k = r(p / 3.0);
+            out.push(vec![
+                [[r(1.0 - p), ZERO], [ZERO, r(1.0 - p)]],
+                [[ZERO, k], [k, ZERO]],
+                [[ZERO, -(I * k)], [I * k, ZERO]],
+                [[k, ZERO], [ZERO, -k]],
+            ]);
+        }
+        let g = self.amplitude_damping;
+        if g > 0.0 {
+            out.push(vec![
+                [[ONE, ZERO], [ZERO, r(1.0 - g)]],
+                [[ZERO, r(g)], [ZERO, ZERO]],
+            ]);
+        }
+        let l = self.phase_damping;
+        if l > 0.0 {
+            out.push(vec![
+                [[ONE, ZERO], [ZERO, r(1.0 - l)]],
+                [[ZERO, ZERO], [ZERO, r(l)]],
+            ]);
+        }
+        out
+    }
+}
+
+/// Noise per gate and per qubit, read from a TOML subset:
+///
+/// ```toml
+/// [default]          # every gate without its own section
+/// depolarizing = 0.001
+///
+/// [gate.CNT]
+/// depolarizing = 0.01
+///
+/// [qubit.input]      # on top of the gate noise, for every gate on $input
+/// amplitude_damping = 0.02
+/// readout = 0.05
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NoiseModel {
+    pub default: Option<Channels>,
+    pub gates: Vec<(String, Channels)>,
+    pub qubits: Vec<(String, Channels)>,
+}
+
+impl NoiseModel {
+    pub fn parse(text: &str) -> Result<NoiseModel, String> {
+        let mut model = NoiseModel::default();
+        // kind of the current section, its channels are the last pushed
+        let mut section: Option<String> = None;
+        let mut seen: Vec<String> = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let err = |msg: String| format!("NOISE_ERROR: line {}: {msg}", i + 1);
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let header = header.trim();
+                if seen.iter().any(|s| s == header) {
+                    return Err(err(format!("section [{header}] is defined twice")));
+                }
+                seen.push(header.to_string());
+                let (kind, name) = match header.split_once('.') {
+                    Some((kind, name)) => (kind.trim(), name.trim().trim_matches('"')),
+                    None => (header, ""),
+                };
+                match (kind, name) {
+                    ("default", "") => model.default = Some(Channels::default()),
+                    ("gate", name) if known_gate(name) => {
+                        model.gates.push((name.to_string(), Channels::default()))
+                    }
+                    ("gate", name) => return Err(err(format!("unknown gate '{name}'"))),
+                    ("qubit", name) if !name.is_empty() => {
+                        model.qubits.push((name.to_string(), Channels::default()))
+                    }
+                    _ => {
+                        return Err(err(format!(
+                            "unknown section [{header}], expected [default], [gate.NAME] or [qubit.NAME]"
+                        )))
+                    }
+                }
+                section = Some(kind.to_string());
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| err(format!("expected 'key = value', got '{line}'")))?;
+            let (key, value) = (key.trim(), value.trim());
+            let p: f64 = value
+                .parse()
+                .map_err(|_| err(format!("'{value}' is not a number")))?;
+            if !(0.0..=1.0).contains(&p) {
+                return Err(err(format!("{key} = {p} is not a probability")));
+            }
+            let channels = match section.as_deref() {
+                None => return Err(err(format!("'{key}' outside of a section"))),
+                Some("default") => model.default.as_mut().unwrap(),
+                Some("gate") => &mut model.gates.last_mut().unwrap().1,
+                Some(_) => &mut model.qubits.last_mut().unwrap().1,
+            };
+            let field = match key {
+                "depolarizing" => &mut channels.depolarizing,
+                "amplitude_damping" => &mut channels.amplitude_damping,
+                "phase_damping" => &mut channels.phase_damping,
+                "readout" => &mut channels.readout,
+                _ => {
+                    return Err(err(format!(
+                        "unknown key '{key}', expected depolarizing, amplitude_damping, phase_damping or readout"
+                    )))
+                }
+            };
+            *field = p;
+        }
+        Ok(model)
+    }
+
+    /// The section of gate `op`, or `[default]` if it has none.
+    fn gate(&self, op: &str) -> Option<&Channels> {
+        self.gates
+            .iter()
+            .find(|(name, _)| name == op)
+            .map(|(_, c)| c)
+            .or(self.default.as_ref())
+    }
+
+    fn qubit(&self, name: &str) -> Option<&Channels> {
+        self.qubits.iter().find(|(n, _)| n == name).map(|(_, c)| c)
+    }
+
+    /// Flip probability of a measurement of `$q`, gate and qubit readout errors are
+    /// independent of each other.
+    fn readout(&self, q: &str) -> f64 {
+        let a = self.gate("MES").map_or(0.0, |c| c.readout);
+        let b = self.qubit(q).map_or(0.0, |c| c.readout);
+        a + b - 2.0 * a * b
+    }
+}
+
+fn known_gate(name: &str) -> bool {
+    GATES.contains(&name) || Gate::from_name(name, Some(0.0)).is_some()
+}
+
+/// Applies the noise of `ins` after it was executed: the channels of its gate section and
+/// then those of each qubit it acted on.
+fn decohere(m: &mut Machine<DensityMatrix>, ins: &Instruction, model: &NoiseModel) {
+    if PASSIVE.contains(&ins.op.as_str()) {
+        return;
+    }
+    let mut touched: Vec<usize> = ins
+        .qubits()
+        .iter()
+        .filter_map(|q| m.qubits.get(*q).copied())
+        .collect();
+    for d in ins.qudits() {
+        touched.extend(m.qudits.members(d).into_iter().flatten());
+    }
+    touched.sort_unstable();
+    touched.dedup();
+    for q in touched {
+        let mut names: Vec<&String> = m
+            .qubits
+            .iter()
+            .filter(|(_, i)| **i == q)
+            .map(|(n, _)| n)
+            .collect();
+        names.sort();
+        let own = names.iter().find_map(|n| model.qubit(n));
+        for channels in model.gate(&ins.op).into_iter().chain(own) {
+            for kraus in channels.kraus() {
+                m.engine.channel(q, &kraus);
+            }
+        }
+    }
+}
+
+/// Flips the result of a measurement with the readout error of its qubit.
+fn read_out<E: Engine>(
+    m: &mut Machine<E>,
+    ins: &Instruction,
+    model: &NoiseModel,
+    flips: &mut Rng,
+) -> Result<(), String> {
+    let q = match (ins.op.as_str(), ins.args.first()) {
+        ("MES", Some(Operand::Qubit(q))) => q,
+        _ => return Ok(()),
+    };
+    if flips.next_f64() >= model.readout(q) {
+        return Ok(());
+    }
+    if let Some(last) = m.measurements.last_mut() {
+        last.1 ^= true;
+    }
+    if let (Some(Operand::Name(name)), 3) = (ins.args.get(1), ins.args.len()) {
+        let k = m.number(ins.args.get(2))? as usize;
+        if let Some((_, bits)) = m.registers.iter_mut().find(|(n, _)| n == name) {
+            bits[k] ^= true;
+        }
+    }
+    Ok(())
+}
+
+/// A noisy multi-shot run next to the ideal one.
+#[derive(Debug, Clone)]
+pub struct NoisyRun {
+    pub noisy: Counts,
+    pub ideal: Counts,
+    /// `<psi|rho|psi>` of the ideal and the noisy state right before the first measurement
+    pub state_fidelity: f64,
+    /// per register the classical fidelity `(sum sqrt(p q))^2` of the outcome distributions
+    pub fidelity: Vec<(String, f64)>,
+}
+
+/// Runs `program` `shots` times on the density matrix simulator under `model` and once
+/// more without noise to compare against.
+pub fn run(
+    program: &Program,
+    externs: &[f64],
+    shots: usize,
+    seed: u64,
+    model: &NoiseModel,
+) -> Result<NoisyRun, String> {
+    let ideal = shots::run(program, externs, shots, seed, Simulator::Statevector)?;
+    let mut rng = Rng::new(seed);
+    // independent of the sampling, without noise the counts equal the ideal ones
+    let mut flips = Rng::new(!seed);
+
+    let mut pure = Machine::new(Statevector::new(), externs.to_vec(), seed);
+    let mut m = Machine::new(DensityMatrix::new(), externs.to_vec(), seed);
+    for ins in trace(program)?.take_while(|i| i.op != "MES") {
+        pure.step(ins)?;
+        m.step(ins)?;
+        decohere(&mut m, ins, model);
+    }
+    let state_fidelity = m.engine.fidelity(&pure.engine.amps);
+
+    let mut registers = vec![];
+    let split = terminal(program);
+    if let Some(split) = split {
+        let sampler = Sampler::new(&m.engine.probabilities());
+        for _ in 0..shots {
+            let idx = sampler.pick(&mut rng);
+            let outcome = replay(
+                &m,
+                m.engine.n,
+                idx,
+                &program.instructions[split..],
+                |s, ins| read_out(s, ins, model, &mut flips),
+            )?;
+            tally(&mut registers, outcome);
+        }
+    } else {
+        for _ in 0..shots {
+            let mut m = Machine::new(DensityMatrix::new(), externs.to_vec(), rng.next_u64());
+            for ins in trace(program)? {
+                m.step(ins)?;
+                decohere(&mut m, ins, model);
+                read_out(&mut m, ins, model, &mut flips)?;
+            }
+            tally(&mut registers, m.outcome());
+        }
+    }
+    let noisy = Counts::new("density matrix", shots, seed, split.is_some(), registers);
+    let fidelity = noisy
+        .registers
+        .iter()
+        .map(|(name, counts)| {
+            let overlap: f64 = counts
+                .iter()
+                .map(|(bits, _)| (noisy.share(name, bits) * ideal.share(name, bits)).sqrt())
+                .sum();
+            (name.clone(), overlap * overlap)
+        })
+        .collect();
+    Ok(NoisyRun {
+        noisy,
+        ideal,
+        state_fidelity,
+        fidelity,
+    })
+}
+
+impl NoisyRun {
+    /// The noisy histogram followed by the fidelities.
+    pub fn report(&self) -> String {
+        let mut out = self.noisy.histogram();
+        out.push_str(&format!(
+            "fidelity with the ideal run\n  state before the first measurement  {:.6}\n",
+            self.state_fidelity
+        ));
+        for (name, f) in &self.fidelity {
+            out.push_str(&format!("  outcomes of {name}  {f:.6}\n"));
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> Json {
+        let fidelity = self
+            .fidelity
+            .iter()
+            .map(|(name, f)| (name.clone(), Json::Num(*f)))
+            .collect();
+        Json::obj(vec![
+            ("noisy", self.noisy.to_json()),
+            ("ideal", self.ideal.to_json()),
+            ("state_fidelity", Json::Num(self.state_fidelity)),
+            ("fidelity", Json::Obj(fidelity)),
+        ])
+    }
+
+    /// `register,bits,count,ideal`, the ideal column holds the noiseless count.
+    pub fn to_csv(&self) -> String {
+        let mut out = "register,bits,count,ideal\n".to_string();
+        for (name, counts) in &self.noisy.registers {
+            let mut rows: Vec<(&str, usize)> =
+                counts.iter().map(|(b, n)| (b.as_str(), *n)).collect();
+            if let Some((_, ideal)) = self.ideal.registers.iter().find(|(n, _)| n == name) {
+                for (bits, _) in ideal {
+                    if !rows.iter().any(|(b, _)| b == bits) {
+                        rows.push((bits, 0));
+                    }
+                }
+            }
+            for (bits, n) in rows {
+                let ideal = self.ideal.share(name, bits) * self.ideal.shots as f64;
+                out.push_str(&format!("{name},{bits},{n},{}\n", ideal.round()));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_program;
+
+    /// `ID $a` under `model`, measured into `c`.
+    fn idle(model: &str, shots: usize) -> NoisyRun {
+        let program =
+            parse_program("QAL & 0 $ \"a\"\nID $a\nBAL \"c\" 1\nMES $a \"c\" 0\n").unwrap();
+        run(&program, &[], shots, 0, &NoiseModel::parse(model).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn depolarizing_on_zero() {
+        // X and Y flip |0>, Z doesn't: |1> with probability 2p/3
+        let run = idle("[default]\ndepolarizing = 0.3\n", 20000);
+        assert!(
+            (run.state_fidelity - 0.8).abs() < 1e-12,
+            "{}",
+            run.state_fidelity
+        );
+        let ones = run.noisy.share("c", "1");
+        assert!((ones - 0.2).abs() < 0.01, "{ones}");
+    }
+
+    #[test]
+    fn readout_flips_the_bit() {
+        let run = idle("[qubit.a]\nreadout = 0.1\n", 20000);
+        assert!((run.state_fidelity - 1.0).abs() < 1e-12);
+        let ones = run.noisy.share("c", "1");
+        assert!((ones - 0.1).abs() < 0.01, "{ones}");
+    }
+
+    #[test]
+    fn channels_preserve_the_trace() {
+        let channels = Channels {
+            depolarizing: 0.2,
+            amplitude_damping: 0.3,
+            phase_damping: 0.4,
+            readout: 0.0,
+        };
+        for kraus in channels.kraus() {
+            // sum K^dagger K = 1
+            for i in 0..2 {
+                for j in 0..2 {
+                    let mut x = ZERO;
+                    for k in &kraus {
+                        x += k[0][i].conj() * k[0][j] + k[1][i].conj() * k[1][j];
+                    }
+                    let expected = if i == j { ONE } else { ZERO };
+                    assert!((x - expected).abs() < 1e-12, "{x:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn without_noise_it_is_the_ideal_run() {
+        let program = parse_program(
+            "QAL & 0 $ \"a\"
+QAL & 0 $ \"b\"
+HAD $a
+CNT $a $b
+RY $b 0.5
+BAL \"c\" 2
+MES $a \"c\" 0
+MES $b \"c\" 1
+",
+        )
+        .unwrap();
+        let run = run(&program, &[], 500, 7, &NoiseModel::default()).unwrap();
+        assert!((run.state_fidelity - 1.0).abs() < 1e-12);
+        assert_eq!(run.noisy.registers, run.ideal.registers);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let e = NoiseModel::parse("[gate.FOO]\n").unwrap_err();
+        assert_eq!(e, "NOISE_ERROR: line 1: unknown gate 'FOO'");
+        let e = NoiseModel::parse("[default]\ndepolarizing = 1.5\n").unwrap_err();
+        assert_eq!(
+            e,
+            "NOISE_ERROR: line 2: depolarizing = 1.5 is not a probability"
+        );
+    }
+}
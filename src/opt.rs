@@ -0,0 +1,368 @@
+use std::f64::consts::PI;
+use std::fmt;
+
+use crate::ir::{Instruction, Operand, Program};
+
+/// One rewrite the optimizer applied, kept so `--print-rewrites` can show what happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rewrite {
+    pub rule: &'static str,
+    pub before: Vec<Instruction>,
+    pub after: Option<Instruction>,
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let before: Vec<String> = self.before.iter().map(|i| i.to_string()).collect();
+        write!(f, "{:<14} {}", self.rule, before.join("; "))?;
+        match &self.after {
+            Some(a) => write!(f, " => {a}"),
+            None => write!(f, " => (removed)"),
+        }
+    }
+}
+
+/// How a gate acts on one of its qubits. Two gates commute if they agree on every
+/// qubit they share and neither of them is `Other` there.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Axis {
+    Z,
+    X,
+    Other,
+}
+
+fn is_diagonal(op: &str) -> bool {
+    ["PZ", "S", "SDG", "T", "TDG", "RZ"].contains(&op)
+}
+
+fn axis(ins: &Instruction, q: &str) -> Axis {
+    let qubits = ins.qubits();
+    let last = qubits.len().saturating_sub(1);
+    match ins.op.as_str() {
+        op if is_diagonal(op) => Axis::Z,
+        "PX" | "RX" => Axis::X,
+        "CNT" | "TOF" if qubits.len() >= 2 => {
+            if qubits[last] == q {
+                Axis::X
+            } else {
+                Axis::Z
+            }
+        }
+        "CY" if qubits.len() == 2 && qubits[0] == q => Axis::Z,
+        _ => Axis::Other,
+    }
+}
+
+/// Registers an instruction reads or writes, including the name an allocation introduces.
+fn registers(ins: &Instruction) -> Vec<&str> {
+    let mut regs = ins.qubits();
+    if ins.op == "QAL" || ins.op == "FRE" {
+        regs.extend(ins.alloc_name());
+    }
+    regs
+}
+
+/// Qudit instructions act on whatever qubits were merged into the qudit at runtime,
+/// so the optimizer does not move anything across them.
+fn is_barrier(ins: &Instruction) -> bool {
+    !ins.qudits().is_empty()
+}
+
+fn commutes(a: &Instruction, b: &Instruction) -> bool {
+    if is_barrier(a) || is_barrier(b) {
+        return false;
+    }
+    let rb = registers(b);
+    registers(a)
+        .into_iter()
+        .filter(|q| rb.contains(q))
+        .all(|q| {
+            let x = axis(a, q);
+            x != Axis::Other && x == axis(b, q)
+        })
+}
+
+fn inverse(op: &str) -> Option<&'static str> {
+    Some(match op {
+        "HAD" => "HAD",
+        "PX" => "PX",
+        "PY" => "PY",
+        "PZ" => "PZ",
+        "CNT" => "CNT",
+        "CY" => "CY",
+        "TOF" => "TOF",
+        "S" => "SDG",
+        "SDG" => "S",
+        "T" => "TDG",
+        "TDG" => "T",
+        _ => return None,
+    })
+}
+
+/// Two applications of a phase gate give the next coarser one.
+fn square(op: &str) -> Option<&'static str> {
+    Some(match op {
+        "T" => "S",
+        "TDG" => "SDG",
+        "S" | "SDG" => "PZ",
+        _ => return None,
+    })
+}
+
+fn angle(ins: &Instruction) -> Option<f64> {
+    match ins.args.get(1) {
+        Some(Operand::Num(n)) => Some(*n),
+        _ => None,
+    }
+}
+
+/// A rotation by a multiple of 2pi is the identity up to global phase.
+fn is_full_turn(a: f64) -> bool {
+    let r = a.rem_euclid(2.0 * PI);
+    r < 1e-12 || 2.0 * PI - r < 1e-12
+}
+
+fn is_rotation(op: &str) -> bool {
+    ["RX", "RY", "RZ"].contains(&op)
+}
+
+/// The result of fusing `a` with a later `b` on the same registers: `after` is `None` if
+/// the pair cancels and the single replacement gate otherwise.
+fn fuse(a: &Instruction, b: &Instruction) -> Option<(&'static str, Option<Instruction>)> {
+    if is_rotation(&a.op) && a.op == b.op && a.args.len() == 2 && a.args[0] == b.args[0] {
+        let sum = angle(a)? + angle(b)?;
+        if is_full_turn(sum) {
+            return Some(("merge-rotation", None));
+        }
+        let merged = Instruction::new(&a.op, vec![a.args[0].clone(), Operand::Num(sum)]);
+        return Some(("merge-rotation", Some(merged)));
+    }
+    if a.args != b.args {
+        return None;
+    }
+    if inverse(&a.op) == Some(b.op.as_str()) {
+        return Some(("cancel", None));
+    }
+    match square(&a.op) {
+        Some(op) if a.op == b.op => {
+            Some(("merge-phase", Some(Instruction::new(op, a.args.clone()))))
+        }
+        _ => None,
+    }
+}
+
+struct Pass {
+    slots: Vec<Option<Instruction>>,
+    level: u8,
+    rewrites: Vec<Rewrite>,
+}
+
+impl Pass {
+    /// Rules that only look at a single instruction.
+    fn simplify(&mut self, i: usize) -> bool {
+        let ins = self.slots[i].as_ref().unwrap();
+        let trivial = match ins.op.as_str() {
+            "ID" => true,
+            op if is_rotation(op) => angle(ins).is_some_and(is_full_turn),
+            "SET" => {
+                ins.args.get(1) == Some(&Operand::Num(1.0))
+                    && ins.args.get(2) == Some(&Operand::Num(0.0))
+                    && self.previous_is_alloc(i)
+            }
+            _ => false,
+        };
+        if trivial {
+            let rule = if ins.op == "SET" {
+                "drop-set-zero"
+            } else {
+                "drop-identity"
+            };
+            let before = vec![self.slots[i].take().unwrap()];
+            self.rewrites.push(Rewrite {
+                rule,
+                before,
+                after: None,
+            });
+        }
+        trivial
+    }
+
+    /// A `SET $q 1 0` directly after the `QAL` of `q` does nothing, fresh qubits start in |0>.
+    fn previous_is_alloc(&self, i: usize) -> bool {
+        let q = match self.slots[i].as_ref().unwrap().args.first() {
+            Some(Operand::Qubit(q)) => q.as_str(),
+            _ => return false,
+        };
+        for prev in self.slots[..i].iter().rev().flatten() {
+            if registers(prev).contains(&q) {
+                return prev.op == "QAL";
+            }
+        }
+        false
+    }
+
+    /// Looks for the next instruction that `i` can be fused with, walking past
+    /// instructions on other registers and, at -O2, past ones that commute with it.
+    fn partner(&self, i: usize) -> Option<usize> {
+        let ins = self.slots[i].as_ref().unwrap();
+        if is_barrier(ins) || inverse(&ins.op).is_none() && !is_rotation(&ins.op) {
+            return None;
+        }
+        let regs = registers(ins);
+        for (j, other) in self.slots.iter().enumerate().skip(i + 1) {
+            let Some(other) = other else {
+                continue;
+            };
+            if is_barrier(other) {
+                return None;
+            }
+            if !registers(other).iter().any(|q| regs.contains(q)) {
+                continue;
+            }
+            if fuse(ins, other).is_some() {
+                return Some(j);
+            }
+            if self.level < 2 || !commutes(ins, other) {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn fuse(&mut self, i: usize) -> bool {
+        let Some(j) = self.partner(i) else {
+            return false;
+        };
+        let a = self.slots[i].take().unwrap();
+        let b = self.slots[j].take().unwrap();
+        let (rule, after) = fuse(&a, &b).unwrap();
+        self.slots[j] = after.clone();
+        self.rewrites.push(Rewrite {
+            rule,
+            before: vec![a, b],
+            after,
+        });
+        true
+    }
+
+    fn run(&mut self) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in 0..self.slots.len() {
+                if self.slots[i].is_some() && (self.simplify(i) || self.fuse(i)) {
+                    changed = true;
+                }
+            }
+        }
+    }
+}
+
+/// Runs the peephole rules selected by `level` until nothing changes any more.
+///
+/// -O0 leaves the program alone, -O1 cancels and merges gates that follow each other
+/// on the same register, -O2 additionally moves gates through commuting neighbours
+/// (phase gates through CNOT controls, X rotations through CNOT targets) to find partners.
+pub fn optimize(program: &Program, level: u8) -> (Program, Vec<Rewrite>) {
+    if level == 0 {
+        return (program.clone(), vec![]);
+    }
+    let mut pass = Pass {
+        slots: program.instructions.iter().cloned().map(Some).collect(),
+        level,
+        rewrites: vec![],
+    };
+    pass.run();
+    let instructions = pass.slots.into_iter().flatten().collect();
+    (Program { instructions }, pass.rewrites)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_program;
+
+    /// The optimized program as text and the rules that fired, in order.
+    fn run(text: &str, level: u8) -> (String, Vec<&'static str>) {
+        let (program, rewrites) = optimize(&parse_program(text).unwrap(), level);
+        (
+            program.to_string(),
+            rewrites.iter().map(|r| r.rule).collect(),
+        )
+    }
+
+    const ALLOC: &str = "QAL & 0 $ \"a\"\nQAL & 0 $ \"b\"\n";
+
+    #[test]
+    fn cancel() {
+        let (out, rules) = run(&format!("{ALLOC}HAD $a\nPX $b\nHAD $a\nS $b\nSDG $b\n"), 1);
+        assert_eq!(out, format!("{ALLOC}PX $b\n"));
+        assert_eq!(rules, ["cancel", "cancel"]);
+    }
+
+    #[test]
+    fn merge_phase() {
+        let (out, rules) = run(&format!("{ALLOC}T $a\nT $a\nS $b\nS $b\n"), 1);
+        assert_eq!(out, format!("{ALLOC}S $a\nPZ $b\n"));
+        assert_eq!(rules, ["merge-phase", "merge-phase"]);
+    }
+
+    #[test]
+    fn merge_rotation() {
+        let (out, rules) = run(
+            &format!("{ALLOC}RZ $a 0.25\nRZ $a 0.5\nRX $b 0.5\nRY $b 1\n"),
+            1,
+        );
+        assert_eq!(out, format!("{ALLOC}RZ $a 0.75\nRX $b 0.5\nRY $b 1\n"));
+        assert_eq!(rules, ["merge-rotation"]);
+        let turn = format!("{ALLOC}RX $a {PI}\nRX $a {PI}\n");
+        assert_eq!(run(&turn, 1), (ALLOC.to_string(), vec!["merge-rotation"]));
+    }
+
+    #[test]
+    fn drop_identity() {
+        let text = format!("{ALLOC}ID $a\nRZ $b {}\nRZ $b 0.5\n", 2.0 * PI);
+        let (out, rules) = run(&text, 1);
+        assert_eq!(out, format!("{ALLOC}RZ $b 0.5\n"));
+        assert_eq!(rules, ["drop-identity", "drop-identity"]);
+    }
+
+    #[test]
+    fn drop_set_zero() {
+        let (out, rules) = run(&format!("{ALLOC}SET $a 1 0\nHAD $b\nSET $b 1 0\n"), 1);
+        // `b` was changed since its allocation, resetting it is not a no-op
+        assert_eq!(out, format!("{ALLOC}HAD $b\nSET $b 1 0\n"));
+        assert_eq!(rules, ["drop-set-zero"]);
+    }
+
+    #[test]
+    fn commute_through_cnot_at_o2() {
+        let control = format!("{ALLOC}T $a\nCNT $a $b\nTDG $a\n");
+        assert_eq!(run(&control, 1), (control.clone(), vec![]));
+        assert_eq!(
+            run(&control, 2),
+            (format!("{ALLOC}CNT $a $b\n"), vec!["cancel"])
+        );
+        let target = format!("{ALLOC}PX $b\nCNT $a $b\nPX $b\n");
+        assert_eq!(
+            run(&target, 2),
+            (format!("{ALLOC}CNT $a $b\n"), vec!["cancel"])
+        );
+        // HAD doesn't commute with either end of a CNOT
+        let blocked = format!("{ALLOC}HAD $a\nCNT $a $b\nHAD $a\n");
+        assert_eq!(run(&blocked, 2), (blocked.clone(), vec![]));
+    }
+
+    #[test]
+    fn qudit_barriers() {
+        let text =
+            format!("{ALLOC}DAL % # \"d\"\nHAD $a\nCMB $a $b %d\nHAD $a\nT $b\nDHAD %d\nTDG $b\n");
+        assert_eq!(run(&text, 2), (text.clone(), vec![]));
+    }
+
+    #[test]
+    fn o0_keeps_everything() {
+        let text = format!("{ALLOC}HAD $a\nHAD $a\nID $b\n");
+        assert_eq!(run(&text, 0), (text.clone(), vec![]));
+    }
+}
@@ -1,6 +1,6 @@
 use std::iter::Peekable;
 
-use crate::tokenizer::Token;
+use crate::tokenizer::{filter_all, tokenize_code, Token, RESERVED};
 
 #[derive(Debug, Clone)]
 pub enum ASTNode {
@@ -50,8 +50,10 @@ pub enum ASTNode {
     // Break, that's not implemented yet either, I have not found the need for it yet
     Void,
     Qbit,
+    Bit, // classical bit, written by MES
     Custom,
     Num(i32),
+    Angle(f64), // rotation angle in radians, written with a decimal point
     Type {
         name: String,
         specifier: Box<ASTNode>,
@@ -59,6 +61,19 @@ pub enum ASTNode {
     Range {
         start: Box<ASTNode>,
         end: Box<ASTNode>,
+        step: Option<Box<ASTNode>>, // `by n`, counts towards `end` either way
+        inclusive: bool,            // `..=`
+    },
+    // `+`, `-` or `*` between numbers and loop indices, evaluated at compile time
+    BinOp {
+        op: char,
+        lhs: Box<ASTNode>,
+        rhs: Box<ASTNode>,
+    },
+    // `a ++ b`, the qubits of both arrays one after the other, without copying them
+    Concat {
+        lhs: Box<ASTNode>,
+        rhs: Box<ASTNode>,
     },
     VariableCall {
         name: String,
@@ -81,11 +96,27 @@ pub enum ASTNode {
     IntCall {
         name: String,
     },
+    // `asm { ... }`, backend instructions with their operands, see `parse_asm_`
+    Asm(Vec<(String, Vec<ASTNode>)>),
+    // an operand of an `asm` instruction written the backend way, `$q`, `"r"`, `&` or `0.5`
+    AsmOperand(String),
     // PointerType, not yet implemented
     PointerType {
         type_: Box<ASTNode>,
     },
     Qdit,
+    // `ctrl(n)`, `inv` or `pow(n)` in front of a gate or function call
+    Modified {
+        modifier: Modifier,
+        call: Box<ASTNode>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Modifier {
+    Ctrl(u32),
+    Inv,
+    Pow(u32),
 }
 
 #[derive(Debug)]
@@ -123,6 +154,17 @@ pub enum Tok {
     Old,
     Num,
     Qudit,
+    Ctrl,
+    Inv,
+    Pow,
+    Bit,
+    DotDotEq,
+    By,
+    Plus,
+    Minus,
+    Concat,
+    Asm,
+    Raw, // body of an `asm` block
 }
 
 pub fn from_tokens(tokens: Vec<Token>) -> Vec<Tok> {
@@ -159,10 +201,22 @@ pub fn from_tokens(tokens: Vec<Token>) -> Vec<Tok> {
             44 => toks.push(Tok::Break),
             45 => toks.push(Tok::PHPRef),
             46 => toks.push(Tok::Qudit),
-            47..=69 => toks.push(Tok::GateCall),
+            47..=54 => toks.push(Tok::GateCall),
+            55 => toks.push(Tok::Ctrl),
+            56 => toks.push(Tok::Inv),
+            57 => toks.push(Tok::Pow),
+            58 => toks.push(Tok::Bit),
+            59 => toks.push(Tok::DotDotEq),
+            60 => toks.push(Tok::By),
+            61 => toks.push(Tok::Plus),
+            62 => toks.push(Tok::Minus),
+            63 => toks.push(Tok::Concat),
+            64 => toks.push(Tok::Asm),
+            65..=69 => toks.push(Tok::GateCall),
             70 => toks.push(Tok::New),
             71 => toks.push(Tok::Old),
             72 => toks.push(Tok::Num),
+            73 => toks.push(Tok::Raw),
             _ => panic!("I don't know how this would even ever happen"),
         }
     }
@@ -202,11 +256,14 @@ where
         Some(Tok::Qbit) => parse_function_def_(tokens, tokens2),
         Some(Tok::Void) => parse_function_def_(tokens, tokens2),
         Some(Tok::Qudit) => parse_function_def_(tokens, tokens2),
+        Some(Tok::Bit) => parse_function_def_(tokens, tokens2),
         //Some(Tok::If) => parse_if(tokens, tokens2),
         Some(Tok::For) => parse_for_(tokens, tokens2),
         Some(Tok::VarDecl) => parse_var_decl(tokens, tokens2),
         Some(Tok::Struct) => parse_struct_def_(tokens, tokens2),
         Some(Tok::GateCall) => parse_gate_call_(tokens, tokens2),
+        Some(Tok::Asm) => parse_asm_(tokens, tokens2),
+        Some(Tok::Ctrl) | Some(Tok::Inv) | Some(Tok::Pow) => parse_modified_(tokens, tokens2),
         Some(Tok::ConstDecl) => parse_var_decl(tokens, tokens2), // disambiguity ends here, now it
         Some(Tok::Return) => {
             advance(tokens, tokens2);
@@ -217,13 +274,36 @@ where
         Some(Tok::Star) => parse_any_2(tokens, tokens2),
         Some(Tok::Reference) => parse_any_3(tokens, tokens2),
         Some(Tok::Num) => {
-            let num = tokens2.peek().unwrap().value.parse::<i32>().unwrap();
+            let value = tokens2.peek().unwrap().value.clone();
             advance(tokens, tokens2);
+            let Ok(num) = value.parse::<i32>() else {
+                return match value.parse::<f64>() {
+                    Ok(angle) => Ok(Some(ASTNode::Angle(angle))),
+                    Err(_) => Err(format!("Expected number, got {value}")),
+                };
+            };
             match tokens.peek() {
-                Some(Tok::DotDot) => parse_range_(tokens, tokens2, num),
+                Some(Tok::DotDot) | Some(Tok::DotDotEq) => {
+                    parse_range_(tokens, tokens2, ASTNode::Num(num)).map(Some)
+                }
                 _ => Ok(Some(ASTNode::Num(num))),
             }
         }
+        // a negative literal, like the angle of `RZ(q, -0.25)`
+        Some(Tok::Minus) => {
+            advance(tokens, tokens2);
+            if !matches!(tokens.peek(), Some(Tok::Num)) {
+                return Err(format!(
+                    "Expected number after '-', got {:?}",
+                    tokens.peek()
+                ));
+            }
+            match parse_statement(tokens, tokens2)? {
+                Some(ASTNode::Num(num)) => Ok(Some(ASTNode::Num(-num))),
+                Some(ASTNode::Angle(angle)) => Ok(Some(ASTNode::Angle(-angle))),
+                other => Err(format!("Expected number after '-', got {other:?}")),
+            }
+        }
         Some(Tok::CBracket) => {
             advance(tokens, tokens2);
             if tokens.peek().is_some() {
@@ -321,6 +401,15 @@ where
             Some(Tok::CBracket) => {
                 return Ok(current_cpy);
             }
+            Some(Tok::DoublePoint) => {
+                let name = parse_qualified(tokens, tokens2, first)?;
+                match tokens.peek() {
+                    Some(Tok::OBracket) => {
+                        current_cpy = parse_function_call_(tokens, tokens2, name)?
+                    }
+                    other => return Err(format!("Expected '(' after '{name}', got {other:?}")),
+                }
+            }
             Some(Tok::Comma) => return Ok(current_cpy),
             Some(Tok::OBracket) => current_cpy = parse_function_call_(tokens, tokens2, first)?,
             Some(Tok::Equal) => {
@@ -332,6 +421,7 @@ where
             Some(Tok::OSBracket) => {
                 current_cpy = parse_array_access(tokens, tokens2, current_cpy.unwrap())?
             }
+            Some(Tok::Concat) => return parse_concat_(tokens, tokens2, current_cpy.unwrap()),
             Some(Tok::Semicolon) => return Ok(current_cpy),
             Some(Tok::PHPRef) => {
                 advance(tokens, tokens2);
@@ -378,6 +468,7 @@ where
             Some(Tok::OSBracket) => {
                 current_cpy = parse_array_access(tokens, tokens2, current_cpy.unwrap())?
             }
+            Some(Tok::Concat) => return parse_concat_(tokens, tokens2, current_cpy.unwrap()),
             Some(Tok::Semicolon) => return Ok(current),
             Some(Tok::PHPRef) => {
                 advance(tokens, tokens2);
@@ -413,29 +504,132 @@ where
 fn parse_range_<I, I2>(
     tokens: &mut Peekable<I>,
     tokens2: &mut Peekable<I2>,
-    num: i32,
-) -> Result<Option<ASTNode>, String>
+    start: ASTNode,
+) -> Result<ASTNode, String>
 where
     I: Iterator<Item = Tok>,    // Expecting an iterator of owned `Tok` instances
     I2: Iterator<Item = Token>, // Expecting an iterator of owned `Tok` instances
 {
-    match tokens.peek() {
+    let inclusive = match tokens.peek() {
         None => return Err("Error: Expected '..', got None".to_string()),
-        Some(Tok::DotDot) => advance(tokens, tokens2),
+        Some(Tok::DotDot) => false,
+        Some(Tok::DotDotEq) => true,
         Some(other) => return Err(format!("Expected '..', got {other:?}")),
+    };
+    advance(tokens, tokens2);
+    let end = parse_bound_(tokens, tokens2)?;
+    let step = match tokens.peek() {
+        Some(Tok::By) => {
+            advance(tokens, tokens2);
+            Some(Box::new(parse_bound_(tokens, tokens2)?))
+        }
+        _ => None,
+    };
+    Ok(ASTNode::Range {
+        start: Box::new(start),
+        end: Box::new(end),
+        step,
+        inclusive,
+    })
+}
+
+// a range bound or step, numbers and loop indices joined by +, - and *
+fn parse_bound_<I, I2>(
+    tokens: &mut Peekable<I>,
+    tokens2: &mut Peekable<I2>,
+) -> Result<ASTNode, String>
+where
+    I: Iterator<Item = Tok>,    // Expecting an iterator of owned `Tok` instances
+    I2: Iterator<Item = Token>, // Expecting an iterator of owned `Tok` instances
+{
+    let mut lhs = parse_factor_(tokens, tokens2)?;
+    loop {
+        let op = match tokens.peek() {
+            Some(Tok::Plus) => '+',
+            Some(Tok::Minus) => '-',
+            _ => return Ok(lhs),
+        };
+        advance(tokens, tokens2);
+        lhs = ASTNode::BinOp {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(parse_factor_(tokens, tokens2)?),
+        };
+    }
+}
+
+fn parse_factor_<I, I2>(
+    tokens: &mut Peekable<I>,
+    tokens2: &mut Peekable<I2>,
+) -> Result<ASTNode, String>
+where
+    I: Iterator<Item = Tok>,    // Expecting an iterator of owned `Tok` instances
+    I2: Iterator<Item = Token>, // Expecting an iterator of owned `Tok` instances
+{
+    let mut lhs = parse_operand_(tokens, tokens2)?;
+    while let Some(Tok::Star) = tokens.peek() {
+        advance(tokens, tokens2);
+        lhs = ASTNode::BinOp {
+            op: '*',
+            lhs: Box::new(lhs),
+            rhs: Box::new(parse_operand_(tokens, tokens2)?),
+        };
     }
-    let second_num = tokens2
-        .peek()
-        .unwrap()
-        .value
-        .clone()
-        .parse::<i32>()
-        .unwrap();
+    Ok(lhs)
+}
+
+fn parse_operand_<I, I2>(
+    tokens: &mut Peekable<I>,
+    tokens2: &mut Peekable<I2>,
+) -> Result<ASTNode, String>
+where
+    I: Iterator<Item = Tok>,    // Expecting an iterator of owned `Tok` instances
+    I2: Iterator<Item = Token>, // Expecting an iterator of owned `Tok` instances
+{
+    let value = tokens2.peek().map(|t| t.value.clone()).unwrap_or_default();
+    let operand = match tokens.peek() {
+        Some(Tok::Num) => ASTNode::Num(
+            value
+                .parse::<i32>()
+                .map_err(|_| format!("Expected whole number, got {value}"))?,
+        ),
+        Some(Tok::Old) | Some(Tok::New) => ASTNode::VariableCall { name: value },
+        Some(Tok::OBracket) => {
+            advance(tokens, tokens2);
+            let inner = parse_bound_(tokens, tokens2)?;
+            return match tokens.peek() {
+                Some(Tok::CBracket) => {
+                    advance(tokens, tokens2);
+                    Ok(inner)
+                }
+                other => Err(format!("Expected ), got {other:?}")),
+            };
+        }
+        other => return Err(format!("Expected number or loop index, got {other:?}")),
+    };
     advance(tokens, tokens2);
-    Ok(Some(ASTNode::Range {
-        start: Box::new(ASTNode::Num(num)),
-        end: Box::new(ASTNode::Num(second_num)),
-    }))
+    Ok(operand)
+}
+
+// what a for loop walks through: a range, or an array, slice `reg[a..b]` or concatenation
+fn parse_container_<I, I2>(
+    tokens: &mut Peekable<I>,
+    tokens2: &mut Peekable<I2>,
+) -> Result<ASTNode, String>
+where
+    I: Iterator<Item = Tok>,    // Expecting an iterator of owned `Tok` instances
+    I2: Iterator<Item = Token>, // Expecting an iterator of owned `Tok` instances
+{
+    let first = parse_bound_(tokens, tokens2)?;
+    match tokens.peek() {
+        Some(Tok::DotDot) | Some(Tok::DotDotEq) => parse_range_(tokens, tokens2, first),
+        Some(Tok::OSBracket) | Some(Tok::Concat)
+            if matches!(first, ASTNode::VariableCall { .. }) =>
+        {
+            Ok(parse_any_(tokens, tokens2, Some(first))?.unwrap())
+        }
+        _ => Ok(first),
+    }
 }
 fn parse_assignment_<I, I2>(
     tokens: &mut Peekable<I>,
@@ -496,16 +690,24 @@ where
     advance(tokens, tokens2);
 
     match tokens.peek() {
-        Some(Tok::Num) => {
-            let n: u32 = tokens2.peek().unwrap().clone().value.parse().unwrap();
-            advance(tokens, tokens2);
+        // an index, `reg[i + 1]`, or a slice, `reg[a..b]`
+        Some(Tok::Num) | Some(Tok::Old) | Some(Tok::OBracket) => {
+            let first = parse_bound_(tokens, tokens2)?;
+            let index = match tokens.peek() {
+                Some(Tok::DotDot) | Some(Tok::DotDotEq) => parse_range_(tokens, tokens2, first)?,
+                _ => match first {
+                    ASTNode::Num(n) => ASTNode::ArrayIndex(n as u32),
+                    ASTNode::VariableCall { name } => ASTNode::IntCall { name },
+                    other => other,
+                },
+            };
             match tokens.peek() {
                 None => Err("Expected ], got None".to_string()),
                 Some(Tok::CSBracket) => {
                     advance(tokens, tokens2);
                     Ok(Some(ASTNode::ArrayAccess {
                         name: Box::new(prev),
-                        index: Box::new(ASTNode::ArrayIndex(n)),
+                        index: Box::new(index),
                     }))
                 }
                 Some(_) => Err(format!(
@@ -564,22 +766,6 @@ where
                 )),
             }
         }
-        Some(Tok::Old) => {
-            let name = tokens2.peek().unwrap().value.clone();
-            advance(tokens, tokens2);
-            if let Some(Tok::CSBracket) = tokens.peek() {
-                advance(tokens, tokens2);
-                Ok(Some(ASTNode::ArrayAccess {
-                    name: Box::new(prev),
-                    index: Box::new(ASTNode::IntCall { name }),
-                }))
-            } else {
-                Err(format!(
-                    "expected ], got {}",
-                    tokens2.peek().unwrap().value.clone()
-                ))
-            }
-        }
         _ => Err(format!(
             "Expected literal or iterator varible, got {}",
             tokens2
@@ -591,6 +777,27 @@ where
     }
 }
 
+// `lhs ++ rhs`, the right side can be another concatenation
+fn parse_concat_<I, I2>(
+    tokens: &mut Peekable<I>,
+    tokens2: &mut Peekable<I2>,
+    lhs: ASTNode,
+) -> Result<Option<ASTNode>, String>
+where
+    I: Iterator<Item = Tok>,    // Expecting an iterator of owned `Tok` instances
+    I2: Iterator<Item = Token>, // Expecting an iterator of owned `Tok` instances
+{
+    advance(tokens, tokens2);
+    let rhs = match tokens.peek() {
+        Some(Tok::Old) | Some(Tok::New) => parse_any_(tokens, tokens2, None)?,
+        other => return Err(format!("Expected array after '++', got {other:?}")),
+    };
+    Ok(Some(ASTNode::Concat {
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs.ok_or("Expected array after '++', got None")?),
+    }))
+}
+
 fn parse_function_call_<I, I2>(
     tokens: &mut Peekable<I>,
     tokens2: &mut Peekable<I2>,
@@ -659,7 +866,9 @@ where
 {
     let mut arguments: Vec<ASTNode> = vec![];
     loop {
-        arguments.push(parse_statement(tokens, tokens2).unwrap().unwrap());
+        arguments.push(
+            parse_statement(tokens, tokens2)?.ok_or("Expected argument, got None".to_string())?,
+        );
         match tokens.peek() {
             None => return Err("Expected ',' or ')', got None".to_string()),
             Some(Tok::Comma) => advance(tokens, tokens2),
@@ -670,6 +879,113 @@ where
     Ok(arguments)
 }
 
+fn parse_modifier_count<I, I2>(
+    tokens: &mut Peekable<I>,
+    tokens2: &mut Peekable<I2>,
+) -> Result<u32, String>
+where
+    I: Iterator<Item = Tok>,    // Expecting an iterator of owned `Tok` instances
+    I2: Iterator<Item = Token>, // Expecting an iterator of owned `Tok` instances
+{
+    match tokens.peek() {
+        Some(Tok::OBracket) => advance(tokens, tokens2),
+        other => return Err(format!("Expected '(' after modifier, got {other:?}")),
+    }
+    let n = match tokens.peek() {
+        Some(Tok::Num) => tokens2
+            .peek()
+            .unwrap()
+            .value
+            .parse::<u32>()
+            .map_err(|e| e.to_string())?,
+        other => return Err(format!("Expected number, got {other:?}")),
+    };
+    advance(tokens, tokens2);
+    match tokens.peek() {
+        Some(Tok::CBracket) => advance(tokens, tokens2),
+        other => return Err(format!("Expected ')', got {other:?}")),
+    }
+    Ok(n)
+}
+
+// ctrl(n) / inv / pow(n), followed by another modifier, a gate or a function call
+fn parse_modified_<I, I2>(
+    tokens: &mut Peekable<I>,
+    tokens2: &mut Peekable<I2>,
+) -> Result<Option<ASTNode>, String>
+where
+    I: Iterator<Item = Tok>,    // Expecting an iterator of owned `Tok` instances
+    I2: Iterator<Item = Token>, // Expecting an iterator of owned `Tok` instances
+{
+    let modifier = match tokens.peek() {
+        Some(Tok::Ctrl) => {
+            advance(tokens, tokens2);
+            Modifier::Ctrl(parse_modifier_count(tokens, tokens2)?)
+        }
+        Some(Tok::Pow) => {
+            advance(tokens, tokens2);
+            Modifier::Pow(parse_modifier_count(tokens, tokens2)?)
+        }
+        Some(Tok::Inv) => {
+            advance(tokens, tokens2);
+            Modifier::Inv
+        }
+        other => return Err(format!("Expected modifier, got {other:?}")),
+    };
+    let call = match tokens.peek() {
+        Some(Tok::Ctrl) | Some(Tok::Inv) | Some(Tok::Pow) => parse_modified_(tokens, tokens2)?,
+        Some(Tok::GateCall) => parse_gate_call_(tokens, tokens2)?,
+        Some(Tok::Old) => {
+            let name = tokens2.peek().unwrap().value.clone();
+            advance(tokens, tokens2);
+            let name = parse_qualified(tokens, tokens2, name)?;
+            match tokens.peek() {
+                Some(Tok::OBracket) => parse_function_call_(tokens, tokens2, name)?,
+                other => return Err(format!("Expected '(' after '{name}', got {other:?}")),
+            }
+        }
+        other => {
+            return Err(format!(
+                "Expected gate or function call after modifier, got {other:?}"
+            ))
+        }
+    };
+    Ok(Some(ASTNode::Modified {
+        modifier,
+        call: Box::new(call.ok_or("Expected call after modifier, got None")?),
+    }))
+}
+
+// `module::function`, `module` has already been consumed
+fn parse_qualified<I, I2>(
+    tokens: &mut Peekable<I>,
+    tokens2: &mut Peekable<I2>,
+    module: String,
+) -> Result<String, String>
+where
+    I: Iterator<Item = Tok>,
+    I2: Iterator<Item = Token>,
+{
+    if !matches!(tokens.peek(), Some(Tok::DoublePoint)) {
+        return Ok(module);
+    }
+    advance(tokens, tokens2);
+    if !matches!(tokens.peek(), Some(Tok::DoublePoint)) {
+        return Err(format!("Expected '::' after '{module}'"));
+    }
+    advance(tokens, tokens2);
+    match tokens.peek() {
+        Some(Tok::New) | Some(Tok::Old) => {
+            let name = tokens2.peek().unwrap().value.clone();
+            advance(tokens, tokens2);
+            Ok(format!("{module}::{name}"))
+        }
+        other => Err(format!(
+            "Expected function name after '{module}::', got {other:?}"
+        )),
+    }
+}
+
 fn parse_gate_call_<I, I2>(
     tokens: &mut Peekable<I>,
     tokens2: &mut Peekable<I2>,
@@ -690,18 +1006,100 @@ where
         Some(Tok::OBracket) => advance(tokens, tokens2),
         Some(other) => return Err(format!("Expected '(', got {other:?}")),
     }
-    let args = parse_call_args(tokens, tokens2);
-    let mut arguments = Vec::new();
-    match args {
-        Err(e) => println!("Error: {e:?}"),
-        Ok(a) => arguments = a,
-    }
+    let arguments = parse_call_args(tokens, tokens2)?;
     Ok(Some(ASTNode::GateCall {
         name,
         args: arguments,
     }))
 }
 
+// `asm { OP a b; ... }`, one instruction per line or `;`. Operands are separated by spaces
+// or commas, operands in the backend syntax are kept as written and the others are QEL
+// names like `q`, `reg[i+1]` or a loop index that code generation resolves
+fn parse_asm_<I, I2>(
+    tokens: &mut Peekable<I>,
+    tokens2: &mut Peekable<I2>,
+) -> Result<Option<ASTNode>, String>
+where
+    I: Iterator<Item = Tok>,    // Expecting an iterator of owned `Tok` instances
+    I2: Iterator<Item = Token>, // Expecting an iterator of owned `Tok` instances
+{
+    advance(tokens, tokens2);
+    match tokens.peek() {
+        Some(Tok::OCBracket) => advance(tokens, tokens2),
+        other => return Err(format!("Expected '{{' after asm, got {other:?}")),
+    }
+    let body = match tokens.peek() {
+        Some(Tok::Raw) => tokens2.peek().unwrap().value.clone(),
+        other => return Err(format!("Expected asm instructions, got {other:?}")),
+    };
+    advance(tokens, tokens2);
+    match tokens.peek() {
+        Some(Tok::CCBracket) => advance(tokens, tokens2),
+        other => return Err(format!("Expected '}}' after asm block, got {other:?}")),
+    }
+    let mut instructions = vec![];
+    for line in body.split(['\n', ';']) {
+        let line = line.trim();
+        let (op, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        if op.is_empty() {
+            continue;
+        }
+        let operands = asm_operands(rest)
+            .into_iter()
+            .map(parse_asm_operand)
+            .collect::<Result<Vec<_>, String>>()?;
+        instructions.push((op.to_string(), operands));
+    }
+    Ok(Some(ASTNode::Asm(instructions)))
+}
+
+// splits at spaces and commas outside of brackets, `reg[i + 1]` is one operand
+fn asm_operands(text: &str) -> Vec<&str> {
+    let mut operands = vec![];
+    let (mut depth, mut start) = (0, 0);
+    for (k, c) in text.char_indices() {
+        match c {
+            '[' | '(' => depth += 1,
+            ']' | ')' => depth -= 1,
+            c if depth == 0 && (c.is_whitespace() || c == ',') => {
+                operands.push(&text[start..k]);
+                start = k + 1;
+            }
+            _ => {}
+        }
+    }
+    operands.push(&text[start..]);
+    operands.into_iter().filter(|o| !o.is_empty()).collect()
+}
+
+fn parse_asm_operand(text: &str) -> Result<ASTNode, String> {
+    let qel = text.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && text
+            .chars()
+            .all(|c| c.is_alphanumeric() || c.is_whitespace() || "_[]()+-*.=".contains(c));
+    if !qel {
+        return Ok(ASTNode::AsmOperand(text.to_string()));
+    }
+    // every name in an instruction refers to something declared before
+    let mut toks = filter_all(tokenize_code(&format!("{text}; ")));
+    for t in toks.iter_mut().filter(|t| t.token == 70) {
+        t.token = 71;
+    }
+    let mut tokens2 = toks.clone().into_iter().peekable();
+    let mut tokens = from_tokens(toks).into_iter().peekable();
+    let node = parse_any_(&mut tokens, &mut tokens2, None)?;
+    match (node, tokens.peek()) {
+        (
+            Some(node @ (ASTNode::VariableCall { .. } | ASTNode::ArrayAccess { .. })),
+            Some(Tok::Semicolon),
+        ) => Ok(node),
+        _ => Err(format!(
+            "Expected register, array element or loop index in asm, got '{text}'"
+        )),
+    }
+}
+
 fn parse_var_decl<I, I2>(
     tokens: &mut Peekable<I>,
     tokens2: &mut Peekable<I2>,
@@ -712,12 +1110,7 @@ where
 {
     let tok = tokens2.peek().unwrap().token;
     advance(tokens, tokens2);
-    let name_ = parse_name(tokens, tokens2);
-    let mut name = String::new();
-    match name_ {
-        Ok(v) => name = v,
-        Err(e) => println!("Error: {e:?}"),
-    }
+    let name = parse_name(tokens, tokens2)?;
     match tokens.peek() {
         None => return Err("Expected ':', got None".to_string()),
         Some(Tok::DoublePoint) => advance(tokens, tokens2),
@@ -736,7 +1129,7 @@ where
             match rval {
                 Ok(Some(v)) => rval_ = v,
                 Ok(None) => return Err("Expected rval expression, got None".to_string()),
-                Err(e) => println!("Error: {e:?}"),
+                Err(e) => eprintln!("Error: {e:?}"),
             }
 
             match tokens.peek() {
@@ -764,12 +1157,8 @@ where
         Some(other) => return Err(format!("Expected ':', got {:?}", other)),
     }
 
-    let type_ = parse_type_(tokens, tokens2);
-    let mut type__ = ASTNode::Void;
-    match type_ {
-        Ok(v) => type__ = v.expect("Error: didn't find valid type in function declaration"),
-        Err(e) => println!("Error: {e:?}"),
-    }
+    let type__ = parse_type_(tokens, tokens2)?
+        .ok_or("Error: didn't find valid type in variable declaration".to_string())?;
 
     match tokens.peek() {
         None => return Err("Expected ';' or '=', got None".to_string()),
@@ -790,11 +1179,21 @@ where
     match rval {
         Ok(Some(v)) => rval_ = v,
         Ok(None) => return Err("Expected rval expression, got None".to_string()),
-        Err(e) => println!("Error: {e:?}"),
+        Err(e) => eprintln!("Error: {e:?}"),
     }
 
     match tokens.peek() {
         None => return Err("Expected ';', got None".to_string()),
+        // gate calls leave their ')' behind
+        Some(Tok::CBracket) if matches!(rval_, ASTNode::GateCall { .. }) => {
+            advance(tokens, tokens2);
+            Ok(Some(ASTNode::VariableDecl {
+                name,
+                value: Some(Box::new(rval_)),
+                type_: Some(Box::new(type__)),
+                token: tok,
+            }))
+        }
         Some(Tok::Semicolon) => {
             return Ok(Some(ASTNode::VariableDecl {
                 name,
@@ -847,12 +1246,7 @@ where
     I2: Iterator<Item = Token>, // Expecting an iterator of owned `Tok` instances
 {
     advance(tokens, tokens2);
-    let name_ = parse_name(tokens, tokens2);
-    let mut name = String::new();
-    match name_ {
-        Ok(v) => name = v,
-        Err(e) => println!("1__Error: {e:?}"),
-    }
+    let name = parse_name(tokens, tokens2)?;
 
     match tokens.peek() {
         None => return Err("Expected {{, got None".to_string()),
@@ -865,7 +1259,7 @@ where
     let mut mems2 = vec![];
     match mems {
         Ok(ref a) => mems2 = a.to_vec(),
-        Err(ref e) => println!("Error: {e:?}"),
+        Err(ref e) => eprintln!("Error: {e:?}"),
     }
     Ok(Some(ASTNode::Struct { name, types: mems2 }))
 }
@@ -884,12 +1278,15 @@ where
     } else {
         return Err("Expected (".to_string());
     }
-    let name_ = parse_name(tokens, tokens2);
-    let mut name = String::new();
-    match name_ {
-        Ok(v) => name = v,
-        Err(e) => println!("Error: {e:?}"),
-    }
+    // a loop variable may reuse the name of an earlier one, it has a scope of its own
+    let name = match tokens.peek() {
+        Some(Tok::Old) => {
+            let n = tokens2.peek().unwrap().value.clone();
+            advance(tokens, tokens2);
+            n
+        }
+        _ => parse_name(tokens, tokens2)?,
+    };
 
     match tokens.peek() {
         Some(Tok::In) => advance(tokens, tokens2),
@@ -897,13 +1294,7 @@ where
         None => return Err("Expected 'in', got None".to_string()),
     }
 
-    let container = parse_statement(tokens, tokens2);
-    let mut container_ = ASTNode::Void;
-    match container {
-        Ok(Some(v)) => container_ = v,
-        Ok(None) => return Err("Expected rval expression, got None".to_string()),
-        Err(e) => println!("Error: {e:?}"),
-    }
+    let container_ = parse_container_(tokens, tokens2)?;
 
     match tokens.peek() {
         None => return Err("Expected ), got None".to_string()),
@@ -922,7 +1313,7 @@ where
     match body_ {
         Ok(Some(v)) => body = Some(Box::new(v)),
         Ok(None) => body = None,
-        Err(e) => println!("Error: {e:?}"),
+        Err(e) => eprintln!("Error: {e:?}"),
     }
     advance(tokens, tokens2);
     Ok(Some(ASTNode::For {
@@ -944,20 +1335,10 @@ where
     let mut type__ = ASTNode::Void;
     match type_ {
         Ok(v) => type__ = v.expect("Error: didn't find valid type in function declaration"),
-        Err(e) => println!("Error: {e:?}"),
-    }
-    let name_ = parse_name(tokens, tokens2);
-    let mut name = String::new();
-    match name_ {
-        Ok(v) => name = v,
-        Err(e) => println!("Error: {e:?}"),
-    }
-    let arguments = parse_arguments_(tokens, tokens2);
-    let mut arguments_ = vec![];
-    match arguments {
-        Ok(o) => arguments_ = o,
-        Err(e) => println!("Error: {e:?}"),
+        Err(e) => eprintln!("Error: {e:?}"),
     }
+    let name = parse_name(tokens, tokens2)?;
+    let arguments_ = parse_arguments_(tokens, tokens2)?;
 
     match tokens.peek() {
         None => return Err("Expected '{', got None".to_string()),
@@ -970,7 +1351,7 @@ where
     match body_ {
         Ok(Some(v)) => body = Some(Box::new(v)),
         Ok(None) => body = None,
-        Err(e) => println!("Error: {e:?}"),
+        Err(e) => eprintln!("Error: {e:?}"),
     }
 
     advance(tokens, tokens2);
@@ -981,10 +1362,62 @@ where
         body,
     }))
 }
+// `qbit[4][3]` is an array of 4 `qbit[3]` rows, any number of dimensions
 fn parse_type_<I, I2>(
     tokens: &mut Peekable<I>,
     tokens2: &mut Peekable<I2>,
 ) -> Result<Option<ASTNode>, String>
+where
+    I: Iterator<Item = Tok>,    // Expecting an iterator of owned `Tok` instances
+    I2: Iterator<Item = Token>, // Expecting an iterator of owned `Tok` instances
+{
+    let qubits = matches!(tokens.peek(), Some(Tok::Qbit) | Some(Tok::Qudit));
+    let base = parse_base_type_(tokens, tokens2)?;
+    let mut dims = vec![];
+    while qubits && matches!(tokens.peek(), Some(Tok::OSBracket)) {
+        advance(tokens, tokens2);
+        match tokens.peek() {
+            Some(Tok::Num) => {
+                let value = tokens2.peek().unwrap().value.clone();
+                dims.push(
+                    value
+                        .parse::<i32>()
+                        .map_err(|_| format!("expected array size, got {value}"))?,
+                );
+                advance(tokens, tokens2);
+            }
+            other => return Err(format!("expected array size, got {other:?}")),
+        }
+        match tokens.peek() {
+            Some(Tok::CSBracket) => advance(tokens, tokens2),
+            other => return Err(format!("expected ], got {other:?}")),
+        }
+    }
+    if dims.is_empty() {
+        return Ok(base);
+    }
+    match base {
+        Some(ASTNode::ArrayType { type_, size }) => {
+            let mut inner = *type_;
+            for n in dims.into_iter().rev() {
+                inner = ASTNode::ArrayType {
+                    type_: Box::new(inner),
+                    size: Box::new(ASTNode::Num(n)),
+                };
+            }
+            Ok(Some(ASTNode::ArrayType {
+                type_: Box::new(inner),
+                size,
+            }))
+        }
+        other => Err(format!("expected array type, got {other:?}")),
+    }
+}
+
+fn parse_base_type_<I, I2>(
+    tokens: &mut Peekable<I>,
+    tokens2: &mut Peekable<I2>,
+) -> Result<Option<ASTNode>, String>
 where
     I: Iterator<Item = Tok>,    // Expecting an iterator of owned `Tok` instances
     I2: Iterator<Item = Token>, // Expecting an iterator of owned `Tok` instances
@@ -995,6 +1428,38 @@ where
             advance(tokens, tokens2);
             Ok(Some(ASTNode::Void))
         }
+        Some(Tok::Bit) => {
+            advance(tokens, tokens2);
+            if let Some(Tok::OSBracket) = tokens.peek() {
+                advance(tokens, tokens2);
+                let n: i32 = match tokens.peek() {
+                    Some(Tok::Num) => {
+                        let value = &tokens2.peek().unwrap().value;
+                        value.parse().map_err(|_| {
+                            format!("expected whole number as size of bit array, got {value}")
+                        })?
+                    }
+                    _ => {
+                        return Err(format!(
+                            "expected size of bit array, got {}",
+                            tokens2
+                                .peek()
+                                .map_or("None".to_string(), |t| t.value.clone())
+                        ))
+                    }
+                };
+                advance(tokens, tokens2);
+                match tokens.peek() {
+                    Some(Tok::CSBracket) => advance(tokens, tokens2),
+                    _ => return Err("Expected ], got something else".to_string()),
+                }
+                return Ok(Some(ASTNode::ArrayType {
+                    type_: Box::new(ASTNode::Bit),
+                    size: Box::new(ASTNode::Num(n)),
+                }));
+            }
+            Ok(Some(ASTNode::Bit))
+        }
         Some(Tok::Qudit) => {
             advance(tokens, tokens2);
             match tokens.peek() {
@@ -1223,6 +1688,7 @@ where
         Some(Tok::Old) => {
             let name = tokens2.peek().unwrap().value.clone();
             advance(tokens, tokens2);
+            let name = parse_qualified(tokens, tokens2, name)?;
             match tokens.peek() {
                 None => Ok(Some(ASTNode::Type {
                     name,
@@ -1344,7 +1810,11 @@ where
     I: Iterator<Item = Tok>,    // Expecting an iterator of owned `Tok` instances
     I2: Iterator<Item = Token>, // Expecting an iterator of owned `Tok` instances
 {
-    if let Some(Tok::New) = tokens.peek() {
+    if let Some(word) = reserved_word(tokens2) {
+        Err(format!(
+            "'{word}' is a reserved word and can't be used as a name"
+        ))
+    } else if let Some(Tok::New) = tokens.peek() {
         let n = tokens2.peek().unwrap().value.clone();
         advance(tokens, tokens2);
         Ok(n)
@@ -1354,6 +1824,14 @@ where
         )
     }
 }
+// depending on what follows, `inv` or `pow` come out of the tokenizer as a name or a keyword
+fn reserved_word<I2>(tokens2: &mut Peekable<I2>) -> Option<String>
+where
+    I2: Iterator<Item = Token>,
+{
+    let value = &tokens2.peek()?.value;
+    RESERVED.contains(&value.as_str()).then(|| value.clone())
+}
 fn parse_body_<I, I2>(
     tokens: &mut Peekable<I>,
     tokens2: &mut Peekable<I2>,
@@ -1383,6 +1861,11 @@ where
     advance(tokens, tokens2);
     let mut nodes: Vec<ASTNode> = vec![];
     loop {
+        if let Some(word) = reserved_word(tokens2) {
+            return Err(format!(
+                "'{word}' is a reserved word and can't be used as a name"
+            ));
+        }
         let name;
         match parse_name(tokens, tokens2) {
             Ok(n) => name = n,
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+
+use crate::ir::{Instruction, Operand, Program};
+
+/// The three CNOTs that exchange the states of two qubits.
+pub fn swap(a: &str, b: &str) -> Vec<Instruction> {
+    let (a, b) = (Operand::Qubit(a.to_string()), Operand::Qubit(b.to_string()));
+    vec![
+        Instruction::new("CNT", vec![a.clone(), b.clone()]),
+        Instruction::new("CNT", vec![b.clone(), a.clone()]),
+        Instruction::new("CNT", vec![a, b]),
+    ]
+}
+
+/// A qubit operation a qudit instruction expands to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op<Q> {
+    /// `HAD` or `PX` on one qubit
+    Gate(&'static str, Q),
+    /// controlled X from the first onto the second qubit
+    Cnot(Q, Q),
+    /// exchange of the states of two qubits
+    Swap(Q, Q),
+}
+
+/// The qudit register model shared by the simulator and `lower`.
+///
+/// A qudit is a row of slots that each hold a qubit, it holds no state of its own. Every
+/// instruction acts on the qubits it currently lists:
+///
+/// - `DAL % # "d"` creates the empty qudit `d`, `DFR % # "d"` forgets it, its qubits stay.
+/// - `CMB $a $b %d` appends `a` and `b`, `DCB $a %d` appends `a`.
+/// - `CNT $c $t %d` applies CNOT(c, t) and makes `d` the list `[c, t]`.
+/// - `DHAD %d` and `DPX %d` apply `HAD` or `PX` to every listed qubit.
+/// - `DCX $t %d` applies CNOT(m, t) for every listed `m` in order, then appends `t`.
+/// - `DTR %d k` removes the qubit in slot `k`, it simply is not part of `d` any more.
+/// - `TR %d $b k` removes the qubit `m` in slot `k` and swaps the states of `m` and `b`,
+///   so `b` holds what was traced out. Nothing happens to the state if `m` is `b`.
+///
+/// Slots count from 0 in the order the qubits were added. A removal empties its slot and
+/// leaves the others where they are, so `k` names the same qubit before and after other
+/// qubits are traced out: in `t2.qel` slot `j` is `bits[j]` throughout, and `TR(cmb,
+/// bits[j], j)` leaves every state as it is. Removing from an empty slot is an error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Qudits<Q> {
+    registers: HashMap<String, Vec<Option<Q>>>,
+}
+
+impl<Q> Default for Qudits<Q> {
+    fn default() -> Self {
+        Qudits {
+            registers: HashMap::new(),
+        }
+    }
+}
+
+impl<Q: Clone + PartialEq> Qudits<Q> {
+    pub fn new() -> Qudits<Q> {
+        Qudits::default()
+    }
+
+    /// The qubits `name` currently lists.
+    pub fn members(&self, name: &str) -> Option<Vec<Q>> {
+        self.registers
+            .get(name)
+            .map(|qs| qs.iter().flatten().cloned().collect())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, Vec<Q>)> {
+        self.registers
+            .iter()
+            .map(|(name, qs)| (name, qs.iter().flatten().cloned().collect()))
+    }
+
+    fn list(&mut self, op: Option<&Operand>) -> Result<&mut Vec<Option<Q>>, String> {
+        match op {
+            Some(Operand::Qudit(n)) => self
+                .registers
+                .get_mut(n)
+                .ok_or_else(|| format!("QUDIT_ERROR: unknown qudit register %{n}")),
+            other => Err(format!(
+                "QUDIT_ERROR: expected qudit register, got {other:?}"
+            )),
+        }
+    }
+
+    /// Executes `ins` if it is a qudit instruction and returns the qubit operations it
+    /// stands for, `None` for every other instruction. `qubit` resolves a qubit operand,
+    /// `number` the index operand of `DTR` and `TR`.
+    pub fn step(
+        &mut self,
+        ins: &Instruction,
+        qubit: impl Fn(Option<&Operand>) -> Result<Q, String>,
+        number: impl Fn(Option<&Operand>) -> Result<f64, String>,
+    ) -> Result<Option<Vec<Op<Q>>>, String> {
+        let a = &ins.args;
+        let take = |qs: &mut Vec<Option<Q>>, op: Option<&Operand>| -> Result<Q, String> {
+            let k = number(op)?;
+            if k < 0.0 || k as usize >= qs.len() {
+                return Err(format!(
+                    "QUDIT_ERROR: index {k} is out of range for a qudit of {} slots",
+                    qs.len()
+                ));
+            }
+            qs[k as usize]
+                .take()
+                .ok_or_else(|| format!("QUDIT_ERROR: slot {k} was already removed"))
+        };
+        Ok(Some(match ins.op.as_str() {
+            "DAL" | "DFR" => {
+                let name = ins
+                    .alloc_name()
+                    .ok_or_else(|| format!("QUDIT_ERROR: {} without register name", ins.op))?;
+                if ins.op == "DAL" {
+                    self.registers.insert(name.to_string(), vec![]);
+                } else {
+                    self.registers.remove(name);
+                }
+                vec![]
+            }
+            "CNT" if a.len() == 3 => {
+                let (c, t) = (qubit(a.first())?, qubit(a.get(1))?);
+                *self.list(a.get(2))? = vec![Some(c.clone()), Some(t.clone())];
+                vec![Op::Cnot(c, t)]
+            }
+            "CMB" => {
+                let (q0, q1) = (qubit(a.first())?, qubit(a.get(1))?);
+                self.list(a.get(2))?.extend([Some(q0), Some(q1)]);
+                vec![]
+            }
+            "DCB" => {
+                let q = qubit(a.first())?;
+                self.list(a.get(1))?.push(Some(q));
+                vec![]
+            }
+            "DHAD" | "DPX" => {
+                let op = if ins.op == "DHAD" { "HAD" } else { "PX" };
+                let qs = self.list(a.first())?;
+                qs.iter()
+                    .flatten()
+                    .map(|q| Op::Gate(op, q.clone()))
+                    .collect()
+            }
+            "DCX" => {
+                let t = qubit(a.first())?;
+                let qs = self.list(a.get(1))?;
+                let ops = qs
+                    .iter()
+                    .flatten()
+                    .map(|q| Op::Cnot(q.clone(), t.clone()))
+                    .collect();
+                qs.push(Some(t));
+                ops
+            }
+            "DTR" => {
+                take(self.list(a.first())?, a.get(1))?;
+                vec![]
+            }
+            "TR" => {
+                let b = qubit(a.get(1))?;
+                let q = take(self.list(a.first())?, a.get(2))?;
+                if q == b {
+                    vec![]
+                } else {
+                    vec![Op::Swap(q, b)]
+                }
+            }
+            "CPY" | "DCP" if !ins.qudits().is_empty() => {
+                return Err("QUDIT_ERROR: copying qudit registers is not supported".to_string())
+            }
+            _ => return Ok(None),
+        }))
+    }
+}
+
+/// Rewrites every qudit instruction into the qubit gates it stands for, following the
+/// register model of `Qudits`. The qudit contents only depend on the instruction sequence,
+/// so they are tracked here at compile time. Passes that only understand qubits (routing,
+/// basis translation) run on the result.
+pub fn lower(program: &Program) -> Result<Program, String> {
+    let mut qudits = Qudits::new();
+    let mut out = vec![];
+    for ins in &program.instructions {
+        lower_step(&mut qudits, ins, &mut out)?;
+    }
+    Ok(Program { instructions: out })
+}
+
+/// Lowers a single instruction onto `out`, for passes that walk the instructions in the
+/// order they run instead of lowering a whole program.
+pub fn lower_step(
+    qudits: &mut Qudits<String>,
+    ins: &Instruction,
+    out: &mut Vec<Instruction>,
+) -> Result<(), String> {
+    let qubit = |op: Option<&Operand>| match op {
+        Some(Operand::Qubit(n)) => Ok(n.clone()),
+        other => Err(format!(
+            "QUDIT_ERROR: expected qubit register, got {other:?}"
+        )),
+    };
+    let number = |op: Option<&Operand>| match op {
+        Some(Operand::Num(k)) => Ok(*k),
+        other => Err(format!(
+            "QUDIT_ERROR: qudit index must be a constant, got {other:?}"
+        )),
+    };
+    let Some(ops) = qudits.step(ins, qubit, number)? else {
+        out.push(ins.clone());
+        return Ok(());
+    };
+    for op in ops {
+        match op {
+            Op::Gate(name, q) => out.push(Instruction::new(name, vec![Operand::Qubit(q)])),
+            Op::Cnot(c, t) => out.push(Instruction::new(
+                "CNT",
+                vec![Operand::Qubit(c), Operand::Qubit(t)],
+            )),
+            Op::Swap(a, b) => out.extend(swap(&a, &b)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::ir::parse_program;
+    use crate::shots;
+    use crate::sim::Simulator;
+
+    /// One of the demo programs at the root of the repository, compiled as it is.
+    fn demo(name: &str) -> Program {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(name);
+        Compiler::new().compile_file(path).unwrap().program
+    }
+
+    /// Both oracles are balanced, so every input qubit has to measure 1 in every shot, with
+    /// the qudit instructions executed natively and lowered to qubit gates, on both simulators.
+    fn finds_balanced_oracle(name: &str, inputs: usize) {
+        let program = demo(name);
+        let lowered = lower(&program).unwrap();
+        assert!(lowered.instructions.iter().all(|i| i.qudits().is_empty()));
+        for program in [&program, &lowered] {
+            for sim in [Simulator::Statevector, Simulator::Stabilizer] {
+                let counts = shots::run(program, &[], 20, 0, sim).unwrap();
+                assert_eq!(counts.registers.len(), inputs, "{name} on {}", sim.name());
+                for (register, outcomes) in &counts.registers {
+                    assert_eq!(outcomes, &[("1".to_string(), 20)], "{name}: {register}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn deutsch() {
+        finds_balanced_oracle("t.qel", 1);
+    }
+
+    #[test]
+    fn deutsch_jozsa() {
+        finds_balanced_oracle("t2.qel", 11);
+    }
+
+    fn members(program: &str) -> Result<Vec<String>, String> {
+        let mut qudits = Qudits::new();
+        let qubit = |op: Option<&Operand>| match op {
+            Some(Operand::Qubit(n)) => Ok(n.clone()),
+            other => Err(format!("{other:?}")),
+        };
+        let number = |op: Option<&Operand>| match op {
+            Some(Operand::Num(k)) => Ok(*k),
+            other => Err(format!("{other:?}")),
+        };
+        for ins in &parse_program(program)?.instructions {
+            qudits.step(ins, qubit, number)?;
+        }
+        Ok(qudits.members("d").unwrap())
+    }
+
+    #[test]
+    fn slots_keep_their_index() {
+        let fill = "DAL % # \"d\"\nCMB $a $b %d\nDCB $c %d\nDCX $t %d\n";
+        let names = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(members(fill).unwrap(), names(&["a", "b", "c", "t"]));
+        // after slot 0 is gone, slot 2 still is c and slot 3 still is t
+        let traced = format!("{fill}DTR %d 0\nTR %d $c 2\nDTR %d 3\n");
+        assert_eq!(members(&traced).unwrap(), names(&["b"]));
+        let twice = format!("{fill}DTR %d 1\nTR %d $x 1\n");
+        assert!(members(&twice)
+            .unwrap_err()
+            .contains("slot 1 was already removed"));
+        let outside = format!("{fill}DTR %d 4\n");
+        assert!(members(&outside).unwrap_err().contains("out of range"));
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+
+use crate::ir::{Instruction, Operand, Program};
+
+/// The lifetime of one register from its allocation to its last use. A name that is
+/// freed and allocated again gets a new interval.
+#[derive(Debug, Clone)]
+struct Interval {
+    name: String,
+    qudit: bool,
+    start: usize,
+    last_use: usize,
+    /// index of a `FRE`/`DFR` already present in the program
+    freed: Option<usize>,
+    /// qudits this qubit was merged into, their operations may still touch it
+    linked: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    pub program: Program,
+    /// physical slot of every allocated qubit, in allocation order
+    pub slots: Vec<(String, usize)>,
+    /// most qubits alive at the same time after allocation
+    pub peak: usize,
+    /// the same number for the program as it was generated
+    pub peak_before: usize,
+}
+
+fn intervals(program: &Program) -> Vec<Interval> {
+    let mut all: Vec<Interval> = vec![];
+    let mut current: HashMap<(String, bool), usize> = HashMap::new();
+    for (i, ins) in program.instructions.iter().enumerate() {
+        match ins.op.as_str() {
+            "QAL" | "DAL" => {
+                let qudit = ins.op == "DAL";
+                if let Some(name) = ins.alloc_name() {
+                    current.insert((name.to_string(), qudit), all.len());
+                    all.push(Interval {
+                        name: name.to_string(),
+                        qudit,
+                        start: i,
+                        last_use: i,
+                        freed: None,
+                        linked: vec![],
+                    });
+                }
+            }
+            "FRE" | "DFR" => {
+                let key = (ins.alloc_name().unwrap_or("").to_string(), ins.op == "DFR");
+                if let Some(k) = current.remove(&key) {
+                    all[k].freed = Some(i);
+                }
+            }
+            _ => {
+                let qudits: Vec<usize> = ins
+                    .qudits()
+                    .iter()
+                    .filter_map(|n| current.get(&(n.to_string(), true)).copied())
+                    .collect();
+                for k in &qudits {
+                    all[*k].last_use = i;
+                }
+                for n in ins.qubits() {
+                    if let Some(k) = current.get(&(n.to_string(), false)) {
+                        all[*k].last_use = i;
+                        all[*k].linked.extend(&qudits);
+                    }
+                }
+            }
+        }
+    }
+    // a qubit inside a qudit lives as long as the qudit does
+    for k in 0..all.len() {
+        let end = all[k].linked.iter().map(|q| all[*q].last_use).max();
+        if let Some(end) = end {
+            all[k].last_use = all[k].last_use.max(end);
+        }
+    }
+    all
+}
+
+/// Assigns every qubit interval the lowest slot that is free at its start, the same
+/// way the simulator hands out freed qubits again. Returns the slots and the peak.
+fn assign(intervals: &[Interval], end: impl Fn(&Interval) -> usize) -> (Vec<usize>, usize) {
+    let mut busy: Vec<usize> = vec![]; // slot -> index after which it is free again
+    let mut slots = vec![];
+    for iv in intervals.iter().filter(|iv| !iv.qudit) {
+        let slot = match (0..busy.len()).find(|s| busy[*s] < iv.start) {
+            Some(s) => s,
+            None => {
+                busy.push(0);
+                busy.len() - 1
+            }
+        };
+        busy[slot] = end(iv);
+        slots.push(slot);
+    }
+    (slots, busy.len())
+}
+
+/// Peak number of qubits alive at once for a program as it stands.
+pub fn peak_qubits(program: &Program) -> usize {
+    assign(&intervals(program), |iv| iv.freed.unwrap_or(usize::MAX)).1
+}
+
+fn free(iv: &Interval) -> Instruction {
+    let (op, sigils) = if iv.qudit {
+        ("DFR", ['%', '#'])
+    } else {
+        ("FRE", ['&', '$'])
+    };
+    let name = Operand::Name(iv.name.clone());
+    Instruction::new(
+        op,
+        vec![Operand::Sigil(sigils[0]), Operand::Sigil(sigils[1]), name],
+    )
+}
+
+/// Computes qubit liveness over the program and releases every register right after
+/// its last use, so that later allocations reuse its slot.
+///
+/// Registers are only released early if an allocation of the same kind follows, freeing
+/// them at the end of the program would not lower the peak. Qubits that were merged into
+/// a qudit stay alive until the qudit is used for the last time.
+pub fn allocate(program: &Program) -> Allocation {
+    let ivs = intervals(program);
+    let last_alloc = |qudit: bool| {
+        program
+            .instructions
+            .iter()
+            .rposition(|i| i.op == if qudit { "DAL" } else { "QAL" })
+    };
+    let (last_qal, last_dal) = (last_alloc(false), last_alloc(true));
+    let moved = |iv: &Interval| {
+        let last = if iv.qudit { last_dal } else { last_qal };
+        last.is_some_and(|l| iv.last_use < l)
+    };
+
+    let mut frees: HashMap<usize, Vec<Instruction>> = HashMap::new();
+    let mut dropped = vec![];
+    for iv in ivs.iter().filter(|iv| moved(iv)) {
+        frees.entry(iv.last_use).or_default().push(free(iv));
+        dropped.extend(iv.freed);
+    }
+    let mut instructions = vec![];
+    for (i, ins) in program.instructions.iter().enumerate() {
+        if !dropped.contains(&i) {
+            instructions.push(ins.clone());
+        }
+        instructions.extend(frees.remove(&i).unwrap_or_default());
+    }
+
+    let end = |iv: &Interval| {
+        if moved(iv) {
+            iv.last_use
+        } else {
+            iv.freed.unwrap_or(usize::MAX)
+        }
+    };
+    let (slots, peak) = assign(&ivs, end);
+    let names = ivs.iter().filter(|iv| !iv.qudit).map(|iv| iv.name.clone());
+    Allocation {
+        program: Program { instructions },
+        slots: names.zip(slots).collect(),
+        peak,
+        peak_before: peak_qubits(program),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_program;
+    use crate::sim::{Machine, Statevector};
+
+    fn lines(program: &Program) -> Vec<String> {
+        program.instructions.iter().map(|i| i.to_string()).collect()
+    }
+
+    fn registers(program: &Program) -> Vec<(String, Vec<bool>)> {
+        let mut m = Machine::new(Statevector::new(), vec![], 0);
+        m.run(program).unwrap();
+        m.registers
+    }
+
+    // b is done after the second CNT, d can take its slot
+    const PROGRAM: &str = "QAL & 0 $ \"a\"
+QAL & 0 $ \"b\"
+PX $a
+CNT $a $b
+BAL \"r\" 3
+MES $b \"r\" 0
+QAL & 0 $ \"c\"
+CNT $b $c
+QAL & 0 $ \"d\"
+TOF $a $c $d
+MES $d \"r\" 1
+MES $c \"r\" 2
+";
+
+    #[test]
+    fn frees_after_the_last_use() {
+        let program = parse_program(PROGRAM).unwrap();
+        let alloc = allocate(&program);
+        let out = lines(&alloc.program);
+        let cnt = out.iter().position(|l| l == "CNT $b $c").unwrap();
+        assert_eq!(out[cnt + 1], "FRE & $ \"b\"");
+        assert_eq!(out[cnt + 2], "QAL & 0 $ \"d\"");
+        // a and c are used up to the end, freeing them earlier would not lower the peak
+        assert_eq!(out.iter().filter(|l| l.starts_with("FRE")).count(), 1);
+        assert_eq!((alloc.peak_before, alloc.peak), (4, 3));
+        assert_eq!(peak_qubits(&alloc.program), alloc.peak);
+        let slot = |name: &str| alloc.slots.iter().find(|(n, _)| n == name).unwrap().1;
+        assert_eq!(slot("d"), slot("b"));
+    }
+
+    #[test]
+    fn simulates_like_the_original() {
+        // b is |1> when it is freed, d has to start in |0> all the same
+        let program = parse_program(PROGRAM).unwrap();
+        let alloc = allocate(&program);
+        let expected = vec![("r".to_string(), vec![true, true, true])];
+        assert_eq!(registers(&program), expected);
+        assert_eq!(registers(&alloc.program), expected);
+    }
+
+    #[test]
+    fn qubits_in_a_qudit_live_as_long_as_it() {
+        let program = parse_program(
+            "QAL & 0 $ \"a\"
+QAL & 0 $ \"b\"
+DAL % # \"q\"
+CMB $a $b %q
+QAL & 0 $ \"c\"
+DPX %q
+QAL & 0 $ \"d\"
+CNT $c $d
+",
+        )
+        .unwrap();
+        let out = lines(&allocate(&program).program);
+        let dpx = out.iter().position(|l| l == "DPX %q").unwrap();
+        for name in ["a", "b"] {
+            let free = out.iter().position(|l| *l == format!("FRE & $ \"{name}\""));
+            assert_eq!(free.map(|f| f > dpx), Some(true), "{out:?}");
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+
+use crate::ir::{Instruction, Operand, Program};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WireKind {
+    Qubit,
+    Qudit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mark {
+    Box(String), // gate box
+    Control,     // control dot
+    Target,      // CNOT target
+    Measure,
+    MergeIn,  // qubit flowing into a qudit (CMB/DCB)
+    MergeOut, // qubit traced back out of a qudit (TR)
+    Merge,    // the qudit side of a merge or trace
+}
+
+/// One column entry: the marks an instruction puts on its wires plus the span of the
+/// vertical connector between them.
+#[derive(Debug, Clone)]
+pub struct Cell {
+    pub marks: Vec<(usize, Mark)>,
+    pub top: usize,
+    pub bottom: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Circuit {
+    pub wires: Vec<(String, WireKind)>,
+    pub columns: Vec<Vec<Cell>>,
+}
+
+fn angle(op: Option<&Operand>) -> String {
+    match op {
+        Some(Operand::Num(n)) => format!("{:.3}", n)
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string(),
+        Some(Operand::Extern(i)) => format!("a{i}"),
+        Some(Operand::Scaled(i, k)) => format!("{k}*a{i}"),
+        _ => "?".to_string(),
+    }
+}
+
+fn label(ins: &Instruction) -> String {
+    match ins.op.as_str() {
+        "HAD" | "DHAD" => "H".to_string(),
+        "PX" | "DPX" => "X".to_string(),
+        "PY" => "Y".to_string(),
+        "PZ" => "Z".to_string(),
+        "SDG" => "Sdg".to_string(),
+        "TDG" => "Tdg".to_string(),
+        "RX" | "RY" | "RZ" => format!("{}({})", ins.op, angle(ins.args.get(1))),
+        "SET" => match (ins.args.get(1), ins.args.get(2)) {
+            (Some(Operand::Num(a)), Some(Operand::Num(b))) if *a == 1.0 && *b == 0.0 => {
+                "|0>".to_string()
+            }
+            (Some(Operand::Num(a)), Some(Operand::Num(b))) if *a == 0.0 && *b == 1.0 => {
+                "|1>".to_string()
+            }
+            _ => format!("|{},{}>", angle(ins.args.get(1)), angle(ins.args.get(2))),
+        },
+        "DTR" => format!("Tr {}", angle(ins.args.get(1))),
+        op => op.to_string(),
+    }
+}
+
+impl Circuit {
+    fn wire(
+        &mut self,
+        rows: &mut HashMap<(String, WireKind), usize>,
+        name: &str,
+        kind: WireKind,
+    ) -> usize {
+        *rows.entry((name.to_string(), kind)).or_insert_with(|| {
+            self.wires.push((name.to_string(), kind));
+            self.wires.len() - 1
+        })
+    }
+
+    /// Labels the wires of array elements by their source name, `grid_1_2` becomes `grid[1][2]`.
+    pub fn rename(&mut self, names: &HashMap<String, String>) {
+        for (name, _) in &mut self.wires {
+            if let Some(source) = names.get(name.as_str()) {
+                *name = source.clone();
+            }
+        }
+    }
+
+    /// Lays out the instruction stream: one wire per `QAL`/`DAL` register, instructions are
+    /// packed into the leftmost column whose wires (including the ones crossed by the
+    /// connector) are still free.
+    pub fn from_program(program: &Program) -> Circuit {
+        let mut c = Circuit::default();
+        let mut rows: HashMap<(String, WireKind), usize> = HashMap::new();
+        let mut cells: Vec<Cell> = vec![];
+
+        for ins in &program.instructions {
+            let a = &ins.args;
+            let mut row = |i: usize| -> Option<usize> {
+                let (name, kind) = match a.get(i) {
+                    Some(Operand::Qubit(n)) => (n, WireKind::Qubit),
+                    Some(Operand::Qudit(n)) => (n, WireKind::Qudit),
+                    _ => return None,
+                };
+                Some(c.wire(&mut rows, name, kind))
+            };
+            let mut marks: Vec<(usize, Mark)> = vec![];
+            match ins.op.as_str() {
+                "QAL" | "DAL" => {
+                    let kind = if ins.op == "QAL" {
+                        WireKind::Qubit
+                    } else {
+                        WireKind::Qudit
+                    };
+                    if let Some(n) = ins.alloc_name() {
+                        c.wire(&mut rows, n, kind);
+                    }
+                    continue;
+                }
+                "FRE" | "DFR" => continue,
+                "CNT" | "CY" | "TOF" => {
+                    let n = a.len();
+                    let has_qudit = matches!(a.last(), Some(Operand::Qudit(_)));
+                    let target = if has_qudit { n - 2 } else { n - 1 };
+                    for i in 0..n {
+                        let Some(r) = row(i) else {
+                            continue;
+                        };
+                        let mark = if i < target {
+                            Mark::Control
+                        } else if i > target {
+                            Mark::Merge
+                        } else if ins.op == "CY" {
+                            Mark::Box("Y".to_string())
+                        } else {
+                            Mark::Target
+                        };
+                        marks.push((r, mark));
+                    }
+                }
+                "DCX" => {
+                    marks.extend(row(0).map(|r| (r, Mark::Target)));
+                    marks.extend(row(1).map(|r| (r, Mark::Control)));
+                }
+                "CMB" | "DCB" => {
+                    for (i, arg) in a.iter().enumerate() {
+                        let mark = if matches!(arg, Operand::Qudit(_)) {
+                            Mark::Merge
+                        } else {
+                            Mark::MergeIn
+                        };
+                        marks.extend(row(i).map(|r| (r, mark)));
+                    }
+                }
+                "TR" => {
+                    let l = format!("Tr {}", angle(a.get(2)));
+                    marks.extend(row(0).map(|r| (r, Mark::Box(l))));
+                    marks.extend(row(1).map(|r| (r, Mark::MergeOut)));
+                }
+                "MES" => marks.extend(row(0).map(|r| (r, Mark::Measure))),
+                "SET" | "RX" | "RY" | "RZ" => {
+                    marks.extend(row(0).map(|r| (r, Mark::Box(label(ins)))))
+                }
+                _ => {
+                    for i in 0..a.len() {
+                        marks.extend(row(i).map(|r| (r, Mark::Box(label(ins)))));
+                    }
+                }
+            }
+            if marks.is_empty() {
+                continue;
+            }
+            let top = marks.iter().map(|m| m.0).min().unwrap();
+            let bottom = marks.iter().map(|m| m.0).max().unwrap();
+            cells.push(Cell { marks, top, bottom });
+        }
+
+        // greedy column packing
+        let mut free_from = vec![0; c.wires.len()];
+        for cell in cells {
+            let col = (cell.top..=cell.bottom)
+                .map(|r| free_from[r])
+                .max()
+                .unwrap_or(0);
+            for f in free_from.iter_mut().take(cell.bottom + 1).skip(cell.top) {
+                *f = col + 1;
+            }
+            if c.columns.len() <= col {
+                c.columns.resize(col + 1, vec![]);
+            }
+            c.columns[col].push(cell);
+        }
+        c
+    }
+}
+
+fn ascii_mark(mark: &Mark) -> String {
+    match mark {
+        Mark::Box(l) => format!("[{l}]"),
+        Mark::Control => "*".to_string(),
+        Mark::Target => "(+)".to_string(),
+        Mark::Measure => "[M]".to_string(),
+        Mark::MergeIn => ">".to_string(),
+        Mark::MergeOut => "<".to_string(),
+        Mark::Merge => "#".to_string(),
+    }
+}
+
+fn center(s: &str, width: usize, fill: char) -> String {
+    let len = s.chars().count();
+    let left = (width - len) / 2;
+    let right = width - len - left;
+    format!(
+        "{}{s}{}",
+        fill.to_string().repeat(left),
+        fill.to_string().repeat(right)
+    )
+}
+
+/// Draws the circuit for a terminal. Qubit wires are `-`, qudit wires `=`.
+pub fn render_ascii(circuit: &Circuit) -> String {
+    let name_width = circuit
+        .wires
+        .iter()
+        .map(|(n, k)| n.len() + if *k == WireKind::Qudit { 1 } else { 0 })
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let n_rows = circuit.wires.len();
+    // every wire has a line below it for vertical connectors
+    let mut lines: Vec<String> = vec![String::new(); n_rows * 2];
+    for (r, (name, kind)) in circuit.wires.iter().enumerate() {
+        let sigil = if *kind == WireKind::Qudit { "%" } else { "" };
+        lines[2 * r] = format!("{:>name_width$} ", format!("{sigil}{name}"));
+        lines[2 * r + 1] = " ".repeat(name_width + 1);
+    }
+    for column in &circuit.columns {
+        let width = column
+            .iter()
+            .flat_map(|cell| cell.marks.iter().map(|(_, m)| ascii_mark(m).len()))
+            .max()
+            .unwrap_or(1)
+            + 2;
+        let mut wire_cells: Vec<Option<String>> = vec![None; n_rows];
+        let mut gap_cells: Vec<bool> = vec![false; n_rows];
+        for cell in column {
+            for r in cell.top..=cell.bottom {
+                if r < cell.bottom {
+                    gap_cells[r] = true;
+                }
+                if wire_cells[r].is_none() {
+                    wire_cells[r] = Some(if r > cell.top && r < cell.bottom {
+                        "|".to_string()
+                    } else {
+                        String::new()
+                    });
+                }
+            }
+            for (r, mark) in &cell.marks {
+                wire_cells[*r] = Some(ascii_mark(mark));
+            }
+        }
+        for r in 0..n_rows {
+            let fill = if circuit.wires[r].1 == WireKind::Qudit {
+                '='
+            } else {
+                '-'
+            };
+            let cell = wire_cells[r].clone().unwrap_or_default();
+            lines[2 * r].push_str(&center(&cell, width, fill));
+            lines[2 * r + 1].push_str(&center(if gap_cells[r] { "|" } else { "" }, width, ' '));
+        }
+    }
+    let mut out = String::new();
+    for l in lines {
+        out.push_str(l.trim_end());
+        out.push('\n');
+    }
+    while out.ends_with("\n\n") {
+        out.pop();
+    }
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Draws the circuit as a standalone SVG document.
+pub fn render_svg(circuit: &Circuit) -> String {
+    const COL: f64 = 56.0;
+    const ROW: f64 = 44.0;
+    let left = 16.0
+        + 8.0
+            * circuit
+                .wires
+                .iter()
+                .map(|(n, _)| n.len() + 1)
+                .max()
+                .unwrap_or(1) as f64;
+    let width = left + COL * (circuit.columns.len() as f64 + 1.0);
+    let height = ROW * (circuit.wires.len() as f64 + 0.5);
+    let y = |r: usize| ROW * (r as f64 + 0.75);
+    let x = |c: usize| left + COL * (c as f64 + 0.5);
+
+    let mut s = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+         viewBox=\"0 0 {width} {height}\" font-family=\"monospace\" font-size=\"13\">\n\
+         <rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n"
+    );
+    for (r, (name, kind)) in circuit.wires.iter().enumerate() {
+        let sigil = if *kind == WireKind::Qudit { "%" } else { "" };
+        s.push_str(&format!(
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"end\" dominant-baseline=\"middle\">{}</text>\n",
+            left - 8.0,
+            y(r),
+            xml_escape(&format!("{sigil}{name}"))
+        ));
+        let offsets: &[f64] = if *kind == WireKind::Qudit {
+            &[-2.0, 2.0]
+        } else {
+            &[0.0]
+        };
+        for o in offsets {
+            s.push_str(&format!(
+                "<line x1=\"{left}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"black\"/>\n",
+                y(r) + o,
+                width - 8.0,
+                y(r) + o
+            ));
+        }
+    }
+    for (c, column) in circuit.columns.iter().enumerate() {
+        for cell in column {
+            if cell.top != cell.bottom {
+                s.push_str(&format!(
+                    "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"black\"/>\n",
+                    x(c),
+                    y(cell.top),
+                    x(c),
+                    y(cell.bottom)
+                ));
+            }
+            for (r, mark) in &cell.marks {
+                let (cx, cy) = (x(c), y(*r));
+                s.push_str(&match mark {
+                    Mark::Box(l) => {
+                        let w = (8.0 * l.chars().count() as f64 + 12.0).max(28.0);
+                        format!(
+                            "<rect x=\"{}\" y=\"{}\" width=\"{w}\" height=\"28\" fill=\"white\" stroke=\"black\"/>\n\
+                             <text x=\"{cx}\" y=\"{cy}\" text-anchor=\"middle\" dominant-baseline=\"middle\">{}</text>\n",
+                            cx - w / 2.0,
+                            cy - 14.0,
+                            xml_escape(l)
+                        )
+                    }
+                    Mark::Control => format!("<circle cx=\"{cx}\" cy=\"{cy}\" r=\"5\" fill=\"black\"/>\n"),
+                    Mark::Target => format!(
+                        "<circle cx=\"{cx}\" cy=\"{cy}\" r=\"10\" fill=\"white\" stroke=\"black\"/>\n\
+                         <line x1=\"{}\" y1=\"{cy}\" x2=\"{}\" y2=\"{cy}\" stroke=\"black\"/>\n\
+                         <line x1=\"{cx}\" y1=\"{}\" x2=\"{cx}\" y2=\"{}\" stroke=\"black\"/>\n",
+                        cx - 10.0,
+                        cx + 10.0,
+                        cy - 10.0,
+                        cy + 10.0
+                    ),
+                    Mark::Measure => format!(
+                        "<rect x=\"{}\" y=\"{}\" width=\"28\" height=\"28\" fill=\"white\" stroke=\"black\"/>\n\
+                         <path d=\"M {} {} A 9 9 0 0 1 {} {}\" fill=\"none\" stroke=\"black\"/>\n\
+                         <line x1=\"{cx}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"black\"/>\n",
+                        cx - 14.0,
+                        cy - 14.0,
+                        cx - 9.0,
+                        cy + 6.0,
+                        cx + 9.0,
+                        cy + 6.0,
+                        cy + 6.0,
+                        cx + 7.0,
+                        cy - 8.0
+                    ),
+                    Mark::MergeIn => format!(
+                        "<path d=\"M {} {} L {cx} {cy} L {} {}\" fill=\"none\" stroke=\"black\"/>\n",
+                        cx - 6.0,
+                        cy - 6.0,
+                        cx - 6.0,
+                        cy + 6.0
+                    ),
+                    Mark::MergeOut => format!(
+                        "<path d=\"M {} {} L {cx} {cy} L {} {}\" fill=\"none\" stroke=\"black\"/>\n",
+                        cx + 6.0,
+                        cy - 6.0,
+                        cx + 6.0,
+                        cy + 6.0
+                    ),
+                    Mark::Merge => format!(
+                        "<rect x=\"{}\" y=\"{}\" width=\"10\" height=\"10\" fill=\"black\"/>\n",
+                        cx - 5.0,
+                        cy - 5.0
+                    ),
+                });
+            }
+        }
+    }
+    s.push_str("</svg>\n");
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+
+    fn circuit(src: &str) -> Circuit {
+        let artifact = Compiler::new().compile_str(src).unwrap();
+        let mut circuit = Circuit::from_program(&artifact.program);
+        circuit.rename(&artifact.registers);
+        circuit
+    }
+
+    const SRC: &str = "let a: qbit;
+let g: qbit[2];
+HAD(a);
+CNT(a, g[1]);
+RZ(g[0], 0.5);
+TOF(a, g[0], g[1]);
+let c: bit = MES(a);
+";
+
+    #[test]
+    fn ascii() {
+        let expected = "    a -[H]---*---------------*---[M]-
+             |               |
+ g[0] -------|---[RZ(0.5)]---*-------
+             |               |
+ g[1] ------(+)-------------(+)------
+";
+        assert_eq!(render_ascii(&circuit(SRC)), expected);
+    }
+
+    #[test]
+    fn svg() {
+        let svg = render_svg(&circuit(SRC));
+        assert!(svg.starts_with("<svg") && svg.trim_end().ends_with("</svg>"));
+        for label in ["g[0]", "g[1]", "RZ(0.5)"] {
+            assert!(svg.contains(label), "{label}");
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+
+use crate::code_gen::{code_gen_with, Comptime};
+use crate::compiler::catch_frontend;
+use crate::ir::{parse_program, unroll, Instruction};
+use crate::lsp::type_name;
+use crate::parser::{from_tokens, parse_, ASTNode};
+use crate::sim::{Machine, Statevector};
+use crate::tokenizer::{filter50s_with, filter_all, tokenize};
+
+const SHOWN_AMPLITUDES: usize = 8;
+
+const HELP: &str = "enter QEL statements, they are compiled and simulated one by one
+  :state   show the full simulated state
+  :regs    list variables and the registers they live in
+  :emit    print the instructions generated so far
+  :reset   forget all variables and start over
+  :quit    leave the repl";
+
+pub struct Repl {
+    pub comptime: Comptime,
+    pub machine: Machine<Statevector>,
+    declared: HashSet<(u32, String)>,
+    externs: Vec<f64>,
+}
+
+impl Repl {
+    pub fn new(externs: Vec<f64>) -> Repl {
+        Repl {
+            comptime: Comptime::new(),
+            machine: Machine::new(Statevector::new(), externs.clone(), 0),
+            declared: HashSet::new(),
+            externs,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Repl::new(self.externs.clone());
+    }
+
+    fn compile(&mut self, src: &str) -> Result<ASTNode, String> {
+        let mut tokens = tokenize(src);
+        tokens = filter50s_with(tokens, &mut self.declared);
+        tokens = filter_all(tokens);
+        let ast = parse_(from_tokens(tokens.clone()), tokens)?;
+        code_gen_with(ast.clone(), &mut self.comptime)?;
+        Ok(ast)
+    }
+
+    /// Compiles one statement against the persistent compile-time state and runs the
+    /// instructions it produced. On failure everything is rolled back to before the statement.
+    pub fn eval<W: Write>(&mut self, src: &str, out: &mut W) -> io::Result<()> {
+        let snapshot = (
+            self.comptime.clone(),
+            self.declared.clone(),
+            self.machine.clone(),
+        );
+        let start = self.comptime.program.len();
+
+        let compiled = catch_frontend(|| self.compile(src));
+
+        let result = compiled.and_then(|ast| {
+            if let ASTNode::Program(nodes) = &ast {
+                if nodes.is_empty() {
+                    return Err("statement was not understood".to_string());
+                }
+            }
+            // the statement's instructions are shown one by one, loops written out
+            parse_program(&self.comptime.program[start..]).and_then(|p| unroll(&p))
+        });
+        let program = match result {
+            Ok(p) => p,
+            Err(e) => {
+                (self.comptime, self.declared, self.machine) = snapshot;
+                return writeln!(out, "error: {e}");
+            }
+        };
+
+        for ins in &program.instructions {
+            let measured = self.machine.measurements.len();
+            if let Err(e) = self.machine.step(ins) {
+                (self.comptime, self.declared, self.machine) = snapshot;
+                return writeln!(out, "error: {e}");
+            }
+            writeln!(out, "  {ins}")?;
+            if let Some((name, bit)) = self.machine.measurements.get(measured) {
+                writeln!(out, "    ${name} = {}", *bit as u8)?;
+            }
+            if is_gate(ins) {
+                self.print_state(out, SHOWN_AMPLITUDES)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn print_state<W: Write>(&self, out: &mut W, limit: usize) -> io::Result<()> {
+        let mut names: Vec<(&String, &usize)> = self.machine.qubits.iter().collect();
+        names.sort_by_key(|(_, q)| **q);
+        let amps = &self.machine.engine.amps;
+        let mut entries: Vec<usize> = (0..amps.len())
+            .filter(|i| amps[*i].norm_sqr() > 1e-12)
+            .collect();
+        entries.sort_by(|a, b| amps[*b].norm_sqr().total_cmp(&amps[*a].norm_sqr()));
+        for i in entries.iter().take(limit) {
+            let label: Vec<String> = names
+                .iter()
+                .map(|(n, q)| format!("{n}={}", (i >> **q) & 1))
+                .collect();
+            writeln!(out, "    {:.4} |{}>", amps[*i], label.join(" "))?;
+        }
+        if entries.len() > limit {
+            writeln!(out, "    ... {} more basis states", entries.len() - limit)?;
+        }
+        Ok(())
+    }
+
+    pub fn print_registers<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut vars: Vec<_> = self.comptime.var_info.iter().collect();
+        vars.sort_by_key(|(n, _)| n.to_string());
+        for (name, (is_const, size, type_)) in vars {
+            writeln!(
+                out,
+                "  {}{name}: {} ({size} register{})",
+                if *is_const { "const " } else { "" },
+                type_name(type_),
+                if *size == 1 { "" } else { "s" }
+            )?;
+        }
+        let mut qubits: Vec<_> = self.machine.qubits.iter().collect();
+        qubits.sort_by_key(|(_, q)| **q);
+        for (name, q) in qubits {
+            writeln!(out, "  ${name} -> qubit {q}")?;
+        }
+        let mut qudits: Vec<_> = self.machine.qudits.iter().collect();
+        qudits.sort_by_key(|(n, _)| n.to_string());
+        for (name, qs) in qudits {
+            writeln!(out, "  %{name} -> qubits {qs:?}")?;
+        }
+        Ok(())
+    }
+}
+
+fn is_gate(ins: &Instruction) -> bool {
+    !["QAL", "DAL", "FRE", "DFR", "MES"].contains(&ins.op.as_str())
+}
+
+fn complete(src: &str) -> bool {
+    let open = src.matches('{').count();
+    let close = src.matches('}').count();
+    let trimmed = src.trim_end();
+    open <= close && (trimmed.ends_with(';') || trimmed.ends_with('}'))
+}
+
+pub fn run<R: BufRead, W: Write>(input: R, mut out: W, externs: Vec<f64>) -> io::Result<()> {
+    let mut repl = Repl::new(externs);
+    let mut pending = String::new();
+    writeln!(out, "QEL repl, :help lists the commands")?;
+    write!(out, "qel> ")?;
+    out.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        if pending.is_empty() && line.trim_start().starts_with(':') {
+            match line.trim() {
+                ":quit" | ":q" => return Ok(()),
+                ":state" => repl.print_state(&mut out, usize::MAX)?,
+                ":regs" => repl.print_registers(&mut out)?,
+                ":emit" => write!(out, "{}", repl.comptime.program)?,
+                ":reset" => {
+                    repl.reset();
+                    writeln!(out, "state cleared")?;
+                }
+                ":help" => writeln!(out, "{HELP}")?,
+                other => writeln!(out, "unknown command {other}, try :help")?,
+            }
+        } else if !line.trim().is_empty() {
+            pending.push_str(&line);
+            pending.push('\n');
+            if complete(&pending) {
+                repl.eval(&pending, &mut out)?;
+                pending.clear();
+            }
+        }
+        write!(
+            out,
+            "{}",
+            if pending.is_empty() { "qel> " } else { "...> " }
+        )?;
+        out.flush()?;
+    }
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(input: &str) -> String {
+        let mut out = Vec::new();
+        run(input.as_bytes(), &mut out, vec![]).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn errors_roll_back_the_statement() {
+        let out = session(
+            "let q: qbit;\nHAD(q);\nHAD(nope);\nlet r: qbit[2]; PX(r[7]);\nMES(q);\n:regs\n:quit\n",
+        );
+        let lines: Vec<&str> = out.lines().map(|l| l.trim_start_matches("qel> ")).collect();
+        assert_eq!(
+            lines[1..lines.len() - 1],
+            [
+                "  QAL & 0 $ \"q\"",
+                "  HAD $q",
+                "    0.7071+0.0000i |q=0>",
+                "    0.7071+0.0000i |q=1>",
+                "error: Expected argument, got None",
+                "error: BACKEND_ERROR: r[7] is out of bounds, 'r' has 2 qubits",
+                "  MES $q",
+                "    $q = 0",
+                "  q: qbit (1 register)",
+                "  $q -> qubit 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn statements_continue_over_lines() {
+        let out = session("let a: qbit;\nfor (i in 0..=1) {\nPX(a);\n}\n:emit\n");
+        let lines: Vec<&str> = out.lines().map(str::trim_end).collect();
+        assert_eq!(
+            lines[2..],
+            [
+                "qel> ...> ...>   PX $a",
+                "    1.0000+0.0000i |a=1>",
+                "  PX $a",
+                "    1.0000+0.0000i |a=0>",
+                "qel> QAL & 0 $ \"a\"",
+                "REP 2",
+                "PX $a",
+                "END",
+                "qel>",
+            ]
+        );
+    }
+}
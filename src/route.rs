@@ -0,0 +1,510 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::ir::{Instruction, Operand, Program};
+use crate::qudit;
+
+/// Number of upcoming two-qubit gates the swap heuristic looks at besides the front layer.
+const EXTENDED_SET: usize = 20;
+const EXTENDED_WEIGHT: f64 = 0.5;
+const DECAY: f64 = 0.001;
+
+/// Which physical qubits of the device can take part in the same two-qubit gate.
+/// Edges are undirected, a CNOT can be applied either way round.
+#[derive(Debug, Clone)]
+pub struct CouplingMap {
+    pub edges: Vec<(usize, usize)>,
+    dist: Vec<Vec<usize>>,
+}
+
+impl CouplingMap {
+    /// Parses an edge list with one `a b` pair per line, `#` starts a comment.
+    pub fn parse(text: &str) -> Result<CouplingMap, String> {
+        let mut edges = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let nums: Vec<usize> = line
+                .split(|c: char| c.is_whitespace() || c == ',' || c == '-')
+                .filter(|s| !s.is_empty())
+                .map(|s| s.parse::<usize>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("ROUTE_ERROR: invalid edge '{line}' (line {})", n + 1))?;
+            match nums[..] {
+                [a, b] if a != b => edges.push((a, b)),
+                _ => {
+                    return Err(format!(
+                        "ROUTE_ERROR: invalid edge '{line}' (line {})",
+                        n + 1
+                    ))
+                }
+            }
+        }
+        let size = edges.iter().map(|(a, b)| a.max(b) + 1).max().unwrap_or(0);
+        let mut map = CouplingMap {
+            edges,
+            dist: vec![],
+        };
+        map.dist = (0..size).map(|p| map.bfs(p, size)).collect();
+        if map.dist.iter().flatten().any(|d| *d == usize::MAX) {
+            return Err("ROUTE_ERROR: the coupling map is not connected".to_string());
+        }
+        Ok(map)
+    }
+
+    pub fn size(&self) -> usize {
+        self.dist.len()
+    }
+
+    pub fn distance(&self, a: usize, b: usize) -> usize {
+        self.dist[a][b]
+    }
+
+    fn neighbours(&self, p: usize) -> impl Iterator<Item = usize> + '_ {
+        self.edges.iter().filter_map(move |&(a, b)| {
+            if a == p {
+                Some(b)
+            } else if b == p {
+                Some(a)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn bfs(&self, from: usize, size: usize) -> Vec<usize> {
+        let mut dist = vec![usize::MAX; size];
+        dist[from] = 0;
+        let mut queue = VecDeque::from([from]);
+        while let Some(p) = queue.pop_front() {
+            for n in self.neighbours(p) {
+                if dist[n] == usize::MAX {
+                    dist[n] = dist[p] + 1;
+                    queue.push_back(n);
+                }
+            }
+        }
+        dist
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Routed {
+    pub program: Program,
+    pub swaps: usize,
+    pub two_qubit_before: usize,
+    pub two_qubit_after: usize,
+    /// physical qubit every logical register starts on and ends on
+    pub initial: Vec<(String, usize)>,
+    pub layout: Vec<(String, usize)>,
+}
+
+/// Name of the register that stands for physical qubit `p` in routed programs.
+pub fn physical(p: usize) -> String {
+    format!("phys_{p}")
+}
+
+enum Step {
+    Gate(usize, Vec<usize>),
+    Swap(usize, usize),
+}
+
+/// The gates as the router sees them: logical qubit indices in operand order plus
+/// the dependencies between gates on the same qubit.
+struct Dag {
+    qubits: Vec<Vec<usize>>,
+    succs: Vec<Vec<usize>>,
+    preds: Vec<usize>,
+}
+
+impl Dag {
+    /// `wires` are the qubits of each gate plus the classical registers it touches,
+    /// gates sharing a wire keep their order.
+    fn new(qubits: Vec<Vec<usize>>, wires: &[Vec<usize>]) -> Dag {
+        let mut succs = vec![vec![]; qubits.len()];
+        let mut preds = vec![0; qubits.len()];
+        let mut last: HashMap<usize, usize> = HashMap::new();
+        for (g, qs) in wires.iter().enumerate() {
+            let mut before: Vec<usize> = qs.iter().filter_map(|q| last.get(q).copied()).collect();
+            before.sort();
+            before.dedup();
+            for p in before {
+                succs[p].push(g);
+                preds[g] += 1;
+            }
+            for q in qs {
+                last.insert(*q, g);
+            }
+        }
+        Dag {
+            qubits,
+            succs,
+            preds,
+        }
+    }
+}
+
+struct Router<'a> {
+    map: &'a CouplingMap,
+    dag: &'a Dag,
+    /// logical -> physical, and the inverse
+    l2p: Vec<usize>,
+    p2l: Vec<usize>,
+    decay: Vec<f64>,
+    steps: Vec<Step>,
+}
+
+impl Router<'_> {
+    fn executable(&self, g: usize) -> bool {
+        match self.dag.qubits[g][..] {
+            [a, b] => self.map.distance(self.l2p[a], self.l2p[b]) == 1,
+            _ => true,
+        }
+    }
+
+    fn exchange(&mut self, a: usize, b: usize) {
+        let (la, lb) = (self.p2l[a], self.p2l[b]);
+        self.p2l.swap(a, b);
+        self.l2p[la] = b;
+        self.l2p[lb] = a;
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.exchange(a, b);
+        self.steps.push(Step::Swap(a, b));
+    }
+
+    fn cost(&self, gates: &[usize]) -> f64 {
+        if gates.is_empty() {
+            return 0.0;
+        }
+        let sum: usize = gates
+            .iter()
+            .map(|g| match self.dag.qubits[*g][..] {
+                [a, b] => self.map.distance(self.l2p[a], self.l2p[b]),
+                _ => 0,
+            })
+            .sum();
+        sum as f64 / gates.len() as f64
+    }
+
+    /// Two-qubit gates that follow the front layer, found breadth first.
+    fn extended(&self, front: &[usize], preds: &[usize]) -> Vec<usize> {
+        let mut preds = preds.to_vec();
+        let mut queue: VecDeque<usize> = front.iter().copied().collect();
+        let mut set = vec![];
+        while let Some(g) = queue.pop_front() {
+            for s in &self.dag.succs[g] {
+                preds[*s] -= 1;
+                if preds[*s] == 0 {
+                    if self.dag.qubits[*s].len() == 2 {
+                        set.push(*s);
+                        if set.len() == EXTENDED_SET {
+                            return set;
+                        }
+                    }
+                    queue.push_back(*s);
+                }
+            }
+        }
+        set
+    }
+
+    fn best_swap(&mut self, front: &[usize], preds: &[usize]) -> (usize, usize) {
+        let extended = self.extended(front, preds);
+        let active: Vec<usize> = front
+            .iter()
+            .flat_map(|g| self.dag.qubits[*g].iter().map(|l| self.l2p[*l]))
+            .collect();
+        let mut best = None;
+        for &(a, b) in &self.map.edges {
+            if !active.contains(&a) && !active.contains(&b) {
+                continue;
+            }
+            self.exchange(a, b);
+            let h = self.decay[a].max(self.decay[b])
+                * (self.cost(front) + EXTENDED_WEIGHT * self.cost(&extended));
+            self.exchange(a, b);
+            if best.is_none_or(|(score, _)| h < score) {
+                best = Some((h, (a, b)));
+            }
+        }
+        best.unwrap().1
+    }
+
+    /// Moves the qubits of a stuck gate towards each other along a shortest path.
+    fn force(&mut self, g: usize) {
+        let (a, b) = (self.dag.qubits[g][0], self.dag.qubits[g][1]);
+        while self.map.distance(self.l2p[a], self.l2p[b]) > 1 {
+            let (pa, pb) = (self.l2p[a], self.l2p[b]);
+            let next = self
+                .map
+                .neighbours(pa)
+                .find(|n| self.map.distance(*n, pb) < self.map.distance(pa, pb))
+                .unwrap();
+            self.swap(pa, next);
+        }
+    }
+
+    fn run(&mut self) {
+        let dag = self.dag;
+        let mut preds = dag.preds.clone();
+        let mut front: Vec<usize> = (0..preds.len()).filter(|g| preds[*g] == 0).collect();
+        let mut stuck = 0;
+        while !front.is_empty() {
+            let ready: Vec<usize> = front
+                .iter()
+                .copied()
+                .filter(|g| self.executable(*g))
+                .collect();
+            if !ready.is_empty() {
+                front.retain(|g| !ready.contains(g));
+                for g in ready {
+                    let phys = dag.qubits[g].iter().map(|l| self.l2p[*l]).collect();
+                    self.steps.push(Step::Gate(g, phys));
+                    for s in &dag.succs[g] {
+                        preds[*s] -= 1;
+                        if preds[*s] == 0 {
+                            front.push(*s);
+                        }
+                    }
+                }
+                front.sort();
+                self.decay.iter_mut().for_each(|d| *d = 1.0);
+                stuck = 0;
+                continue;
+            }
+            stuck += 1;
+            if stuck > 10 * self.map.size() {
+                self.force(front[0]);
+                continue;
+            }
+            let (a, b) = self.best_swap(&front, &preds);
+            self.swap(a, b);
+            self.decay[a] += DECAY;
+            self.decay[b] += DECAY;
+        }
+    }
+}
+
+fn sabre(map: &CouplingMap, dag: &Dag, l2p: Vec<usize>) -> (Vec<Step>, Vec<usize>) {
+    let mut p2l = vec![0; l2p.len()];
+    for (l, p) in l2p.iter().enumerate() {
+        p2l[*p] = l;
+    }
+    let mut router = Router {
+        map,
+        dag,
+        l2p,
+        p2l,
+        decay: vec![1.0; map.size()],
+        steps: vec![],
+    };
+    router.run();
+    (router.steps, router.l2p)
+}
+
+/// Maps the logical registers of a program onto the physical qubits of a device and
+/// inserts SWAPs, each lowered to three CNOTs, until every two-qubit gate acts on
+/// neighbouring qubits.
+///
+/// SWAPs are picked with the SABRE heuristic: the one that most reduces the distance
+/// of the gates in the front layer, with a smaller weight on the gates right behind it.
+/// The initial layout comes from routing the program forwards and then backwards once.
+pub fn route(program: &Program, map: &CouplingMap) -> Result<Routed, String> {
+    let program = qudit::lower(program)?;
+    let mut names: Vec<String> = vec![];
+    let mut gates: Vec<&Instruction> = vec![];
+    for ins in &program.instructions {
+        if ins.op == "QAL" || ins.op == "FRE" {
+            continue;
+        }
+        if ins.qubits().len() > 2 {
+            return Err(format!(
+                "ROUTE_ERROR: {} acts on more than two qubits, lower it to CNTs first",
+                ins.op
+            ));
+        }
+        for q in ins.qubits() {
+            if !names.iter().any(|n| n == q) {
+                names.push(q.to_string());
+            }
+        }
+        gates.push(ins);
+    }
+    let n = map.size();
+    if names.len() > n {
+        return Err(format!(
+            "ROUTE_ERROR: the program needs {} qubits but the device only has {n}",
+            names.len()
+        ));
+    }
+    let index = |q: &str| names.iter().position(|n| n == q).unwrap();
+    let qubits: Vec<Vec<usize>> = gates
+        .iter()
+        .map(|g| g.qubits().into_iter().map(index).collect())
+        .collect();
+
+    let mut registers: Vec<&str> = vec![];
+    let wires: Vec<Vec<usize>> = gates
+        .iter()
+        .zip(&qubits)
+        .map(|(g, qs)| {
+            let mut w = qs.clone();
+            for r in g.registers() {
+                if !registers.contains(&r) {
+                    registers.push(r);
+                }
+                w.push(n + registers.iter().position(|x| *x == r).unwrap());
+            }
+            w
+        })
+        .collect();
+
+    let forward = Dag::new(qubits.clone(), &wires);
+    let reversed: Vec<Vec<usize>> = wires.iter().rev().cloned().collect();
+    let backward = Dag::new(qubits.iter().rev().cloned().collect(), &reversed);
+    let (_, layout) = sabre(map, &forward, (0..n).collect());
+    let (_, initial) = sabre(map, &backward, layout);
+    let (steps, layout) = sabre(map, &forward, initial.clone());
+
+    let mut instructions: Vec<Instruction> = vec![];
+    let mut allocated = vec![false; n];
+    let mut alloc = |p: usize, out: &mut Vec<Instruction>| {
+        if !allocated[p] {
+            allocated[p] = true;
+            let args = vec![
+                Operand::Sigil('&'),
+                Operand::Num(0.0),
+                Operand::Sigil('$'),
+                Operand::Name(physical(p)),
+            ];
+            out.push(Instruction::new("QAL", args));
+        }
+    };
+    let mut swaps = 0;
+    for step in steps {
+        match step {
+            Step::Swap(a, b) => {
+                alloc(a, &mut instructions);
+                alloc(b, &mut instructions);
+                instructions.extend(qudit::swap(&physical(a), &physical(b)));
+                swaps += 1;
+            }
+            Step::Gate(g, phys) => {
+                let mut ins = gates[g].clone();
+                let mut phys = phys.into_iter();
+                for arg in ins.args.iter_mut() {
+                    if let Operand::Qubit(q) = arg {
+                        let p = phys.next().unwrap();
+                        alloc(p, &mut instructions);
+                        *q = physical(p);
+                    }
+                }
+                instructions.push(ins);
+            }
+        }
+    }
+
+    let two_qubit = |p: &[Instruction]| p.iter().filter(|i| i.qubits().len() == 2).count();
+    let placed = |l2p: &[usize]| -> Vec<(String, usize)> {
+        names.iter().cloned().zip(l2p.iter().copied()).collect()
+    };
+    Ok(Routed {
+        two_qubit_before: two_qubit(&program.instructions),
+        two_qubit_after: two_qubit(&instructions),
+        program: Program { instructions },
+        swaps,
+        initial: placed(&initial),
+        layout: placed(&layout),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::complex::ZERO;
+    use crate::ir::parse_program;
+    use crate::unitary::{phase_distance, unitary, Matrix};
+
+    const LINE: &str = "0 1\n1 2\n2 3\n";
+    const RING: &str = "0 1\n1 2\n2 3\n3 4\n4 0\n";
+
+    /// Gates between every pair of `names`, so most of them need SWAPs on a sparse map.
+    fn program(names: &[&str]) -> Program {
+        let mut text: String = names
+            .iter()
+            .map(|q| format!("QAL & 0 $ \"{q}\"\nHAD ${q}\n"))
+            .collect();
+        for (k, a) in names.iter().enumerate() {
+            for b in &names[k + 1..] {
+                text.push_str(&format!("CNT ${a} ${b}\nT ${b}\nCY ${b} ${a}\n"));
+            }
+        }
+        parse_program(&text).unwrap()
+    }
+
+    fn index(p: &str) -> usize {
+        p.strip_prefix("phys_").unwrap().parse().unwrap()
+    }
+
+    /// The unitary of the routed program over the logical registers: inputs are read on
+    /// the `initial` physical qubits and outputs on the `layout` ones.
+    fn logical(routed: &Routed) -> Matrix {
+        let order: Vec<String> = routed.initial.iter().map(|(_, p)| physical(*p)).collect();
+        let v = unitary(&routed.program, &order, &[]).unwrap();
+        let bit = |p: usize| routed.initial.iter().position(|(_, q)| *q == p).unwrap();
+        let mut u = vec![vec![ZERO; v.len()]; v.len()];
+        for (row, amps) in v.iter().enumerate() {
+            let moved: usize = routed
+                .layout
+                .iter()
+                .enumerate()
+                .map(|(k, (_, p))| (row >> bit(*p) & 1) << k)
+                .sum();
+            u[moved] = amps.clone();
+        }
+        u
+    }
+
+    fn check(names: &[&str], edges: &str) {
+        let map = CouplingMap::parse(edges).unwrap();
+        let program = program(names);
+        let routed = route(&program, &map).unwrap();
+        assert!(routed.swaps > 0);
+        for ins in &routed.program.instructions {
+            if let [a, b] = ins.qubits()[..] {
+                let (a, b) = (index(a), index(b));
+                assert!(
+                    map.edges.contains(&(a, b)) || map.edges.contains(&(b, a)),
+                    "{ins}"
+                );
+            }
+        }
+        let names: Vec<String> = routed.initial.iter().map(|(n, _)| n.clone()).collect();
+        let expected = unitary(&program, &names, &[]).unwrap();
+        let error = phase_distance(&expected, &logical(&routed));
+        assert!(error < 1e-9, "off by {error:.1e}");
+    }
+
+    #[test]
+    fn line() {
+        check(&["a", "b", "c", "d"], LINE);
+    }
+
+    #[test]
+    fn ring() {
+        check(&["a", "b", "c", "d", "e"], RING);
+    }
+
+    #[test]
+    fn invalid_maps() {
+        let e = CouplingMap::parse("0 1\n1 1\n").unwrap_err();
+        assert_eq!(e, "ROUTE_ERROR: invalid edge '1 1' (line 2)");
+        let e = CouplingMap::parse("0 1\n2 3\n").unwrap_err();
+        assert_eq!(e, "ROUTE_ERROR: the coupling map is not connected");
+        // a qubit below the largest index that no edge reaches
+        assert!(CouplingMap::parse("0 2\n").is_err());
+    }
+}
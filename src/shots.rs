@@ -0,0 +1,356 @@
+use crate::complex::Complex;
+use crate::ir::{Instruction, Program};
+use crate::json::Json;
+use crate::sim::{Engine, Gate, Machine, Rng, Simulator, Statevector};
+use crate::stabilizer::Tableau;
+
+/// Instructions that may follow the first measurement without making it a mid-circuit one.
+const TAIL: [&str; 6] = ["MES", "BAL", "BCP", "BFR", "FRE", "DFR"];
+
+/// Outcome counts of a multi-shot run.
+#[derive(Debug, Clone)]
+pub struct Counts {
+    /// the engine that ran the shots
+    pub simulator: &'static str,
+    pub shots: usize,
+    pub seed: u64,
+    /// the statevector was simulated once and sampled, instead of once per shot
+    pub sampled: bool,
+    /// per classical register the observed bit strings (bit 0 first), most frequent first
+    pub registers: Tally,
+}
+
+/// Index from which on the program only measures and handles classical registers, or `None`
+/// if a gate follows a measurement.
+pub(crate) fn terminal(program: &Program) -> Option<usize> {
+    let first = program
+        .instructions
+        .iter()
+        .position(|i| i.op == "MES")
+        .unwrap_or(program.instructions.len());
+    program.instructions[first..]
+        .iter()
+        .all(|i| TAIL.contains(&i.op.as_str()))
+        .then_some(first)
+}
+
+/// The instructions before `split`. `terminal` never splits inside a loop, so every `REP`
+/// of the prefix keeps its `END`.
+pub(crate) fn prefix(program: &Program, split: usize) -> Program {
+    Program {
+        instructions: program.instructions[..split].to_vec(),
+    }
+}
+
+/// A computational basis state, enough to replay the measurements of a sampled shot.
+#[derive(Debug, Clone)]
+pub(crate) struct Basis(Vec<bool>);
+
+impl Engine for Basis {
+    fn alloc(&mut self) -> Result<usize, String> {
+        self.0.push(false);
+        Ok(self.0.len() - 1)
+    }
+
+    fn apply(&mut self, gate: &Gate, controls: &[usize], target: usize) -> Result<(), String> {
+        match gate {
+            Gate::X if controls.iter().all(|c| self.0[*c]) => self.0[target] ^= true,
+            Gate::X => {}
+            _ => return Err(format!("SIM_ERROR: {gate:?} on a sampled basis state")),
+        }
+        Ok(())
+    }
+
+    fn measure(&mut self, q: usize, _: &mut Rng) -> Result<bool, String> {
+        Ok(self.0[q])
+    }
+
+    fn set(&mut self, q: usize, a: Complex, b: Complex) -> Result<(), String> {
+        match (a.abs() < 1e-12, b.abs() < 1e-12) {
+            (true, false) => self.0[q] = true,
+            (false, true) => self.0[q] = false,
+            _ => return Err("SIM_ERROR: SET to a superposition on a sampled basis state".into()),
+        }
+        Ok(())
+    }
+
+    fn amplitudes(&self, q: usize) -> Result<(Complex, Complex), String> {
+        let one = Complex::real(1.0);
+        Ok(if self.0[q] {
+            (Complex::default(), one)
+        } else {
+            (one, Complex::default())
+        })
+    }
+}
+
+pub(crate) fn tally(
+    registers: &mut Vec<(String, Vec<(String, usize)>)>,
+    outcome: Vec<(String, String)>,
+) {
+    for (name, bits) in outcome {
+        let k = match registers.iter().position(|(n, _)| *n == name) {
+            Some(k) => k,
+            None => {
+                registers.push((name, vec![]));
+                registers.len() - 1
+            }
+        };
+        let counts = &mut registers[k].1;
+        match counts.iter_mut().find(|(b, _)| *b == bits) {
+            Some(entry) => entry.1 += 1,
+            None => counts.push((bits, 1)),
+        }
+    }
+}
+
+/// Continues a shot from basis state `idx` of the `n` qubits the machine `m` left behind
+/// and runs `tail` on it. `after` sees every instruction once it was executed.
+pub(crate) fn replay<E: Engine>(
+    m: &Machine<E>,
+    n: usize,
+    idx: usize,
+    tail: &[Instruction],
+    mut after: impl FnMut(&mut Machine<Basis>, &Instruction) -> Result<(), String>,
+) -> Result<Vec<(String, String)>, String> {
+    let basis = Basis((0..n).map(|q| idx >> q & 1 == 1).collect());
+    let mut shot = Machine::new(basis, m.externs.clone(), 0);
+    shot.qubits = m.qubits.clone();
+    shot.qudits = m.qudits.clone();
+    shot.registers = m.registers.clone();
+    shot.measurements = m.measurements.clone();
+    for ins in tail {
+        shot.step(ins)?;
+        after(&mut shot, ins)?;
+    }
+    Ok(shot.outcome())
+}
+
+/// Per classical register the bit strings seen so far and how often.
+pub(crate) type Tally = Vec<(String, Vec<(String, usize)>)>;
+
+/// Runs `program` `shots` times on `sim` and counts the bit strings of every classical
+/// register.
+///
+/// If nothing but measurements and classical bookkeeping follows the first `MES`, the
+/// program is simulated once up to that point. The statevector then draws the basis states
+/// of all shots from its distribution, the tableau measures a copy of itself per shot.
+/// Otherwise every shot is simulated from the start. `REP` blocks run in place, a loop
+/// before the first `MES` is walked once and not written out.
+pub fn run(
+    program: &Program,
+    externs: &[f64],
+    shots: usize,
+    seed: u64,
+    sim: Simulator,
+) -> Result<Counts, String> {
+    let split = terminal(program);
+    let registers = match (sim, split) {
+        (Simulator::Statevector, Some(split)) => {
+            let mut rng = Rng::new(seed);
+            let mut m = Machine::new(Statevector::new(), externs.to_vec(), seed);
+            m.run(&prefix(program, split))?;
+            let probabilities: Vec<f64> = m.engine.amps.iter().map(|a| a.norm_sqr()).collect();
+            let sampler = Sampler::new(&probabilities);
+            let mut registers = vec![];
+            for _ in 0..shots {
+                let idx = sampler.pick(&mut rng);
+                let tail = &program.instructions[split..];
+                let outcome = replay(&m, m.engine.n, idx, tail, |_, _| Ok(()))?;
+                tally(&mut registers, outcome);
+            }
+            registers
+        }
+        (Simulator::Stabilizer, Some(split)) => {
+            branch(program, externs, shots, seed, split, Tableau::new())?
+        }
+        (Simulator::Statevector, None) => {
+            resimulate(program, externs, shots, seed, Statevector::new)?
+        }
+        (Simulator::Stabilizer, None) => resimulate(program, externs, shots, seed, Tableau::new)?,
+    };
+    Ok(Counts::new(
+        sim.name(),
+        shots,
+        seed,
+        split.is_some(),
+        registers,
+    ))
+}
+
+/// Runs the program up to `split` once and every shot on its own copy of the machine.
+fn branch<E: Engine + Clone>(
+    program: &Program,
+    externs: &[f64],
+    shots: usize,
+    seed: u64,
+    split: usize,
+    engine: E,
+) -> Result<Tally, String> {
+    let mut rng = Rng::new(seed);
+    let mut m = Machine::new(engine, externs.to_vec(), seed);
+    m.run(&prefix(program, split))?;
+    let mut registers = vec![];
+    for _ in 0..shots {
+        let mut shot = m.clone();
+        shot.rng = Rng::new(rng.next_u64());
+        for ins in &program.instructions[split..] {
+            shot.step(ins)?;
+        }
+        tally(&mut registers, shot.outcome());
+    }
+    Ok(registers)
+}
+
+fn resimulate<E: Engine>(
+    program: &Program,
+    externs: &[f64],
+    shots: usize,
+    seed: u64,
+    engine: impl Fn() -> E,
+) -> Result<Tally, String> {
+    let mut rng = Rng::new(seed);
+    let mut registers = vec![];
+    for _ in 0..shots {
+        let mut m = Machine::new(engine(), externs.to_vec(), rng.next_u64());
+        m.run(program)?;
+        tally(&mut registers, m.outcome());
+    }
+    Ok(registers)
+}
+
+/// Draws basis state indices from a discrete distribution.
+pub(crate) struct Sampler(Vec<f64>);
+
+impl Sampler {
+    pub(crate) fn new(probabilities: &[f64]) -> Sampler {
+        let mut total = 0.0;
+        Sampler(
+            probabilities
+                .iter()
+                .map(|p| {
+                    total += p.max(0.0);
+                    total
+                })
+                .collect(),
+        )
+    }
+
+    pub(crate) fn pick(&self, rng: &mut Rng) -> usize {
+        let x = rng.next_f64() * self.0.last().copied().unwrap_or(0.0);
+        self.0.partition_point(|c| *c <= x).min(self.0.len() - 1)
+    }
+}
+
+impl Counts {
+    /// Sorts the tallied outcomes, most frequent first.
+    pub(crate) fn new(
+        simulator: &'static str,
+        shots: usize,
+        seed: u64,
+        sampled: bool,
+        mut registers: Tally,
+    ) -> Counts {
+        for (_, counts) in registers.iter_mut() {
+            counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        }
+        Counts {
+            simulator,
+            shots,
+            seed,
+            sampled,
+            registers,
+        }
+    }
+
+    /// Relative frequency of `bits` in register `name`.
+    pub fn share(&self, name: &str, bits: &str) -> f64 {
+        self.registers
+            .iter()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, counts)| counts.iter().find(|(b, _)| b == bits))
+            .map_or(0.0, |(_, n)| *n as f64 / self.shots.max(1) as f64)
+    }
+
+    /// One block per register, each outcome with its count, share and a bar.
+    pub fn histogram(&self) -> String {
+        let how = if self.sampled {
+            "sampled from a single simulation"
+        } else {
+            "simulated per shot"
+        };
+        let mut out = format!(
+            "{} shots, seed {}, {} simulator ({how})\n",
+            self.shots, self.seed, self.simulator
+        );
+        for (name, counts) in &self.registers {
+            out.push_str(&format!("{name}\n"));
+            let width = counts.iter().map(|(b, _)| b.len()).max().unwrap_or(0);
+            for (bits, n) in counts {
+                let share = *n as f64 / self.shots.max(1) as f64;
+                out.push_str(&format!(
+                    "  {bits:<width$}  {n:>6}  {:>5.1}%  {}\n",
+                    share * 100.0,
+                    "#".repeat((share * 40.0).round() as usize)
+                ));
+            }
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> Json {
+        let registers = self
+            .registers
+            .iter()
+            .map(|(name, counts)| {
+                let counts = counts
+                    .iter()
+                    .map(|(bits, n)| (bits.clone(), Json::Num(*n as f64)))
+                    .collect();
+                (name.clone(), Json::Obj(counts))
+            })
+            .collect();
+        Json::obj(vec![
+            ("simulator", Json::str(self.simulator)),
+            ("shots", Json::Num(self.shots as f64)),
+            ("seed", Json::Num(self.seed as f64)),
+            ("sampled", Json::Bool(self.sampled)),
+            ("registers", Json::Obj(registers)),
+        ])
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut out = "register,bits,count\n".to_string();
+        for (name, counts) in &self.registers {
+            for (bits, n) in counts {
+                out.push_str(&format!("{name},{bits},{n}\n"));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_program;
+
+    #[test]
+    fn loops_run_in_place() {
+        // written out the skipped block alone would be 10^16 instructions
+        let skipped = "REP 0\nREP 100000000\nREP 100000000\nPX $a\nEND\nEND\nEND\n";
+        for (body, sampled) in [("PX $a\nMES $a\n", true), ("REP 3\nMES $a\nEND\n", false)] {
+            let text = format!("QAL & 0 $ \"a\"\n{skipped}REP 3\nPX $a\nEND\n{body}");
+            let program = parse_program(&text).unwrap();
+            for sim in [Simulator::Statevector, Simulator::Stabilizer] {
+                let counts = run(&program, &[], 10, 0, sim).unwrap();
+                assert_eq!(counts.sampled, sampled);
+                let outcome = if sampled { "0" } else { "1" };
+                assert_eq!(
+                    counts.registers,
+                    [("$a".to_string(), vec![(outcome.to_string(), 10)])]
+                );
+            }
+        }
+    }
+}
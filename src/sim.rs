@@ -0,0 +1,545 @@
+use std::collections::HashMap;
+use std::f64::consts::FRAC_1_SQRT_2;
+
+use crate::complex::{Complex, I, ONE, ZERO};
+use crate::ir::{trace, Instruction, Operand, Program};
+use crate::qudit::{Op as QuditOp, Qudits};
+use crate::stabilizer::{describe, non_clifford};
+
+pub type Matrix2 = [[Complex; 2]; 2];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gate {
+    H,
+    X,
+    Y,
+    Z,
+    S,
+    Sdg,
+    T,
+    Tdg,
+    SX,
+    Id,
+    RX(f64),
+    RY(f64),
+    RZ(f64),
+}
+
+impl Gate {
+    /// Maps a backend instruction name to a single qubit gate, rotations take their angle from `angle`.
+    pub fn from_name(name: &str, angle: Option<f64>) -> Option<Gate> {
+        Some(match name {
+            "HAD" => Gate::H,
+            "PX" => Gate::X,
+            "PY" => Gate::Y,
+            "PZ" => Gate::Z,
+            "S" => Gate::S,
+            "SDG" => Gate::Sdg,
+            "T" => Gate::T,
+            "TDG" => Gate::Tdg,
+            "SX" => Gate::SX,
+            "ID" => Gate::Id,
+            "RX" => Gate::RX(angle?),
+            "RY" => Gate::RY(angle?),
+            "RZ" => Gate::RZ(angle?),
+            _ => return None,
+        })
+    }
+
+    pub fn matrix(&self) -> Matrix2 {
+        let h = Complex::real(FRAC_1_SQRT_2);
+        match *self {
+            Gate::H => [[h, h], [h, -h]],
+            Gate::X => [[ZERO, ONE], [ONE, ZERO]],
+            Gate::Y => [[ZERO, -I], [I, ZERO]],
+            Gate::Z => [[ONE, ZERO], [ZERO, -ONE]],
+            Gate::S => [[ONE, ZERO], [ZERO, I]],
+            Gate::Sdg => [[ONE, ZERO], [ZERO, -I]],
+            Gate::T => [
+                [ONE, ZERO],
+                [ZERO, Complex::phase(std::f64::consts::FRAC_PI_4)],
+            ],
+            Gate::Tdg => [
+                [ONE, ZERO],
+                [ZERO, Complex::phase(-std::f64::consts::FRAC_PI_4)],
+            ],
+            Gate::SX => {
+                let a = Complex::new(0.5, 0.5);
+                let b = Complex::new(0.5, -0.5);
+                [[a, b], [b, a]]
+            }
+            Gate::Id => [[ONE, ZERO], [ZERO, ONE]],
+            Gate::RX(t) => {
+                let c = Complex::real((t / 2.0).cos());
+                let s = Complex::new(0.0, -(t / 2.0).sin());
+                [[c, s], [s, c]]
+            }
+            Gate::RY(t) => {
+                let c = Complex::real((t / 2.0).cos());
+                let s = Complex::real((t / 2.0).sin());
+                [[c, -s], [s, c]]
+            }
+            Gate::RZ(t) => [
+                [Complex::phase(-t / 2.0), ZERO],
+                [ZERO, Complex::phase(t / 2.0)],
+            ],
+        }
+    }
+}
+
+/// splitmix64, good enough for sampling measurement outcomes reproducibly.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// A quantum state the `Machine` can drive. Registers are addressed by index, the machine keeps
+/// track of which backend register name lives where.
+pub trait Engine {
+    fn alloc(&mut self) -> Result<usize, String>;
+    fn apply(&mut self, gate: &Gate, controls: &[usize], target: usize) -> Result<(), String>;
+    fn measure(&mut self, q: usize, rng: &mut Rng) -> Result<bool, String>;
+    /// Replaces the state of `q` with a|0> + b|1>, `q` must not be entangled.
+    fn set(&mut self, q: usize, a: Complex, b: Complex) -> Result<(), String>;
+    /// The single qubit state of `q`, fails if `q` is entangled with other registers.
+    fn amplitudes(&self, q: usize) -> Result<(Complex, Complex), String>;
+
+    fn reset(&mut self, q: usize, rng: &mut Rng) -> Result<(), String> {
+        if self.measure(q, rng)? {
+            self.apply(&Gate::X, &[], q)?;
+        }
+        Ok(())
+    }
+
+    fn swap(&mut self, a: usize, b: usize) -> Result<(), String> {
+        self.apply(&Gate::X, &[a], b)?;
+        self.apply(&Gate::X, &[b], a)?;
+        self.apply(&Gate::X, &[a], b)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Statevector {
+    pub n: usize,
+    pub amps: Vec<Complex>,
+}
+
+impl Default for Statevector {
+    fn default() -> Self {
+        Statevector::new()
+    }
+}
+
+impl Statevector {
+    pub fn new() -> Statevector {
+        Statevector {
+            n: 0,
+            amps: vec![ONE],
+        }
+    }
+
+    fn reduced(&self, q: usize) -> (f64, f64, Complex) {
+        let bit = 1 << q;
+        let (mut p0, mut p1, mut c) = (0.0, 0.0, ZERO);
+        for i in 0..self.amps.len() {
+            if i & bit == 0 {
+                let (a, b) = (self.amps[i], self.amps[i | bit]);
+                p0 += a.norm_sqr();
+                p1 += b.norm_sqr();
+                c += a * b.conj();
+            }
+        }
+        (p0, p1, c)
+    }
+}
+
+/// Largest register count the statevector holds, 2^26 amplitudes.
+pub const MAX_QUBITS: usize = 26;
+
+impl Engine for Statevector {
+    fn alloc(&mut self) -> Result<usize, String> {
+        if self.n >= MAX_QUBITS {
+            return Err(format!(
+                "SIM_ERROR: the statevector simulator is limited to {MAX_QUBITS} qubits"
+            ));
+        }
+        self.amps.resize(self.amps.len() * 2, ZERO);
+        self.n += 1;
+        Ok(self.n - 1)
+    }
+
+    fn apply(&mut self, gate: &Gate, controls: &[usize], target: usize) -> Result<(), String> {
+        let m = gate.matrix();
+        let bit = 1 << target;
+        let mask = controls.iter().fold(0, |m, c| m | (1 << c));
+        if mask & bit != 0 {
+            return Err("SIM_ERROR: control and target are the same register".to_string());
+        }
+        for i in 0..self.amps.len() {
+            if i & bit == 0 && i & mask == mask {
+                let (a, b) = (self.amps[i], self.amps[i | bit]);
+                self.amps[i] = m[0][0] * a + m[0][1] * b;
+                self.amps[i | bit] = m[1][0] * a + m[1][1] * b;
+            }
+        }
+        Ok(())
+    }
+
+    fn measure(&mut self, q: usize, rng: &mut Rng) -> Result<bool, String> {
+        let bit = 1 << q;
+        let p1: f64 = self
+            .amps
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i & bit != 0)
+            .map(|(_, a)| a.norm_sqr())
+            .sum();
+        let outcome = rng.next_f64() < p1;
+        let norm = if outcome { p1 } else { 1.0 - p1 }.sqrt();
+        for (i, a) in self.amps.iter_mut().enumerate() {
+            if (i & bit != 0) == outcome {
+                *a = a.scale(1.0 / norm);
+            } else {
+                *a = ZERO;
+            }
+        }
+        Ok(outcome)
+    }
+
+    fn set(&mut self, q: usize, a: Complex, b: Complex) -> Result<(), String> {
+        let norm = (a.norm_sqr() + b.norm_sqr()).sqrt();
+        if norm == 0.0 {
+            return Err("SIM_ERROR: SET with a zero state".to_string());
+        }
+        let (a, b) = (a.scale(1.0 / norm), b.scale(1.0 / norm));
+        let (x0, x1) = self.amplitudes(q)?;
+        let bit = 1 << q;
+        for i in 0..self.amps.len() {
+            if i & bit == 0 {
+                let rest = x0.conj() * self.amps[i] + x1.conj() * self.amps[i | bit];
+                self.amps[i] = rest * a;
+                self.amps[i | bit] = rest * b;
+            }
+        }
+        Ok(())
+    }
+
+    fn amplitudes(&self, q: usize) -> Result<(Complex, Complex), String> {
+        let (p0, p1, c) = self.reduced(q);
+        if (p0 * p0 + p1 * p1 + 2.0 * c.norm_sqr() - 1.0).abs() > 1e-9 {
+            return Err(
+                "SIM_ERROR: register is entangled, its state can't be read or set".to_string(),
+            );
+        }
+        if p0 > 1e-12 {
+            let x0 = p0.sqrt();
+            Ok((Complex::real(x0), c.conj().scale(1.0 / x0)))
+        } else {
+            Ok((ZERO, ONE))
+        }
+    }
+}
+
+/// The engines a program can be executed on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Simulator {
+    Statevector,
+    Stabilizer,
+}
+
+impl Simulator {
+    /// `auto` leaves the choice to `select`.
+    pub fn parse(name: &str) -> Result<Option<Simulator>, String> {
+        match name {
+            "auto" => Ok(None),
+            "statevector" => Ok(Some(Simulator::Statevector)),
+            "stabilizer" => Ok(Some(Simulator::Stabilizer)),
+            _ => Err(format!(
+                "unknown simulator {name}, expected auto, statevector or stabilizer"
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Simulator::Statevector => "statevector",
+            Simulator::Stabilizer => "stabilizer",
+        }
+    }
+
+    /// Clifford-only programs run on the stabilizer tableau, everything else on the
+    /// statevector as long as it fits.
+    pub fn select(program: &Program, requested: Option<Simulator>) -> Result<Simulator, String> {
+        let gates = non_clifford(program);
+        match requested {
+            Some(Simulator::Stabilizer) if !gates.is_empty() => Err(format!(
+                "STABILIZER_ERROR: the program is not Clifford-only, non-Clifford instructions: {}",
+                describe(&gates)
+            )),
+            Some(sim) => Ok(sim),
+            None if gates.is_empty() => Ok(Simulator::Stabilizer),
+            None => {
+                let qubits = peak_qubits(program);
+                if qubits > MAX_QUBITS {
+                    return Err(format!(
+                        "SIM_ERROR: the program needs {qubits} qubits, more than the statevector simulator's {MAX_QUBITS}, and is not Clifford-only for the stabilizer simulator, non-Clifford instructions: {}",
+                        describe(&gates)
+                    ));
+                }
+                Ok(Simulator::Statevector)
+            }
+        }
+    }
+}
+
+/// Qubits allocated at the same time, freed ones are reused by the machine.
+fn peak_qubits(program: &Program) -> usize {
+    let (mut live, mut peak) = (0usize, 0);
+    for ins in &program.instructions {
+        match ins.op.as_str() {
+            "QAL" => live += 1,
+            "FRE" => live = live.saturating_sub(1),
+            _ => {}
+        }
+        peak = peak.max(live);
+    }
+    peak
+}
+
+/// Executes backend instructions on an `Engine`, resolving register names to engine indices.
+#[derive(Debug, Clone)]
+pub struct Machine<E: Engine> {
+    pub engine: E,
+    pub qubits: HashMap<String, usize>,
+    pub qudits: Qudits<usize>,
+    pub measurements: Vec<(String, bool)>,
+    /// classical registers in declaration order, bit k is `MES $q "name" k`
+    pub registers: Vec<(String, Vec<bool>)>,
+    pub externs: Vec<f64>,
+    pub rng: Rng,
+    free: Vec<usize>,
+}
+
+impl<E: Engine> Machine<E> {
+    pub fn new(engine: E, externs: Vec<f64>, seed: u64) -> Machine<E> {
+        Machine {
+            engine,
+            qubits: HashMap::new(),
+            qudits: Qudits::new(),
+            measurements: vec![],
+            registers: vec![],
+            externs,
+            rng: Rng::new(seed),
+            free: vec![],
+        }
+    }
+
+    /// Executes `program`, `REP` blocks run in place without being written out.
+    pub fn run(&mut self, program: &Program) -> Result<(), String> {
+        for ins in trace(program)? {
+            self.step(ins)?;
+        }
+        Ok(())
+    }
+
+    /// The classical result of a run, every register as a bit string with bit 0 first.
+    /// Programs without registers report their measurements by qubit instead.
+    pub fn outcome(&self) -> Vec<(String, String)> {
+        let bits = |bs: &[bool]| bs.iter().map(|b| if *b { '1' } else { '0' }).collect();
+        if self.registers.is_empty() {
+            let mut out: Vec<(String, String)> = vec![];
+            for (q, b) in &self.measurements {
+                let name = format!("${q}");
+                match out.iter_mut().find(|(n, _)| *n == name) {
+                    Some(entry) => entry.1 = bits(&[*b]),
+                    None => out.push((name, bits(&[*b]))),
+                }
+            }
+            return out;
+        }
+        self.registers
+            .iter()
+            .map(|(name, bs)| (name.clone(), bits(bs)))
+            .collect()
+    }
+
+    fn qubit(&self, op: Option<&Operand>) -> Result<usize, String> {
+        match op {
+            Some(Operand::Qubit(n)) => self
+                .qubits
+                .get(n)
+                .copied()
+                .ok_or_else(|| format!("SIM_ERROR: unknown qubit register ${n}")),
+            other => Err(format!("SIM_ERROR: expected qubit register, got {other:?}")),
+        }
+    }
+
+    pub fn number(&self, op: Option<&Operand>) -> Result<f64, String> {
+        match op {
+            Some(Operand::Num(n)) => Ok(*n),
+            Some(Operand::Extern(i)) => self.externs.get(*i).copied().ok_or_else(|| {
+                format!("SIM_ERROR: extern argument ??{i} was not provided (see args.txt)")
+            }),
+            Some(Operand::Scaled(i, k)) => Ok(self.number(Some(&Operand::Extern(*i)))? * k),
+            other => Err(format!("SIM_ERROR: expected number, got {other:?}")),
+        }
+    }
+
+    fn register(&self, op: Option<&Operand>) -> Result<usize, String> {
+        match op {
+            Some(Operand::Name(n)) => self
+                .registers
+                .iter()
+                .position(|(r, _)| r == n)
+                .ok_or_else(|| format!("SIM_ERROR: unknown classical register \"{n}\"")),
+            other => Err(format!(
+                "SIM_ERROR: expected classical register, got {other:?}"
+            )),
+        }
+    }
+
+    pub fn step(&mut self, ins: &Instruction) -> Result<(), String> {
+        let mut qudits = std::mem::take(&mut self.qudits);
+        let ops = qudits.step(ins, |op| self.qubit(op), |op| self.number(op));
+        self.qudits = qudits;
+        if let Some(ops) = ops? {
+            for op in ops {
+                match op {
+                    QuditOp::Gate(name, q) => {
+                        let gate = Gate::from_name(name, None).unwrap();
+                        self.engine.apply(&gate, &[], q)?;
+                    }
+                    QuditOp::Cnot(c, t) => self.engine.apply(&Gate::X, &[c], t)?,
+                    QuditOp::Swap(a, b) => self.engine.swap(a, b)?,
+                }
+            }
+            return Ok(());
+        }
+        let a = &ins.args;
+        match ins.op.as_str() {
+            "QAL" => {
+                let name = ins
+                    .alloc_name()
+                    .ok_or("SIM_ERROR: QAL without register name")?;
+                // hand out the lowest freed index first, like the register allocator assumes
+                let lowest = (0..self.free.len()).min_by_key(|i| self.free[*i]);
+                let q = match lowest.map(|i| self.free.swap_remove(i)) {
+                    Some(q) => {
+                        self.engine.reset(q, &mut self.rng)?;
+                        q
+                    }
+                    None => self.engine.alloc()?,
+                };
+                self.qubits.insert(name.to_string(), q);
+            }
+            "FRE" => {
+                let name = ins
+                    .alloc_name()
+                    .ok_or("SIM_ERROR: FRE without register name")?;
+                if let Some(q) = self.qubits.remove(name) {
+                    if !self.qubits.values().any(|v| *v == q) {
+                        self.free.push(q);
+                    }
+                }
+            }
+            "SET" => {
+                let q = self.qubit(a.first())?;
+                let x0 = self.number(a.get(1))?;
+                let x1 = self.number(a.get(2))?;
+                self.engine.set(q, Complex::real(x0), Complex::real(x1))?;
+            }
+            "CPY" if matches!(a.first(), Some(Operand::Qubit(_))) => {
+                let dst = self.qubit(a.first())?;
+                let src = self.qubit(a.get(1))?;
+                let (x0, x1) = self.engine.amplitudes(src)?;
+                self.engine.set(dst, x0, x1)?;
+            }
+            "CPY" | "DCP" => {
+                return Err("SIM_ERROR: copying qudit registers is not supported".to_string())
+            }
+            "CNT" => {
+                let c = self.qubit(a.first())?;
+                let t = self.qubit(a.get(1))?;
+                self.engine.apply(&Gate::X, &[c], t)?;
+            }
+            "CY" => {
+                let c = self.qubit(a.first())?;
+                let t = self.qubit(a.get(1))?;
+                self.engine.apply(&Gate::Y, &[c], t)?;
+            }
+            "TOF" => {
+                let c0 = self.qubit(a.first())?;
+                let c1 = self.qubit(a.get(1))?;
+                let t = self.qubit(a.get(2))?;
+                self.engine.apply(&Gate::X, &[c0, c1], t)?;
+            }
+            "MES" => {
+                let q = self.qubit(a.first())?;
+                let bit = self.engine.measure(q, &mut self.rng)?;
+                if let Some(Operand::Qubit(name)) = a.first() {
+                    self.measurements.push((name.clone(), bit));
+                }
+                if a.len() == 3 {
+                    let r = self.register(a.get(1))?;
+                    let k = self.number(a.get(2))? as usize;
+                    let (name, bits) = &mut self.registers[r];
+                    *bits.get_mut(k).ok_or_else(|| {
+                        format!("SIM_ERROR: bit {k} is out of range for \"{name}\"")
+                    })? = bit;
+                }
+            }
+            "BAL" => {
+                let name = ins
+                    .alloc_name()
+                    .ok_or("SIM_ERROR: BAL without register name")?;
+                let width = self.number(a.get(1))? as usize;
+                self.registers.retain(|(n, _)| n != name);
+                self.registers.push((name.to_string(), vec![false; width]));
+            }
+            "BFR" => {
+                let r = self.register(a.first())?;
+                self.registers.remove(r);
+            }
+            "BCP" => {
+                let dst = self.register(a.first())?;
+                let src = self.register(a.get(1))?;
+                let bits = self.registers[src].1.clone();
+                if bits.len() != self.registers[dst].1.len() {
+                    return Err(format!(
+                        "SIM_ERROR: can not copy {} bits into a register of {}",
+                        bits.len(),
+                        self.registers[dst].1.len()
+                    ));
+                }
+                self.registers[dst].1 = bits;
+            }
+            op => {
+                let angle = match a.get(1) {
+                    Some(_) => Some(self.number(a.get(1))?),
+                    None => None,
+                };
+                let gate = Gate::from_name(op, angle)
+                    .ok_or_else(|| format!("SIM_ERROR: unknown instruction {op}"))?;
+                let q = self.qubit(a.first())?;
+                self.engine.apply(&gate, &[], q)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,431 @@
+use std::f64::consts::FRAC_1_SQRT_2;
+
+use crate::complex::{Complex, ONE, ZERO};
+use crate::ir::{Instruction, Operand, Program};
+use crate::sim::{Engine, Gate, Rng};
+
+/// Instructions the tableau can execute, loops included. `SET` only counts if its state is a
+/// stabilizer state.
+const CLIFFORD: [&str; 28] = [
+    "QAL", "FRE", "DAL", "DFR", "BAL", "BCP", "BFR", "MES", "CPY", "HAD", "PX", "PY", "PZ", "S",
+    "SDG", "SX", "ID", "CNT", "CY", "CMB", "DCB", "DTR", "TR", "DHAD", "DPX", "DCX", "REP", "END",
+];
+
+/// `SET` amplitudes that describe a stabilizer state, up to normalisation.
+fn stabilizer_state(x0: f64, x1: f64) -> bool {
+    let norm = (x0 * x0 + x1 * x1).sqrt();
+    norm > 0.0 && {
+        let (a, b) = ((x0 / norm).abs(), (x1 / norm).abs());
+        a < 1e-9 || b < 1e-9 || ((a - FRAC_1_SQRT_2).abs() < 1e-9 && (b - a).abs() < 1e-9)
+    }
+}
+
+fn is_clifford(ins: &Instruction) -> bool {
+    match (ins.op.as_str(), ins.args.get(1), ins.args.get(2)) {
+        ("SET", Some(Operand::Num(x0)), Some(Operand::Num(x1))) => stabilizer_state(*x0, *x1),
+        ("SET", _, _) => false,
+        (op, _, _) => CLIFFORD.contains(&op),
+    }
+}
+
+/// The instructions of `program` outside the Clifford group, with how often each occurs.
+pub fn non_clifford(program: &Program) -> Vec<(String, usize)> {
+    let mut found: Vec<(String, usize)> = vec![];
+    for ins in program.instructions.iter().filter(|i| !is_clifford(i)) {
+        match found.iter_mut().find(|(op, _)| *op == ins.op) {
+            Some(entry) => entry.1 += 1,
+            None => found.push((ins.op.clone(), 1)),
+        }
+    }
+    found
+}
+
+/// Lists the gates of `non_clifford` as `T (3x), RX (1x)`.
+pub fn describe(gates: &[(String, usize)]) -> String {
+    let gates: Vec<String> = gates.iter().map(|(op, n)| format!("{op} ({n}x)")).collect();
+    gates.join(", ")
+}
+
+/// A Pauli product with sign, one bit per qubit packed into words.
+#[derive(Debug, Clone, Default)]
+struct Row {
+    x: Vec<u64>,
+    z: Vec<u64>,
+    r: bool,
+}
+
+impl Row {
+    fn get(bits: &[u64], q: usize) -> bool {
+        bits[q / 64] >> (q % 64) & 1 == 1
+    }
+
+    fn flip(bits: &mut [u64], q: usize) {
+        bits[q / 64] ^= 1 << (q % 64);
+    }
+
+    fn x(&self, q: usize) -> bool {
+        Row::get(&self.x, q)
+    }
+
+    fn z(&self, q: usize) -> bool {
+        Row::get(&self.z, q)
+    }
+
+    fn single(words: usize, q: usize, z: bool) -> Row {
+        let mut row = Row {
+            x: vec![0; words],
+            z: vec![0; words],
+            r: false,
+        };
+        Row::flip(if z { &mut row.z } else { &mut row.x }, q);
+        row
+    }
+
+    /// `self = self * other`, the phase exponent of the product is tracked mod 4.
+    fn multiply(&mut self, other: &Row) {
+        let mut phase = 2 * (self.r as i64 + other.r as i64);
+        for w in 0..self.x.len() {
+            let (x1, z1, x2, z2) = (other.x[w], other.z[w], self.x[w], self.z[w]);
+            // g(x1, z1, x2, z2) of Aaronson and Gottesman, split into the +1 and -1 cases
+            let plus = (x1 & !z1 & x2 & z2) | (x1 & z1 & !x2 & z2) | (!x1 & z1 & x2 & !z2);
+            let minus = (x1 & !z1 & !x2 & z2) | (x1 & z1 & x2 & !z2) | (!x1 & z1 & x2 & z2);
+            phase += plus.count_ones() as i64 - minus.count_ones() as i64;
+            self.x[w] ^= x1;
+            self.z[w] ^= z1;
+        }
+        self.r = phase.rem_euclid(4) == 2;
+    }
+}
+
+/// CHP stabilizer tableau (Aaronson, Gottesman 2004): `n` destabilizer and `n` stabilizer
+/// generators. Gates cost O(n), measurements O(n^2 / 64), so thousands of qubits are fine
+/// as long as the program stays within the Clifford group.
+#[derive(Debug, Clone, Default)]
+pub struct Tableau {
+    pub n: usize,
+    destabilizers: Vec<Row>,
+    stabilizers: Vec<Row>,
+}
+
+impl Tableau {
+    pub fn new() -> Tableau {
+        Tableau::default()
+    }
+
+    /// Generator `k`, the `n` destabilizers first, as its sign (`true` for -1) and the
+    /// non-identity factors `(register index, 'X' | 'Y' | 'Z')`. Without measurements these
+    /// are the images of X and Z on every register under the gates applied so far.
+    pub fn generator(&self, k: usize) -> (bool, Vec<(usize, char)>) {
+        let row = match k < self.n {
+            true => &self.destabilizers[k],
+            false => &self.stabilizers[k - self.n],
+        };
+        let factors = (0..self.n)
+            .filter_map(|q| match (row.x(q), row.z(q)) {
+                (true, true) => Some((q, 'Y')),
+                (true, false) => Some((q, 'X')),
+                (false, true) => Some((q, 'Z')),
+                (false, false) => None,
+            })
+            .collect();
+        (row.r, factors)
+    }
+
+    fn words(&self) -> usize {
+        self.n.div_ceil(64)
+    }
+
+    fn rows(&mut self) -> impl Iterator<Item = &mut Row> {
+        self.destabilizers
+            .iter_mut()
+            .chain(self.stabilizers.iter_mut())
+    }
+
+    fn hadamard(&mut self, q: usize) {
+        let (w, bit) = (q / 64, 1u64 << (q % 64));
+        for row in self.rows() {
+            let (x, z) = (row.x[w] & bit, row.z[w] & bit);
+            row.r ^= x != 0 && z != 0;
+            row.x[w] ^= x ^ z;
+            row.z[w] ^= x ^ z;
+        }
+    }
+
+    fn phase(&mut self, q: usize) {
+        for row in self.rows() {
+            let (x, z) = (row.x(q), row.z(q));
+            row.r ^= x && z;
+            if x {
+                Row::flip(&mut row.z, q);
+            }
+        }
+    }
+
+    fn phase_dagger(&mut self, q: usize) {
+        for row in self.rows() {
+            let (x, z) = (row.x(q), row.z(q));
+            row.r ^= x && !z;
+            if x {
+                Row::flip(&mut row.z, q);
+            }
+        }
+    }
+
+    /// Pauli `x`/`z` on `q`, X flips the sign of generators with a Z part and vice versa.
+    fn pauli(&mut self, q: usize, x: bool, z: bool) {
+        for row in self.rows() {
+            row.r ^= (x && row.z(q)) ^ (z && row.x(q));
+        }
+    }
+
+    fn cnot(&mut self, c: usize, t: usize) {
+        for row in self.rows() {
+            let (xc, zc, xt, zt) = (row.x(c), row.z(c), row.x(t), row.z(t));
+            row.r ^= xc && zt && !(xt ^ zc);
+            if xc {
+                Row::flip(&mut row.x, t);
+            }
+            if zt {
+                Row::flip(&mut row.z, c);
+            }
+        }
+    }
+
+    /// The outcome of measuring `q` if it is determined, computed without changing the state.
+    fn deterministic(&self, q: usize) -> Option<bool> {
+        if self.stabilizers.iter().any(|s| s.x(q)) {
+            return None;
+        }
+        let mut scratch = Row {
+            x: vec![0; self.words()],
+            z: vec![0; self.words()],
+            r: false,
+        };
+        for (d, s) in self.destabilizers.iter().zip(&self.stabilizers) {
+            if d.x(q) {
+                scratch.multiply(s);
+            }
+        }
+        Some(scratch.r)
+    }
+
+    /// Brings the unentangled qubit `q` to |0>.
+    fn clear(&mut self, q: usize) -> Result<(), String> {
+        self.amplitudes(q)?;
+        // q is a product factor, the random outcome does not affect any other qubit
+        if self.measure(q, &mut Rng::new(0))? {
+            self.pauli(q, true, false);
+        }
+        Ok(())
+    }
+}
+
+impl Engine for Tableau {
+    fn alloc(&mut self) -> Result<usize, String> {
+        let q = self.n;
+        self.n += 1;
+        let words = self.words();
+        for row in self.rows() {
+            row.x.resize(words, 0);
+            row.z.resize(words, 0);
+        }
+        self.destabilizers.push(Row::single(words, q, false));
+        self.stabilizers.push(Row::single(words, q, true));
+        Ok(q)
+    }
+
+    fn apply(&mut self, gate: &Gate, controls: &[usize], target: usize) -> Result<(), String> {
+        let t = target;
+        match (gate, controls) {
+            (_, [c]) if *c == t => {
+                return Err("SIM_ERROR: control and target are the same register".to_string())
+            }
+            (Gate::X, [c]) => self.cnot(*c, t),
+            (Gate::Z, [c]) => {
+                self.hadamard(t);
+                self.cnot(*c, t);
+                self.hadamard(t);
+            }
+            (Gate::Y, [c]) => {
+                self.phase_dagger(t);
+                self.cnot(*c, t);
+                self.phase(t);
+            }
+            (Gate::H, []) => self.hadamard(t),
+            (Gate::S, []) => self.phase(t),
+            (Gate::Sdg, []) => self.phase_dagger(t),
+            (Gate::SX, []) => {
+                self.hadamard(t);
+                self.phase(t);
+                self.hadamard(t);
+            }
+            (Gate::X, []) => self.pauli(t, true, false),
+            (Gate::Y, []) => self.pauli(t, true, true),
+            (Gate::Z, []) => self.pauli(t, false, true),
+            (Gate::Id, []) => {}
+            (gate, controls) => {
+                return Err(format!(
+                    "STABILIZER_ERROR: {gate:?} with {} controls is not a Clifford gate",
+                    controls.len()
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn measure(&mut self, q: usize, rng: &mut Rng) -> Result<bool, String> {
+        let Some(p) = self.stabilizers.iter().position(|s| s.x(q)) else {
+            return Ok(self.deterministic(q).unwrap());
+        };
+        let (pivot, n) = (self.stabilizers[p].clone(), self.n);
+        for (i, row) in self.rows().enumerate() {
+            if i != n + p && row.x(q) {
+                row.multiply(&pivot);
+            }
+        }
+        let outcome = rng.next_f64() < 0.5;
+        let mut z = Row::single(self.words(), q, true);
+        z.r = outcome;
+        self.destabilizers[p] = std::mem::replace(&mut self.stabilizers[p], z);
+        Ok(outcome)
+    }
+
+    fn set(&mut self, q: usize, a: Complex, b: Complex) -> Result<(), String> {
+        let (x0, x1) = (a.re, b.re);
+        if a.im != 0.0 || b.im != 0.0 || !stabilizer_state(x0, x1) {
+            return Err(format!(
+                "STABILIZER_ERROR: SET to {x0}|0> + {x1}|1> is not a stabilizer state"
+            ));
+        }
+        self.clear(q)?;
+        if x0.abs() < 1e-9 {
+            self.pauli(q, true, false);
+        } else if x1.abs() > 1e-9 {
+            if x0 * x1 < 0.0 {
+                self.pauli(q, true, false);
+            }
+            self.hadamard(q);
+        }
+        Ok(())
+    }
+
+    fn amplitudes(&self, q: usize) -> Result<(Complex, Complex), String> {
+        let h = Complex::real(FRAC_1_SQRT_2);
+        if let Some(one) = self.deterministic(q) {
+            return Ok(if one { (ZERO, ONE) } else { (ONE, ZERO) });
+        }
+        // an unentangled qubit is an eigenstate of X, Y or Z, try the other two bases
+        let mut probe = self.clone();
+        probe.hadamard(q);
+        if let Some(minus) = probe.deterministic(q) {
+            return Ok((h, if minus { -h } else { h }));
+        }
+        probe.hadamard(q);
+        probe.phase_dagger(q);
+        probe.hadamard(q);
+        if let Some(minus) = probe.deterministic(q) {
+            let i = Complex::new(0.0, FRAC_1_SQRT_2);
+            return Ok((h, if minus { -i } else { i }));
+        }
+        Err("SIM_ERROR: register is entangled, its state can't be read or set".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_program;
+    use crate::shots;
+    use crate::sim::{Machine, Simulator};
+
+    const GATES: [&str; 9] = ["HAD", "S", "SDG", "SX", "PX", "PY", "PZ", "CNT", "CY"];
+
+    /// `gates` random Clifford gates on `n` qubits, every qubit measured into `c` at the end,
+    /// with a mid-circuit measurement of qubit 0 into `m` if `mid` is set.
+    fn random_clifford(n: usize, gates: usize, seed: u64, mid: bool) -> Program {
+        let mut rng = Rng::new(seed);
+        let mut pick = |k: usize| (rng.next_u64() % k as u64) as usize;
+        let mut text: String = (0..n).map(|q| format!("QAL & 0 $ \"q{q}\"\n")).collect();
+        text.push_str(&format!("BAL \"c\" {n}\nBAL \"m\" 1\n"));
+        for g in 0..gates {
+            let op = GATES[pick(GATES.len())];
+            let a = pick(n);
+            match op {
+                "CNT" | "CY" => {
+                    let b = (a + 1 + pick(n - 1)) % n;
+                    text.push_str(&format!("{op} $q{a} $q{b}\n"));
+                }
+                _ => text.push_str(&format!("{op} $q{a}\n")),
+            }
+            if mid && g == gates / 2 {
+                text.push_str("MES $q0 \"m\" 0\n");
+            }
+        }
+        for q in 0..n {
+            text.push_str(&format!("MES $q{q} \"c\" {q}\n"));
+        }
+        parse_program(&text).unwrap()
+    }
+
+    #[test]
+    fn statistics_match_the_statevector() {
+        let shots = 4000;
+        for seed in 0..8 {
+            let program = random_clifford(4, 30, seed, seed % 2 == 1);
+            let run = |sim| shots::run(&program, &[], shots, seed, sim).unwrap();
+            let (ideal, chp) = (run(Simulator::Statevector), run(Simulator::Stabilizer));
+            for (name, counts) in &ideal.registers {
+                let seen = |c: &shots::Counts| {
+                    let (_, counts) = c.registers.iter().find(|(n, _)| n == name).unwrap();
+                    let mut bits: Vec<String> = counts.iter().map(|(b, _)| b.clone()).collect();
+                    bits.sort();
+                    bits
+                };
+                // stabilizer states spread evenly over their outcomes, at least 1/16 each
+                assert_eq!(seen(&ideal), seen(&chp), "seed {seed}, register {name}");
+                for (bits, _) in counts {
+                    let (p, q) = (ideal.share(name, bits), chp.share(name, bits));
+                    assert!(
+                        (p - q).abs() < 0.05,
+                        "seed {seed}: {name} = {bits}, {p} vs {q}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_non_clifford_gates() {
+        let program = parse_program("QAL & 0 $ \"a\"\nHAD $a\nT $a\nT $a\nRX $a 0.3\n").unwrap();
+        assert_eq!(non_clifford(&program).len(), 2);
+        let e = Simulator::select(&program, Some(Simulator::Stabilizer)).unwrap_err();
+        assert!(e.contains("T (2x), RX (1x)"), "{e}");
+        let mut m = Machine::new(Tableau::new(), vec![], 0);
+        let e = m.run(&program).unwrap_err();
+        assert!(e.starts_with("STABILIZER_ERROR"), "{e}");
+        // a SET to a state that isn't a stabilizer state
+        let set = parse_program("QAL & 0 $ \"a\"\nSET $a 0.6 0.8\n").unwrap();
+        assert_eq!(non_clifford(&set), [("SET".to_string(), 1)]);
+    }
+
+    #[test]
+    fn thousands_of_qubits() {
+        // a GHZ state: every qubit measures the same random bit
+        let n = 2000;
+        let mut text: String = (0..n).map(|q| format!("QAL & 0 $ \"q{q}\"\n")).collect();
+        text.push_str(&format!("BAL \"c\" {n}\nHAD $q0\n"));
+        for q in 1..n {
+            text.push_str(&format!("CNT $q{} $q{q}\n", q - 1));
+        }
+        for q in 0..n {
+            text.push_str(&format!("MES $q{q} \"c\" {q}\n"));
+        }
+        let program = parse_program(&text).unwrap();
+        assert_eq!(Simulator::select(&program, None), Ok(Simulator::Stabilizer));
+        let mut m = Machine::new(Tableau::new(), vec![], 0);
+        m.run(&program).unwrap();
+        let bits = &m.registers[0].1;
+        assert_eq!(bits.len(), n);
+        assert!(bits.iter().all(|b| *b == bits[0]));
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::f64::consts::FRAC_PI_4;
+use std::rc::Rc;
+
+use crate::ir::{loops, repetitions, Instruction, Operand, Program};
+use crate::json::Json;
+use crate::qudit::{self, Qudits};
+
+/// Instructions that manage registers and take no time on the device.
+const BOOKKEEPING: [&str; 7] = ["QAL", "FRE", "DAL", "DFR", "BAL", "BCP", "BFR"];
+
+/// T gates in the standard Toffoli decomposition.
+const TOFFOLI_T: usize = 7;
+
+/// Resource estimate of a compiled program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// qubit and qudit registers the program allocates
+    pub qubits: usize,
+    pub qudits: usize,
+    /// operations per instruction name, most frequent first
+    pub gates: Vec<(String, usize)>,
+    pub two_qubit: usize,
+    /// `T` and `TDG`, rotations by odd multiples of pi/4 and seven per Toffoli
+    pub t_count: usize,
+    /// rotations by any other angle, their T cost depends on the synthesis
+    pub rotations: usize,
+    /// layers of operations when every operation starts as early as its qubits allow
+    pub depth: usize,
+    /// one chain of operations of length `depth`, loop iterations that were added at once
+    /// stand in it as one entry
+    pub critical_path: Vec<String>,
+    pub peak_qubits: usize,
+}
+
+/// Analyzes `program` with its qudit instructions lowered to the qubit gates they stand
+/// for and loops counted as often as they run, so gate counts and depth describe what
+/// actually runs.
+///
+/// `REP` blocks are walked without being written out. Once an iteration leaves the qudits
+/// and live registers as it found them and moves every qubit it touched the same number of
+/// levels deeper, the remaining iterations would do exactly that again, so their counts and
+/// depth are added at once.
+pub fn analyze(program: &Program) -> Result<Stats, String> {
+    let ends: HashMap<usize, usize> = loops(program)?.into_iter().collect();
+    let mut walk = Walk::default();
+    walk.block(program, &ends, 0, program.instructions.len())?;
+
+    let mut gates = walk.gates;
+    gates.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let named = |names: &[&str]| {
+        gates
+            .iter()
+            .filter(|(op, _)| names.contains(&op.as_str()))
+            .map(|(_, n)| n)
+            .sum::<usize>()
+    };
+    // the deepest qubit, of equally deep ones the one that got there first
+    let end = walk
+        .last
+        .values()
+        .max_by_key(|(level, seq, _)| (*level, std::cmp::Reverse(*seq)));
+    let mut critical_path = vec![];
+    let mut at = end.map(|(_, _, step)| step.clone());
+    while let Some(step) = at {
+        critical_path.push(step.text.clone());
+        at = step.previous.clone();
+    }
+    critical_path.reverse();
+
+    Ok(Stats {
+        qubits: walk.qubits,
+        qudits: walk.qudits,
+        two_qubit: walk.two_qubit,
+        t_count: named(&["T", "TDG"]) + walk.t_rotations + TOFFOLI_T * named(&["TOF"]),
+        rotations: walk.rotations,
+        depth: end.map_or(0, |(level, _, _)| *level),
+        critical_path,
+        peak_qubits: walk.peak,
+        gates,
+    })
+}
+
+/// Iterations of a loop kept to compare the current one against, a qudit that cycles
+/// through its values only repeats after that many.
+const WINDOW: usize = 16;
+
+/// One operation of a critical path, shared by the paths that continue it.
+#[derive(Debug)]
+struct Step {
+    text: String,
+    previous: Option<Rc<Step>>,
+}
+
+impl Drop for Step {
+    // a path can be as long as the program runs, dropping it recursively would overflow
+    fn drop(&mut self) {
+        let mut next = self.previous.take();
+        while let Some(step) = next {
+            next = Rc::try_unwrap(step)
+                .ok()
+                .and_then(|mut step| step.previous.take());
+        }
+    }
+}
+
+/// What `analyze` has seen so far while walking the program in the order it runs.
+#[derive(Debug, Default)]
+struct Walk {
+    qubits: usize,
+    qudits: usize,
+    gates: Vec<(String, usize)>,
+    two_qubit: usize,
+    t_rotations: usize,
+    rotations: usize,
+    /// operations so far, orders operations of the same level
+    ops: usize,
+    /// per qubit the level of its last operation, when that ran and the path up to it
+    last: HashMap<String, (usize, usize, Rc<Step>)>,
+    /// qubits allocated and not freed yet, the same way `peak_qubits` counts them
+    live: HashSet<String>,
+    alive: usize,
+    peak: usize,
+    lowering: Qudits<String>,
+}
+
+/// The state of a `Walk` at the start of a loop iteration.
+struct Mark {
+    qubits: usize,
+    qudits: usize,
+    gates: Vec<(String, usize)>,
+    two_qubit: usize,
+    t_rotations: usize,
+    rotations: usize,
+    ops: usize,
+    levels: HashMap<String, usize>,
+    live: HashSet<String>,
+    alive: usize,
+    lowering: Qudits<String>,
+}
+
+impl Walk {
+    /// Walks the instructions in `from..to`, `ends` maps every `REP` to its `END`.
+    fn block(
+        &mut self,
+        program: &Program,
+        ends: &HashMap<usize, usize>,
+        from: usize,
+        to: usize,
+    ) -> Result<(), String> {
+        let mut pc = from;
+        while pc < to {
+            let ins = &program.instructions[pc];
+            if ins.op == "REP" {
+                let end = ends[&pc];
+                self.repeat(program, ends, pc + 1, end, repetitions(ins)?)?;
+                pc = end + 1;
+            } else {
+                self.instruction(ins)?;
+                pc += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks a loop body `n` times, or fewer and adds the rest once it repeats itself.
+    fn repeat(
+        &mut self,
+        program: &Program,
+        ends: &HashMap<usize, usize>,
+        start: usize,
+        end: usize,
+        n: usize,
+    ) -> Result<(), String> {
+        // the most recent iteration first
+        let mut marks: VecDeque<Mark> = VecDeque::new();
+        let mut done = 0;
+        while done < n {
+            marks.push_front(self.mark());
+            marks.truncate(WINDOW);
+            self.block(program, ends, start, end)?;
+            done += 1;
+            let period = marks
+                .iter()
+                .enumerate()
+                .find_map(|(k, mark)| Some((k + 1, self.shift(mark)?)));
+            if let Some((period, shift)) = period {
+                let times = (n - done) / period;
+                if times > 0 {
+                    self.skip(&marks[period - 1], shift, times, period);
+                    done += times * period;
+                    marks.clear();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn mark(&self) -> Mark {
+        Mark {
+            qubits: self.qubits,
+            qudits: self.qudits,
+            gates: self.gates.clone(),
+            two_qubit: self.two_qubit,
+            t_rotations: self.t_rotations,
+            rotations: self.rotations,
+            ops: self.ops,
+            levels: self
+                .last
+                .iter()
+                .map(|(q, (l, _, _))| (q.clone(), *l))
+                .collect(),
+            live: self.live.clone(),
+            alive: self.alive,
+            lowering: self.lowering.clone(),
+        }
+    }
+
+    /// How many levels deeper every qubit touched since `mark` got, if they all moved by
+    /// the same amount and nothing else changed that decides what the next iterations do.
+    fn shift(&self, mark: &Mark) -> Option<usize> {
+        if self.lowering != mark.lowering
+            || self.live != mark.live
+            || self.alive != mark.alive
+            || self.last.len() != mark.levels.len()
+        {
+            return None;
+        }
+        let mut shift = None;
+        for (q, (level, _, _)) in &self.last {
+            let before = *mark.levels.get(q)?;
+            if *level != before && *shift.get_or_insert(level - before) != level - before {
+                return None;
+            }
+        }
+        Some(shift.unwrap_or(0))
+    }
+
+    /// Adds `times` more runs of the `period` iterations since `mark`.
+    fn skip(&mut self, mark: &Mark, shift: usize, times: usize, period: usize) {
+        let more = |now: usize, then: usize| now + times * (now - then);
+        for (op, n) in self.gates.iter_mut() {
+            let then = mark
+                .gates
+                .iter()
+                .find(|(o, _)| o == op)
+                .map_or(0, |(_, n)| *n);
+            *n = more(*n, then);
+        }
+        self.qubits = more(self.qubits, mark.qubits);
+        self.qudits = more(self.qudits, mark.qudits);
+        self.two_qubit = more(self.two_qubit, mark.two_qubit);
+        self.t_rotations = more(self.t_rotations, mark.t_rotations);
+        self.rotations = more(self.rotations, mark.rotations);
+        let ops = self.ops - mark.ops;
+        self.ops += times * ops;
+        for (q, (level, seq, path)) in self.last.iter_mut() {
+            if *level != mark.levels[q] {
+                *level += times * shift;
+                *seq += times * ops;
+                *path = Rc::new(Step {
+                    text: format!("({} more iterations)", times * period),
+                    previous: Some(path.clone()),
+                });
+            }
+        }
+    }
+
+    fn instruction(&mut self, ins: &Instruction) -> Result<(), String> {
+        match ins.op.as_str() {
+            "QAL" => self.qubits += 1,
+            "DAL" => self.qudits += 1,
+            _ => {}
+        }
+        let mut lowered = vec![];
+        qudit::lower_step(&mut self.lowering, ins, &mut lowered)?;
+        for ins in &lowered {
+            self.operation(ins);
+        }
+        Ok(())
+    }
+
+    fn operation(&mut self, ins: &Instruction) {
+        match ins.op.as_str() {
+            "QAL" => {
+                if let Some(name) = ins.alloc_name() {
+                    // allocating a live name again leaves the old qubit allocated
+                    self.live.insert(name.to_string());
+                    self.alive += 1;
+                    self.peak = self.peak.max(self.alive);
+                }
+            }
+            "FRE" if self.live.remove(ins.alloc_name().unwrap_or("")) => self.alive -= 1,
+            _ => {}
+        }
+        if BOOKKEEPING.contains(&ins.op.as_str()) {
+            return;
+        }
+        match self.gates.iter_mut().find(|(op, _)| *op == ins.op) {
+            Some(entry) => entry.1 += 1,
+            None => self.gates.push((ins.op.clone(), 1)),
+        }
+        let qs = ins.qubits();
+        if qs.len() == 2 {
+            self.two_qubit += 1;
+        }
+        // multiples of pi/2 are Clifford, odd multiples of pi/4 a T gate up to Cliffords
+        if ["RX", "RY", "RZ"].contains(&ins.op.as_str()) {
+            let quarters = match ins.args.get(1) {
+                Some(Operand::Num(angle)) => Some(angle / FRAC_PI_4),
+                _ => None,
+            };
+            match quarters {
+                Some(k) if (k - k.round()).abs() < 1e-9 => {
+                    self.t_rotations += (k.round() as i64 % 2 != 0) as usize
+                }
+                _ => self.rotations += 1,
+            }
+        }
+
+        // of equally deep qubits the one touched last, the path continues from there
+        let before = qs
+            .iter()
+            .filter_map(|q| self.last.get(*q))
+            .max_by_key(|(level, seq, _)| (*level, *seq));
+        let level = before.map_or(0, |(level, _, _)| *level) + 1;
+        let step = Rc::new(Step {
+            text: ins.to_string().trim().to_string(),
+            previous: before.map(|(_, _, step)| step.clone()),
+        });
+        for q in qs {
+            self.last
+                .insert(q.to_string(), (level, self.ops, step.clone()));
+        }
+        self.ops += 1;
+    }
+}
+
+impl Stats {
+    /// Writes the registers of array elements on the critical path by their source name.
+    pub fn rename(&mut self, names: &HashMap<String, String>) {
+        for ins in &mut self.critical_path {
+            let words: Vec<String> = ins
+                .split(' ')
+                .map(|w| match w.strip_prefix('$').and_then(|r| names.get(r)) {
+                    Some(source) => format!("${source}"),
+                    None => w.to_string(),
+                })
+                .collect();
+            *ins = words.join(" ");
+        }
+    }
+
+    /// The estimate as aligned text, long critical paths are shortened in the middle.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let mut line = |label: &str, value: String| out.push_str(&format!("{label:<20}{value}\n"));
+        line("qubits", self.qubits.to_string());
+        line("qudits", self.qudits.to_string());
+        line("peak live qubits", self.peak_qubits.to_string());
+        let total: usize = self.gates.iter().map(|(_, n)| n).sum();
+        line("operations", total.to_string());
+        line("two-qubit gates", self.two_qubit.to_string());
+        line("T-count", self.t_count.to_string());
+        line("rotations", self.rotations.to_string());
+        line("depth", self.depth.to_string());
+        let path = &self.critical_path;
+        let shown = if path.len() > 12 {
+            [&path[..5], &["...".to_string()], &path[path.len() - 5..]].concat()
+        } else {
+            path.clone()
+        };
+        line("critical path", shown.join(" -> "));
+        out.push_str("gates\n");
+        for (op, n) in &self.gates {
+            out.push_str(&format!("  {op:<8}{n:>8}\n"));
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> Json {
+        let num = |n: usize| Json::Num(n as f64);
+        Json::obj(vec![
+            ("qubits", num(self.qubits)),
+            ("qudits", num(self.qudits)),
+            ("peak_qubits", num(self.peak_qubits)),
+            (
+                "gates",
+                Json::Obj(
+                    self.gates
+                        .iter()
+                        .map(|(op, n)| (op.clone(), num(*n)))
+                        .collect(),
+                ),
+            ),
+            ("two_qubit", num(self.two_qubit)),
+            ("t_count", num(self.t_count)),
+            ("rotations", num(self.rotations)),
+            ("depth", num(self.depth)),
+            (
+                "critical_path",
+                Json::Arr(self.critical_path.iter().map(|s| Json::str(s)).collect()),
+            ),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{parse_program, unroll};
+
+    const HEADER: &str = "QAL & 0 $ \"a\"\nQAL & 0 $ \"b\"\nQAL & 0 $ \"c\"\nDAL % # \"d\"\n";
+
+    /// Everything but the critical path, which shows skipped iterations as one entry.
+    fn counts(stats: Stats) -> Stats {
+        Stats {
+            critical_path: vec![],
+            ..stats
+        }
+    }
+
+    #[test]
+    fn loops_count_as_written_out() {
+        let bodies = [
+            "HAD $a\nCNT $a $b\nT $b\n",
+            "QAL & 0 $ \"e\"\nCNT $a $e\nFRE & $ \"e\"\nRZ $b 0.3\n",
+            "QAL & 0 $ \"e\"\nRY $e 0.7853981633974483\n",
+            // the qudit grows, no iteration leaves it as it found it
+            "DCX $c %d\nDHAD %d\n",
+            "REP 3\nTOF $a $b $c\nEND\nPX $a\n",
+        ];
+        for body in bodies {
+            for n in [0, 1, 2, 5, 40] {
+                let text = format!("{HEADER}REP {n}\n{body}END\nHAD $c\nMES $a\n");
+                let program = parse_program(&text).unwrap();
+                let written = unroll(&program).unwrap();
+                assert_eq!(
+                    counts(analyze(&program).unwrap()),
+                    counts(analyze(&written).unwrap()),
+                    "{n} times {body}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn long_loops_are_not_written_out() {
+        let text = format!("{HEADER}REP 50000000\nHAD $a\nCNT $a $b\nT $b\nEND\n");
+        let stats = analyze(&parse_program(&text).unwrap()).unwrap();
+        let gates: Vec<(&str, usize)> = stats.gates.iter().map(|(g, n)| (g.as_str(), *n)).collect();
+        assert_eq!(
+            gates,
+            [("CNT", 50000000), ("HAD", 50000000), ("T", 50000000)]
+        );
+        assert_eq!(stats.t_count, 50000000);
+        // every iteration adds HAD $a and CNT after the T of the one before
+        assert_eq!(stats.depth, 100000001);
+        assert!(stats
+            .critical_path
+            .contains(&"(49999998 more iterations)".to_string()));
+        // a block that never runs is skipped however large it is
+        let text = format!("{HEADER}REP 0\nREP 100000000\nREP 100000000\nPX $a\nEND\nEND\nEND\n");
+        assert_eq!(analyze(&parse_program(&text).unwrap()).unwrap().depth, 0);
+    }
+}
@@ -0,0 +1,209 @@
+/// The standard library, written in QEL and shipped inside the compiler. Programs import a
+/// module as `std/<name>.qel`, `use qft::qft3;` finds it as well unless a `qft.qel` of the
+/// program's own comes first.
+pub const MODULES: [(&str, &str); 4] = [
+    ("qft", include_str!("../std/qft.qel")),
+    ("grover", include_str!("../std/grover.qel")),
+    ("phase", include_str!("../std/phase.qel")),
+    ("arith", include_str!("../std/arith.qel")),
+];
+
+/// The source of the library module at `path`, e.g. `std/qft.qel`.
+pub fn source(path: &str) -> Option<&'static str> {
+    let name = path.strip_prefix("std/")?.strip_suffix(".qel")?;
+    MODULES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, src)| *src)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::{FRAC_PI_4, FRAC_PI_8, PI};
+
+    use crate::compiler::Compiler;
+    use crate::complex::{Complex, ONE, ZERO};
+    use crate::unitary::{phase_distance, qubits, unitary, Matrix};
+
+    fn from_fn(n: usize, entry: impl Fn(usize, usize) -> Complex) -> Matrix {
+        let dim = 1 << n;
+        (0..dim)
+            .map(|row| (0..dim).map(|col| entry(row, col)).collect())
+            .collect()
+    }
+
+    /// The permutation sending basis state `col` to `image(col)`.
+    fn permutation(n: usize, image: impl Fn(usize) -> usize) -> Matrix {
+        from_fn(n, |row, col| if image(col) == row { ONE } else { ZERO })
+    }
+
+    fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+        let dim = a.len();
+        from_fn(dim.trailing_zeros() as usize, |row, col| {
+            let mut x = ZERO;
+            for k in 0..dim {
+                x += a[row][k] * b[k][col];
+            }
+            x
+        })
+    }
+
+    fn bits(x: usize, from: usize, len: usize) -> usize {
+        (x >> from) & ((1 << len) - 1)
+    }
+
+    /// `e^(sign 2 pi i j k / 2^n) / sqrt(2^n)`, the QFT for `sign` 1 and its inverse for -1.
+    fn fourier(n: usize, sign: f64) -> Matrix {
+        let dim = (1 << n) as f64;
+        from_fn(n, |k, j| {
+            Complex::phase(sign * 2.0 * PI * (j * k) as f64 / dim).scale(1.0 / dim.sqrt())
+        })
+    }
+
+    fn reflection(n: usize) -> Matrix {
+        let dim = (1 << n) as f64;
+        from_fn(n, |row, col| {
+            Complex::real(2.0 / dim - if row == col { 1.0 } else { 0.0 })
+        })
+    }
+
+    /// Phase estimation of T: Hadamards on the counting qubits 0-2, T^K on qubit 3 for counting
+    /// value K, then the inverse QFT on the counting qubits.
+    fn phase_estimation() -> Matrix {
+        let same_target = |row: usize, col: usize| row >> 3 == col >> 3;
+        let hadamards = from_fn(4, |row, col| {
+            if !same_target(row, col) {
+                return ZERO;
+            }
+            let sign = if (row & col & 7).count_ones() % 2 == 1 {
+                -1.0
+            } else {
+                1.0
+            };
+            Complex::real(sign / 8f64.sqrt())
+        });
+        let kickback = from_fn(4, |row, col| match row == col {
+            true => Complex::phase(FRAC_PI_4 * (bits(col, 0, 3) * (col >> 3)) as f64),
+            false => ZERO,
+        });
+        let inverse = fourier(3, -1.0);
+        let readout = from_fn(4, |row, col| match same_target(row, col) {
+            true => inverse[row & 7][col & 7],
+            false => ZERO,
+        });
+        multiply(&readout, &multiply(&kickback, &hadamards))
+    }
+
+    fn adder() -> Matrix {
+        permutation(4, |x| {
+            let (a, b, c) = (x & 1, x >> 1 & 1, x >> 2 & 1);
+            let carry = (a & b) | (c & (a ^ b));
+            a | (a ^ b ^ c) << 1 | c << 2 | ((x >> 3) ^ carry) << 3
+        })
+    }
+
+    /// Registers `a` and `b` of `n` qubits, then carry in and carry out.
+    fn ripple_adder(n: usize) -> Matrix {
+        permutation(2 * n + 2, |x| {
+            let (a, b, c, z) = (
+                bits(x, 0, n),
+                bits(x, n, n),
+                bits(x, 2 * n, 1),
+                bits(x, 2 * n + 1, 1),
+            );
+            let sum = a + b + c;
+            a | bits(sum, 0, n) << n | c << (2 * n) | (z ^ (sum >> n)) << (2 * n + 1)
+        })
+    }
+
+    /// Compiles the calls of `functions` of `module`, each on the registers `q0`..`q{n-1}`,
+    /// and compares their unitary with `expected` up to global phase.
+    fn simulate(module: &str, functions: &[&str], n: usize, expected: Matrix) {
+        let names: Vec<String> = (0..n).map(|k| format!("q{k}")).collect();
+        let mut src = format!("import \"std/{module}.qel\";\n");
+        for name in &names {
+            src.push_str(&format!("let {name}: qbit;\n"));
+        }
+        for function in functions {
+            src.push_str(&format!("{module}::{function}({});\n", names.join(", ")));
+        }
+        let program = Compiler::new().compile_str(&src).unwrap().program;
+        let actual = unitary(&program, &qubits(&program), &[]).unwrap();
+        let error = phase_distance(&expected, &actual);
+        assert!(
+            error < 1e-9,
+            "{module}::{functions:?} is off by {error:.1e}"
+        );
+    }
+
+    #[test]
+    fn qft() {
+        for n in 2..=4 {
+            simulate("qft", &[&format!("qft{n}")], n, fourier(n, 1.0));
+            simulate("qft", &[&format!("iqft{n}")], n, fourier(n, -1.0));
+        }
+    }
+
+    #[test]
+    fn cphase8() {
+        let expected = from_fn(2, |row, col| match (row == col, row) {
+            (true, 3) => Complex::phase(FRAC_PI_8),
+            (true, _) => ONE,
+            _ => ZERO,
+        });
+        simulate("qft", &["cphase8"], 2, expected);
+    }
+
+    #[test]
+    fn swap2() {
+        simulate(
+            "qft",
+            &["swap2"],
+            2,
+            permutation(2, |x| x >> 1 | (x & 1) << 1),
+        );
+    }
+
+    #[test]
+    fn diffusion() {
+        simulate("grover", &["diffusion2"], 2, reflection(2));
+        simulate("grover", &["diffusion3"], 3, reflection(3));
+    }
+
+    #[test]
+    fn phase() {
+        let hadamards = from_fn(3, |row, col| {
+            let sign = if (row & col).count_ones() % 2 == 1 {
+                -1.0
+            } else {
+                1.0
+            };
+            Complex::real(sign / 8f64.sqrt())
+        });
+        simulate("phase", &["prepare3"], 3, hadamards);
+        simulate("phase", &["estimate_t3"], 4, phase_estimation());
+    }
+
+    #[test]
+    fn full_adder() {
+        simulate("arith", &["full_adder"], 4, adder());
+    }
+
+    #[test]
+    fn maj_and_uma() {
+        // the carry majority into c, then uma restores a and c and leaves the sum in b
+        let majority = permutation(3, |x| {
+            let (a, b, c) = (x & 1, x >> 1 & 1, x >> 2 & 1);
+            (a ^ c) | (b ^ c) << 1 | ((a & b) | (c & (a ^ b))) << 2
+        });
+        simulate("arith", &["maj"], 3, majority);
+        let sum = permutation(3, |x| x & 5 | ((x ^ x >> 1 ^ x >> 2) & 1) << 1);
+        simulate("arith", &["maj", "uma"], 3, sum);
+    }
+
+    #[test]
+    fn ripple_adders() {
+        simulate("arith", &["add2"], 6, ripple_adder(2));
+        simulate("arith", &["add3"], 8, ripple_adder(3));
+    }
+}
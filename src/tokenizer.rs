@@ -1,10 +1,20 @@
 use std::collections::HashSet;
 
-pub const __TOKENS: [&str; 55] = [
+pub const __TOKENS: [&str; 65] = [
     "//", "*/", "/*", "(", ")", "{", "}", "[", "]", ":", ";", ",", "=", "&", "*", "..", "let",
     "const", "struct", "qbit", "void", "#", "macro", "gate", "HAD", "PX", "PY", "PZ", "CNT", "CY",
     "ID", "TOF", "RX", "RY", "RZ", "S", "T", "SDG", "TDG", ".asdlkj", "if", "for", "in", "return",
-    "break", "$", "qudit", "MES", "TR", "DPX", "CMB", "DCB", "DHAD", "DCX", "DTR",
+    "break", "$", "qudit", "MES", "TR", "DPX", "CMB", "DCB", "DHAD", "DCX", "DTR", "ctrl", "inv",
+    "pow", "bit", "..=", "by", "+", "-", "++", "asm",
+];
+
+/// Keywords that read like names and so can't be used as one.
+pub const RESERVED: [&str; 5] = ["ctrl", "inv", "pow", "by", "asm"];
+
+/// Built-in gates and backend instructions that can be called from QEL.
+pub const GATES: [&str; 23] = [
+    "HAD", "PX", "PY", "PZ", "CNT", "CY", "ID", "TOF", "RX", "RY", "RZ", "S", "T", "SDG", "TDG",
+    "MES", "TR", "DPX", "CMB", "DCB", "DHAD", "DCX", "DTR",
 ];
 
 #[derive(Debug, Clone, PartialEq)]
@@ -14,33 +24,37 @@ pub struct Token {
 }
 
 pub fn rm_comments(input: &str) -> String {
+    let chars: Vec<char> = input.chars().collect();
+    // indexing the chars keeps this linear, `chars().nth(i)` walks the string every time
+    let pair =
+        |i: usize, a: char, b: char| i + 1 < chars.len() && chars[i] == a && chars[i + 1] == b;
     let mut output = String::new();
     let mut in_comment = false;
     let mut in_multiline_comment = false;
     let mut i = 0;
 
-    while i < input.len() {
+    while i < chars.len() {
         if in_multiline_comment {
-            if i + 1 < input.len() && &input[i..i + 2] == "*/" {
+            if pair(i, '*', '/') {
                 in_multiline_comment = false;
                 i += 2;
             } else {
                 i += 1;
             }
         } else if in_comment {
-            if input.chars().nth(i).unwrap() == '\n' {
+            if chars[i] == '\n' {
                 in_comment = false;
                 output.push('\n');
             }
             i += 1;
-        } else if i + 1 < input.len() && &input[i..i + 2] == "/*" {
+        } else if pair(i, '/', '*') {
             in_multiline_comment = true;
             i += 2;
-        } else if i + 1 < input.len() && &input[i..i + 2] == "//" {
+        } else if pair(i, '/', '/') {
             in_comment = true;
             i += 2;
         } else {
-            output.push(input.chars().nth(i).unwrap());
+            output.push(chars[i]);
             i += 1;
         }
     }
@@ -49,18 +63,35 @@ pub fn rm_comments(input: &str) -> String {
 }
 
 pub fn tokenize(input: &str) -> Vec<Token> {
-    let input = rm_comments(input);
+    tokenize_code(&rm_comments(input))
+}
+
+/// `tokenize` for source that is already free of comments.
+pub fn tokenize_code(input: &str) -> Vec<Token> {
+    let chars: Vec<char> = input.chars().collect();
+    // like `rm_comments`, `i` counts chars and never bytes
+    let at = |i: usize, token: &str| {
+        chars
+            .get(i..i + token.len())
+            .is_some_and(|s| s.iter().copied().eq(token.chars()))
+    };
     let mut tokens: Vec<Token> = Vec::new();
     let mut i = 0;
     let mut _str = String::new();
 
-    while i < input.len() {
+    while i < chars.len() {
+        if _str.is_empty() {
+            if let Some(len) = asm_block(&chars[i..], &mut tokens) {
+                i += len;
+                continue;
+            }
+        }
         let mut found = false;
         let mut longest_match_len = 0;
         let mut longest_match_token = None;
 
         for (j, token) in __TOKENS.iter().enumerate() {
-            if i + token.len() <= input.len() && &input[i..i + token.len()] == *token {
+            if at(i, token) {
                 if token.len() > longest_match_len {
                     longest_match_len = token.len();
                     longest_match_token = Some((j as i32, token));
@@ -68,12 +99,22 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                 found = true;
             }
         }
+        // a keyword at the end of a longer name, like the `T` of `xT`, is part of the name
+        let inside_name = longest_match_token
+            .is_some_and(|(_, t)| t.starts_with(|c: char| c.is_alphanumeric()))
+            && _str.ends_with(|c: char| c.is_alphanumeric() || c == '_');
         if found
-            && (['(', '[', ';', ' '].contains(&input.chars().nth(i + longest_match_len).unwrap())
+            && !inside_name
+            && (chars
+                .get(i + longest_match_len)
+                .is_some_and(|c| ['(', '[', ';', ' ', ',', ')'].contains(c))
                 || ((longest_match_len == 1
-                    && ['(', ')', '{', '}', '[', ']', ':', ';', ',', '&', '*', '$']
-                        .contains(&input.chars().nth(i).unwrap()))
-                    || &input[i..i + longest_match_len] == ".."))
+                    && [
+                        '(', ')', '{', '}', '[', ']', ':', ';', ',', '&', '*', '$', '+', '-',
+                    ]
+                    .contains(&chars[i]))
+                    || at(i, "..")
+                    || at(i, "++")))
         {
             if let Some((token_index, token_value)) = longest_match_token {
                 if !_str.is_empty() {
@@ -90,8 +131,16 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                 i += token_value.len();
             }
         } else {
-            let current_char = input.chars().nth(i).unwrap();
-            if current_char.is_whitespace()
+            let current_char = chars[i];
+            // decimal point of an angle like 0.785
+            let decimal = current_char == '.'
+                && !_str.is_empty()
+                && _str.chars().all(|c| c.is_ascii_digit())
+                && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit());
+            if decimal {
+                _str.push(current_char);
+                i += 1;
+            } else if current_char.is_whitespace()
                 || "!@#$%^&*()-=+[]{}|;:'\",.<>?/".contains(current_char)
             {
                 if !_str.is_empty() {
@@ -122,26 +171,89 @@ pub fn tokenize(input: &str) -> Vec<Token> {
         });
     }
 
+    bit_names(&mut tokens);
     tokens
 }
 
+// `bit` is a type after `:` and in front of a function definition, anywhere else it is a
+// name, e.g. in `let bit: qbit = 0; HAD(bit);`
+fn bit_names(tokens: &mut [Token]) {
+    for k in 0..tokens.len() {
+        if tokens[k].token != 58 {
+            continue;
+        }
+        let typed = k > 0 && tokens[k - 1].token == 9;
+        let statement = k == 0 || [5, 6, 10].contains(&tokens[k - 1].token);
+        let function = statement
+            && tokens.get(k + 1).is_some_and(|t| t.token == 70)
+            && tokens.get(k + 2).is_some_and(|t| t.token == 3);
+        if !typed && !function {
+            tokens[k].token = 70;
+        }
+    }
+}
+
+/// `asm { ... }` at the start of `input` as the `asm` and brace tokens around one token 73
+/// holding the body unchanged, its lines are backend instructions and not QEL. Returns the
+/// length of the block in chars.
+fn asm_block(input: &[char], tokens: &mut Vec<Token>) -> Option<usize> {
+    if !input.starts_with(&['a', 's', 'm']) {
+        return None;
+    }
+    let open = input[3..].iter().take_while(|c| c.is_whitespace()).count();
+    if input.get(3 + open) != Some(&'{') {
+        return None;
+    }
+    let body_start = 3 + open + 1;
+    let mut depth = 1;
+    let mut end = None;
+    for (k, c) in input[body_start..].iter().enumerate() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            end = Some(body_start + k);
+            break;
+        }
+    }
+    let body_end = end.unwrap_or(input.len());
+    let token = |token: i32, value: String| Token { token, value };
+    tokens.push(token(64, "asm".to_string()));
+    tokens.push(token(5, "{".to_string()));
+    tokens.push(token(73, input[body_start..body_end].iter().collect()));
+    match end {
+        Some(_) => {
+            tokens.push(token(6, "}".to_string()));
+            Some(body_end + 1)
+        }
+        None => Some(body_end),
+    }
+}
+
 pub fn is_num(s: String) -> bool {
     s.parse::<f64>().is_ok()
 }
 
-pub fn filter50s(mut tokens: Vec<Token>) -> Vec<Token> {
-    let mut lookup: HashSet<(u32, String)> = HashSet::new();
+pub fn filter50s(tokens: Vec<Token>) -> Vec<Token> {
+    filter50s_with(tokens, &mut HashSet::new())
+}
+
+// same as filter50s, but names declared in earlier calls stay known (used by the repl)
+pub fn filter50s_with(mut tokens: Vec<Token>, lookup: &mut HashSet<(u32, String)>) -> Vec<Token> {
     let mut current_scope: u32 = 0;
+    // parameters belong to the body that follows them, so two functions can both take `x`
+    let mut params = false;
+    let mut prev = -1;
     for tok in tokens.iter_mut() {
         match tok.token {
+            3 if prev == 70 => params = true,
+            4 => params = false,
             5 => current_scope += 1,
             6 => {
                 current_scope -= 1;
-                lookup = lookup
-                    .iter()
-                    .filter(|x| x.0 <= current_scope)
-                    .map(|x| x.to_owned())
-                    .collect();
+                lookup.retain(|x| x.0 <= current_scope);
             }
             70 => {
                 if lookup.iter().position(|s| s.1 == tok.value).is_some() {
@@ -150,12 +262,13 @@ pub fn filter50s(mut tokens: Vec<Token>) -> Vec<Token> {
                     tok.token = 72;
                 } else {
                     if !is_num(tok.value.clone()) {
-                        lookup.insert((current_scope, tok.value.clone()));
+                        lookup.insert((current_scope + params as u32, tok.value.clone()));
                     }
                 }
             }
             _ => {}
         }
+        prev = tok.token;
     }
     tokens
 }
@@ -169,3 +282,60 @@ pub fn filter_all(tokens: Vec<Token>) -> Vec<Token> {
     }
     cpy
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::parser::{from_tokens, Tok};
+
+    fn values(src: &str) -> Vec<String> {
+        tokenize_code(src).into_iter().map(|t| t.value).collect()
+    }
+
+    #[test]
+    fn keywords_at_the_end_of_a_name_stay_in_it() {
+        assert_eq!(values("HAD(xT)"), ["HAD", "(", "xT", ")"]);
+        assert_eq!(values("CNT(aS, b)"), ["CNT", "(", "aS", ",", "b", ")"]);
+        assert_eq!(values("HAD(pin)"), ["HAD", "(", "pin", ")"]);
+        assert_eq!(values("HAD(T)")[2], "T");
+        assert_eq!(tokenize_code("HAD(T)")[2].token, 36);
+        let cases = [
+            "let pin: qbit;\nHAD(pin);\n",
+            "let qubit: qbit;\nHAD(qubit);\n",
+            "let xT: qbit;\nHAD(xT);\n",
+            "let aS: qbit;\nlet b: qbit;\nCNT(aS, b);\n",
+            "let reg: qbit[2];\nlet cS: qbit;\nCNT(reg[0], cS);\n",
+        ];
+        for src in cases {
+            assert!(Compiler::new().compile_str(src).is_ok(), "{src}");
+        }
+    }
+
+    #[test]
+    fn names_may_hold_any_letter() {
+        assert_eq!(values("HAD(qä);"), ["HAD", "(", "qä", ")", ";"]);
+        assert_eq!(values("CNT(ä, ö);"), ["CNT", "(", "ä", ",", "ö", ")", ";"]);
+        assert_eq!(values("asm { PX $ä }\nT(ä);")[2], " PX $ä ");
+        assert_eq!(
+            values("asm { PX $ä }\nT(ä);")[4..],
+            ["T", "(", "ä", ")", ";"]
+        );
+    }
+
+    #[test]
+    fn gates_are_the_gate_call_tokens() {
+        // the comment markers never reach the parser
+        let calls: Vec<&str> = (3..__TOKENS.len())
+            .filter(|&j| {
+                let token = Token {
+                    token: j as i32,
+                    value: __TOKENS[j].to_string(),
+                };
+                matches!(from_tokens(vec![token])[..], [Tok::GateCall])
+            })
+            .map(|j| __TOKENS[j])
+            .collect();
+        assert_eq!(calls, GATES);
+    }
+}
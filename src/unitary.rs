@@ -0,0 +1,110 @@
+use crate::complex::{Complex, ONE, ZERO};
+use crate::ir::{trace, Program};
+use crate::sim::{Machine, Statevector};
+
+/// Largest register count a full unitary is built for, 2^10 x 2^10 entries.
+pub const MAX_QUBITS: usize = 10;
+
+/// `m[row][col]`, qubit k of the register list is bit k of the row and column index.
+pub type Matrix = Vec<Vec<Complex>>;
+
+/// The qubit registers of a program in the order they are first mentioned.
+pub fn qubits(program: &Program) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    for ins in &program.instructions {
+        let regs = match ins.op.as_str() {
+            "QAL" => ins.alloc_name().into_iter().collect(),
+            _ => ins.qubits(),
+        };
+        for q in regs {
+            if !names.iter().any(|n| n == q) {
+                names.push(q.to_string());
+            }
+        }
+    }
+    names
+}
+
+/// Runs `program` on `state`, an amplitude vector over `qubits` (bit k is `qubits[k]`), and
+/// returns the final amplitudes over the same registers. `QAL`/`DAL` of `qubits` are skipped,
+/// other registers are ancillas that have to end in |0>. Instructions that are not unitary
+/// (measurement, `SET`, copies) are rejected.
+pub fn apply(
+    program: &Program,
+    qubits: &[String],
+    state: Vec<Complex>,
+    externs: &[f64],
+) -> Result<Vec<Complex>, String> {
+    if let Some(ins) = program
+        .instructions
+        .iter()
+        .find(|i| ["MES", "SET", "CPY", "DCP"].contains(&i.op.as_str()))
+    {
+        return Err(format!("UNITARY_ERROR: '{ins}' is not a unitary operation"));
+    }
+    let n = qubits.len();
+    let mut m = Machine::new(Statevector { n, amps: state }, externs.to_vec(), 0);
+    for (k, q) in qubits.iter().enumerate() {
+        m.qubits.insert(q.clone(), k);
+    }
+    for ins in trace(program)? {
+        let input = ins
+            .alloc_name()
+            .is_some_and(|name| qubits.iter().any(|q| q == name));
+        if !(input && ["QAL", "DAL"].contains(&ins.op.as_str())) {
+            m.step(ins)?;
+        }
+    }
+    let mut amps = m.engine.amps;
+    let dirty: f64 = amps[1 << n..].iter().map(|a| a.norm_sqr()).sum();
+    if dirty > 1e-9 {
+        return Err(
+            "UNITARY_ERROR: ancilla registers are not returned to |0> at the end".to_string(),
+        );
+    }
+    amps.truncate(1 << n);
+    Ok(amps)
+}
+
+/// Builds the unitary of a gate sequence by simulating it on every basis state, see `apply`.
+pub fn unitary(program: &Program, qubits: &[String], externs: &[f64]) -> Result<Matrix, String> {
+    let n = qubits.len();
+    if n > MAX_QUBITS {
+        return Err(format!(
+            "UNITARY_ERROR: {n} qubits are too many for a full unitary, at most {MAX_QUBITS} are supported"
+        ));
+    }
+    let dim = 1 << n;
+    let mut u = vec![vec![ZERO; dim]; dim];
+    for col in 0..dim {
+        let mut amps = vec![ZERO; dim];
+        amps[col] = ONE;
+        for (row, amp) in apply(program, qubits, amps, externs)?
+            .into_iter()
+            .enumerate()
+        {
+            u[row][col] = amp;
+        }
+    }
+    Ok(u)
+}
+
+/// Largest entry wise difference between `a` and `b` after removing the global phase
+/// that best aligns them. Zero means the two are the same operation.
+pub fn phase_distance(a: &Matrix, b: &Matrix) -> f64 {
+    let mut overlap = ZERO;
+    for (ra, rb) in a.iter().zip(b) {
+        for (x, y) in ra.iter().zip(rb) {
+            overlap += x.conj() * *y;
+        }
+    }
+    let phase = if overlap.abs() < 1e-12 {
+        ONE
+    } else {
+        overlap.scale(1.0 / overlap.abs())
+    };
+    a.iter()
+        .zip(b)
+        .flat_map(|(ra, rb)| ra.iter().zip(rb).map(|(x, y)| (*x * phase - *y).abs()))
+        .fold(0.0, f64::max)
+}
@@ -0,0 +1,925 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
+use crate::basis::translate;
+use crate::ir::{Instruction, Operand, Program};
+use crate::opt::optimize;
+use crate::stats;
+
+/// Gates a diagram is built from. Toffoli and CY are decomposed into them first.
+const GATES: [&str; 14] = [
+    "HAD", "PX", "PY", "PZ", "S", "SDG", "T", "TDG", "SX", "ID", "RX", "RY", "RZ", "CNT",
+];
+
+/// Bound on the rounds of gadget fusion, every round removes spiders so this is never hit
+/// in practice.
+const ROUNDS: usize = 1000;
+
+/// Instructions that commute with every gate, they are moved in front of the gates around
+/// them instead of ending the run of gates that is simplified as one diagram.
+const PASSIVE: [&str; 4] = ["QAL", "BAL", "BCP", "BFR"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edge {
+    Simple,
+    Hadamard,
+}
+
+impl Edge {
+    fn toggle(self) -> Edge {
+        match self {
+            Edge::Simple => Edge::Hadamard,
+            Edge::Hadamard => Edge::Simple,
+        }
+    }
+}
+
+fn normalize(phase: f64) -> f64 {
+    let p = phase.rem_euclid(2.0 * PI);
+    if p < 1e-9 || 2.0 * PI - p < 1e-9 {
+        0.0
+    } else {
+        p
+    }
+}
+
+/// `phase` as a multiple of pi/2, if it is one.
+fn quarter(phase: f64) -> Option<u8> {
+    let k = phase / FRAC_PI_2;
+    ((k - k.round()).abs() < 1e-9).then(|| (k.round() as i64).rem_euclid(4) as u8)
+}
+
+fn is_pauli(phase: f64) -> bool {
+    matches!(quarter(phase), Some(0 | 2))
+}
+
+fn is_proper_clifford(phase: f64) -> bool {
+    matches!(quarter(phase), Some(1 | 3))
+}
+
+/// A graph-like ZX diagram: Z spiders, boundaries with exactly one edge, and Hadamard edges
+/// between spiders. Scalars are dropped, so everything holds up to global phase.
+#[derive(Debug, Clone, Default)]
+struct Graph {
+    boundary: Vec<bool>,
+    phase: Vec<f64>,
+    edges: Vec<BTreeMap<usize, Edge>>,
+    removed: Vec<bool>,
+    inputs: Vec<usize>,
+    outputs: Vec<usize>,
+}
+
+impl Graph {
+    fn add(&mut self, boundary: bool, phase: f64) -> usize {
+        self.boundary.push(boundary);
+        self.phase.push(normalize(phase));
+        self.edges.push(BTreeMap::new());
+        self.removed.push(false);
+        self.boundary.len() - 1
+    }
+
+    fn spiders(&self) -> Vec<usize> {
+        (0..self.boundary.len())
+            .filter(|v| !self.removed[*v] && !self.boundary[*v])
+            .collect()
+    }
+
+    fn alive(&self, v: usize) -> bool {
+        !self.removed[v]
+    }
+
+    fn neighbors(&self, v: usize) -> Vec<usize> {
+        self.edges[v].keys().copied().collect()
+    }
+
+    fn degree(&self, v: usize) -> usize {
+        self.edges[v].len()
+    }
+
+    fn edge(&self, u: usize, v: usize) -> Option<Edge> {
+        self.edges[u].get(&v).copied()
+    }
+
+    fn set_edge(&mut self, u: usize, v: usize, e: Edge) {
+        self.edges[u].insert(v, e);
+        self.edges[v].insert(u, e);
+    }
+
+    fn remove_edge(&mut self, u: usize, v: usize) {
+        self.edges[u].remove(&v);
+        self.edges[v].remove(&u);
+    }
+
+    fn remove(&mut self, v: usize) {
+        for w in self.neighbors(v) {
+            self.remove_edge(v, w);
+        }
+        self.removed[v] = true;
+    }
+
+    fn add_phase(&mut self, v: usize, phase: f64) {
+        self.phase[v] = normalize(self.phase[v] + phase);
+    }
+
+    /// Adds an edge between spiders, applying the Hopf rule to parallel Hadamard edges and
+    /// turning a Hadamard self-loop into a pi phase.
+    fn connect(&mut self, u: usize, v: usize, e: Edge) {
+        if u == v {
+            if e == Edge::Hadamard {
+                self.add_phase(u, PI);
+            }
+            return;
+        }
+        match (self.edge(u, v), e) {
+            (None, _) => self.set_edge(u, v, e),
+            (Some(Edge::Hadamard), Edge::Hadamard) => self.remove_edge(u, v),
+            (Some(Edge::Simple), Edge::Simple) => {}
+            (Some(_), _) => {
+                self.set_edge(u, v, Edge::Simple);
+                self.add_phase(u, PI);
+            }
+        }
+    }
+
+    fn toggle(&mut self, u: usize, v: usize) {
+        self.connect(u, v, Edge::Hadamard);
+    }
+
+    /// Spider fusion: `v` is merged into `u`, the two have to be joined by a plain wire. A
+    /// Hadamard edge between them becomes a self-loop.
+    fn fuse(&mut self, u: usize, v: usize) {
+        self.add_phase(u, self.phase[v]);
+        for (w, e) in std::mem::take(&mut self.edges[v]) {
+            self.edges[w].remove(&v);
+            self.connect(u, w, e);
+        }
+        self.removed[v] = true;
+    }
+
+    fn touches_boundary(&self, v: usize) -> bool {
+        self.edges[v].keys().any(|w| self.boundary[*w])
+    }
+
+    /// A spider whose neighbors are all spiders, joined by Hadamard edges.
+    fn interior(&self, v: usize) -> bool {
+        self.alive(v)
+            && !self.boundary[v]
+            && self.edges[v]
+                .iter()
+                .all(|(w, e)| !self.boundary[*w] && *e == Edge::Hadamard)
+    }
+
+    /// Has a neighbor of degree one, the leaf of a phase gadget.
+    fn is_hub(&self, v: usize) -> bool {
+        self.edges[v]
+            .keys()
+            .any(|w| self.degree(*w) == 1 && !self.boundary[*w])
+    }
+
+    /// An interior Pauli spider that pivoting may remove, gadget hubs are kept.
+    fn pivotable(&self, v: usize) -> bool {
+        self.interior(v) && is_pauli(self.phase[v]) && !self.is_hub(v)
+    }
+
+    /// The diagram of a gate sequence on `qubits` registers.
+    fn from_gates(gates: &[Instruction], qubits: &[&str]) -> Graph {
+        let mut g = Graph::default();
+        // the last vertex of every wire and whether a Hadamard is pending on it
+        let mut wires: Vec<(usize, bool)> = vec![];
+        for _ in qubits {
+            let b = g.add(true, 0.0);
+            g.inputs.push(b);
+            wires.push((b, false));
+        }
+        let index = |name: &str| qubits.iter().position(|q| *q == name).unwrap();
+        let push = |g: &mut Graph, wires: &mut [(usize, bool)], q: usize, phase: f64| {
+            let (last, hadamard) = wires[q];
+            if !hadamard && !g.boundary[last] {
+                g.add_phase(last, phase);
+                return last;
+            }
+            let v = g.add(false, phase);
+            let e = if hadamard {
+                Edge::Hadamard
+            } else {
+                Edge::Simple
+            };
+            g.set_edge(last, v, e);
+            wires[q] = (v, false);
+            v
+        };
+        let had = |wires: &mut [(usize, bool)], q: usize| wires[q].1 = !wires[q].1;
+        for ins in gates {
+            let qs: Vec<usize> = ins.qubits().iter().map(|q| index(q)).collect();
+            let angle = match ins.args.get(1) {
+                Some(Operand::Num(a)) => *a,
+                _ => 0.0,
+            };
+            let q = qs[0];
+            match ins.op.as_str() {
+                "HAD" => had(&mut wires, q),
+                "PZ" => _ = push(&mut g, &mut wires, q, PI),
+                "S" => _ = push(&mut g, &mut wires, q, FRAC_PI_2),
+                "SDG" => _ = push(&mut g, &mut wires, q, -FRAC_PI_2),
+                "T" => _ = push(&mut g, &mut wires, q, FRAC_PI_4),
+                "TDG" => _ = push(&mut g, &mut wires, q, -FRAC_PI_4),
+                "RZ" => _ = push(&mut g, &mut wires, q, angle),
+                "PX" | "SX" | "RX" => {
+                    let phase = match ins.op.as_str() {
+                        "PX" => PI,
+                        "SX" => FRAC_PI_2,
+                        _ => angle,
+                    };
+                    had(&mut wires, q);
+                    push(&mut g, &mut wires, q, phase);
+                    had(&mut wires, q);
+                }
+                // Y = X Z and RY = S RX SDG, both up to global phase
+                "PY" | "RY" => {
+                    let (before, phase) = match ins.op.as_str() {
+                        "PY" => (PI, PI),
+                        _ => (-FRAC_PI_2, angle),
+                    };
+                    push(&mut g, &mut wires, q, before);
+                    had(&mut wires, q);
+                    push(&mut g, &mut wires, q, phase);
+                    had(&mut wires, q);
+                    if ins.op == "RY" {
+                        push(&mut g, &mut wires, q, FRAC_PI_2);
+                    }
+                }
+                "CNT" => {
+                    let c = push(&mut g, &mut wires, q, 0.0);
+                    had(&mut wires, qs[1]);
+                    let t = push(&mut g, &mut wires, qs[1], 0.0);
+                    had(&mut wires, qs[1]);
+                    g.toggle(c, t);
+                }
+                _ => {}
+            }
+        }
+        for (last, hadamard) in wires {
+            let b = g.add(true, 0.0);
+            g.outputs.push(b);
+            let e = if hadamard {
+                Edge::Hadamard
+            } else {
+                Edge::Simple
+            };
+            g.set_edge(last, b, e);
+        }
+        g
+    }
+
+    /// Removes phase-free spiders with two neighbors, fusing the neighbors.
+    fn identities(&mut self) -> bool {
+        let mut changed = false;
+        for v in self.spiders() {
+            if !self.alive(v) || self.phase[v] != 0.0 || self.degree(v) != 2 {
+                continue;
+            }
+            let [(a, ea), (b, eb)] = [0, 1].map(|k| {
+                let (w, e) = self.edges[v].iter().nth(k).unwrap();
+                (*w, *e)
+            });
+            if self.boundary[a] || self.boundary[b] {
+                continue;
+            }
+            if ea == eb && self.touches_boundary(a) && self.touches_boundary(b) {
+                continue;
+            }
+            self.remove(v);
+            match ea == eb {
+                true => self.fuse(a, b),
+                false => self.toggle(a, b),
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    /// Local complementation removes interior spiders with phase +-pi/2.
+    fn local_complementations(&mut self) -> bool {
+        let mut changed = false;
+        for v in self.spiders() {
+            if !self.interior(v) || !is_proper_clifford(self.phase[v]) {
+                continue;
+            }
+            let ns = self.neighbors(v);
+            let phase = self.phase[v];
+            for (i, a) in ns.iter().enumerate() {
+                for b in &ns[i + 1..] {
+                    self.toggle(*a, *b);
+                }
+                self.add_phase(*a, -phase);
+            }
+            self.remove(v);
+            changed = true;
+        }
+        changed
+    }
+
+    /// Pivoting along the Hadamard edge `u`-`v` between two Pauli spiders removes both.
+    fn pivot(&mut self, u: usize, v: usize) {
+        let nu: BTreeSet<usize> = self.neighbors(u).into_iter().filter(|w| *w != v).collect();
+        let nv: BTreeSet<usize> = self.neighbors(v).into_iter().filter(|w| *w != u).collect();
+        let shared: Vec<usize> = nu.intersection(&nv).copied().collect();
+        let only_u: Vec<usize> = nu.difference(&nv).copied().collect();
+        let only_v: Vec<usize> = nv.difference(&nu).copied().collect();
+        for (xs, ys) in [(&only_u, &only_v), (&only_u, &shared), (&only_v, &shared)] {
+            for x in xs {
+                for y in ys {
+                    self.toggle(*x, *y);
+                }
+            }
+        }
+        let (pu, pv) = (self.phase[u], self.phase[v]);
+        for w in &only_u {
+            self.add_phase(*w, pv);
+        }
+        for w in &only_v {
+            self.add_phase(*w, pu);
+        }
+        for w in &shared {
+            self.add_phase(*w, pu + pv + PI);
+        }
+        self.remove(u);
+        self.remove(v);
+    }
+
+    fn pivots(&mut self) -> bool {
+        let mut changed = false;
+        for u in self.spiders() {
+            if !self.pivotable(u) {
+                continue;
+            }
+            let partner = self.neighbors(u).into_iter().find(|v| self.pivotable(*v));
+            if let Some(v) = partner {
+                self.pivot(u, v);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Pivots an interior Pauli spider with a Pauli neighbor on the boundary, after moving
+    /// the boundary edge onto a new spider.
+    fn boundary_pivots(&mut self) -> bool {
+        let mut changed = false;
+        for u in self.spiders() {
+            if !self.pivotable(u) {
+                continue;
+            }
+            let partner = self.neighbors(u).into_iter().find(|v| {
+                let boundaries = self.edges[*v].keys().filter(|w| self.boundary[**w]).count();
+                boundaries == 1
+                    && is_pauli(self.phase[*v])
+                    && !self.is_hub(*v)
+                    && self.edges[*v]
+                        .iter()
+                        .all(|(w, e)| self.boundary[*w] || *e == Edge::Hadamard)
+            });
+            if let Some(v) = partner {
+                self.unfuse_boundary(v);
+                self.pivot(u, v);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Replaces the boundary edge of `v` by a Hadamard edge to a new phase-free spider that
+    /// takes over the boundary.
+    fn unfuse_boundary(&mut self, v: usize) -> usize {
+        let (b, e) = self.edges[v]
+            .iter()
+            .find(|(w, _)| self.boundary[**w])
+            .map(|(w, e)| (*w, *e))
+            .unwrap();
+        self.remove_edge(v, b);
+        let w = self.add(false, 0.0);
+        self.set_edge(w, b, e.toggle());
+        self.set_edge(v, w, Edge::Hadamard);
+        w
+    }
+
+    /// Interior non-Clifford spiders next to an interior Pauli spider become phase gadgets:
+    /// the phase moves to a leaf that hangs off a new hub, then the pair is pivoted away.
+    fn gadget_pivots(&mut self) -> bool {
+        let mut changed = false;
+        for u in self.spiders() {
+            if !self.pivotable(u) {
+                continue;
+            }
+            let partner = self.neighbors(u).into_iter().find(|v| {
+                self.interior(*v)
+                    && quarter(self.phase[*v]).is_none()
+                    && self.degree(*v) > 1
+                    && !self.is_hub(*v)
+            });
+            if let Some(v) = partner {
+                let phase = self.phase[v];
+                self.phase[v] = 0.0;
+                let hub = self.add(false, 0.0);
+                let leaf = self.add(false, phase);
+                self.set_edge(v, hub, Edge::Hadamard);
+                self.set_edge(hub, leaf, Edge::Hadamard);
+                self.pivot(u, v);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// The leaf of `hub` if it is the hub of a phase gadget.
+    fn leaf(&self, hub: usize) -> Option<usize> {
+        if !self.interior(hub) || !is_pauli(self.phase[hub]) {
+            return None;
+        }
+        let leaves: Vec<usize> = self.edges[hub]
+            .keys()
+            .copied()
+            .filter(|w| self.degree(*w) == 1)
+            .collect();
+        match leaves.as_slice() {
+            [leaf] if self.degree(hub) > 1 => Some(*leaf),
+            _ => None,
+        }
+    }
+
+    /// Fuses phase gadgets acting on the same spiders and drops the ones without phase.
+    fn gadgets(&mut self) -> bool {
+        let mut changed = false;
+        let mut seen: BTreeMap<Vec<usize>, usize> = BTreeMap::new();
+        for hub in self.spiders() {
+            let Some(leaf) = self.leaf(hub) else {
+                continue;
+            };
+            if self.phase[hub] != 0.0 {
+                self.phase[hub] = 0.0;
+                self.phase[leaf] = normalize(-self.phase[leaf]);
+            }
+            let support: Vec<usize> = self
+                .neighbors(hub)
+                .into_iter()
+                .filter(|w| *w != leaf)
+                .collect();
+            let target = match support.as_slice() {
+                [single] => Some(*single),
+                _ => None,
+            };
+            if let Some(&first) = seen.get(&support) {
+                self.add_phase(first, self.phase[leaf]);
+                self.remove(hub);
+                self.remove(leaf);
+                changed = true;
+            } else if let Some(s) = target {
+                self.add_phase(s, self.phase[leaf]);
+                self.remove(hub);
+                self.remove(leaf);
+                changed = true;
+            } else if self.phase[leaf] == 0.0 {
+                self.remove(hub);
+                self.remove(leaf);
+                changed = true;
+            } else {
+                seen.insert(support, leaf);
+            }
+        }
+        for leaf in seen.into_values() {
+            if !self.alive(leaf) || self.phase[leaf] != 0.0 {
+                continue;
+            }
+            let hub = self.neighbors(leaf)[0];
+            if self.leaf(hub) == Some(leaf) && self.phase[hub] == 0.0 {
+                self.remove(hub);
+                self.remove(leaf);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn interior_clifford(&mut self) {
+        while self.identities() || self.local_complementations() || self.pivots() {}
+    }
+
+    fn clifford(&mut self) {
+        loop {
+            self.interior_clifford();
+            if !self.boundary_pivots() {
+                break;
+            }
+        }
+    }
+
+    /// Spider fusion, local complementation and pivoting until nothing changes, with
+    /// non-Clifford phases moved into gadgets so the ones on the same qubits merge.
+    fn simplify(&mut self) {
+        self.interior_clifford();
+        self.gadget_pivots();
+        for _ in 0..ROUNDS {
+            self.clifford();
+            let fused = self.gadgets();
+            self.interior_clifford();
+            let pivoted = self.gadget_pivots();
+            if !fused && !pivoted {
+                break;
+            }
+        }
+        for v in self.spiders() {
+            if self.degree(v) == 0 {
+                self.remove(v);
+            }
+        }
+    }
+}
+
+/// A gate of the extracted circuit, on qubit indices.
+#[derive(Debug, Clone, Copy)]
+enum Gate {
+    Had(usize),
+    Phase(usize, f64),
+    Cz(usize, usize),
+    Cnot(usize, usize),
+    Swap(usize, usize),
+}
+
+/// Gaussian elimination over GF(2), returns the row additions `(dst, src)` it made.
+fn eliminate(m: &mut [Vec<bool>]) -> Vec<(usize, usize)> {
+    let mut ops = vec![];
+    let cols = m.first().map_or(0, |r| r.len());
+    let mut add = |m: &mut [Vec<bool>], dst: usize, src: usize| {
+        let added = m[src].clone();
+        for (x, y) in m[dst].iter_mut().zip(added) {
+            *x ^= y;
+        }
+        ops.push((dst, src));
+    };
+    let mut row = 0;
+    for col in 0..cols {
+        let Some(p) = (row..m.len()).find(|r| m[*r][col]) else {
+            continue;
+        };
+        if p != row {
+            add(m, row, p);
+        }
+        for r in 0..m.len() {
+            if r != row && m[r][col] {
+                add(m, r, row);
+            }
+        }
+        row += 1;
+    }
+    ops
+}
+
+/// Turns a diagram with a causal flow structure back into a circuit, peeling gates off the
+/// outputs (Backens et al., "There and back again", 2021).
+fn extract(g: &mut Graph) -> Result<Vec<Gate>, String> {
+    let n = g.outputs.len();
+    let mut rev = vec![];
+    let mut frontier: Vec<Option<usize>> = vec![None; n];
+    for (i, &o) in g.outputs.clone().iter().enumerate() {
+        let v = g.neighbors(o)[0];
+        if g.edge(o, v) == Some(Edge::Hadamard) {
+            rev.push(Gate::Had(i));
+            g.set_edge(o, v, Edge::Simple);
+        }
+        if !g.boundary[v] {
+            frontier[i] = Some(v);
+        }
+    }
+    let budget = 4 * g.boundary.len() + 16;
+    for _ in 0..budget {
+        for (i, v) in frontier.iter().enumerate() {
+            if let Some(v) = *v {
+                if g.phase[v] != 0.0 {
+                    rev.push(Gate::Phase(i, g.phase[v]));
+                    g.phase[v] = 0.0;
+                }
+            }
+        }
+        for i in 0..n {
+            for j in i + 1..n {
+                if let (Some(a), Some(b)) = (frontier[i], frontier[j]) {
+                    if g.edge(a, b).is_some() {
+                        rev.push(Gate::Cz(i, j));
+                        g.remove_edge(a, b);
+                    }
+                }
+            }
+        }
+        let mut neighbors = BTreeSet::new();
+        for (i, slot) in frontier.iter_mut().enumerate() {
+            let Some(v) = *slot else {
+                continue;
+            };
+            let mut d: Vec<usize> = g
+                .neighbors(v)
+                .into_iter()
+                .filter(|w| *w != g.outputs[i])
+                .collect();
+            if let Some(b) = d.iter().copied().find(|w| g.inputs.contains(w)) {
+                if d.len() == 1 {
+                    if g.edge(v, b) == Some(Edge::Hadamard) {
+                        rev.push(Gate::Had(i));
+                        g.set_edge(v, b, Edge::Simple);
+                    }
+                    *slot = None;
+                    continue;
+                }
+                let e = g.edge(v, b).unwrap();
+                g.remove_edge(v, b);
+                let w = g.add(false, 0.0);
+                g.set_edge(w, b, e.toggle());
+                g.set_edge(v, w, Edge::Hadamard);
+                d.retain(|x| *x != b);
+                d.push(w);
+            }
+            neighbors.extend(d);
+        }
+        if frontier.iter().all(Option::is_none) {
+            return Ok(finish(g, rev));
+        }
+        // a frontier spider next to a gadget hub is pivoted with it, the gadget's phase
+        // becomes an ordinary spider
+        let gadget = (0..n).find_map(|i| {
+            let v = frontier[i]?;
+            g.neighbors(v)
+                .into_iter()
+                .find(|w| g.leaf(*w).is_some())
+                .map(|hub| (i, v, hub))
+        });
+        if let Some((i, v, hub)) = gadget {
+            let o = g.outputs[i];
+            g.remove_edge(v, o);
+            let z = g.add(false, 0.0);
+            g.set_edge(z, o, Edge::Simple);
+            g.set_edge(v, z, Edge::Hadamard);
+            g.pivot(v, hub);
+            rev.push(Gate::Had(i));
+            frontier[i] = Some(z);
+            continue;
+        }
+        let rows: Vec<usize> = (0..n).filter(|i| frontier[*i].is_some()).collect();
+        let cols: Vec<usize> = neighbors.into_iter().collect();
+        let mut m: Vec<Vec<bool>> = rows
+            .iter()
+            .map(|i| {
+                let v = frontier[*i].unwrap();
+                cols.iter().map(|w| g.edge(v, *w).is_some()).collect()
+            })
+            .collect();
+        // elimination costs CNOTs, it is only needed if no spider can be extracted as is
+        if !m.iter().any(|row| row.iter().filter(|x| **x).count() == 1) {
+            for (dst, src) in eliminate(&mut m) {
+                rev.push(Gate::Cnot(rows[dst], rows[src]));
+            }
+        }
+        let mut extracted = false;
+        for (r, i) in rows.iter().enumerate() {
+            let v = frontier[*i].unwrap();
+            for (c, w) in cols.iter().enumerate() {
+                match (m[r][c], g.edge(v, *w).is_some()) {
+                    (true, false) => g.set_edge(v, *w, Edge::Hadamard),
+                    (false, true) => g.remove_edge(v, *w),
+                    _ => {}
+                }
+            }
+        }
+        for (r, i) in rows.iter().enumerate() {
+            let ones: Vec<usize> = (0..cols.len()).filter(|c| m[r][*c]).collect();
+            if let [c] = ones.as_slice() {
+                let (v, w) = (frontier[*i].unwrap(), cols[*c]);
+                rev.push(Gate::Had(*i));
+                g.remove(v);
+                g.set_edge(w, g.outputs[*i], Edge::Simple);
+                frontier[*i] = Some(w);
+                extracted = true;
+            }
+        }
+        if !extracted {
+            return Err(
+                "ZX_ERROR: no spider can be extracted, the diagram has no flow".to_string(),
+            );
+        }
+    }
+    Err("ZX_ERROR: extraction did not finish".to_string())
+}
+
+/// The wires left between inputs and outputs form a permutation, realised with swaps that
+/// come first in time.
+fn finish(g: &Graph, mut rev: Vec<Gate>) -> Vec<Gate> {
+    let n = g.outputs.len();
+    let mut source = vec![0; n];
+    for (i, &o) in g.outputs.iter().enumerate() {
+        let mut v = g.neighbors(o)[0];
+        if !g.boundary[v] {
+            v = g.neighbors(v).into_iter().find(|w| *w != o).unwrap();
+        }
+        source[i] = g.inputs.iter().position(|b| *b == v).unwrap();
+    }
+    // content[k] is the input whose state is on qubit k
+    let mut content: Vec<usize> = (0..n).collect();
+    let mut swaps = vec![];
+    for (i, src) in source.iter().enumerate() {
+        let k = content.iter().position(|c| c == src).unwrap();
+        if k != i {
+            swaps.push(Gate::Swap(k, i));
+            content.swap(k, i);
+        }
+    }
+    rev.extend(swaps.into_iter().rev());
+    rev.reverse();
+    rev
+}
+
+fn instructions(gates: &[Gate], qubits: &[&str]) -> Vec<Instruction> {
+    let q = |i: usize| Operand::Qubit(qubits[i].to_string());
+    let one = |op: &str, i: usize| Instruction::new(op, vec![q(i)]);
+    let cnt = |c: usize, t: usize| Instruction::new("CNT", vec![q(c), q(t)]);
+    let mut out = vec![];
+    for gate in gates {
+        match *gate {
+            Gate::Had(i) => out.push(one("HAD", i)),
+            Gate::Cnot(c, t) => out.push(cnt(c, t)),
+            Gate::Cz(a, b) => out.extend([one("HAD", b), cnt(a, b), one("HAD", b)]),
+            Gate::Swap(a, b) => out.extend([cnt(a, b), cnt(b, a), cnt(a, b)]),
+            Gate::Phase(i, phase) => {
+                let k = phase / FRAC_PI_4;
+                let ops: &[&str] = match ((k - k.round()).abs() < 1e-9).then(|| k.round() as i64) {
+                    Some(1) => &["T"],
+                    Some(2) => &["S"],
+                    Some(3) => &["S", "T"],
+                    Some(4) => &["PZ"],
+                    Some(5) => &["PZ", "T"],
+                    Some(6) => &["SDG"],
+                    Some(7) => &["TDG"],
+                    Some(_) => &[],
+                    None => {
+                        out.push(Instruction::new("RZ", vec![q(i), Operand::Num(phase)]));
+                        &[]
+                    }
+                };
+                out.extend(ops.iter().map(|op| one(op, i)));
+            }
+        }
+    }
+    out
+}
+
+/// T-count and CNOTs of a gate sequence.
+fn cost(gates: &[Instruction]) -> Result<(usize, usize), String> {
+    let stats = stats::analyze(&Program {
+        instructions: gates.to_vec(),
+    })?;
+    let cnots = gates.iter().filter(|i| i.op == "CNT").count();
+    Ok((stats.t_count, cnots))
+}
+
+/// Simplifies one run of gates, keeping the original unless the T-count drops, or stays
+/// the same with fewer CNOTs.
+fn reduce_run(gates: Vec<Instruction>) -> Result<Vec<Instruction>, String> {
+    if gates.is_empty() {
+        return Ok(gates);
+    }
+    let mut qubits: Vec<&str> = vec![];
+    for q in gates.iter().flat_map(|i| i.qubits()) {
+        if !qubits.contains(&q) {
+            qubits.push(q);
+        }
+    }
+    let mut g = Graph::from_gates(&gates, &qubits);
+    g.simplify();
+    let Ok(extracted) = extract(&mut g) else {
+        return Ok(gates);
+    };
+    let (reduced, _) = optimize(
+        &Program {
+            instructions: instructions(&extracted, &qubits),
+        },
+        2,
+    );
+    let (before, after) = (cost(&gates)?, cost(&reduced.instructions)?);
+    Ok(
+        match after.0 < before.0 || (after.0 == before.0 && after.1 < before.1) {
+            true => reduced.instructions,
+            false => gates,
+        },
+    )
+}
+
+fn is_gate(ins: &Instruction) -> bool {
+    GATES.contains(&ins.op.as_str())
+        && ins
+            .args
+            .iter()
+            .all(|a| matches!(a, Operand::Qubit(_) | Operand::Num(_)))
+}
+
+/// What the ZX pass did, counted on the program after Toffoli and CY gates are decomposed.
+#[derive(Debug, Clone)]
+pub struct Reduction {
+    pub program: Program,
+    pub t_before: usize,
+    pub t_after: usize,
+    pub cnots_before: usize,
+    pub cnots_after: usize,
+}
+
+/// Rewrites every run of gates as a ZX diagram, simplifies it and extracts a circuit from
+/// the result again. Qudit instructions are lowered first, measurements, `SET`, frees and
+/// rotations by extern angles end a run.
+pub fn reduce(program: &Program) -> Result<Reduction, String> {
+    let basis: Vec<String> = GATES.iter().map(|g| g.to_string()).collect();
+    let program = translate(program, &basis)?;
+    let mut instructions = vec![];
+    let mut run = vec![];
+    for ins in &program.instructions {
+        if is_gate(ins) {
+            run.push(ins.clone());
+        } else if PASSIVE.contains(&ins.op.as_str()) {
+            instructions.push(ins.clone());
+        } else {
+            instructions.extend(reduce_run(std::mem::take(&mut run))?);
+            instructions.push(ins.clone());
+        }
+    }
+    instructions.extend(reduce_run(run)?);
+    let (t_before, cnots_before) = cost(&program.instructions)?;
+    let (t_after, cnots_after) = cost(&instructions)?;
+    Ok(Reduction {
+        program: Program { instructions },
+        t_before,
+        t_after,
+        cnots_before,
+        cnots_after,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_program;
+    use crate::unitary::{phase_distance, qubits, unitary};
+
+    /// `text` after a `QAL` of each of `names`.
+    fn program(names: &[&str], text: &str) -> Program {
+        let mut src: String = names
+            .iter()
+            .map(|q| format!("QAL & 0 $ \"{q}\"\n"))
+            .collect();
+        src.push_str(text);
+        parse_program(&src).unwrap()
+    }
+
+    /// Reduces `program` and checks that the result is the same unitary up to global phase.
+    fn reduce_same(program: &Program) -> Reduction {
+        let reduced = reduce(program).unwrap();
+        let names = qubits(program);
+        let before = unitary(program, &names, &[]).unwrap();
+        let after = unitary(&reduced.program, &names, &[]).unwrap();
+        let error = phase_distance(&before, &after);
+        assert!(error < 1e-9, "{} is off by {error:.1e}", reduced.program);
+        reduced
+    }
+
+    #[test]
+    fn extraction_keeps_the_unitary() {
+        let circuits = [
+            "HAD $a\nT $a\nCNT $a $b\nS $b\nHAD $b\nCNT $b $c\nTDG $c\nRZ $a 0.3\n",
+            "TOF $a $b $c\nHAD $a\nCNT $c $a\nT $b\nCY $b $c\nSX $c\n",
+            "RX $a 0.7\nCNT $a $b\nRY $b -1.1\nCNT $b $c\nT $c\nHAD $c\nCNT $c $a\nTDG $a\n",
+        ];
+        for text in circuits {
+            reduce_same(&program(&["a", "b", "c"], text));
+        }
+    }
+
+    #[test]
+    fn t_count_drops() {
+        // two T on the parity of a and b are an S on it
+        let parity = "CNT $a $b\nT $b\nCNT $a $b\nHAD $a\nHAD $a\nCNT $a $b\nT $b\nCNT $a $b\n";
+        let reduced = reduce_same(&program(&["a", "b"], parity));
+        assert_eq!((reduced.t_before, reduced.t_after), (2, 0));
+        // a Toffoli undone by a second one
+        let reduced = reduce_same(&program(&["a", "b", "c"], "TOF $a $b $c\nTOF $a $b $c\n"));
+        assert_eq!(reduced.t_before, 14);
+        assert_eq!((reduced.t_after, reduced.cnots_after), (0, 0));
+    }
+
+    #[test]
+    fn measurements_end_a_run() {
+        let p = program(&["a"], "T $a\nBAL \"c\" 1\nMES $a \"c\" 0\nT $a\n");
+        let reduced = reduce(&p).unwrap();
+        let ops: Vec<&str> = reduced
+            .program
+            .instructions
+            .iter()
+            .map(|i| i.op.as_str())
+            .collect();
+        // the BAL commutes with the gates and moves in front of them
+        assert_eq!(ops, ["QAL", "BAL", "T", "MES", "T"]);
+    }
+}